@@ -0,0 +1,119 @@
+use std::{sync::Arc, time::Instant};
+
+use rspotify::{
+    clients::{BaseClient, OAuthClient},
+    http::{HttpClient, Query},
+    sync::Mutex,
+    AuthCodeSpotify, ClientResult, Config, Credentials, OAuth, Token,
+};
+use serde_json::Value;
+
+use super::rate_limit::{RateLimitConfig, RateLimiter, RateLimiterStats};
+use crate::metrics::METRICS;
+
+/// `AuthCodeSpotify` with a rate limiter on its Web API requests
+#[derive(Clone, Debug, Default)]
+pub struct SpotifyClient {
+    inner: AuthCodeSpotify,
+    limiter: Arc<RateLimiter>,
+}
+
+impl SpotifyClient {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            inner: AuthCodeSpotify::default(),
+            limiter: Arc::new(RateLimiter::new(config)),
+        }
+    }
+
+    pub fn rate_limiter_stats(&self) -> RateLimiterStats {
+        self.limiter.stats()
+    }
+
+    /// Run a raw upstream request, e.g. one with a non-JSON body,
+    /// under the rate limiter
+    pub async fn limited<T, F>(&self, request: F) -> ClientResult<T>
+    where
+        F: std::future::Future<Output = ClientResult<T>>,
+    {
+        self.limiter.run(request).await
+    }
+}
+
+/// The singleflight key of a GET request
+fn flight_key(url: &str, payload: &Query<'_>) -> String {
+    let mut params: Vec<_> = payload.iter().collect();
+    params.sort();
+    let params: Vec<_> = params.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    format!("{}?{}", url, params.join("&"))
+}
+
+/// Record the latency and errors of an upstream request
+async fn observe<T, F>(method: &str, url: &str, request: F) -> ClientResult<T>
+where
+    F: std::future::Future<Output = ClientResult<T>>,
+{
+    let started_at = Instant::now();
+    let result = request.await;
+    METRICS.observe_spotify_request(method, url, started_at, &result);
+    result
+}
+
+#[async_trait::async_trait]
+impl BaseClient for SpotifyClient {
+    fn get_config(&self) -> &Config {
+        self.inner.get_config()
+    }
+
+    fn get_http(&self) -> &HttpClient {
+        self.inner.get_http()
+    }
+
+    fn get_creds(&self) -> &Credentials {
+        self.inner.get_creds()
+    }
+
+    fn get_token(&self) -> Arc<Mutex<Option<Token>>> {
+        self.inner.get_token()
+    }
+
+    async fn refetch_token(&self) -> ClientResult<Option<Token>> {
+        self.inner.refetch_token().await
+    }
+
+    async fn api_get(&self, url: &str, payload: &Query<'_>) -> ClientResult<String> {
+        let key = flight_key(url, payload);
+        self.limiter
+            .coalesce(key, observe("GET", url, self.inner.api_get(url, payload)))
+            .await
+    }
+
+    async fn api_post(&self, url: &str, payload: &Value) -> ClientResult<String> {
+        self.limiter
+            .run(observe("POST", url, self.inner.api_post(url, payload)))
+            .await
+    }
+
+    async fn api_put(&self, url: &str, payload: &Value) -> ClientResult<String> {
+        self.limiter
+            .run(observe("PUT", url, self.inner.api_put(url, payload)))
+            .await
+    }
+
+    async fn api_delete(&self, url: &str, payload: &Value) -> ClientResult<String> {
+        self.limiter
+            .run(observe("DELETE", url, self.inner.api_delete(url, payload)))
+            .await
+    }
+}
+
+#[async_trait::async_trait]
+impl OAuthClient for SpotifyClient {
+    fn get_oauth(&self) -> &OAuth {
+        self.inner.get_oauth()
+    }
+
+    async fn request_token(&self, code: &str) -> ClientResult<()> {
+        self.inner.request_token(code).await
+    }
+}
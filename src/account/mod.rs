@@ -1,23 +1,41 @@
 use std::{
     collections::{HashMap, HashSet},
     path::Path,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use tokio::{sync, time::timeout};
+use tracing::Instrument;
 
 use chrono::{DateTime, Utc};
 use librespot::core::{
     authentication::Credentials, cache::Cache, config::SessionConfig, keymaster, session::Session,
 };
-use rspotify::AuthCodeSpotify;
+use rspotify::{
+    clients::{BaseClient, OAuthClient},
+    model::Market,
+};
 use tokio::sync::RwLock;
 use url::Url;
 
-use crate::{common::crypto, errors::ServerError};
+use crate::{
+    common::crypto,
+    errors::ServerError,
+    events::{EventKind, EVENTS},
+    metrics::METRICS,
+};
 
+pub mod client;
+pub mod rate_limit;
 pub mod utils;
 
+use client::SpotifyClient;
+use rate_limit::RateLimitConfig;
+
+/// How long the `Market::FromToken` fallback is kept when `/me` fails, so the
+/// requests of a rate-limited account don't each try `/me` again
+const MARKET_FALLBACK_TTL: Duration = Duration::from_secs(60);
+
 struct Expiration {
     expires_in: i64,
     token_expiration: DateTime<Utc>,
@@ -42,12 +60,14 @@ impl Default for Expiration {
 pub struct SpotifyAccount {
     pub credentials: Credentials,
     pub session: RwLock<Session>,
-    pub client: AuthCodeSpotify,
+    pub client: SpotifyClient,
     expiration: RwLock<Expiration>,
+    // The country of the account's profile, and when a fallback expires
+    market: RwLock<Option<(Market, Option<Instant>)>>,
     cache: Option<Cache>,
     proxy: Option<Url>,
-    // Secret key
-    secret: [u8; 16],
+    // Key of the signed uris, derived from the server secret and the username
+    sign_key: SignKey,
     lock: sync::Mutex<()>,
 }
 
@@ -56,6 +76,8 @@ impl SpotifyAccount {
         credentials: Credentials,
         cache: Cache,
         proxy: Option<Url>,
+        rate_limit: RateLimitConfig,
+        secret: [u8; 32],
     ) -> Result<Self, ServerError> {
         let config = SessionConfig {
             proxy: proxy.clone(),
@@ -64,16 +86,16 @@ impl SpotifyAccount {
         let (session, credentials) =
             Session::connect(config, credentials.clone(), Some(cache.clone()), true).await?;
 
-        let client: AuthCodeSpotify = AuthCodeSpotify::default();
-        let secret: [u8; 16] = rand::random();
+        let client = SpotifyClient::new(rate_limit);
         let account = SpotifyAccount {
             credentials,
             session: RwLock::new(session),
             client,
             expiration: RwLock::new(Expiration::default()),
+            market: RwLock::new(None),
             cache: Some(cache),
             proxy,
-            secret,
+            sign_key: SignKey::new(secret),
             lock: sync::Mutex::new(()),
         };
 
@@ -84,12 +106,14 @@ impl SpotifyAccount {
         credentials: Credentials,
         cache_dir: Option<P>,
         proxy: Option<Url>,
+        rate_limit: RateLimitConfig,
+        secret: [u8; 32],
     ) -> Result<Self, ServerError>
     where
         P: AsRef<Path>,
     {
         let cache = Cache::new(cache_dir, None, None, None)?;
-        SpotifyAccount::new(credentials, cache, proxy).await
+        SpotifyAccount::new(credentials, cache, proxy, rate_limit, secret).await
     }
 
     async fn token_expires(&self) -> bool {
@@ -99,14 +123,10 @@ impl SpotifyAccount {
     }
 
     async fn set_token(&self, token: keymaster::Token) -> Result<(), ServerError> {
-        println!(
-            "----------------\n================ toke.expires_in: {}\n----------------",
-            token.expires_in
-        );
+        tracing::info!(expires_in = token.expires_in, "Set token");
 
-        let mut rtoken = self
-            .client
-            .token
+        let token_lock = self.client.get_token();
+        let mut rtoken = token_lock
             .lock()
             .await
             .map_err(|e| ServerError::InnerError(format!("can't update token: {:?}", e)))?;
@@ -137,18 +157,32 @@ impl SpotifyAccount {
                 let mut session = self.session.write().await;
                 session.shutdown();
                 *session = new_session;
-                if let Ok(token) = keymaster::get_token(&session, client_id, scope).await {
+                METRICS.session_reset(&self.credentials.username);
+                EVENTS.emit(
+                    EventKind::SessionReset,
+                    &self.credentials.username,
+                    serde_json::json!({}),
+                );
+                let token = keymaster::get_token(&session, client_id, scope)
+                    .instrument(tracing::info_span!("keymaster::get_token"))
+                    .await;
+                if let Ok(token) = token {
                     self.set_token(token).await.unwrap();
                 }
                 break;
             } else {
                 tracing::warn!("Fail reset session");
+                EVENTS.emit(
+                    EventKind::SessionResetFailed,
+                    &self.credentials.username,
+                    serde_json::json!({}),
+                );
             }
         }
     }
 
     pub async fn update_token(&self, client_id: &str, scope: &str) -> Result<(), ServerError> {
-        let lock = self.lock.lock().await;
+        let _lock = self.lock.lock().await;
 
         if !self.token_expires().await {
             return Ok(());
@@ -156,11 +190,25 @@ impl SpotifyAccount {
 
         tracing::info!("Token expires");
         let session = self.session.read().await;
-        if let Ok(token) = keymaster::get_token(&session, client_id, scope).await {
+        let token = keymaster::get_token(&session, client_id, scope)
+            .instrument(tracing::info_span!("keymaster::get_token"))
+            .await;
+        if let Ok(token) = token {
+            METRICS.token_refreshed(&self.credentials.username);
+            EVENTS.emit(
+                EventKind::TokenRefreshed,
+                &self.credentials.username,
+                serde_json::json!({}),
+            );
             return self.set_token(token).await;
         }
 
         tracing::warn!("keymaster::get_token fails");
+        EVENTS.emit(
+            EventKind::TokenRefreshFailed,
+            &self.credentials.username,
+            serde_json::json!({ "reason": "keymaster::get_token failed" }),
+        );
 
         drop(session);
 
@@ -185,6 +233,11 @@ impl SpotifyAccount {
                 Ok(result) => return result,
                 Err(err) => {
                     if i + 1 == retries {
+                        EVENTS.emit(
+                            EventKind::TokenRefreshFailed,
+                            &self.credentials.username,
+                            serde_json::json!({ "reason": "timeout", "retries": retries }),
+                        );
                         return Err(ServerError::InnerError(format!(
                             "Failed to update token after {} retries, the last error is {}",
                             retries, err
@@ -193,22 +246,78 @@ impl SpotifyAccount {
                 }
             }
         }
-        return Err(ServerError::InnerError(format!(
+        Err(ServerError::InnerError(format!(
             "Failed to update token after {} retries",
             retries
-        )));
+        )))
+    }
+
+    /// The default market of the account, which is the country of `/me`
+    ///
+    /// It falls back to `Market::FromToken` when the country is unknown, and
+    /// for `MARKET_FALLBACK_TTL` when `/me` fails.
+    pub async fn default_market(&self) -> Market {
+        if let Some((market, expires_at)) = *self.market.read().await {
+            if expires_at.is_none_or(|expires_at| Instant::now() < expires_at) {
+                return market;
+            }
+        }
+
+        let cached = match self.client.me().await {
+            Ok(user) => {
+                let market = user
+                    .country
+                    .map(Market::Country)
+                    .unwrap_or(Market::FromToken);
+                (market, None)
+            }
+            Err(err) => {
+                tracing::warn!("Failed to get the default market: {:?}", err);
+                (
+                    Market::FromToken,
+                    Some(Instant::now() + MARKET_FALLBACK_TTL),
+                )
+            }
+        };
+        *self.market.write().await = Some(cached);
+        cached.0
     }
 
-    /// AES-128 encryption with `SpotifyAccount.secret`
+    pub fn sign_key(&self) -> &SignKey {
+        &self.sign_key
+    }
+}
+
+/// The key of a user's signed uris, the AES-128 key and the HMAC-SHA256 key
+pub struct SignKey([u8; 32]);
+
+impl SignKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        SignKey(key)
+    }
+
+    /// AES-128 encryption, followed by the HMAC-SHA256 of the iv and the
+    /// ciphertext
     pub fn encrypt(&self, buf: &[u8]) -> (Vec<u8>, [u8; 16]) {
+        let (key, mac_key) = self.0.split_at(16);
         let iv: [u8; 16] = rand::random();
-        let enc = crypto::encrypt_aes128(&self.secret, &iv, buf);
+        let mut enc = crypto::encrypt_aes128(key, &iv, buf);
+        let mac = crypto::hmac_sha256(mac_key, &[&iv[..], &enc].concat());
+        enc.extend_from_slice(&mac);
         (enc, iv)
     }
 
-    /// AES-128 decryption with `SpotifyAccount.secret`
+    /// AES-128 decryption, once the HMAC-SHA256 of `encrypt` is verified
     pub fn decrypt(&self, iv: &[u8], buf: &[u8]) -> Result<Vec<u8>, ServerError> {
-        crypto::decrypt_aes128(&self.secret, iv, buf)
+        let (key, mac_key) = self.0.split_at(16);
+        let Some(mac_start) = buf.len().checked_sub(32) else {
+            return Err(ServerError::InnerError("Missing signature".to_owned()));
+        };
+        let (enc, mac) = buf.split_at(mac_start);
+        if !crypto::verify_hmac_sha256(mac_key, &[iv, enc].concat(), mac) {
+            return Err(ServerError::InnerError("Invalid signature".to_owned()));
+        }
+        crypto::decrypt_aes128(key, iv, enc)
             .map_err(|e| ServerError::InnerError(format!("{:?}", e)))
     }
 }
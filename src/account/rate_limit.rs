@@ -0,0 +1,375 @@
+//! Spotify Web API rate limit awareness
+//!
+//! Spotify answers `429 Too Many Requests` with a `Retry-After` header when an
+//! account sends too many requests. `RateLimiter` remembers it, so following
+//! requests of the account wait (queue) or fail fast (shed) instead of making
+//! things worse. It also coalesces identical in-flight GET requests.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use rspotify::{http::HttpError, ClientError, ClientResult};
+use tokio::sync::{broadcast, Semaphore};
+
+/// The wait when Spotify doesn't send `Retry-After`
+const DEFAULT_RETRY_AFTER: u64 = 1;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimitConfig {
+    /// Requests wait at most this long for a `Retry-After` to pass,
+    /// otherwise they are shed
+    pub max_wait: Duration,
+    /// The maximum number of concurrent upstream requests, the rest queue
+    pub max_concurrency: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_wait: Duration::from_secs(10),
+            max_concurrency: 8,
+        }
+    }
+}
+
+/// A snapshot of the `RateLimiter` state
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct RateLimiterStats {
+    /// Seconds until Spotify accepts requests again
+    pub blocked_seconds: u64,
+    /// Requests waiting for a `Retry-After` or a concurrency slot
+    pub queued: u64,
+    /// Upstream requests in flight
+    pub in_flight: u64,
+    /// `429` responses received from Spotify
+    pub throttled_total: u64,
+    /// Requests rejected without calling Spotify
+    pub shed_total: u64,
+    /// Requests served by another identical in-flight request
+    pub coalesced_total: u64,
+}
+
+/// Per-account rate limiter of Spotify Web API requests
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    blocked_until: Mutex<Option<Instant>>,
+    slots: Semaphore,
+    // The followers of in-flight GET requests, `None` means the leader failed
+    flights: Mutex<HashMap<String, broadcast::Sender<Option<String>>>>,
+    queued: AtomicU64,
+    in_flight: AtomicU64,
+    throttled_total: AtomicU64,
+    shed_total: AtomicU64,
+    coalesced_total: AtomicU64,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(RateLimitConfig::default())
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            blocked_until: Mutex::new(None),
+            slots: Semaphore::new(config.max_concurrency.max(1)),
+            flights: Mutex::new(HashMap::new()),
+            queued: AtomicU64::new(0),
+            in_flight: AtomicU64::new(0),
+            throttled_total: AtomicU64::new(0),
+            shed_total: AtomicU64::new(0),
+            coalesced_total: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> RateLimiterStats {
+        RateLimiterStats {
+            blocked_seconds: self.blocked_for().map(|d| d.as_secs()).unwrap_or(0),
+            queued: self.queued.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            throttled_total: self.throttled_total.load(Ordering::Relaxed),
+            shed_total: self.shed_total.load(Ordering::Relaxed),
+            coalesced_total: self.coalesced_total.load(Ordering::Relaxed),
+        }
+    }
+
+    fn blocked_for(&self) -> Option<Duration> {
+        let blocked_until = (*self.blocked_until.lock().unwrap())?;
+        blocked_until.checked_duration_since(Instant::now())
+    }
+
+    /// Block the following requests for `seconds`
+    fn block(&self, seconds: u64) {
+        let until = Instant::now() + Duration::from_secs(seconds);
+        let mut blocked_until = self.blocked_until.lock().unwrap();
+        if blocked_until.map(|u| u < until).unwrap_or(true) {
+            *blocked_until = Some(until);
+        }
+    }
+
+    /// Run an upstream request after `Retry-After` passes and a concurrency slot is free
+    ///
+    /// The request is shed with a synthetic `429` when it would wait longer than
+    /// `RateLimitConfig::max_wait`.
+    pub async fn run<T, F>(&self, request: F) -> ClientResult<T>
+    where
+        F: std::future::Future<Output = ClientResult<T>>,
+    {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        let permit = self.wait().await;
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        let _permit = permit?;
+
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let result = request.await;
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+
+        if let Err(ClientError::Http(err)) = &result {
+            if let HttpError::StatusCode(response) = err.as_ref() {
+                if response.status().as_u16() == 429 {
+                    let seconds = response
+                        .headers()
+                        .get(reqwest::header::RETRY_AFTER)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.parse().ok())
+                        .unwrap_or(DEFAULT_RETRY_AFTER);
+                    tracing::warn!("Spotify rate limits the account for {} seconds", seconds);
+                    self.throttled_total.fetch_add(1, Ordering::Relaxed);
+                    self.block(seconds);
+                }
+            }
+        }
+        result
+    }
+
+    async fn wait(&self) -> ClientResult<tokio::sync::SemaphorePermit<'_>> {
+        let deadline = Instant::now() + self.config.max_wait;
+        loop {
+            match self.blocked_for() {
+                Some(wait) if Instant::now() + wait > deadline => {
+                    self.shed_total.fetch_add(1, Ordering::Relaxed);
+                    return Err(too_many_requests(wait.as_secs().max(1)));
+                }
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => break,
+            }
+        }
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match tokio::time::timeout(remaining, self.slots.acquire()).await {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => {
+                self.shed_total.fetch_add(1, Ordering::Relaxed);
+                Err(too_many_requests(DEFAULT_RETRY_AFTER))
+            }
+        }
+    }
+
+    /// Run a GET request once for all identical concurrent callers (singleflight)
+    pub async fn coalesce<F>(&self, key: String, request: F) -> ClientResult<String>
+    where
+        F: std::future::Future<Output = ClientResult<String>>,
+    {
+        let follower = {
+            let mut flights = self.flights.lock().unwrap();
+            match flights.get(&key) {
+                Some(sender) => Some(sender.subscribe()),
+                None => {
+                    let (sender, _) = broadcast::channel(1);
+                    flights.insert(key.clone(), sender);
+                    None
+                }
+            }
+        };
+
+        if let Some(mut receiver) = follower {
+            if let Ok(Some(body)) = receiver.recv().await {
+                self.coalesced_total.fetch_add(1, Ordering::Relaxed);
+                return Ok(body);
+            }
+            // The leader failed, so make the request on our own
+            return self.run(request).await;
+        }
+
+        let flight = Flight { limiter: self, key };
+        let result = self.run(request).await;
+        flight.finish(result.as_ref().ok().cloned());
+        result
+    }
+}
+
+/// The leader of an in-flight GET request
+///
+/// It removes the flight when the leader finishes or is dropped (cancelled).
+struct Flight<'a> {
+    limiter: &'a RateLimiter,
+    key: String,
+}
+
+impl Flight<'_> {
+    fn finish(mut self, body: Option<String>) {
+        let key = std::mem::take(&mut self.key);
+        let sender = self.limiter.flights.lock().unwrap().remove(&key);
+        if let Some(sender) = sender {
+            let _ = sender.send(body);
+        }
+    }
+}
+
+impl Drop for Flight<'_> {
+    fn drop(&mut self) {
+        if !self.key.is_empty() {
+            self.limiter.flights.lock().unwrap().remove(&self.key);
+        }
+    }
+}
+
+/// A synthetic `429` which is handled as Spotify's own
+fn too_many_requests(retry_after: u64) -> ClientError {
+    let response = http::Response::builder()
+        .status(429)
+        .header(reqwest::header::RETRY_AFTER, retry_after)
+        .body("")
+        .expect("valid response");
+    ClientError::from(HttpError::StatusCode(reqwest::Response::from(response)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    fn limiter(max_wait: Duration, max_concurrency: usize) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            max_wait,
+            max_concurrency,
+        })
+    }
+
+    /// The `Retry-After` seconds of a `429`, `None` for other results
+    fn retry_after<T>(result: &ClientResult<T>) -> Option<u64> {
+        match result {
+            Err(ClientError::Http(err)) => match err.as_ref() {
+                HttpError::StatusCode(response) if response.status().as_u16() == 429 => response
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse().ok()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn identical_gets_make_one_upstream_call() {
+        let limiter = limiter(Duration::from_secs(10), 8);
+        let calls = AtomicUsize::new(0);
+        let request = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok("body".to_owned())
+        };
+
+        let results = futures::future::join_all(
+            (0..5).map(|_| limiter.coalesce("/tracks/a".to_owned(), request())),
+        )
+        .await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(results
+            .iter()
+            .all(|result| result.as_deref().ok() == Some("body")));
+        assert_eq!(limiter.stats().coalesced_total, 4);
+        assert!(limiter.flights.lock().unwrap().is_empty());
+
+        // Other keys and later calls go upstream
+        let (a, b) = tokio::join!(
+            limiter.coalesce("/tracks/a".to_owned(), request()),
+            limiter.coalesce("/tracks/b".to_owned(), request()),
+        );
+        assert!(a.is_ok() && b.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn cancelled_leader_removes_its_flight() {
+        let limiter = limiter(Duration::from_secs(10), 8);
+        let cancelled = tokio::time::timeout(
+            Duration::from_millis(10),
+            limiter.coalesce("/tracks/a".to_owned(), async {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                Ok("body".to_owned())
+            }),
+        )
+        .await;
+        assert!(cancelled.is_err());
+        assert!(limiter.flights.lock().unwrap().is_empty());
+
+        let result = limiter
+            .coalesce("/tracks/a".to_owned(), async { Ok("next".to_owned()) })
+            .await;
+        assert_eq!(result.unwrap(), "next");
+    }
+
+    #[tokio::test]
+    async fn retry_after_blocks_until_the_deadline() {
+        let limiter = limiter(Duration::from_secs(10), 8);
+        let throttled = limiter
+            .run(async { Err::<(), _>(too_many_requests(1)) })
+            .await;
+        assert_eq!(retry_after(&throttled), Some(1));
+        assert_eq!(limiter.stats().throttled_total, 1);
+        assert!(limiter.blocked_for().is_some());
+
+        let start = Instant::now();
+        let result = limiter.run(async { Ok(Instant::now()) }).await.unwrap();
+        assert!(result.duration_since(start) >= Duration::from_millis(900));
+        assert!(limiter.blocked_for().is_none());
+        assert_eq!(limiter.stats().shed_total, 0);
+    }
+
+    #[tokio::test]
+    async fn requests_are_shed_instead_of_waiting_too_long() {
+        // Blocked past the longest wait
+        let limiter = limiter(Duration::from_secs(1), 8);
+        limiter.block(60);
+        let calls = AtomicUsize::new(0);
+        let result = limiter
+            .run(async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })
+            .await;
+        assert!(retry_after(&result).is_some_and(|seconds| seconds >= 59));
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        assert_eq!(limiter.stats().shed_total, 1);
+
+        // A full queue, as the only slot is taken for longer
+        let limiter = self::limiter(Duration::from_millis(50), 1);
+        let (slow, queued) = tokio::join!(
+            limiter.run(async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                Ok(())
+            }),
+            async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                limiter.run(async { Ok(()) }).await
+            },
+        );
+        assert!(slow.is_ok());
+        assert_eq!(retry_after(&queued), Some(DEFAULT_RETRY_AFTER));
+        let stats = limiter.stats();
+        assert_eq!(stats.shed_total, 1);
+        assert_eq!((stats.queued, stats.in_flight), (0, 0));
+    }
+}
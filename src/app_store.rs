@@ -1,13 +1,29 @@
-use std::path::PathBuf;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    time::Duration,
+};
 
 use librespot::{core::cache::Cache, discovery::Credentials};
+use rand::RngCore;
 use tokio::sync::{self, RwLockReadGuard};
 use url::Url;
 
 use crate::{
-    account::{utils::load_credentials, SpotifyAccount, SpotifyAccounts, UserName},
-    common::retry::retry,
+    account::{
+        rate_limit::RateLimitConfig, utils::load_credentials, SpotifyAccount, SpotifyAccounts,
+        UserName,
+    },
+    cache::ResponseCache,
+    common::crypto,
+    config::Reloader,
+    cors::CorsConfig,
+    dlna::DlnaConfig,
     errors::ServerError,
+    quota::Quotas,
+    scrobble::{Playback, Scrobbler},
+    session_store::FileSessionStore,
+    webhooks::Webhooks,
 };
 
 // pub const DEFAULT_CLIENT_ID: &str = "a7cebe3e317645469d64c7d374a1aa10";
@@ -15,7 +31,11 @@ use crate::{
 // pub const DEFAULT_SCOPE: &str = "user-read-private,playlist-read-private,playlist-read-collaborative,playlist-modify-public,playlist-modify-private,user-follow-modify,user-follow-read,user-library-read,user-library-modify,user-top-read,user-read-recently-played";
 
 pub const DEFAULT_CLIENT_ID: &str = "d420a117a32841c2b3474932e49fb54b";
-pub const DEFAULT_SCOPE: &str = "user-read-private,playlist-read-private,playlist-read-collaborative,playlist-modify-public,playlist-modify-private,user-follow-modify,user-follow-read,user-library-read,user-library-modify,user-top-read,user-read-recently-played";
+pub const DEFAULT_SCOPE: &str = "user-read-private,playlist-read-private,playlist-read-collaborative,playlist-modify-public,playlist-modify-private,user-follow-modify,user-follow-read,user-library-read,user-library-modify,user-top-read,user-read-recently-played,ugc-image-upload";
+
+/// The timeouts of the shared HTTP client, so a slow CDN can't hold a worker
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
 
 /// App Store Data
 /// It stores `SpotifyAccounts` and a global `Mutex`
@@ -24,6 +44,30 @@ pub struct AppStore {
     pub client_id: String,
     pub cache_dir: PathBuf,
     pub proxy: Option<Url>,
+    pub response_cache: ResponseCache,
+    pub rate_limit: RateLimitConfig,
+    pub quotas: Quotas,
+    pub scope: String,
+    // Users allowed to log in and use the server, all users when empty
+    allowed_users: std::sync::RwLock<HashSet<String>>,
+    pub admin_token: Option<String>,
+    pub reloader: Option<Reloader>,
+    // Server-side sessions which can be listed and revoked
+    pub sessions: Option<FileSessionStore>,
+    // Passwords of the Subsonic API users
+    pub subsonic_users: HashMap<String, String>,
+    // The DLNA MediaServer, disabled without it
+    pub dlna: Option<DlnaConfig>,
+    // Submits the listens of streamed tracks, disabled without it
+    pub scrobbler: Option<Scrobbler>,
+    // Delivers the events to the webhook targets, disabled without it
+    pub webhooks: Option<Webhooks>,
+    // The origins of cross-origin frontends, which may open event WebSockets
+    pub cors: CorsConfig,
+    // The client of the requests outside rspotify, e.g. images
+    pub http_client: reqwest::Client,
+    // The persisted server secret, which the keys of signed uris derive from
+    secret: [u8; 64],
 }
 
 impl AppStore {
@@ -33,28 +77,192 @@ impl AppStore {
             client_id: client_id.to_string(),
             cache_dir: PathBuf::from(cache_dir),
             proxy,
+            response_cache: ResponseCache::default(),
+            rate_limit: RateLimitConfig::default(),
+            quotas: Quotas::default(),
+            scope: DEFAULT_SCOPE.to_owned(),
+            allowed_users: std::sync::RwLock::new(HashSet::new()),
+            admin_token: None,
+            reloader: None,
+            sessions: None,
+            subsonic_users: HashMap::new(),
+            dlna: None,
+            scrobbler: None,
+            webhooks: None,
+            cors: CorsConfig::default(),
+            http_client: reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("Failed to build the HTTP client"),
+            secret: {
+                let mut secret = [0u8; 64];
+                rand::thread_rng().fill_bytes(&mut secret);
+                secret
+            },
+        }
+    }
+
+    pub fn with_secret(mut self, secret: [u8; 64]) -> Self {
+        self.secret = secret;
+        self
+    }
+
+    pub fn with_cors(mut self, cors: CorsConfig) -> Self {
+        self.cors = cors;
+        self
+    }
+
+    pub fn with_response_cache(mut self, response_cache: ResponseCache) -> Self {
+        self.response_cache = response_cache;
+        self
+    }
+
+    pub fn with_rate_limit(mut self, rate_limit: RateLimitConfig) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    pub fn with_quotas(mut self, quotas: Quotas) -> Self {
+        self.quotas = quotas;
+        self
+    }
+
+    pub fn with_scope(mut self, scope: &str) -> Self {
+        self.scope = scope.to_owned();
+        self
+    }
+
+    pub fn with_allowed_users(self, allowed_users: HashSet<String>) -> Self {
+        self.set_allowed_users(allowed_users);
+        self
+    }
+
+    pub fn with_admin_token(mut self, admin_token: Option<String>) -> Self {
+        self.admin_token = admin_token;
+        self
+    }
+
+    pub fn with_reloader(mut self, reloader: Reloader) -> Self {
+        self.reloader = Some(reloader);
+        self
+    }
+
+    pub fn with_sessions(mut self, sessions: Option<FileSessionStore>) -> Self {
+        self.sessions = sessions;
+        self
+    }
+
+    pub fn with_subsonic_users(mut self, subsonic_users: HashMap<String, String>) -> Self {
+        self.subsonic_users = subsonic_users;
+        self
+    }
+
+    pub fn with_dlna(mut self, dlna: Option<DlnaConfig>) -> Self {
+        self.dlna = dlna;
+        self
+    }
+
+    pub fn with_scrobbler(mut self, scrobbler: Option<Scrobbler>) -> Self {
+        self.scrobbler = scrobbler;
+        self
+    }
+
+    pub fn with_webhooks(mut self, webhooks: Option<Webhooks>) -> Self {
+        self.webhooks = webhooks;
+        self
+    }
+
+    /// Report the stream of `id` as playing for `username`, with scrobbling
+    /// enabled
+    pub async fn now_playing(
+        &self,
+        username: &str,
+        account: &SpotifyAccount,
+        id: &str,
+    ) -> Option<Playback> {
+        self.scrobbler
+            .as_ref()?
+            .now_playing(username, account, id)
+            .await
+    }
+
+    pub fn sessions(&self) -> Result<&FileSessionStore, ServerError> {
+        self.sessions.as_ref().ok_or_else(|| {
+            ServerError::ParamsError(
+                "Sessions are only listed with `--session-store file`".to_owned(),
+            )
+        })
+    }
+
+    pub fn set_allowed_users(&self, allowed_users: HashSet<String>) {
+        *self.allowed_users.write().unwrap() = allowed_users;
+    }
+
+    pub fn is_allowed(&self, username: &str) -> bool {
+        let allowed_users = self.allowed_users.read().unwrap();
+        allowed_users.is_empty() || allowed_users.contains(username)
+    }
+
+    /// Reload the config file, see `Reloader`
+    pub fn reload_config(&self) -> Result<(), ServerError> {
+        match &self.reloader {
+            Some(reloader) => reloader.reload(self),
+            None => Err(ServerError::ParamsError(
+                "The server runs without a config file".to_owned(),
+            )),
         }
     }
 
     pub async fn load_cache(&self) -> Result<(), ServerError> {
         for entry in self.cache_dir.read_dir()?.flatten() {
-            let creds_dir = entry.path();
-            if let Some(credentials) = load_credentials(creds_dir.clone()) {
-                let username = creds_dir.file_name().unwrap().to_str().unwrap();
-                let cache = Cache::new(Some(creds_dir.clone()), None, None, None)?;
-                let account = SpotifyAccount::new(credentials, cache, self.proxy.clone()).await?;
-                self.insert_account(username, account).await;
+            self.load_cached_account(entry.path()).await?;
+        }
+        Ok(())
+    }
+
+    /// Load the cached accounts of `usernames`
+    pub async fn load_accounts(&self, usernames: &[String]) -> Result<(), ServerError> {
+        for username in usernames {
+            if !self
+                .load_cached_account(self.cache_dir.join(username))
+                .await?
+            {
+                tracing::warn!("No cached credentials of {}", username);
             }
         }
         Ok(())
     }
 
+    async fn load_cached_account(&self, creds_dir: PathBuf) -> Result<bool, ServerError> {
+        if let Some(credentials) = load_credentials(creds_dir.clone()) {
+            let username = creds_dir.file_name().unwrap().to_str().unwrap();
+            let cache = Cache::new(Some(creds_dir.clone()), None, None, None)?;
+            let account = SpotifyAccount::new(
+                credentials,
+                cache,
+                self.proxy.clone(),
+                self.rate_limit,
+                crypto::user_key(&self.secret, username),
+            )
+            .await?;
+            self.insert_account(username, account).await;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
     pub async fn create_account(
         &self,
         username: &str,
         password: &str,
         to_cache: bool,
     ) -> Result<(), ServerError> {
+        if !self.is_allowed(username) {
+            return Err(ServerError::AuthenticationError);
+        }
+
         let cred_dir = if to_cache {
             Some(self.cache_dir.join(username))
         } else {
@@ -67,7 +275,14 @@ impl AppStore {
             Credentials::with_password(username, password)
         };
 
-        let account = SpotifyAccount::create(credentials, cred_dir, self.proxy.clone()).await?;
+        let account = SpotifyAccount::create(
+            credentials,
+            cred_dir,
+            self.proxy.clone(),
+            self.rate_limit,
+            crypto::user_key(&self.secret, username),
+        )
+        .await?;
         self.insert_account(username, account).await;
 
         Ok(())
@@ -78,10 +293,15 @@ impl AppStore {
         spotify_accounts.insert(username, account);
     }
 
-    pub async fn authorize<'a>(
-        &'a self,
+    pub async fn authorize(
+        &self,
         username: impl Into<UserName>,
     ) -> Result<RwLockReadGuard<'_, SpotifyAccount>, ServerError> {
+        let username = username.into();
+        if !self.is_allowed(username.as_ref()) {
+            return Err(ServerError::AuthenticationError);
+        }
+
         let spotify_accounts = self.spotify_accounts.read().await;
 
         let account = RwLockReadGuard::try_map(spotify_accounts, |sa| sa.get(username));
@@ -94,7 +314,7 @@ impl AppStore {
                 //     3,
                 // )
                 // .await?;
-                a.retry_update_token(&self.client_id, DEFAULT_SCOPE, 3)
+                a.retry_update_token(&self.client_id, &self.scope, 3)
                     .await?;
                 Ok(a)
             }
@@ -0,0 +1,654 @@
+//! Library backups
+//!
+//! A versioned JSON snapshot of the saved tracks, albums, shows and episodes,
+//! the followed artists, and the owned and followed playlists with their
+//! items, which restores into the same or another account.
+
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use rspotify::{
+    clients::{BaseClient, OAuthClient},
+    http::Query,
+    model::{
+        AlbumId, ArtistId, EpisodeId, Id, PlayableId, PlayableItem, PlaylistId, ShowId, TrackId,
+    },
+};
+
+use crate::{
+    account::SpotifyAccount,
+    endpoints::{
+        albums::all_saved_albums,
+        artists::all_followed_artists,
+        episodes::all_saved_episodes,
+        params::{PlaylistDescData, MAX_IDS_PER_REQUEST},
+        playlists::{all_current_user_playlists, all_tracks, new_playlist},
+        shows::all_saved_shows,
+        tracks::all_saved_tracks,
+    },
+    errors::ServerError,
+    events::{EventKind, EVENTS},
+};
+
+/// The version of the snapshot format, bumped on incompatible changes
+pub const BACKUP_VERSION: u32 = 1;
+
+/// The maximum number of items Spotify accepts in one playlist request
+const MAX_PLAYLIST_ITEMS_PER_REQUEST: usize = 100;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Backup {
+    pub version: u32,
+    pub created_at: DateTime<Utc>,
+    /// The Spotify user of the snapshot
+    pub user_id: String,
+    pub tracks: Vec<BackupItem>,
+    pub albums: Vec<BackupItem>,
+    pub shows: Vec<BackupItem>,
+    pub episodes: Vec<BackupItem>,
+    pub artists: Vec<BackupItem>,
+    pub playlists: Vec<BackupPlaylist>,
+}
+
+/// A library item, newest first as Spotify lists them
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct BackupItem {
+    pub uri: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub added_at: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct BackupPlaylist {
+    pub uri: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub public: Option<bool>,
+    pub collaborative: bool,
+    /// Owned playlists are created again, the others are followed
+    pub owned: bool,
+    pub items: Vec<BackupItem>,
+}
+
+/// What a restore did, or would do on a dry run
+#[derive(Debug, Default, serde::Serialize)]
+pub struct RestoreReport {
+    pub dry_run: bool,
+    pub tracks: usize,
+    pub albums: usize,
+    pub shows: usize,
+    pub episodes: usize,
+    pub artists: usize,
+    pub playlists_created: usize,
+    pub playlists_followed: usize,
+    /// Owned playlists which still exist in the account
+    pub playlists_skipped: usize,
+    pub playlist_items: usize,
+    /// The sections which failed, as the restore goes on with the others
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<SectionError>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SectionError {
+    /// `tracks`, `albums`, `shows`, `episodes`, `artists`, `playlists` or a
+    /// playlist uri
+    pub section: String,
+    pub error: String,
+}
+
+impl RestoreReport {
+    fn fail(&mut self, section: &str, err: impl Into<ServerError>) {
+        let error = err.into().to_string();
+        tracing::warn!("Failed to restore {}: {}", section, error);
+        self.errors.push(SectionError {
+            section: section.to_owned(),
+            error,
+        });
+    }
+}
+
+/// The parsed ids of a snapshot, and what restoring them would do
+struct RestorePlan<'a> {
+    report: RestoreReport,
+    track_ids: Vec<TrackId<'a>>,
+    album_ids: Vec<AlbumId<'a>>,
+    show_ids: Vec<ShowId<'a>>,
+    episode_ids: Vec<EpisodeId<'a>>,
+    artist_ids: Vec<ArtistId<'a>>,
+    playlists: Vec<(&'a BackupPlaylist, PlaylistId<'a>, Vec<PlayableId<'a>>)>,
+}
+
+/// The description of a playlist, which simplified playlists lack
+async fn playlist_description(
+    account: &SpotifyAccount,
+    playlist_id: &PlaylistId<'_>,
+) -> Result<Option<String>, ServerError> {
+    let url = format!("playlists/{}", playlist_id.id());
+    let mut params = Query::new();
+    params.insert("fields", "description");
+    let result = account.client.api_get(&url, &params).await?;
+    let value = serde_json::from_str::<serde_json::Value>(&result)?;
+    Ok(value["description"]
+        .as_str()
+        .filter(|description| !description.is_empty())
+        .map(str::to_owned))
+}
+
+/// The items of a playlist, without local files
+async fn playlist_items(
+    account: &SpotifyAccount,
+    playlist_id: PlaylistId<'_>,
+) -> Result<Vec<BackupItem>, ServerError> {
+    Ok(all_tracks(account, playlist_id, None, None)
+        .await?
+        .into_iter()
+        .filter_map(|item| {
+            let added_at = item.added_at.map(|added_at| added_at.to_rfc3339());
+            let (uri, name) = match item.track? {
+                PlayableItem::Track(track) => (track.id?.uri(), track.name),
+                PlayableItem::Episode(episode) => (episode.id.uri(), episode.name),
+            };
+            Some(BackupItem {
+                uri,
+                name,
+                added_at,
+            })
+        })
+        .collect())
+}
+
+/// Parse the uris of the items, oldest first so that they are saved in order
+fn ids<'a, T>(
+    items: &'a [BackupItem],
+    parse: impl Fn(&'a str) -> Result<T, rspotify::model::IdError>,
+) -> Result<Vec<T>, ServerError> {
+    items
+        .iter()
+        .rev()
+        .map(|item| {
+            parse(&item.uri)
+                .map_err(|_| ServerError::ParamsError(format!("Invalid uri: {}", item.uri)))
+        })
+        .collect()
+}
+
+/// Emit `library.saved` of restored ids
+fn saved<T: Id>(username: &str, item_type: &str, ids: &[T]) {
+    let ids = ids.iter().map(|id| id.id()).collect::<Vec<_>>();
+    EVENTS.library_changed(true, username, item_type, &ids);
+}
+
+fn playable_id(uri: &str) -> Result<PlayableId<'_>, rspotify::model::IdError> {
+    match TrackId::from_uri(uri) {
+        Ok(id) => Ok(PlayableId::Track(id)),
+        Err(_) => EpisodeId::from_uri(uri).map(PlayableId::Episode),
+    }
+}
+
+impl Backup {
+    /// The snapshot of the account library
+    pub async fn snapshot(account: &SpotifyAccount) -> Result<Self, ServerError> {
+        let user_id = account.client.me().await?.id;
+        let market = Some(account.default_market().await);
+
+        let tracks = all_saved_tracks(account, market)
+            .await?
+            .into_iter()
+            .filter_map(|saved| {
+                Some(BackupItem {
+                    uri: saved.track.id?.uri(),
+                    name: saved.track.name,
+                    added_at: Some(saved.added_at.to_rfc3339()),
+                })
+            })
+            .collect();
+        let albums = all_saved_albums(account, market)
+            .await?
+            .into_iter()
+            .map(|saved| BackupItem {
+                uri: saved.album.id.uri(),
+                name: saved.album.name,
+                added_at: Some(saved.added_at.to_rfc3339()),
+            })
+            .collect();
+        let shows = all_saved_shows(account)
+            .await?
+            .into_iter()
+            .map(|saved| BackupItem {
+                uri: saved.show.id.uri(),
+                name: saved.show.name,
+                added_at: Some(saved.added_at),
+            })
+            .collect();
+        let episodes = all_saved_episodes(account, market)
+            .await?
+            .into_iter()
+            .filter_map(|saved| {
+                Some(BackupItem {
+                    uri: saved["episode"]["uri"].as_str()?.to_owned(),
+                    name: saved["episode"]["name"].as_str()?.to_owned(),
+                    added_at: saved["added_at"].as_str().map(str::to_owned),
+                })
+            })
+            .collect();
+        let artists = all_followed_artists(account)
+            .await?
+            .into_iter()
+            .map(|artist| BackupItem {
+                uri: artist.id.uri(),
+                name: artist.name,
+                added_at: None,
+            })
+            .collect();
+
+        let mut playlists = vec![];
+        for playlist in all_current_user_playlists(account).await? {
+            let owned = playlist.owner.id == user_id;
+            let description = if owned {
+                playlist_description(account, &playlist.id).await?
+            } else {
+                None
+            };
+            playlists.push(BackupPlaylist {
+                uri: playlist.id.uri(),
+                items: playlist_items(account, playlist.id).await?,
+                name: playlist.name,
+                description,
+                public: playlist.public,
+                collaborative: playlist.collaborative,
+                owned,
+            });
+        }
+
+        Ok(Backup {
+            version: BACKUP_VERSION,
+            created_at: Utc::now(),
+            user_id: user_id.id().to_owned(),
+            tracks,
+            albums,
+            shows,
+            episodes,
+            artists,
+            playlists,
+        })
+    }
+
+    /// Check the snapshot against the playlists `existing` in the account
+    ///
+    /// The uris are all parsed before anything is written, and the report
+    /// counts what the restore would do.
+    fn plan<'a>(
+        &'a self,
+        existing: &HashSet<PlaylistId<'a>>,
+        dry_run: bool,
+    ) -> Result<RestorePlan<'a>, ServerError> {
+        if self.version > BACKUP_VERSION {
+            return Err(ServerError::ParamsError(format!(
+                "Unsupported backup version: {}",
+                self.version
+            )));
+        }
+
+        let track_ids = ids(&self.tracks, TrackId::from_uri)?;
+        let album_ids = ids(&self.albums, AlbumId::from_uri)?;
+        let show_ids = ids(&self.shows, ShowId::from_uri)?;
+        let episode_ids = ids(&self.episodes, EpisodeId::from_uri)?;
+        let artist_ids = ids(&self.artists, ArtistId::from_uri)?;
+        let mut playlists = vec![];
+        for playlist in &self.playlists {
+            let playlist_id = PlaylistId::from_uri(&playlist.uri)
+                .map_err(|_| ServerError::ParamsError(format!("Invalid uri: {}", playlist.uri)))?;
+            // Playlist items keep their order
+            let mut items = ids(&playlist.items, playable_id)?;
+            items.reverse();
+            playlists.push((playlist, playlist_id, items));
+        }
+
+        let mut report = RestoreReport {
+            dry_run,
+            tracks: track_ids.len(),
+            albums: album_ids.len(),
+            shows: show_ids.len(),
+            episodes: episode_ids.len(),
+            artists: artist_ids.len(),
+            ..Default::default()
+        };
+        for (playlist, playlist_id, items) in &playlists {
+            if !playlist.owned {
+                report.playlists_followed += 1;
+            } else if existing.contains(playlist_id) {
+                report.playlists_skipped += 1;
+            } else {
+                report.playlists_created += 1;
+                report.playlist_items += items.len();
+            }
+        }
+
+        Ok(RestorePlan {
+            report,
+            track_ids,
+            album_ids,
+            show_ids,
+            episode_ids,
+            artist_ids,
+            playlists,
+        })
+    }
+
+    /// Replay the snapshot into the account
+    ///
+    /// A dry run only reports what the restore would do. Otherwise the report
+    /// counts what was restored, and lists the sections which failed. The
+    /// restored items are library and playlist events of the server user
+    /// `username`.
+    pub async fn restore(
+        &self,
+        username: &str,
+        account: &SpotifyAccount,
+        dry_run: bool,
+    ) -> Result<RestoreReport, ServerError> {
+        // Owned playlists which still exist are restored into the same account
+        let existing = all_current_user_playlists(account)
+            .await?
+            .into_iter()
+            .map(|playlist| playlist.id)
+            .collect::<HashSet<_>>();
+
+        let RestorePlan {
+            report,
+            track_ids,
+            album_ids,
+            show_ids,
+            episode_ids,
+            artist_ids,
+            playlists,
+        } = self.plan(&existing, dry_run)?;
+        if dry_run {
+            return Ok(report);
+        }
+
+        // A failed section stops at its first error, and is reported while
+        // the others are still restored
+        let client = &account.client;
+        let mut restored = RestoreReport {
+            dry_run,
+            ..Default::default()
+        };
+        for chunk in track_ids.chunks(MAX_IDS_PER_REQUEST) {
+            match client
+                .current_user_saved_tracks_add(chunk.iter().cloned())
+                .await
+            {
+                Ok(_) => {
+                    restored.tracks += chunk.len();
+                    saved(username, "track", chunk);
+                }
+                Err(err) => {
+                    restored.fail("tracks", err);
+                    break;
+                }
+            }
+        }
+        for chunk in album_ids.chunks(MAX_IDS_PER_REQUEST) {
+            match client
+                .current_user_saved_albums_add(chunk.iter().cloned())
+                .await
+            {
+                Ok(_) => {
+                    restored.albums += chunk.len();
+                    saved(username, "album", chunk);
+                }
+                Err(err) => {
+                    restored.fail("albums", err);
+                    break;
+                }
+            }
+        }
+        for chunk in show_ids.chunks(MAX_IDS_PER_REQUEST) {
+            match client.save_shows(chunk.iter().cloned()).await {
+                Ok(_) => {
+                    restored.shows += chunk.len();
+                    saved(username, "show", chunk);
+                }
+                Err(err) => {
+                    restored.fail("shows", err);
+                    break;
+                }
+            }
+        }
+        for chunk in episode_ids.chunks(MAX_IDS_PER_REQUEST) {
+            let ids = chunk.iter().map(|id| id.id()).collect::<Vec<_>>();
+            match client
+                .api_put("me/episodes", &serde_json::json!({ "ids": ids }))
+                .await
+            {
+                Ok(_) => {
+                    restored.episodes += chunk.len();
+                    saved(username, "episode", chunk);
+                }
+                Err(err) => {
+                    restored.fail("episodes", err);
+                    break;
+                }
+            }
+        }
+        for chunk in artist_ids.chunks(MAX_IDS_PER_REQUEST) {
+            match client.user_follow_artists(chunk.iter().cloned()).await {
+                Ok(_) => {
+                    restored.artists += chunk.len();
+                    saved(username, "artist", chunk);
+                }
+                Err(err) => {
+                    restored.fail("artists", err);
+                    break;
+                }
+            }
+        }
+
+        let user_id = match client.me().await {
+            Ok(user) => Some(user.id),
+            Err(err) => {
+                restored.fail("playlists", err);
+                None
+            }
+        };
+        for (playlist, playlist_id, items) in playlists {
+            if !playlist.owned {
+                match client.playlist_follow(playlist_id.clone(), None).await {
+                    Ok(_) => {
+                        restored.playlists_followed += 1;
+                        EVENTS.library_changed(true, username, "playlist", &[playlist_id.id()]);
+                    }
+                    Err(err) => restored.fail(&playlist.uri, err),
+                }
+                continue;
+            }
+            if existing.contains(&playlist_id) {
+                restored.playlists_skipped += 1;
+                continue;
+            }
+            let Some(user_id) = &user_id else {
+                continue;
+            };
+            let desc = PlaylistDescData {
+                name: Some(playlist.name.clone()),
+                // Collaborative playlists can't be public
+                public: if playlist.collaborative {
+                    Some(false)
+                } else {
+                    playlist.public
+                },
+                collaborative: Some(playlist.collaborative),
+                description: playlist.description.clone(),
+            };
+            let created = match new_playlist(account, user_id.clone(), &desc).await {
+                Ok(created) => created,
+                Err(err) => {
+                    restored.fail(&playlist.uri, err);
+                    continue;
+                }
+            };
+            restored.playlists_created += 1;
+            EVENTS.emit(
+                EventKind::PlaylistCreated,
+                username,
+                serde_json::json!({ "id": created.id.id(), "name": created.name }),
+            );
+            for chunk in items.chunks(MAX_PLAYLIST_ITEMS_PER_REQUEST) {
+                let result = client
+                    .playlist_add_items(
+                        created.id.clone(),
+                        chunk.iter().map(|item| item.as_ref()),
+                        None,
+                    )
+                    .await;
+                match result {
+                    Ok(_) => {
+                        restored.playlist_items += chunk.len();
+                        EVENTS.emit(
+                            EventKind::PlaylistModified,
+                            username,
+                            serde_json::json!({ "id": created.id.id(), "change": "items_added" }),
+                        );
+                    }
+                    Err(err) => {
+                        restored.fail(&playlist.uri, err);
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(restored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(uri: &str) -> BackupItem {
+        BackupItem {
+            uri: uri.to_owned(),
+            name: uri.to_owned(),
+            added_at: None,
+        }
+    }
+
+    fn playlist(uri: &str, owned: bool, items: &[&str]) -> BackupPlaylist {
+        BackupPlaylist {
+            uri: uri.to_owned(),
+            name: uri.to_owned(),
+            description: None,
+            public: Some(true),
+            collaborative: false,
+            owned,
+            items: items.iter().map(|uri| item(uri)).collect(),
+        }
+    }
+
+    fn backup() -> Backup {
+        Backup {
+            version: BACKUP_VERSION,
+            created_at: Utc::now(),
+            user_id: "user".to_owned(),
+            tracks: vec![
+                item("spotify:track:4iV5W9uYEdYUVa79Axb7Rh"),
+                item("spotify:track:1301WleyT98MSxVHPZCA6M"),
+            ],
+            albums: vec![item("spotify:album:0sNOF9WDwhWunNAHPD3Baj")],
+            shows: vec![],
+            episodes: vec![item("spotify:episode:512ojhOuo1ktJprKbVcKyQ")],
+            artists: vec![item("spotify:artist:0OdUWJ0sBjDrqHygGUXeCF")],
+            playlists: vec![
+                playlist(
+                    "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M",
+                    false,
+                    &["spotify:track:4iV5W9uYEdYUVa79Axb7Rh"],
+                ),
+                playlist(
+                    "spotify:playlist:3cEYpjA9oz9GiPac4AsH4n",
+                    true,
+                    &[
+                        "spotify:track:4iV5W9uYEdYUVa79Axb7Rh",
+                        "spotify:episode:512ojhOuo1ktJprKbVcKyQ",
+                    ],
+                ),
+                playlist("spotify:playlist:5RyIqJb0LSJVNBq5zqN4zD", true, &[]),
+            ],
+        }
+    }
+
+    #[test]
+    fn ids_are_oldest_first() {
+        let backup = backup();
+        let track_ids = ids(&backup.tracks, TrackId::from_uri).unwrap();
+        assert_eq!(
+            track_ids.iter().map(|id| id.id()).collect::<Vec<_>>(),
+            ["1301WleyT98MSxVHPZCA6M", "4iV5W9uYEdYUVa79Axb7Rh"]
+        );
+
+        let items = [item("spotify:album:0sNOF9WDwhWunNAHPD3Baj")];
+        assert!(matches!(
+            ids(&items, TrackId::from_uri),
+            Err(ServerError::ParamsError(_))
+        ));
+    }
+
+    #[test]
+    fn dry_run_reports_without_writing() {
+        let backup = backup();
+        let existing = [PlaylistId::from_id("5RyIqJb0LSJVNBq5zqN4zD").unwrap()]
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let plan = backup.plan(&existing, true).unwrap();
+
+        let report = plan.report;
+        assert!(report.dry_run);
+        assert_eq!(
+            (report.tracks, report.albums, report.shows, report.episodes),
+            (2, 1, 0, 1)
+        );
+        assert_eq!(report.artists, 1);
+        assert_eq!(report.playlists_followed, 1);
+        assert_eq!(report.playlists_created, 1);
+        assert_eq!(report.playlists_skipped, 1);
+        assert_eq!(report.playlist_items, 2);
+        assert!(report.errors.is_empty());
+
+        // Playlist items keep their order
+        let (_, _, items) = &plan.playlists[1];
+        assert!(matches!(items[0], PlayableId::Track(_)));
+        assert!(matches!(items[1], PlayableId::Episode(_)));
+    }
+
+    #[test]
+    fn invalid_uris_fail_before_writing() {
+        let mut backup = backup();
+        backup.playlists.push(playlist(
+            "spotify:playlist:3cEYpjA9oz9GiPac4AsH4n",
+            true,
+            &["x"],
+        ));
+        assert!(matches!(
+            backup.plan(&HashSet::new(), false),
+            Err(ServerError::ParamsError(_))
+        ));
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        let mut backup = backup();
+        backup.version = BACKUP_VERSION + 1;
+        let err = backup.plan(&HashSet::new(), true).err().unwrap();
+        assert!(
+            matches!(err, ServerError::ParamsError(ref message) if message.contains("version"))
+        );
+
+        backup.version = BACKUP_VERSION;
+        assert!(backup.plan(&HashSet::new(), true).is_ok());
+    }
+}
@@ -0,0 +1,412 @@
+//! Response cache for catalog metadata
+//!
+//! Catalog data (albums, artists, tracks, ...) barely changes, so the JSON
+//! bodies are kept in memory for a while and, optionally, on disk.
+//! Per-user library endpoints must not use it, and the responses which depend
+//! on the account market are keyed by the account.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    http::header::{self, ContentType},
+    HttpRequest, HttpResponse,
+};
+use rspotify::model::Market;
+use sha2::Digest;
+
+use crate::{common::hex, errors::ServerError};
+
+/// The disk tier is evicted once per this many writes, as it lists the files
+const DISK_WRITES_PER_EVICTION: usize = 100;
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+struct CacheEntry {
+    body: String,
+    etag: String,
+    // Unix timestamp in seconds
+    created_at: u64,
+}
+
+impl CacheEntry {
+    fn new(body: String) -> Self {
+        let etag = format!(
+            "\"{}\"",
+            &hex::encode(&sha2::Sha256::digest(body.as_bytes()))[..32]
+        );
+        CacheEntry {
+            body,
+            etag,
+            created_at: now(),
+        }
+    }
+
+    /// Seconds before the entry expires
+    fn remaining(&self, ttl: Duration) -> Option<u64> {
+        let age = now().saturating_sub(self.created_at);
+        ttl.as_secs().checked_sub(age).filter(|r| *r > 0)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Build a cache key from the endpoint name and the parts which change its response,
+/// e.g. ids, market and locale
+pub fn cache_key(endpoint: &str, parts: &[&str]) -> String {
+    format!("{}:{}", endpoint, parts.join("|"))
+}
+
+/// The cache key part of `market`
+///
+/// `from_token` is the market of the account, so it is keyed by the account
+/// rather than shared with other users.
+pub fn market_part(market: Market, account_username: &str) -> String {
+    match market {
+        Market::FromToken => format!("from_token:{}", account_username),
+        market => Into::<&str>::into(market).to_owned(),
+    }
+}
+
+/// In-process response cache with TTL and max-entries,
+/// and an optional disk-backed tier
+pub struct ResponseCache {
+    ttl: Duration,
+    max_entries: usize,
+    disk_dir: Option<PathBuf>,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+    disk_writes: AtomicUsize,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        ResponseCache::new(Duration::from_secs(3600), 1000, None)
+    }
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration, max_entries: usize, disk_dir: Option<PathBuf>) -> Self {
+        Self {
+            ttl,
+            max_entries,
+            disk_dir,
+            entries: Mutex::new(HashMap::new()),
+            disk_writes: AtomicUsize::new(0),
+        }
+    }
+
+    /// A zero TTL or zero max entries disables the cache
+    pub fn enabled(&self) -> bool {
+        !self.ttl.is_zero() && self.max_entries > 0
+    }
+
+    /// Respond with the cached body of `key`, or fetch and cache it
+    ///
+    /// The response carries `ETag` and `Cache-Control` headers, and it is
+    /// `304 Not Modified` when `If-None-Match` matches the `ETag`.
+    pub async fn respond<F, Fut>(
+        &self,
+        req: &HttpRequest,
+        key: String,
+        fetch: F,
+    ) -> Result<HttpResponse, ServerError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<String, ServerError>>,
+    {
+        if !self.enabled() {
+            let body = fetch().await?;
+            return Ok(HttpResponse::Ok()
+                .content_type(ContentType::json())
+                .body(body));
+        }
+
+        let entry = match self.get(&key).await {
+            Some(entry) => entry,
+            None => {
+                let entry = CacheEntry::new(fetch().await?);
+                self.insert(key, entry.clone()).await;
+                entry
+            }
+        };
+        let max_age = entry.remaining(self.ttl).unwrap_or(0);
+
+        let not_modified = req
+            .headers()
+            .get(header::IF_NONE_MATCH)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.split(',').any(|tag| tag.trim() == entry.etag))
+            .unwrap_or(false);
+
+        let mut response = if not_modified {
+            HttpResponse::NotModified()
+        } else {
+            HttpResponse::Ok()
+        };
+        response
+            .insert_header((header::ETAG, entry.etag.as_str()))
+            .insert_header((
+                header::CACHE_CONTROL,
+                format!("private, max-age={}", max_age),
+            ));
+
+        if not_modified {
+            Ok(response.finish())
+        } else {
+            Ok(response.content_type(ContentType::json()).body(entry.body))
+        }
+    }
+
+    async fn get(&self, key: &str) -> Option<CacheEntry> {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some(entry) = entries.get(key) {
+                if entry.remaining(self.ttl).is_some() {
+                    return Some(entry.clone());
+                }
+                entries.remove(key);
+            }
+        }
+
+        // Fall back to the disk tier
+        let entry = self.read_disk(key).await?;
+        entry.remaining(self.ttl)?;
+        self.insert_memory(key.to_owned(), entry.clone());
+        Some(entry)
+    }
+
+    async fn insert(&self, key: String, entry: CacheEntry) {
+        self.write_disk(&key, &entry).await;
+        self.insert_memory(key, entry);
+    }
+
+    fn insert_memory(&self, key: String, entry: CacheEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let ttl = self.ttl;
+            entries.retain(|_, e| e.remaining(ttl).is_some());
+
+            // Evict the oldest entry when it is still full
+            if entries.len() >= self.max_entries {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, e)| e.created_at)
+                    .map(|(k, _)| k.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(key, entry);
+    }
+
+    fn disk_path(&self, key: &str) -> Option<PathBuf> {
+        let name = hex::encode(&sha2::Sha256::digest(key.as_bytes()));
+        self.disk_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.json", name)))
+    }
+
+    async fn read_disk(&self, key: &str) -> Option<CacheEntry> {
+        let path = self.disk_path(key)?;
+        let data = tokio::fs::read(path).await.ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    /// Write the file of `key` through a temporary file, so concurrent reads
+    /// never see half of it
+    async fn write_disk(&self, key: &str, entry: &CacheEntry) {
+        let (Some(dir), Some(path)) = (self.disk_dir.as_ref(), self.disk_path(key)) else {
+            return;
+        };
+        let tmp_path = path.with_extension(format!("json.{:016x}.tmp", rand::random::<u64>()));
+        let result = async {
+            tokio::fs::create_dir_all(dir).await?;
+            tokio::fs::write(&tmp_path, serde_json::to_vec(entry)?).await?;
+            tokio::fs::rename(&tmp_path, &path).await
+        }
+        .await;
+        if let Err(err) = result {
+            tracing::warn!("Failed to write response cache {:?}: {:?}", path, err);
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return;
+        }
+
+        let writes = self.disk_writes.fetch_add(1, Ordering::Relaxed) + 1;
+        if writes.is_multiple_of(DISK_WRITES_PER_EVICTION) {
+            if let Err(err) = self.evict_disk(dir).await {
+                tracing::warn!("Failed to evict response cache {:?}: {:?}", dir, err);
+            }
+        }
+    }
+
+    /// Remove the expired files of the disk tier, and the oldest ones beyond
+    /// max entries
+    ///
+    /// Temporary files are only removed once they expire, as they may be
+    /// written right now.
+    async fn evict_disk(&self, dir: &Path) -> std::io::Result<()> {
+        let expired_before = SystemTime::now() - self.ttl;
+        let mut files = Vec::new();
+        let mut read_dir = tokio::fs::read_dir(dir).await?;
+        while let Some(file) = read_dir.next_entry().await? {
+            let modified = match file.metadata().await {
+                Ok(metadata) => metadata.modified()?,
+                // Another request evicted it first
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            let path = file.path();
+            let is_entry = path
+                .extension()
+                .is_some_and(|extension| extension == "json");
+            if is_entry || modified < expired_before {
+                files.push((modified, path));
+            }
+        }
+
+        files.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+        for (i, (modified, path)) in files.iter().enumerate() {
+            if i >= self.max_entries || *modified < expired_before {
+                match tokio::fs::remove_file(path).await {
+                    // Another request evicted it first
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                    result => result?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{body::MessageBody, test::TestRequest};
+
+    use super::*;
+
+    async fn body(response: HttpResponse) -> String {
+        let bytes = response.into_body().try_into_bytes().unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    fn header(response: &HttpResponse, name: header::HeaderName) -> &str {
+        response.headers().get(name).unwrap().to_str().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn etag_and_not_modified() {
+        let cache = ResponseCache::new(Duration::from_secs(60), 10, None);
+        let req = TestRequest::get().to_http_request();
+        let response = cache
+            .respond(&req, "album:1".to_owned(), || async {
+                Ok(r#"{"id":"1"}"#.to_owned())
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let etag = header(&response, header::ETAG).to_owned();
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+        assert!(header(&response, header::CACHE_CONTROL).starts_with("private, max-age="));
+        assert_eq!(body(response).await, r#"{"id":"1"}"#);
+
+        // Cached, so it is not fetched again
+        let req = TestRequest::get()
+            .insert_header((header::IF_NONE_MATCH, format!("\"other\", {}", etag)))
+            .to_http_request();
+        let response = cache
+            .respond(&req, "album:1".to_owned(), || async {
+                unreachable!("the entry is cached")
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 304);
+        assert_eq!(header(&response, header::ETAG), etag);
+        assert!(body(response).await.is_empty());
+
+        let req = TestRequest::get()
+            .insert_header((header::IF_NONE_MATCH, "\"other\""))
+            .to_http_request();
+        let response = cache
+            .respond(&req, "album:1".to_owned(), || async {
+                unreachable!("the entry is cached")
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(body(response).await, r#"{"id":"1"}"#);
+    }
+
+    #[actix_web::test]
+    async fn expired_entries_are_fetched_again() {
+        let cache = ResponseCache::new(Duration::from_secs(60), 10, None);
+        let mut stale = CacheEntry::new("stale".to_owned());
+        stale.created_at = now() - 60;
+        cache.insert_memory("album:1".to_owned(), stale);
+        let mut fresh = CacheEntry::new("fresh".to_owned());
+        fresh.created_at = now() - 50;
+        cache.insert_memory("album:2".to_owned(), fresh);
+
+        assert!(cache.get("album:1").await.is_none());
+        assert!(!cache.entries.lock().unwrap().contains_key("album:1"));
+        assert_eq!(cache.get("album:2").await.unwrap().body, "fresh");
+
+        let req = TestRequest::get().to_http_request();
+        let response = cache
+            .respond(&req, "album:1".to_owned(), || async {
+                Ok("new".to_owned())
+            })
+            .await
+            .unwrap();
+        assert_eq!(body(response).await, "new");
+        let response = cache
+            .respond(&req, "album:2".to_owned(), || async {
+                unreachable!("the entry is fresh")
+            })
+            .await
+            .unwrap();
+        // The age may have crossed a second since
+        let max_age = header(&response, header::CACHE_CONTROL)
+            .strip_prefix("private, max-age=")
+            .and_then(|max_age| max_age.parse::<u64>().ok());
+        assert!(matches!(max_age, Some(9 | 10)));
+    }
+
+    #[actix_web::test]
+    async fn disk_tier() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ResponseCache::new(Duration::from_secs(60), 2, Some(dir.path().to_owned()));
+        for i in 0..DISK_WRITES_PER_EVICTION {
+            cache
+                .insert(format!("album:{}", i), CacheEntry::new(i.to_string()))
+                .await;
+        }
+
+        // Another cache reads the files of the latest entries
+        let other = ResponseCache::new(Duration::from_secs(60), 2, Some(dir.path().to_owned()));
+        let last = DISK_WRITES_PER_EVICTION - 1;
+        assert_eq!(
+            other.get(&format!("album:{}", last)).await.unwrap().body,
+            last.to_string()
+        );
+        let files = std::fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(files, 2);
+
+        // Left over temporary files stay until they expire
+        std::fs::write(dir.path().join("entry.json.0.tmp"), "{").unwrap();
+        cache.evict_disk(dir.path()).await.unwrap();
+        assert!(dir.path().join("entry.json.0.tmp").exists());
+    }
+}
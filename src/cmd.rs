@@ -1,12 +1,44 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches};
+
 use rand::RngCore;
+use regex::Regex;
 use sha2::Digest;
 use url::Url;
 
-use crate::app_store::DEFAULT_CLIENT_ID;
+use crate::{
+    account::{rate_limit::RateLimitConfig, utils::CONFIG_ROOT},
+    app_store::{DEFAULT_CLIENT_ID, DEFAULT_SCOPE},
+    cache::ResponseCache,
+    config::Config,
+    cors::CorsConfig,
+    dlna::{self, DlnaConfig},
+    errors::ServerError,
+    events::EventKind,
+    mpd::MpdConfig,
+    quota::{QuotaConfig, Quotas},
+    scrobble::{self, ScrobbleConfig, ScrobbleService},
+    session::CookieConfig,
+    session_store::{FileSessionStore, SessionBackend, SessionStoreKind},
+    tls::TlsConfig,
+    webhooks::WebhookConfig,
+};
 
-#[derive(clap::Parser, Debug)]
+#[derive(clap::Parser, Clone, Debug)]
 #[clap(author, version, about, long_about = None)]
 pub struct Cmd {
+    #[clap(
+        long,
+        help = "TOML config file, defaults to ./config/config.toml when it exists"
+    )]
+    pub config: Option<PathBuf>,
+
     #[clap(long, default_value_t = String::from(DEFAULT_CLIENT_ID), help = "Spotify client ID")]
     pub client_id: String,
 
@@ -16,39 +48,721 @@ pub struct Cmd {
     #[clap(long, default_value_t = 0, help = "Server listen port")]
     pub port: u16,
 
-    #[clap(long, help = "Session secret")]
+    #[clap(
+        long,
+        help = "Secret of the sessions and signed uris, generated and kept in the cache directory by default"
+    )]
     pub session_secret: Option<String>,
 
+    #[clap(long, value_enum, default_value_t = SessionStoreKind::Cookie, help = "Session store")]
+    pub session_store: SessionStoreKind,
+
+    #[clap(long, default_value_t = 14 * 24 * 3600, help = "Seconds before an idle session expires, with the file session store")]
+    pub session_idle_timeout: u64,
+
+    #[clap(long, default_value_t = 90 * 24 * 3600, help = "Seconds before any session expires, with the file session store")]
+    pub session_max_lifetime: u64,
+
+    #[clap(long, help = "TLS certificate chain (PEM), enables HTTPS")]
+    pub tls_cert: Option<PathBuf>,
+
+    #[clap(long, help = "TLS private key (PEM)")]
+    pub tls_key: Option<PathBuf>,
+
+    #[clap(
+        long,
+        help = "Plain HTTP port which redirects to HTTPS, with TLS enabled"
+    )]
+    pub https_redirect_port: Option<u16>,
+
+    #[clap(long, default_value_t = String::from("id"), help = "Session cookie name")]
+    pub cookie_name: String,
+
+    #[clap(long, help = "Session cookie domain")]
+    pub cookie_domain: Option<String>,
+
+    #[clap(long, help = "Secure session cookie, always on with TLS enabled")]
+    pub cookie_secure: bool,
+
+    #[clap(long, help = "HttpOnly session cookie")]
+    pub cookie_http_only: bool,
+
+    #[clap(
+        long,
+        help = "Session cookie max age in seconds, until the browser closes by default"
+    )]
+    pub cookie_max_age: Option<i64>,
+
     #[clap(long, help = "Load cached authentication when server starts")]
     pub load_cache: bool,
 
     #[clap(long, default_value_t = String::from("~/.spotify-web-server/authentication"), help = "Cache directory")]
     pub cache_dir: String,
 
-    #[clap(long, default_value_t = String::from("info"), help = "Cache directory")]
+    #[clap(long, default_value_t = String::from("info"), help = "Log level")]
     pub log_level: String,
 
     #[clap(long, help = "Proxy url")]
     pub proxy: Option<Url>,
+
+    #[clap(long, default_value_t = String::from(DEFAULT_SCOPE), help = "Spotify token scopes")]
+    pub scope: String,
+
+    #[clap(
+        long = "account",
+        value_delimiter = ',',
+        help = "Cached accounts to load when server starts"
+    )]
+    pub accounts: Vec<String>,
+
+    #[clap(
+        long = "allowed-user",
+        value_delimiter = ',',
+        help = "Users allowed to log in and use the server, all users when empty"
+    )]
+    pub allowed_users: Vec<String>,
+
+    #[clap(
+        long,
+        help = "Bearer token of the admin endpoints and `/metrics`, which are disabled without it"
+    )]
+    pub admin_token: Option<String>,
+
+    #[clap(
+        long = "subsonic-user",
+        help = "Subsonic API user as user:password, for a server user"
+    )]
+    pub subsonic_users: Vec<String>,
+
+    #[clap(
+        long,
+        help = "MPD protocol port, the MPD frontend is disabled without it"
+    )]
+    pub mpd_port: Option<u16>,
+
+    #[clap(
+        long,
+        help = "Server user whose Spotify account backs the MPD frontend"
+    )]
+    pub mpd_user: Option<String>,
+
+    #[clap(long, help = "Password MPD clients must send")]
+    pub mpd_password: Option<String>,
+
+    #[clap(
+        long,
+        help = "Base url of the song files given to MPD clients, defaults to the server address, required when it binds loopback or port 0"
+    )]
+    pub mpd_base_url: Option<String>,
+
+    #[clap(
+        long,
+        help = "Server user whose Spotify account backs the DLNA MediaServer, which is disabled without it"
+    )]
+    pub dlna_user: Option<String>,
+
+    #[clap(long, default_value_t = String::from("Spotify Web Server"), help = "DLNA MediaServer name")]
+    pub dlna_name: String,
+
+    #[clap(
+        long,
+        help = "Base url of the DLNA description and songs, defaults to the server address on the LAN, required when it binds loopback or port 0"
+    )]
+    pub dlna_base_url: Option<String>,
+
+    #[clap(long, value_enum, default_value_t = ScrobbleService::ListenBrainz, help = "Scrobbling API")]
+    pub scrobble_service: ScrobbleService,
+
+    #[clap(
+        long,
+        help = "Base url of the scrobbling API, defaults to the one of the service"
+    )]
+    pub scrobble_url: Option<String>,
+
+    #[clap(long, help = "Last.fm API key, for scrobbling to Last.fm")]
+    pub scrobble_api_key: Option<String>,
+
+    #[clap(long, help = "Last.fm API secret, for scrobbling to Last.fm")]
+    pub scrobble_api_secret: Option<String>,
+
+    #[clap(
+        long = "scrobble-token",
+        help = "Scrobbling token as user:token, for a server user, scrobbling is disabled without tokens"
+    )]
+    pub scrobble_tokens: Vec<String>,
+
+    #[clap(
+        long = "webhook",
+        help = "Webhook target url, webhooks are disabled without targets"
+    )]
+    pub webhooks: Vec<String>,
+
+    #[clap(long, help = "Secret of the HMAC-SHA256 signatures of webhook bodies")]
+    pub webhook_secret: Option<String>,
+
+    #[clap(
+        long = "webhook-event",
+        value_delimiter = ',',
+        help = "Event types sent to webhooks, e.g. login,stream.started, all types when empty"
+    )]
+    pub webhook_events: Vec<String>,
+
+    #[clap(
+        long,
+        default_value_t = 8,
+        help = "Attempts of a webhook delivery before it fails"
+    )]
+    pub webhook_max_attempts: u32,
+
+    #[clap(
+        long = "cors-origin",
+        value_delimiter = ',',
+        help = "CORS allowed origins, `*` allows any origin without credentials, CORS is disabled without origins"
+    )]
+    pub cors_origins: Vec<String>,
+
+    #[clap(
+        long,
+        help = "CORS allowed origins regex, e.g. ^https://.*\\.example\\.com$"
+    )]
+    pub cors_origin_regex: Option<String>,
+
+    #[clap(
+        long,
+        default_value_t = true,
+        action = clap::ArgAction::Set,
+        help = "Allow credentials (the session cookie) on CORS requests"
+    )]
+    pub cors_credentials: bool,
+
+    #[clap(
+        long,
+        default_value_t = 3600,
+        help = "Seconds for browsers to cache CORS preflight responses"
+    )]
+    pub cors_max_age: usize,
+
+    #[clap(
+        long,
+        default_value_t = 3600,
+        help = "Catalog response cache TTL in seconds, 0 disables the cache"
+    )]
+    pub cache_ttl: u64,
+
+    #[clap(
+        long,
+        default_value_t = 1000,
+        help = "Catalog response cache max entries"
+    )]
+    pub cache_max_entries: usize,
+
+    #[clap(
+        long,
+        help = "Persist catalog response cache under the cache directory"
+    )]
+    pub disk_cache: bool,
+
+    #[clap(
+        long,
+        default_value_t = 10,
+        help = "Max seconds a request waits for Spotify rate limits before it is rejected"
+    )]
+    pub spotify_max_wait: u64,
+
+    #[clap(
+        long,
+        default_value_t = 8,
+        help = "Max concurrent Spotify Web API requests per account"
+    )]
+    pub spotify_max_concurrency: usize,
+
+    #[clap(
+        long,
+        default_value_t = 600,
+        help = "Catalog requests per minute per user, 0 is unlimited"
+    )]
+    pub catalog_rate: u32,
+
+    #[clap(
+        long,
+        default_value_t = 120,
+        help = "Library write requests per minute per user, 0 is unlimited"
+    )]
+    pub library_write_rate: u32,
+
+    #[clap(
+        long,
+        default_value_t = 60,
+        help = "Audio requests per minute per user, 0 is unlimited"
+    )]
+    pub audio_stream_rate: u32,
+
+    #[clap(
+        long,
+        default_value_t = 1200,
+        help = "Requests per minute per Spotify account, 0 is unlimited"
+    )]
+    pub account_rate: u32,
+
+    #[clap(
+        long,
+        default_value_t = 4,
+        help = "Concurrent audio streams per Spotify account, 0 is unlimited"
+    )]
+    pub max_streams: usize,
+
+    #[cfg(feature = "otlp")]
+    #[clap(
+        long,
+        help = "OpenTelemetry OTLP gRPC endpoint, e.g. http://localhost:4317"
+    )]
+    pub otlp_endpoint: Option<String>,
+
+    #[cfg(feature = "otlp")]
+    #[clap(
+        long,
+        default_value_t = String::from(env!("CARGO_PKG_NAME")),
+        help = "OpenTelemetry service name"
+    )]
+    pub otlp_service_name: String,
+
+    // The flags given on the command line, which override the config file
+    #[clap(skip)]
+    cli_args: HashSet<String>,
+}
+
+/// Set the fields of `Cmd` from `Config`, unless they are given on the command line
+macro_rules! merge_fields {
+    ($cmd:ident, $config:ident, $($field:ident),* $(,)?) => {
+        $(
+            if let Some(value) = $config.$field {
+                if !$cmd.cli_args.contains(stringify!($field)) {
+                    $cmd.$field = value.into();
+                }
+            }
+        )*
+    };
 }
 
 impl Cmd {
-    pub fn session_secret(&self) -> [u8; 64] {
+    /// Parse the command line, without the config file
+    ///
+    /// The reloader keeps this one, so the keys removed from the config file
+    /// revert to their command line or default values.
+    pub fn from_command_line() -> Cmd {
+        let mut cmd = Cmd::from_matches(&Cmd::command().get_matches()).unwrap_or_else(|e| e.exit());
+
+        if cmd.config.is_none() {
+            let default_config = Path::new(CONFIG_ROOT).join("config.toml");
+            if default_config.exists() {
+                cmd.config = Some(default_config);
+            }
+        }
+        cmd
+    }
+
+    /// The `Cmd` of parsed arguments, which remembers the flags given on the
+    /// command line
+    pub(crate) fn from_matches(matches: &ArgMatches) -> Result<Cmd, clap::Error> {
+        let mut cmd = Cmd::from_arg_matches(matches)?;
+        cmd.cli_args = matches
+            .ids()
+            .filter(|id| matches.value_source(id.as_str()) == Some(ValueSource::CommandLine))
+            .map(|id| id.to_string())
+            .collect();
+        Ok(cmd)
+    }
+
+    /// Merge the config file onto the command line
+    pub fn load(&self) -> Result<Cmd, ServerError> {
+        let mut cmd = self.clone();
+        if let Some(path) = &cmd.config {
+            let config = Config::from_file(path)?;
+            cmd.merge(config);
+        }
+        Ok(cmd)
+    }
+
+    pub fn merge(&mut self, config: Config) {
+        merge_fields!(
+            self,
+            config,
+            client_id,
+            bind,
+            port,
+            session_secret,
+            session_store,
+            session_idle_timeout,
+            session_max_lifetime,
+            tls_cert,
+            tls_key,
+            https_redirect_port,
+            cookie_name,
+            cookie_domain,
+            cookie_secure,
+            cookie_http_only,
+            cookie_max_age,
+            load_cache,
+            cache_dir,
+            log_level,
+            proxy,
+            scope,
+            accounts,
+            allowed_users,
+            admin_token,
+            subsonic_users,
+            mpd_port,
+            mpd_user,
+            mpd_password,
+            mpd_base_url,
+            dlna_user,
+            dlna_name,
+            dlna_base_url,
+            scrobble_service,
+            scrobble_url,
+            scrobble_api_key,
+            scrobble_api_secret,
+            scrobble_tokens,
+            webhooks,
+            webhook_secret,
+            webhook_events,
+            webhook_max_attempts,
+            cors_origins,
+            cors_origin_regex,
+            cors_credentials,
+            cors_max_age,
+            cache_ttl,
+            cache_max_entries,
+            disk_cache,
+            spotify_max_wait,
+            spotify_max_concurrency,
+            catalog_rate,
+            library_write_rate,
+            audio_stream_rate,
+            account_rate,
+            max_streams,
+        );
+        #[cfg(feature = "otlp")]
+        merge_fields!(self, config, otlp_endpoint, otlp_service_name);
+    }
+
+    pub fn response_cache(&self) -> ResponseCache {
+        let disk_dir = if self.disk_cache {
+            Some(Path::new(&self.cache_dir).join(".responses"))
+        } else {
+            None
+        };
+        ResponseCache::new(
+            Duration::from_secs(self.cache_ttl),
+            self.cache_max_entries,
+            disk_dir,
+        )
+    }
+
+    pub fn rate_limit(&self) -> RateLimitConfig {
+        RateLimitConfig {
+            max_wait: Duration::from_secs(self.spotify_max_wait),
+            max_concurrency: self.spotify_max_concurrency,
+        }
+    }
+
+    pub fn tls_enabled(&self) -> bool {
+        self.tls_cert.is_some() && self.tls_key.is_some()
+    }
+
+    pub fn tls(&self) -> Result<Option<TlsConfig>, ServerError> {
+        let (cert, key) = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => (cert.clone(), key.clone()),
+            (None, None) if self.https_redirect_port.is_some() => {
+                return Err(ServerError::ParamsError(
+                    "--https-redirect-port needs --tls-cert and --tls-key".to_owned(),
+                ))
+            }
+            (None, None) => return Ok(None),
+            _ => {
+                return Err(ServerError::ParamsError(
+                    "Both --tls-cert and --tls-key are required for TLS".to_owned(),
+                ))
+            }
+        };
+        // The redirect can't point at a port picked by the OS
+        if self.https_redirect_port.is_some() && self.port == 0 {
+            return Err(ServerError::ParamsError(
+                "--https-redirect-port needs a fixed --port".to_owned(),
+            ));
+        }
+        Ok(Some(TlsConfig {
+            cert,
+            key,
+            redirect_port: self.https_redirect_port,
+        }))
+    }
+
+    pub fn cookie(&self) -> CookieConfig {
+        CookieConfig {
+            name: self.cookie_name.clone(),
+            domain: self.cookie_domain.clone(),
+            secure: self.cookie_secure || self.tls_enabled(),
+            http_only: self.cookie_http_only,
+            // Server-side sessions outlive the browser
+            max_age: self.cookie_max_age.or(match self.session_store {
+                SessionStoreKind::Cookie => None,
+                SessionStoreKind::File => Some(self.session_max_lifetime as i64),
+            }),
+        }
+    }
+
+    pub fn mpd(&self) -> Result<Option<MpdConfig>, ServerError> {
+        let Some(port) = self.mpd_port else {
+            return Ok(None);
+        };
+        let username = self.mpd_user.clone().ok_or_else(|| {
+            ServerError::ParamsError("--mpd-user is required with --mpd-port".to_owned())
+        })?;
+        if self.mpd_base_url.is_none() && !self.bind_reachable() {
+            return Err(ServerError::ParamsError(
+                "--mpd-base-url is required with a loopback --bind or --port 0".to_owned(),
+            ));
+        }
+        Ok(Some(MpdConfig {
+            bind: self.bind.clone(),
+            port,
+            username,
+            password: self.mpd_password.clone(),
+            base_url: self.base_url(self.mpd_base_url.as_deref()),
+        }))
+    }
+
+    pub fn dlna(&self) -> Result<Option<DlnaConfig>, ServerError> {
+        let Some(username) = self.dlna_user.clone() else {
+            return Ok(None);
+        };
+        // Renderers on the LAN fetch the description and songs from it
+        if self.dlna_base_url.is_none() && !self.bind_reachable() {
+            return Err(ServerError::ParamsError(
+                "--dlna-base-url is required with a loopback --bind or --port 0".to_owned(),
+            ));
+        }
+        Ok(Some(DlnaConfig::new(
+            username,
+            self.dlna_name.clone(),
+            self.base_url(self.dlna_base_url.as_deref()),
+        )))
+    }
+
+    pub fn scrobble(&self) -> Result<Option<ScrobbleConfig>, ServerError> {
+        if self.scrobble_tokens.is_empty() {
+            return Ok(None);
+        }
+        let (api_key, api_secret) = match self.scrobble_service {
+            ScrobbleService::ListenBrainz => (String::new(), String::new()),
+            ScrobbleService::LastFm => match (&self.scrobble_api_key, &self.scrobble_api_secret) {
+                (Some(api_key), Some(api_secret)) => (api_key.clone(), api_secret.clone()),
+                _ => {
+                    return Err(ServerError::ParamsError(
+                        "--scrobble-api-key and --scrobble-api-secret are required for Last.fm"
+                            .to_owned(),
+                    ))
+                }
+            },
+        };
+        Ok(Some(ScrobbleConfig {
+            service: self.scrobble_service,
+            base_url: self
+                .scrobble_url
+                .as_deref()
+                .unwrap_or(self.scrobble_service.default_url())
+                .trim_end_matches('/')
+                .to_owned(),
+            api_key,
+            api_secret,
+            tokens: scrobble::parse_tokens(&self.scrobble_tokens)?,
+            queue_path: Path::new(&self.cache_dir).join("scrobbles.json"),
+        }))
+    }
+
+    pub fn webhooks(&self) -> Result<Option<WebhookConfig>, ServerError> {
+        if self.webhooks.is_empty() {
+            return Ok(None);
+        }
+        let secret = self.webhook_secret.clone().ok_or_else(|| {
+            ServerError::ParamsError("--webhook-secret is required with --webhook".to_owned())
+        })?;
+        Ok(Some(WebhookConfig {
+            targets: self.webhooks.clone(),
+            secret,
+            events: self
+                .webhook_events
+                .iter()
+                .map(|event| event.parse::<EventKind>())
+                .collect::<Result<_, _>>()?,
+            max_attempts: self.webhook_max_attempts.max(1),
+        }))
+    }
+
+    /// Whether other devices can reach the server at the bind address and port,
+    /// which the default base url is made of
+    fn bind_reachable(&self) -> bool {
+        let loopback = match self.bind.parse::<IpAddr>() {
+            Ok(ip) => ip.is_loopback(),
+            Err(_) => self.bind == "localhost",
+        };
+        self.port != 0 && !loopback
+    }
+
+    /// The url of the server which other devices use, `base_url` when it is
+    /// given, else the bind address, or the LAN address when it binds all
+    /// interfaces
+    fn base_url(&self, base_url: Option<&str>) -> String {
+        if let Some(base_url) = base_url {
+            return base_url.trim_end_matches('/').to_owned();
+        }
+        let scheme = if self.tls_enabled() { "https" } else { "http" };
+        let host = match self.bind.parse::<IpAddr>() {
+            Ok(ip) if ip.is_unspecified() => dlna::local_ip().unwrap_or(ip),
+            Ok(ip) => ip,
+            Err(_) => return format!("{}://{}:{}", scheme, self.bind, self.port),
+        };
+        format!("{}://{}", scheme, SocketAddr::new(host, self.port))
+    }
+
+    pub fn cors(&self) -> Result<CorsConfig, ServerError> {
+        let origin_regex = self
+            .cors_origin_regex
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .map_err(|e| ServerError::ParamsError(format!("Invalid CORS origin regex: {}", e)))?;
+        // Any site could read the responses of a logged in user
+        if self.cors_credentials && self.cors_origins.iter().any(|origin| origin == "*") {
+            return Err(ServerError::ParamsError(
+                "CORS origin `*` needs --cors-credentials false".to_owned(),
+            ));
+        }
+        Ok(CorsConfig {
+            origins: self.cors_origins.clone(),
+            origin_regex,
+            credentials: self.cors_credentials,
+            max_age: self.cors_max_age,
+        })
+    }
+
+    pub fn quota_config(&self) -> QuotaConfig {
+        QuotaConfig {
+            catalog: self.catalog_rate,
+            library_write: self.library_write_rate,
+            audio_stream: self.audio_stream_rate,
+            account: self.account_rate,
+            max_streams: self.max_streams,
+        }
+    }
+
+    pub fn quotas(&self) -> Quotas {
+        Quotas::new(self.quota_config())
+    }
+
+    pub fn session_backend(&self) -> Result<SessionBackend, ServerError> {
+        match self.session_store {
+            SessionStoreKind::Cookie => Ok(SessionBackend::Cookie),
+            SessionStoreKind::File => Ok(SessionBackend::File(FileSessionStore::open(
+                Path::new(&self.cache_dir).join("sessions.json"),
+                Duration::from_secs(self.session_idle_timeout),
+                Duration::from_secs(self.session_max_lifetime),
+            )?)),
+        }
+    }
+
+    /// The secret of `--session-secret`, else the one kept in the cache
+    /// directory, which is generated on the first run
+    pub fn session_secret(&self) -> Result<[u8; 64], ServerError> {
         let mut result = [0u8; 64];
         if let Some(secret) = &self.session_secret {
             let mut hasher = sha2::Sha512::new();
             hasher.update(secret.as_bytes());
             let output = hasher.finalize();
             result.copy_from_slice(&output[..]);
-        } else {
-            let mut rand_array = [0u8; 64];
-            rand::thread_rng().fill_bytes(&mut rand_array);
-            let mut hasher = sha2::Sha256::new();
-            hasher.update(&rand_array);
-            let output = hasher.finalize();
-            result.copy_from_slice(&output[..]);
+            return Ok(result);
         }
 
-        result
+        let path = Path::new(&self.cache_dir).join("session_secret");
+        match std::fs::read(&path) {
+            Ok(secret) if secret.len() == result.len() => result.copy_from_slice(&secret),
+            Ok(_) => {
+                return Err(ServerError::ParamsError(format!(
+                    "Invalid session secret file {:?}",
+                    path
+                )))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                rand::thread_rng().fill_bytes(&mut result);
+                write_secret(&path, &result)?;
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        Ok(result)
+    }
+}
+
+/// Write a file only readable by the owner
+fn write_secret(path: &Path, secret: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Cmd {
+        let args = std::iter::once("server").chain(args.iter().copied());
+        Cmd::from_matches(&Cmd::command().get_matches_from(args)).unwrap()
+    }
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn command_line_overrides_the_config_file() {
+        let mut cmd = parse(&["--port", "9000", "--allowed-user", "carol"]);
+        cmd.merge(config(
+            r#"
+            port = 8080
+            allowed_users = ["alice", "bob"]
+            catalog_rate = 300
+            log_level = "debug"
+            "#,
+        ));
+        assert_eq!(cmd.port, 9000);
+        assert_eq!(cmd.allowed_users, vec!["carol"]);
+        assert_eq!(cmd.catalog_rate, 300);
+        assert_eq!(cmd.log_level, "debug");
+    }
+
+    #[test]
+    fn config_file_fills_the_unset_flags() {
+        let mut cmd = parse(&[]);
+        cmd.merge(config(
+            r#"
+            port = 8080
+            allowed_users = ["alice", "bob"]
+            cors_credentials = true
+            "#,
+        ));
+        assert_eq!(cmd.port, 8080);
+        assert_eq!(cmd.allowed_users, vec!["alice", "bob"]);
+        assert!(cmd.cors_credentials);
+
+        // Default values don't count as given on the command line
+        assert_eq!(cmd.catalog_rate, 600);
+        cmd.merge(config("catalog_rate = 60"));
+        assert_eq!(cmd.catalog_rate, 60);
     }
 }
@@ -4,9 +4,12 @@ use aes::cipher::{
     block_padding::{Pkcs7, UnpadError},
     BlockDecryptMut, BlockEncryptMut, KeyIvInit,
 };
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 
 type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
 type Aes128CbcEnc = cbc::Encryptor<aes::Aes128>;
+type HmacSha256 = Hmac<Sha256>;
 
 pub fn encrypt_aes128(key: &[u8], iv: &[u8], buf: &[u8]) -> Vec<u8> {
     let cipher = Aes128CbcEnc::new(key.into(), iv.into());
@@ -17,3 +20,64 @@ pub fn decrypt_aes128(key: &[u8], iv: &[u8], buf: &[u8]) -> Result<Vec<u8>, Unpa
     let cipher = Aes128CbcDec::new(key.into(), iv.into());
     cipher.decrypt_padded_vec_mut::<Pkcs7>(buf)
 }
+
+/// The key of a user's signed uris, which stays valid across restarts
+pub fn user_key(secret: &[u8], username: &str) -> [u8; 32] {
+    hmac_sha256(secret, format!("signed-uri:{}", username).as_bytes())
+}
+
+/// HMAC-SHA256 of `data` with `key`
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Whether `tag` is the HMAC-SHA256 of `data` with `key`, in constant time
+pub fn verify_hmac_sha256(key: &[u8], data: &[u8], tag: &[u8]) -> bool {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.verify_slice(tag).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hmac_sha256_rfc4231() {
+        let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(
+            crate::common::hex::encode(&mac),
+            "5BDCC146BF60754E6A042426089575C75A003F089D2739839DEC58B964EC3843"
+        );
+    }
+
+    #[test]
+    fn hmac_sha256_long_keys() {
+        // RFC 4231 test case 6, a key longer than the block size
+        let mac = hmac_sha256(
+            &[0xaa; 131],
+            b"Test Using Larger Than Block-Size Key - Hash Key First",
+        );
+        assert_eq!(
+            crate::common::hex::encode(&mac),
+            "60E431591EE0B67F0D8A26AACBF5B77F8E0BC6213728C5140546040F0EE37F54"
+        );
+        assert!(verify_hmac_sha256(
+            &[0xaa; 131],
+            b"Test Using Larger Than Block-Size Key - Hash Key First",
+            &mac
+        ));
+        assert!(!verify_hmac_sha256(&[0xaa; 131], b"other data", &mac));
+        assert!(!verify_hmac_sha256(&[0xaa; 131], b"", &mac[..16]));
+    }
+
+    #[test]
+    fn user_keys() {
+        let secret = [7u8; 64];
+        assert_eq!(user_key(&secret, "alice"), user_key(&secret, "alice"));
+        assert_ne!(user_key(&secret, "alice"), user_key(&secret, "bob"));
+        assert_ne!(user_key(&secret, "alice"), user_key(&[8u8; 64], "alice"));
+    }
+}
@@ -24,11 +24,8 @@ pub fn encode(bytes: &[u8]) -> String {
 
 /// Decode a hex string to `Vec<u8>`
 pub fn decode(hex_str: &str) -> Result<Vec<u8>, std::io::Error> {
-    if hex_str.len() % 2 != 0 {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Invalid hex string",
-        ));
+    if !hex_str.len().is_multiple_of(2) {
+        return Err(std::io::Error::other("Invalid hex string"));
     }
     let array: Vec<u8> = hex_str
         .as_bytes()
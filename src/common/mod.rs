@@ -1,3 +1,4 @@
 pub mod crypto;
 pub mod hex;
 pub mod retry;
+pub mod xml;
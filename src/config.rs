@@ -0,0 +1,203 @@
+//! TOML configuration file
+//!
+//! The keys are the long CLI flags with underscores, e.g. `cache_ttl = 600`.
+//! Flags given on the command line override the file.
+//!
+//! ```toml
+//! bind = "0.0.0.0"
+//! port = 8080
+//! log_level = "info"
+//! proxy = "socks5://127.0.0.1:1080"
+//! accounts = ["alice"]
+//! allowed_users = ["alice", "bob"]
+//! admin_token = "secret"
+//! cors_origins = ["https://app.example.com"]
+//! catalog_rate = 300
+//! ```
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use url::Url;
+
+use crate::{
+    app_store::AppStore, cmd::Cmd, errors::ServerError, scrobble::ScrobbleService,
+    session_store::SessionStoreKind,
+};
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub client_id: Option<String>,
+    pub bind: Option<String>,
+    pub port: Option<u16>,
+    pub session_secret: Option<String>,
+    pub session_store: Option<SessionStoreKind>,
+    pub session_idle_timeout: Option<u64>,
+    pub session_max_lifetime: Option<u64>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub https_redirect_port: Option<u16>,
+    pub cookie_name: Option<String>,
+    pub cookie_domain: Option<String>,
+    pub cookie_secure: Option<bool>,
+    pub cookie_http_only: Option<bool>,
+    pub cookie_max_age: Option<i64>,
+    pub load_cache: Option<bool>,
+    pub cache_dir: Option<String>,
+    pub log_level: Option<String>,
+    pub proxy: Option<Url>,
+    pub scope: Option<String>,
+    pub accounts: Option<Vec<String>>,
+    pub allowed_users: Option<Vec<String>>,
+    pub admin_token: Option<String>,
+    pub subsonic_users: Option<Vec<String>>,
+    pub mpd_port: Option<u16>,
+    pub mpd_user: Option<String>,
+    pub mpd_password: Option<String>,
+    pub mpd_base_url: Option<String>,
+    pub dlna_user: Option<String>,
+    pub dlna_name: Option<String>,
+    pub dlna_base_url: Option<String>,
+    pub scrobble_service: Option<ScrobbleService>,
+    pub scrobble_url: Option<String>,
+    pub scrobble_api_key: Option<String>,
+    pub scrobble_api_secret: Option<String>,
+    pub scrobble_tokens: Option<Vec<String>>,
+    pub webhooks: Option<Vec<String>>,
+    pub webhook_secret: Option<String>,
+    pub webhook_events: Option<Vec<String>>,
+    pub webhook_max_attempts: Option<u32>,
+    pub cors_origins: Option<Vec<String>>,
+    pub cors_origin_regex: Option<String>,
+    pub cors_credentials: Option<bool>,
+    pub cors_max_age: Option<usize>,
+    pub cache_ttl: Option<u64>,
+    pub cache_max_entries: Option<usize>,
+    pub disk_cache: Option<bool>,
+    pub spotify_max_wait: Option<u64>,
+    pub spotify_max_concurrency: Option<usize>,
+    pub catalog_rate: Option<u32>,
+    pub library_write_rate: Option<u32>,
+    pub audio_stream_rate: Option<u32>,
+    pub account_rate: Option<u32>,
+    pub max_streams: Option<usize>,
+    // Only used with the `otlp` feature
+    pub otlp_endpoint: Option<String>,
+    pub otlp_service_name: Option<String>,
+}
+
+impl Config {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, ServerError> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+        toml::from_str(&content)
+            .map_err(|e| ServerError::InnerError(format!("Invalid config file {:?}: {}", path, e)))
+    }
+}
+
+/// Change the log filter of the running subscriber
+pub type SetLogFilter = Box<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+/// Reload the safe subset of the configuration without a restart:
+/// the log filter, the rate limits and the allowed users
+pub struct Reloader {
+    /// The command line before the config file is merged, so the keys
+    /// removed from the file revert to their defaults
+    cmd: Cmd,
+    config_path: PathBuf,
+    set_log_filter: SetLogFilter,
+}
+
+impl Reloader {
+    pub fn new(cmd: Cmd, config_path: PathBuf, set_log_filter: SetLogFilter) -> Self {
+        Self {
+            cmd,
+            config_path,
+            set_log_filter,
+        }
+    }
+
+    pub fn reload(&self, app_store: &AppStore) -> Result<(), ServerError> {
+        let mut cmd = self.cmd.clone();
+        cmd.merge(Config::from_file(&self.config_path)?);
+
+        (self.set_log_filter)(&cmd.log_level)
+            .map_err(|e| ServerError::InnerError(format!("Invalid log level: {}", e)))?;
+        app_store.quotas.set_config(cmd.quota_config());
+        app_store.set_allowed_users(cmd.allowed_users.iter().cloned().collect::<HashSet<_>>());
+
+        tracing::info!("Reloaded config {:?}", self.config_path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use clap::CommandFactory;
+
+    use super::*;
+
+    fn write_config(dir: &Path, toml: &str) -> PathBuf {
+        let path = dir.join("config.toml");
+        std::fs::write(&path, toml).unwrap();
+        path
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_config(dir.path(), "port = 8080\ncatalog_rates = 60\n");
+        let err = Config::from_file(&path).unwrap_err().to_string();
+        assert!(err.contains("catalog_rates"), "{}", err);
+
+        let path = write_config(dir.path(), "port = \"8080\"\n");
+        assert!(Config::from_file(&path).is_err());
+    }
+
+    #[test]
+    fn reload_applies_the_file_onto_the_command_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_config(
+            dir.path(),
+            "log_level = \"debug\"\ncatalog_rate = 60\nallowed_users = [\"alice\"]\n",
+        );
+        let matches = Cmd::command().get_matches_from(["server", "--account-rate", "100"]);
+        let cmd = Cmd::from_matches(&matches).unwrap();
+
+        let log_filters = Arc::new(Mutex::new(vec![]));
+        let recorded = log_filters.clone();
+        let reloader = Reloader::new(
+            cmd,
+            path.clone(),
+            Box::new(move |filter| {
+                recorded.lock().unwrap().push(filter.to_owned());
+                Ok(())
+            }),
+        );
+        let app_store = AppStore::new("client", dir.path().to_str().unwrap(), None);
+
+        reloader.reload(&app_store).unwrap();
+        assert_eq!(app_store.quotas.config().catalog, 60);
+        assert_eq!(app_store.quotas.config().account, 100);
+        assert!(app_store.is_allowed("alice"));
+        assert!(!app_store.is_allowed("bob"));
+
+        // The keys removed from the file revert to their defaults
+        write_config(dir.path(), "account_rate = 50\n");
+        reloader.reload(&app_store).unwrap();
+        assert_eq!(app_store.quotas.config().catalog, 600);
+        assert_eq!(app_store.quotas.config().account, 100);
+        assert!(app_store.is_allowed("bob"));
+        assert_eq!(*log_filters.lock().unwrap(), vec!["debug", "info"]);
+
+        // An invalid file keeps the current config
+        write_config(dir.path(), "catalog_rate = -1\n");
+        assert!(reloader.reload(&app_store).is_err());
+        assert_eq!(app_store.quotas.config().catalog, 600);
+    }
+}
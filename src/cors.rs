@@ -0,0 +1,114 @@
+//! CORS for browser frontends on other origins
+//!
+//! The session cookie is `SameSite=None`, so cross-origin frontends can send
+//! credentialed requests once their origins are allowed here.
+
+use actix_cors::Cors;
+use actix_web::http::{header, Method};
+use regex::Regex;
+
+/// The headers which frontends can read, e.g. `Content-Range` of audio streams
+const EXPOSED_HEADERS: [header::HeaderName; 5] = [
+    header::CONTENT_RANGE,
+    header::CONTENT_LENGTH,
+    header::ACCEPT_RANGES,
+    header::RETRY_AFTER,
+    header::ETAG,
+];
+
+#[derive(Clone, Debug, Default)]
+pub struct CorsConfig {
+    /// Allowed origins, `*` allows any origin, only without credentials
+    pub origins: Vec<String>,
+    /// Origins matching the regex are allowed too
+    pub origin_regex: Option<Regex>,
+    /// Allow cookies, i.e. the session, on cross-origin requests
+    pub credentials: bool,
+    /// Seconds for browsers to cache preflight responses
+    pub max_age: usize,
+}
+
+impl CorsConfig {
+    /// CORS is disabled when no origin is allowed
+    pub fn enabled(&self) -> bool {
+        !self.origins.is_empty() || self.origin_regex.is_some()
+    }
+
+    /// Whether `origin` may send credentialed requests, which WebSocket
+    /// handshakes always are, as browsers send cookies without CORS checks
+    pub fn is_allowed(&self, origin: &str) -> bool {
+        self.credentials
+            && (self.origins.iter().any(|allowed| allowed == origin)
+                || self
+                    .origin_regex
+                    .as_ref()
+                    .is_some_and(|regex| regex.is_match(origin)))
+    }
+
+    pub fn build(&self) -> Cors {
+        let mut cors = Cors::default()
+            .allowed_methods([
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::DELETE,
+                Method::OPTIONS,
+            ])
+            .allow_any_header()
+            .expose_headers(EXPOSED_HEADERS)
+            .max_age(self.max_age);
+
+        if self.credentials {
+            cors = cors.supports_credentials();
+        }
+
+        // `Cmd::cors` only allows any origin without credentials
+        if self.origins.iter().any(|origin| origin == "*") {
+            return cors.allow_any_origin();
+        }
+        for origin in &self.origins {
+            cors = cors.allowed_origin(origin);
+        }
+        if let Some(regex) = self.origin_regex.clone() {
+            cors = cors.allowed_origin_fn(move |origin, _| {
+                origin
+                    .to_str()
+                    .map(|origin| regex.is_match(origin))
+                    .unwrap_or(false)
+            });
+        }
+        cors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(origins: &[&str], regex: Option<&str>, credentials: bool) -> CorsConfig {
+        CorsConfig {
+            origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            origin_regex: regex.map(|regex| Regex::new(regex).unwrap()),
+            credentials,
+            max_age: 0,
+        }
+    }
+
+    #[test]
+    fn allowed_origins() {
+        let cors = config(
+            &["https://app.example.com"],
+            Some(r"^https://[a-z]+\.example\.org$"),
+            true,
+        );
+        assert!(cors.is_allowed("https://app.example.com"));
+        assert!(cors.is_allowed("https://web.example.org"));
+        assert!(!cors.is_allowed("https://evil.example.net"));
+        assert!(!cors.is_allowed("http://app.example.com"));
+
+        // Without credentials, no origin gets the session
+        let cors = config(&["https://app.example.com", "*"], None, false);
+        assert!(!cors.is_allowed("https://app.example.com"));
+        assert!(!CorsConfig::default().is_allowed("https://app.example.com"));
+    }
+}
@@ -0,0 +1,709 @@
+//! The ContentDirectory tree
+//!
+//! ```text
+//! 0
+//! ├── playlists       My Playlists    playlist:{id} → track:{id}, episode:{id}
+//! ├── albums          Saved Albums    album:{id} → track:{id}
+//! ├── tracks          Saved Tracks    track:{id}
+//! ├── shows           Saved Shows     show:{id} → episode:{id}
+//! ├── categories      Browse Categories  category:{id} → playlist:{id}
+//! └── new-releases    New Releases    album:{id}
+//! ```
+
+use std::future::Future;
+
+use rspotify::{
+    clients::{BaseClient, OAuthClient},
+    model::{
+        AlbumId, EpisodeId, FullAlbum, FullEpisode, FullTrack, Id, Image, Page, PlayableItem,
+        PlaylistId, ShowId, SimplifiedAlbum, SimplifiedArtist, SimplifiedEpisode,
+        SimplifiedPlaylist, SimplifiedShow, TrackId,
+    },
+    ClientResult,
+};
+
+use crate::{
+    account::{SpotifyAccount, UserName},
+    endpoints::{audios::signed_audio_uri, categories::all_categories},
+    errors::ServerError,
+};
+
+use super::{
+    didl::{self, Container, Item, Object},
+    soap::SoapError,
+    DlnaConfig,
+};
+
+const ROOT: &str = "0";
+
+/// The page size of the Spotify requests
+const PAGE_SIZE: usize = 50;
+
+/// The most children of one `Browse`, renderers page through the rest
+const MAX_BROWSE_COUNT: usize = 200;
+
+/// The containers of the root
+const TOP_CONTAINERS: [(&str, &str); 6] = [
+    ("playlists", "My Playlists"),
+    ("albums", "Saved Albums"),
+    ("tracks", "Saved Tracks"),
+    ("shows", "Saved Shows"),
+    ("categories", "Browse Categories"),
+    ("new-releases", "New Releases"),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BrowseFlag {
+    Metadata,
+    DirectChildren,
+}
+
+impl BrowseFlag {
+    pub fn parse(flag: &str) -> Option<Self> {
+        match flag {
+            "BrowseMetadata" => Some(BrowseFlag::Metadata),
+            "BrowseDirectChildren" => Some(BrowseFlag::DirectChildren),
+            _ => None,
+        }
+    }
+}
+
+/// The `Browse` result
+pub struct Browse {
+    /// DIDL-Lite
+    pub result: String,
+    pub number_returned: usize,
+    pub total_matches: usize,
+}
+
+fn art(images: &[Image]) -> Option<String> {
+    images.first().map(|image| image.url.clone())
+}
+
+fn artist_names(artists: &[SimplifiedArtist]) -> String {
+    artists
+        .iter()
+        .map(|artist| artist.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn storage_folder(id: &str, title: &str) -> Object {
+    Object::Container(Container {
+        id: id.to_owned(),
+        parent_id: ROOT.to_owned(),
+        title: title.to_owned(),
+        class: didl::STORAGE_FOLDER,
+        child_count: None,
+        art: None,
+    })
+}
+
+fn playlist_container(playlist: &SimplifiedPlaylist, parent_id: &str) -> Object {
+    Object::Container(Container {
+        id: format!("playlist:{}", playlist.id.id()),
+        parent_id: parent_id.to_owned(),
+        title: playlist.name.clone(),
+        class: didl::PLAYLIST_CONTAINER,
+        child_count: Some(playlist.tracks.total),
+        art: art(&playlist.images),
+    })
+}
+
+fn album_container(album: &SimplifiedAlbum, parent_id: &str) -> Option<Object> {
+    Some(Object::Container(Container {
+        id: format!("album:{}", album.id.as_ref()?.id()),
+        parent_id: parent_id.to_owned(),
+        title: album.name.clone(),
+        class: didl::MUSIC_ALBUM,
+        child_count: None,
+        art: art(&album.images),
+    }))
+}
+
+fn full_album_container(album: &FullAlbum, parent_id: &str) -> Object {
+    Object::Container(Container {
+        id: format!("album:{}", album.id.id()),
+        parent_id: parent_id.to_owned(),
+        title: album.name.clone(),
+        class: didl::MUSIC_ALBUM,
+        child_count: Some(album.tracks.total),
+        art: art(&album.images),
+    })
+}
+
+fn show_container(show: &SimplifiedShow) -> Object {
+    Object::Container(Container {
+        id: format!("show:{}", show.id.id()),
+        parent_id: "shows".to_owned(),
+        title: show.name.clone(),
+        class: didl::STORAGE_FOLDER,
+        child_count: None,
+        art: art(&show.images),
+    })
+}
+
+/// What items show of episodes
+struct EpisodeRef<'a> {
+    id: &'a EpisodeId<'a>,
+    name: &'a str,
+    release_date: &'a str,
+    images: &'a [Image],
+    duration: chrono::Duration,
+    show: &'a str,
+}
+
+impl<'a> EpisodeRef<'a> {
+    fn simplified(episode: &'a SimplifiedEpisode, show: &'a str) -> Self {
+        EpisodeRef {
+            id: &episode.id,
+            name: &episode.name,
+            release_date: &episode.release_date,
+            images: &episode.images,
+            duration: episode.duration,
+            show,
+        }
+    }
+
+    fn full(episode: &'a FullEpisode) -> Self {
+        EpisodeRef {
+            id: &episode.id,
+            name: &episode.name,
+            release_date: &episode.release_date,
+            images: &episode.images,
+            duration: episode.duration,
+            show: &episode.show.name,
+        }
+    }
+}
+
+/// `Browse` the root and its top containers, which need no Spotify account,
+/// `None` for the other objects
+pub fn browse_static(
+    config: &DlnaConfig,
+    object_id: &str,
+    flag: BrowseFlag,
+    starting_index: usize,
+    requested_count: usize,
+) -> Option<Browse> {
+    let window = Window::new(starting_index, requested_count);
+    let (objects, total_matches) = match (object_id, flag) {
+        (ROOT, BrowseFlag::Metadata) => {
+            let root = Object::Container(Container {
+                id: ROOT.to_owned(),
+                parent_id: "-1".to_owned(),
+                title: config.friendly_name.clone(),
+                class: didl::STORAGE_FOLDER,
+                child_count: Some(TOP_CONTAINERS.len() as u32),
+                art: None,
+            });
+            (vec![root], 1)
+        }
+        (ROOT, BrowseFlag::DirectChildren) => {
+            let folders = TOP_CONTAINERS
+                .iter()
+                .skip(window.offset)
+                .take(window.count)
+                .map(|(id, title)| storage_folder(id, title))
+                .collect();
+            (folders, TOP_CONTAINERS.len())
+        }
+        (object_id, BrowseFlag::Metadata) => {
+            let (id, title) = TOP_CONTAINERS.iter().find(|(id, _)| *id == object_id)?;
+            (vec![storage_folder(id, title)], 1)
+        }
+        _ => return None,
+    };
+    Some(Browse {
+        result: didl::didl(&objects),
+        number_returned: objects.len(),
+        total_matches,
+    })
+}
+
+/// The children which a `Browse` requests
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Window {
+    offset: usize,
+    count: usize,
+}
+
+impl Window {
+    /// `StartingIndex` and `RequestedCount`, where a count of 0 asks for all
+    /// children, up to `MAX_BROWSE_COUNT`
+    fn new(starting_index: usize, requested_count: usize) -> Self {
+        let count = match requested_count {
+            0 => MAX_BROWSE_COUNT,
+            count => count.min(MAX_BROWSE_COUNT),
+        };
+        Window {
+            offset: starting_index,
+            count,
+        }
+    }
+
+    /// Fetch the Spotify pages which cover the window, and the total of items
+    async fn fetch<T, F, Fut>(&self, fetch: F) -> Result<(Vec<T>, usize), ServerError>
+    where
+        F: Fn(Option<u32>, Option<u32>) -> Fut,
+        Fut: Future<Output = ClientResult<Page<T>>>,
+    {
+        let mut items = vec![];
+        loop {
+            let limit = (self.count - items.len()).min(PAGE_SIZE);
+            let offset = self.offset + items.len();
+            let page = fetch(Some(limit as u32), Some(offset as u32)).await?;
+            let total = page.total as usize;
+            let done = page.items.is_empty() || page.next.is_none();
+            items.extend(page.items);
+            if done || items.len() >= self.count {
+                return Ok((items, total));
+            }
+        }
+    }
+}
+
+/// The ContentDirectory of the account of `--dlna-user`
+pub struct ContentDirectory<'a> {
+    config: &'a DlnaConfig,
+    account: &'a SpotifyAccount,
+}
+
+impl<'a> ContentDirectory<'a> {
+    pub fn new(config: &'a DlnaConfig, account: &'a SpotifyAccount) -> Self {
+        ContentDirectory { config, account }
+    }
+
+    /// `Browse` the objects of the Spotify account, see `browse_static` for
+    /// the others
+    pub async fn browse(
+        &self,
+        object_id: &str,
+        flag: BrowseFlag,
+        starting_index: usize,
+        requested_count: usize,
+    ) -> Result<Browse, SoapError> {
+        let window = Window::new(starting_index, requested_count);
+        let (objects, total_matches) = match flag {
+            BrowseFlag::Metadata => (vec![self.metadata(object_id).await?], 1),
+            BrowseFlag::DirectChildren => self.children(object_id, window).await?,
+        };
+        Ok(Browse {
+            result: didl::didl(&objects),
+            number_returned: objects.len(),
+            total_matches,
+        })
+    }
+
+    /// The signed stream url of an audio
+    fn url(&self, uri: &str) -> Result<String, ServerError> {
+        let username = UserName::from(self.config.username.as_str());
+        Ok(format!(
+            "{}{}",
+            self.config.base_url,
+            signed_audio_uri(self.account, &username, uri)?
+        ))
+    }
+
+    fn track_item(
+        &self,
+        track: &FullTrack,
+        parent_id: &str,
+    ) -> Result<Option<Object>, ServerError> {
+        let Some(id) = &track.id else {
+            return Ok(None);
+        };
+        Ok(Some(Object::Item(Item {
+            id: format!("track:{}", id.id()),
+            parent_id: parent_id.to_owned(),
+            title: track.name.clone(),
+            class: didl::MUSIC_TRACK,
+            artist: Some(artist_names(&track.artists)),
+            album: Some(track.album.name.clone()),
+            track_number: Some(track.track_number),
+            date: track.album.release_date.clone(),
+            art: art(&track.album.images),
+            duration: track.duration.num_milliseconds(),
+            url: self.url(&id.uri())?,
+        })))
+    }
+
+    fn episode_item(
+        &self,
+        episode: EpisodeRef<'_>,
+        parent_id: &str,
+    ) -> Result<Object, ServerError> {
+        Ok(Object::Item(Item {
+            id: format!("episode:{}", episode.id.id()),
+            parent_id: parent_id.to_owned(),
+            title: episode.name.to_owned(),
+            class: didl::AUDIO_ITEM,
+            artist: None,
+            album: Some(episode.show.to_owned()),
+            track_number: None,
+            date: Some(episode.release_date.to_owned()),
+            art: art(episode.images),
+            duration: episode.duration.num_milliseconds(),
+            url: self.url(&episode.id.uri())?,
+        }))
+    }
+
+    /// The children in `window`, and the number of all children
+    async fn children(
+        &self,
+        object_id: &str,
+        window: Window,
+    ) -> Result<(Vec<Object>, usize), SoapError> {
+        let account = self.account;
+        let client = &account.client;
+        let market = Some(account.default_market().await);
+        let mut objects = vec![];
+        let total = match object_id.split_once(':') {
+            None => match object_id {
+                "playlists" => {
+                    let (playlists, total) = window
+                        .fetch(|limit, offset| client.current_user_playlists_manual(limit, offset))
+                        .await?;
+                    for playlist in playlists {
+                        objects.push(playlist_container(&playlist, object_id));
+                    }
+                    total
+                }
+                "albums" => {
+                    let (saved, total) = window
+                        .fetch(|limit, offset| {
+                            client.current_user_saved_albums_manual(market, limit, offset)
+                        })
+                        .await?;
+                    for saved in saved {
+                        objects.push(full_album_container(&saved.album, object_id));
+                    }
+                    total
+                }
+                "tracks" => {
+                    let (saved, total) = window
+                        .fetch(|limit, offset| {
+                            client.current_user_saved_tracks_manual(market, limit, offset)
+                        })
+                        .await?;
+                    for saved in saved {
+                        objects.extend(self.track_item(&saved.track, object_id)?);
+                    }
+                    total
+                }
+                "shows" => {
+                    let (saved, total) = window
+                        .fetch(|limit, offset| client.get_saved_show_manual(limit, offset))
+                        .await?;
+                    for saved in saved {
+                        objects.push(show_container(&saved.show));
+                    }
+                    total
+                }
+                "categories" => {
+                    let (categories, total) = window
+                        .fetch(|limit, offset| {
+                            client.categories_manual(None, market, limit, offset)
+                        })
+                        .await?;
+                    for category in categories {
+                        objects.push(Object::Container(Container {
+                            id: format!("category:{}", category.id),
+                            parent_id: object_id.to_owned(),
+                            title: category.name,
+                            class: didl::STORAGE_FOLDER,
+                            child_count: None,
+                            art: art(&category.icons),
+                        }));
+                    }
+                    total
+                }
+                "new-releases" => {
+                    let (albums, total) = window
+                        .fetch(|limit, offset| client.new_releases_manual(market, limit, offset))
+                        .await?;
+                    for album in albums {
+                        objects.extend(album_container(&album, object_id));
+                    }
+                    total
+                }
+                _ => return Err(SoapError::no_such_object()),
+            },
+            Some(("playlist", id)) => {
+                let playlist_id =
+                    PlaylistId::from_id(id).map_err(|_| SoapError::no_such_object())?;
+                let (items, total) = window
+                    .fetch(|limit, offset| {
+                        client.playlist_items_manual(
+                            playlist_id.clone(),
+                            None,
+                            market,
+                            limit,
+                            offset,
+                        )
+                    })
+                    .await?;
+                for item in items {
+                    match item.track {
+                        Some(PlayableItem::Track(track)) => {
+                            objects.extend(self.track_item(&track, object_id)?);
+                        }
+                        Some(PlayableItem::Episode(episode)) => {
+                            objects.push(self.episode_item(EpisodeRef::full(&episode), object_id)?);
+                        }
+                        None => {}
+                    }
+                }
+                total
+            }
+            Some(("album", id)) => {
+                let album_id = AlbumId::from_id(id).map_err(|_| SoapError::no_such_object())?;
+                let album = client
+                    .album(album_id.clone(), market)
+                    .await
+                    .map_err(ServerError::from)?;
+                let (tracks, total) = window
+                    .fetch(|limit, offset| {
+                        client.album_track_manual(album_id.clone(), market, limit, offset)
+                    })
+                    .await?;
+                for track in &tracks {
+                    let Some(track_id) = &track.id else {
+                        continue;
+                    };
+                    objects.push(Object::Item(Item {
+                        id: format!("track:{}", track_id.id()),
+                        parent_id: object_id.to_owned(),
+                        title: track.name.clone(),
+                        class: didl::MUSIC_TRACK,
+                        artist: Some(artist_names(&track.artists)),
+                        album: Some(album.name.clone()),
+                        track_number: Some(track.track_number),
+                        date: Some(album.release_date.clone()),
+                        art: art(&album.images),
+                        duration: track.duration.num_milliseconds(),
+                        url: self.url(&track_id.uri())?,
+                    }));
+                }
+                total
+            }
+            Some(("show", id)) => {
+                let show_id = ShowId::from_id(id).map_err(|_| SoapError::no_such_object())?;
+                let show = client
+                    .get_a_show(show_id.clone(), market)
+                    .await
+                    .map_err(ServerError::from)?;
+                let (episodes, total) = window
+                    .fetch(|limit, offset| {
+                        client.get_shows_episodes_manual(show_id.clone(), market, limit, offset)
+                    })
+                    .await?;
+                for episode in &episodes {
+                    let episode = EpisodeRef::simplified(episode, &show.name);
+                    objects.push(self.episode_item(episode, object_id)?);
+                }
+                total
+            }
+            Some(("category", id)) => {
+                let (playlists, total) = window
+                    .fetch(|limit, offset| {
+                        client.category_playlists_manual(id, market, limit, offset)
+                    })
+                    .await?;
+                for playlist in playlists {
+                    objects.push(playlist_container(&playlist, object_id));
+                }
+                total
+            }
+            // Items have no children
+            Some(("track" | "episode", _)) => 0,
+            Some(_) => return Err(SoapError::no_such_object()),
+        };
+        Ok((objects, total))
+    }
+
+    async fn metadata(&self, object_id: &str) -> Result<Object, SoapError> {
+        let account = self.account;
+        let market = account.default_market().await;
+        match object_id.split_once(':') {
+            None => Err(SoapError::no_such_object()),
+            Some(("playlist", id)) => {
+                let playlist_id =
+                    PlaylistId::from_id(id).map_err(|_| SoapError::no_such_object())?;
+                let playlist = account
+                    .client
+                    .playlist(playlist_id, None, Some(market))
+                    .await
+                    .map_err(ServerError::from)?;
+                Ok(Object::Container(Container {
+                    id: object_id.to_owned(),
+                    parent_id: "playlists".to_owned(),
+                    title: playlist.name,
+                    class: didl::PLAYLIST_CONTAINER,
+                    child_count: Some(playlist.tracks.total),
+                    art: art(&playlist.images),
+                }))
+            }
+            Some(("album", id)) => {
+                let album_id = AlbumId::from_id(id).map_err(|_| SoapError::no_such_object())?;
+                let album = account
+                    .client
+                    .album(album_id, Some(market))
+                    .await
+                    .map_err(ServerError::from)?;
+                Ok(full_album_container(&album, "albums"))
+            }
+            Some(("show", id)) => {
+                let show_id = ShowId::from_id(id).map_err(|_| SoapError::no_such_object())?;
+                let show = account
+                    .client
+                    .get_a_show(show_id, Some(market))
+                    .await
+                    .map_err(ServerError::from)?;
+                Ok(Object::Container(Container {
+                    id: object_id.to_owned(),
+                    parent_id: "shows".to_owned(),
+                    title: show.name,
+                    class: didl::STORAGE_FOLDER,
+                    child_count: None,
+                    art: art(&show.images),
+                }))
+            }
+            Some(("category", id)) => all_categories(account, None, Some(market))
+                .await?
+                .into_iter()
+                .find(|category| category.id == id)
+                .map(|category| {
+                    Object::Container(Container {
+                        id: object_id.to_owned(),
+                        parent_id: "categories".to_owned(),
+                        title: category.name,
+                        class: didl::STORAGE_FOLDER,
+                        child_count: None,
+                        art: art(&category.icons),
+                    })
+                })
+                .ok_or_else(SoapError::no_such_object),
+            Some(("track", id)) => {
+                let track_id = TrackId::from_id(id).map_err(|_| SoapError::no_such_object())?;
+                let track = account
+                    .client
+                    .track(track_id, Some(market))
+                    .await
+                    .map_err(ServerError::from)?;
+                let parent_id = track
+                    .album
+                    .id
+                    .as_ref()
+                    .map(|id| format!("album:{}", id.id()))
+                    .unwrap_or_else(|| "tracks".to_owned());
+                self.track_item(&track, &parent_id)?
+                    .ok_or_else(SoapError::no_such_object)
+            }
+            Some(("episode", id)) => {
+                let episode_id = EpisodeId::from_id(id).map_err(|_| SoapError::no_such_object())?;
+                let episode = account
+                    .client
+                    .get_an_episode(episode_id, Some(market))
+                    .await
+                    .map_err(ServerError::from)?;
+                let parent_id = format!("show:{}", episode.show.id.id());
+                Ok(self.episode_item(EpisodeRef::full(&episode), &parent_id)?)
+            }
+            Some(_) => Err(SoapError::no_such_object()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// A Spotify page of `total` numbers
+    fn page(total: usize, limit: Option<u32>, offset: Option<u32>) -> Page<usize> {
+        let (limit, offset) = (limit.unwrap() as usize, offset.unwrap() as usize);
+        let end = total.min(offset + limit);
+        Page {
+            href: String::new(),
+            items: (offset.min(end)..end).collect(),
+            limit: limit as u32,
+            next: (end < total).then(String::new),
+            offset: offset as u32,
+            previous: None,
+            total: total as u32,
+        }
+    }
+
+    #[test]
+    fn windows() {
+        assert_eq!(
+            Window::new(5, 10),
+            Window {
+                offset: 5,
+                count: 10
+            }
+        );
+        assert_eq!(Window::new(0, 0).count, MAX_BROWSE_COUNT);
+        assert_eq!(Window::new(0, 10_000).count, MAX_BROWSE_COUNT);
+    }
+
+    #[tokio::test]
+    async fn fetch_maps_the_window_onto_pages() {
+        let requests = Mutex::new(vec![]);
+        let (items, total) = Window::new(10, 120)
+            .fetch(|limit, offset| {
+                requests.lock().unwrap().push((limit, offset));
+                async move { Ok(page(1000, limit, offset)) }
+            })
+            .await
+            .unwrap();
+        assert_eq!(items, (10..130).collect::<Vec<_>>());
+        assert_eq!(total, 1000);
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                (Some(50), Some(10)),
+                (Some(50), Some(60)),
+                (Some(20), Some(110))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fetch_stops_at_the_last_page() {
+        let (items, total) = Window::new(95, 0)
+            .fetch(|limit, offset| async move { Ok(page(100, limit, offset)) })
+            .await
+            .unwrap();
+        assert_eq!(items, (95..100).collect::<Vec<_>>());
+        assert_eq!(total, 100);
+
+        let (items, total) = Window::new(200, 10)
+            .fetch(|limit, offset| async move { Ok(page(100, limit, offset)) })
+            .await
+            .unwrap();
+        assert!(items.is_empty());
+        assert_eq!(total, 100);
+    }
+
+    #[test]
+    fn static_browse() {
+        let config = DlnaConfig::new(
+            "alice".to_owned(),
+            "Spotify".to_owned(),
+            "http://192.168.1.2:8080".to_owned(),
+        );
+        let root = browse_static(&config, ROOT, BrowseFlag::DirectChildren, 4, 10).unwrap();
+        assert_eq!(root.number_returned, 2);
+        assert_eq!(root.total_matches, TOP_CONTAINERS.len());
+        assert!(root.result.contains(r#"<container id="categories""#));
+
+        let folder = browse_static(&config, "albums", BrowseFlag::Metadata, 0, 0).unwrap();
+        assert!(folder.result.contains("<dc:title>Saved Albums</dc:title>"));
+
+        assert!(browse_static(&config, "albums", BrowseFlag::DirectChildren, 0, 0).is_none());
+        assert!(browse_static(&config, "album:1", BrowseFlag::Metadata, 0, 0).is_none());
+    }
+}
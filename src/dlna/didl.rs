@@ -0,0 +1,181 @@
+//! DIDL-Lite, the object listing of `Browse` results
+
+use std::fmt::Write;
+
+use crate::common::xml::escape;
+
+pub const STORAGE_FOLDER: &str = "object.container.storageFolder";
+pub const PLAYLIST_CONTAINER: &str = "object.container.playlistContainer";
+pub const MUSIC_ALBUM: &str = "object.container.album.musicAlbum";
+pub const MUSIC_TRACK: &str = "object.item.audioItem.musicTrack";
+pub const AUDIO_ITEM: &str = "object.item.audioItem";
+
+/// What `/audio-stream-with-sign` serves
+pub const PROTOCOL_INFO: &str = "http-get:*:audio/ogg:DLNA.ORG_OP=00;DLNA.ORG_CI=0;DLNA.ORG_FLAGS=01700000000000000000000000000000";
+
+#[derive(Debug)]
+pub struct Container {
+    pub id: String,
+    pub parent_id: String,
+    pub title: String,
+    pub class: &'static str,
+    pub child_count: Option<u32>,
+    pub art: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Item {
+    pub id: String,
+    pub parent_id: String,
+    pub title: String,
+    pub class: &'static str,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track_number: Option<u32>,
+    pub date: Option<String>,
+    pub art: Option<String>,
+    /// Milliseconds
+    pub duration: i64,
+    /// The signed stream url
+    pub url: String,
+}
+
+#[derive(Debug)]
+pub enum Object {
+    Container(Container),
+    Item(Item),
+}
+
+/// `H:MM:SS.mmm` of `res@duration`
+fn duration(millis: i64) -> String {
+    let seconds = millis / 1000;
+    format!(
+        "{}:{:02}:{:02}.{:03}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        millis % 1000
+    )
+}
+
+fn write_element(out: &mut String, name: &str, value: Option<&str>) {
+    if let Some(value) = value {
+        let _ = write!(out, "<{name}>{}</{name}>", escape(value));
+    }
+}
+
+impl Object {
+    fn write(&self, out: &mut String) {
+        match self {
+            Object::Container(container) => {
+                let _ = write!(
+                    out,
+                    r#"<container id="{}" parentID="{}" restricted="1" searchable="0""#,
+                    escape(&container.id),
+                    escape(&container.parent_id)
+                );
+                if let Some(count) = container.child_count {
+                    let _ = write!(out, r#" childCount="{}""#, count);
+                }
+                out.push('>');
+                write_element(out, "dc:title", Some(&container.title));
+                write_element(out, "upnp:class", Some(container.class));
+                write_element(out, "upnp:albumArtURI", container.art.as_deref());
+                out.push_str("</container>");
+            }
+            Object::Item(item) => {
+                let _ = write!(
+                    out,
+                    r#"<item id="{}" parentID="{}" restricted="1">"#,
+                    escape(&item.id),
+                    escape(&item.parent_id)
+                );
+                write_element(out, "dc:title", Some(&item.title));
+                write_element(out, "upnp:class", Some(item.class));
+                write_element(out, "dc:creator", item.artist.as_deref());
+                write_element(out, "upnp:artist", item.artist.as_deref());
+                write_element(out, "upnp:album", item.album.as_deref());
+                write_element(
+                    out,
+                    "upnp:originalTrackNumber",
+                    item.track_number.map(|n| n.to_string()).as_deref(),
+                );
+                write_element(out, "dc:date", item.date.as_deref());
+                write_element(out, "upnp:albumArtURI", item.art.as_deref());
+                let _ = write!(
+                    out,
+                    r#"<res protocolInfo="{}" duration="{}">{}</res>"#,
+                    PROTOCOL_INFO,
+                    duration(item.duration),
+                    escape(&item.url)
+                );
+                out.push_str("</item>");
+            }
+        }
+    }
+}
+
+pub fn didl(objects: &[Object]) -> String {
+    let mut out = String::from(
+        r#"<DIDL-Lite xmlns="urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:upnp="urn:schemas-upnp-org:metadata-1-0/upnp/">"#,
+    );
+    for object in objects {
+        object.write(&mut out);
+    }
+    out.push_str("</DIDL-Lite>");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(duration(0), "0:00:00.000");
+        assert_eq!(duration(3_723_004), "1:02:03.004");
+    }
+
+    #[test]
+    fn objects() {
+        let objects = [
+            Object::Container(Container {
+                id: "album:1".to_owned(),
+                parent_id: "albums".to_owned(),
+                title: "Rock & Roll".to_owned(),
+                class: MUSIC_ALBUM,
+                child_count: Some(2),
+                art: None,
+            }),
+            Object::Item(Item {
+                id: "track:2".to_owned(),
+                parent_id: "album:1".to_owned(),
+                title: "Song".to_owned(),
+                class: MUSIC_TRACK,
+                artist: Some("Band".to_owned()),
+                album: None,
+                track_number: Some(3),
+                date: None,
+                art: Some("http://i/1.jpg".to_owned()),
+                duration: 61_500,
+                url: "http://h/audio?a=1&b=2".to_owned(),
+            }),
+        ];
+        let didl = didl(&objects);
+        assert!(didl.starts_with("<DIDL-Lite xmlns="));
+        assert!(didl.ends_with("</DIDL-Lite>"));
+        assert!(didl.contains(concat!(
+            r#"<container id="album:1" parentID="albums" restricted="1" searchable="0" childCount="2">"#,
+            r#"<dc:title>Rock &amp; Roll</dc:title>"#,
+            r#"<upnp:class>object.container.album.musicAlbum</upnp:class></container>"#
+        )));
+        assert!(didl.contains(concat!(
+            r#"<item id="track:2" parentID="album:1" restricted="1"><dc:title>Song</dc:title>"#,
+            r#"<upnp:class>object.item.audioItem.musicTrack</upnp:class>"#,
+            r#"<dc:creator>Band</dc:creator><upnp:artist>Band</upnp:artist>"#,
+            r#"<upnp:originalTrackNumber>3</upnp:originalTrackNumber>"#,
+            r#"<upnp:albumArtURI>http://i/1.jpg</upnp:albumArtURI>"#,
+        )));
+        assert!(didl.contains(r#"duration="0:01:01.500">http://h/audio?a=1&amp;b=2</res></item>"#));
+    }
+}
@@ -0,0 +1,63 @@
+//! UPnP/DLNA MediaServer
+//!
+//! The server advertises itself over SSDP, and serves the device description
+//! and the ContentDirectory and ConnectionManager services under `/dlna`.
+//! The content is the library of the Spotify account of `--dlna-user`, and
+//! its items are `/audio-stream-with-sign` urls which renderers can play.
+
+pub mod content;
+pub mod description;
+pub mod didl;
+pub mod soap;
+pub mod ssdp;
+
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+
+use sha2::{Digest, Sha256};
+
+use crate::common::hex;
+
+#[derive(Clone, Debug)]
+pub struct DlnaConfig {
+    /// The server user whose Spotify account backs the MediaServer
+    pub username: String,
+    /// The name renderers show
+    pub friendly_name: String,
+    /// The device UUID, stable for the user so renderers remember the server
+    pub uuid: String,
+    /// Prefix of the description and song urls, reachable from the LAN
+    pub base_url: String,
+}
+
+impl DlnaConfig {
+    pub fn new(username: String, friendly_name: String, base_url: String) -> Self {
+        let digest = Sha256::digest(format!("spotify-web-server:dlna:{}", username).as_bytes());
+        let hex = hex::encode(&digest[..16]).to_lowercase();
+        let uuid = format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        );
+        DlnaConfig {
+            username,
+            friendly_name,
+            uuid,
+            base_url,
+        }
+    }
+
+    pub fn description_url(&self) -> String {
+        format!("{}/dlna/description.xml", self.base_url)
+    }
+}
+
+/// The address of the interface which routes to the SSDP multicast group,
+/// which LAN devices can reach
+pub fn local_ip() -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((ssdp::MULTICAST_ADDR, ssdp::PORT)).ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
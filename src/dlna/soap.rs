@@ -0,0 +1,156 @@
+//! SOAP control requests and responses
+
+use regex::Regex;
+
+use crate::{
+    common::xml::{escape, unescape},
+    errors::ServerError,
+};
+
+/// A UPnP error, sent as a SOAP fault
+#[derive(Debug)]
+pub struct SoapError {
+    pub code: u32,
+    pub description: String,
+}
+
+impl SoapError {
+    pub fn invalid_action() -> Self {
+        SoapError {
+            code: 401,
+            description: "Invalid Action".to_owned(),
+        }
+    }
+
+    pub fn invalid_args() -> Self {
+        SoapError {
+            code: 402,
+            description: "Invalid Args".to_owned(),
+        }
+    }
+
+    pub fn no_such_object() -> Self {
+        SoapError {
+            code: 701,
+            description: "No such object".to_owned(),
+        }
+    }
+}
+
+impl From<ServerError> for SoapError {
+    fn from(err: ServerError) -> Self {
+        SoapError {
+            code: 501,
+            description: err.to_string(),
+        }
+    }
+}
+
+/// The action name of a `SOAPACTION: "urn:...:service:Name:1#Action"` header
+pub fn action(header: &str) -> Option<&str> {
+    header
+        .trim_matches('"')
+        .rsplit_once('#')
+        .map(|(_, action)| action)
+}
+
+/// An `in` argument of the action, which is a child element of the action
+/// element in the body
+pub fn argument(body: &str, name: &str) -> Option<String> {
+    let re = Regex::new(&format!(
+        r"(?s)<(?:\w+:)?{name}(?:\s[^>]*)?>(.*?)</(?:\w+:)?{name}>|<(?:\w+:)?{name}(?:\s[^>]*)?/>",
+        name = regex::escape(name)
+    ))
+    .ok()?;
+    let caps = re.captures(body)?;
+    Some(unescape(caps.get(1).map_or("", |m| m.as_str())))
+}
+
+/// The response envelope of an action with its `out` arguments
+pub fn response(service: &str, action: &str, arguments: &[(&str, String)]) -> String {
+    let arguments = arguments
+        .iter()
+        .map(|(name, value)| format!("<{name}>{}</{name}>", escape(value)))
+        .collect::<String>();
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><u:{action}Response xmlns:u="{service}">{arguments}</u:{action}Response></s:Body></s:Envelope>
+"#
+    )
+}
+
+pub fn fault(err: &SoapError) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail><UPnPError xmlns="urn:schemas-upnp-org:control-1-0"><errorCode>{}</errorCode><errorDescription>{}</errorDescription></UPnPError></detail></s:Fault></s:Body></s:Envelope>
+"#,
+        err.code,
+        escape(&err.description)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BROWSE: &str = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
+  <s:Body>
+    <u:Browse xmlns:u="urn:schemas-upnp-org:service:ContentDirectory:1">
+      <ObjectID>album:1&amp;2</ObjectID>
+      <BrowseFlag>BrowseDirectChildren</BrowseFlag>
+      <Filter>*</Filter>
+      <StartingIndex>0</StartingIndex>
+      <RequestedCount>10</RequestedCount>
+      <SortCriteria/>
+    </u:Browse>
+  </s:Body>
+</s:Envelope>"#;
+
+    #[test]
+    fn actions() {
+        assert_eq!(
+            action(r#""urn:schemas-upnp-org:service:ContentDirectory:1#Browse""#),
+            Some("Browse")
+        );
+        assert_eq!(action("Browse"), None);
+    }
+
+    #[test]
+    fn arguments() {
+        assert_eq!(argument(BROWSE, "ObjectID").as_deref(), Some("album:1&2"));
+        assert_eq!(argument(BROWSE, "RequestedCount").as_deref(), Some("10"));
+        assert_eq!(argument(BROWSE, "SortCriteria").as_deref(), Some(""));
+        assert_eq!(argument(BROWSE, "SearchCriteria"), None);
+        assert_eq!(
+            argument("<u:ObjectID >0</u:ObjectID>", "ObjectID").as_deref(),
+            Some("0")
+        );
+    }
+
+    #[test]
+    fn responses() {
+        let response = response(
+            "urn:schemas-upnp-org:service:ContentDirectory:1",
+            "Browse",
+            &[
+                ("Result", "<DIDL-Lite/>".to_owned()),
+                ("NumberReturned", "0".to_owned()),
+            ],
+        );
+        assert!(response.contains(
+            r#"<u:BrowseResponse xmlns:u="urn:schemas-upnp-org:service:ContentDirectory:1"><Result>&lt;DIDL-Lite/&gt;</Result><NumberReturned>0</NumberReturned></u:BrowseResponse>"#
+        ));
+    }
+
+    #[test]
+    fn faults() {
+        let fault = fault(&SoapError {
+            code: 701,
+            description: "No such <object>".to_owned(),
+        });
+        assert!(fault.contains("<errorCode>701</errorCode>"));
+        assert!(fault.contains("<errorDescription>No such &lt;object&gt;</errorDescription>"));
+        assert!(fault.contains("<faultstring>UPnPError</faultstring>"));
+    }
+}
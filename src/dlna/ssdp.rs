@@ -0,0 +1,212 @@
+//! SSDP discovery
+//!
+//! Answers `M-SEARCH` requests for the MediaServer and announces it with
+//! `NOTIFY ssdp:alive` at startup and before the announcements expire.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+use super::DlnaConfig;
+
+pub const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+pub const PORT: u16 = 1900;
+
+/// Seconds the announcements are valid
+const MAX_AGE: u64 = 1800;
+
+/// The notification and search targets of the MediaServer
+const DEVICE_TYPES: [&str; 4] = [
+    "upnp:rootdevice",
+    "urn:schemas-upnp-org:device:MediaServer:1",
+    "urn:schemas-upnp-org:service:ContentDirectory:1",
+    "urn:schemas-upnp-org:service:ConnectionManager:1",
+];
+
+fn server_header() -> String {
+    format!(
+        "{}/{} UPnP/1.0 spotify-web-server/{}",
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// All targets, including the device UUID
+fn targets(config: &DlnaConfig) -> Vec<String> {
+    let mut targets = vec![format!("uuid:{}", config.uuid)];
+    targets.extend(DEVICE_TYPES.iter().map(|t| t.to_string()));
+    targets
+}
+
+fn usn(config: &DlnaConfig, target: &str) -> String {
+    if target.starts_with("uuid:") {
+        target.to_owned()
+    } else {
+        format!("uuid:{}::{}", config.uuid, target)
+    }
+}
+
+fn search_response(config: &DlnaConfig, target: &str) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\n\
+         CACHE-CONTROL: max-age={}\r\n\
+         EXT:\r\n\
+         LOCATION: {}\r\n\
+         SERVER: {}\r\n\
+         ST: {}\r\n\
+         USN: {}\r\n\
+         \r\n",
+        MAX_AGE,
+        config.description_url(),
+        server_header(),
+        target,
+        usn(config, target)
+    )
+}
+
+fn alive_notification(config: &DlnaConfig, target: &str) -> String {
+    format!(
+        "NOTIFY * HTTP/1.1\r\n\
+         HOST: {}:{}\r\n\
+         CACHE-CONTROL: max-age={}\r\n\
+         LOCATION: {}\r\n\
+         NT: {}\r\n\
+         NTS: ssdp:alive\r\n\
+         SERVER: {}\r\n\
+         USN: {}\r\n\
+         \r\n",
+        MULTICAST_ADDR,
+        PORT,
+        MAX_AGE,
+        config.description_url(),
+        target,
+        server_header(),
+        usn(config, target)
+    )
+}
+
+/// The search target of an `M-SEARCH` request
+fn search_target(request: &str) -> Option<&str> {
+    let mut lines = request.lines();
+    if !lines.next()?.starts_with("M-SEARCH ") {
+        return None;
+    }
+    lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("ST")
+            .then_some(value.trim())
+    })
+}
+
+/// A UDP socket in the SSDP multicast group, shared with other SSDP services
+/// of the host
+fn bind() -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, PORT).into())?;
+    socket.join_multicast_v4(&MULTICAST_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Answer searches and announce the MediaServer until the server stops
+pub async fn advertise(config: DlnaConfig) -> std::io::Result<()> {
+    let socket = bind()?;
+    tracing::info!(
+        "DLNA MediaServer {} advertised at {}",
+        config.uuid,
+        config.description_url()
+    );
+
+    let multicast = SocketAddr::from((MULTICAST_ADDR, PORT));
+    let mut announce = tokio::time::interval(Duration::from_secs(MAX_AGE / 2));
+    let mut buf = [0u8; 2048];
+    loop {
+        tokio::select! {
+            _ = announce.tick() => {
+                for target in targets(&config) {
+                    let notification = alive_notification(&config, &target);
+                    if let Err(err) = socket.send_to(notification.as_bytes(), multicast).await {
+                        tracing::warn!("Failed to announce the DLNA MediaServer: {}", err);
+                    }
+                }
+            }
+            received = socket.recv_from(&mut buf) => {
+                let (len, addr) = received?;
+                let request = String::from_utf8_lossy(&buf[..len]);
+                let Some(target) = search_target(&request) else {
+                    continue;
+                };
+                tracing::debug!("SSDP search for {} from {}", target, addr);
+                let matched = targets(&config)
+                    .into_iter()
+                    .filter(|t| target == "ssdp:all" || t == target)
+                    .collect::<Vec<_>>();
+                for target in matched {
+                    let response = search_response(&config, &target);
+                    if let Err(err) = socket.send_to(response.as_bytes(), addr).await {
+                        tracing::warn!("Failed to answer the SSDP search of {}: {}", addr, err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> DlnaConfig {
+        DlnaConfig::new(
+            "alice".to_owned(),
+            "Spotify".to_owned(),
+            "http://192.168.1.2:8080".to_owned(),
+        )
+    }
+
+    #[test]
+    fn search_targets() {
+        let request = "M-SEARCH * HTTP/1.1\r\n\
+                       HOST: 239.255.255.250:1900\r\n\
+                       MAN: \"ssdp:discover\"\r\n\
+                       MX: 2\r\n\
+                       st: urn:schemas-upnp-org:device:MediaServer:1\r\n\
+                       \r\n";
+        assert_eq!(
+            search_target(request),
+            Some("urn:schemas-upnp-org:device:MediaServer:1")
+        );
+        assert_eq!(search_target("M-SEARCH * HTTP/1.1\r\nMX: 2\r\n\r\n"), None);
+        let notify = "NOTIFY * HTTP/1.1\r\nST: ssdp:all\r\n\r\n";
+        assert_eq!(search_target(notify), None);
+    }
+
+    #[test]
+    fn search_responses() {
+        let config = config();
+        let response = search_response(&config, "upnp:rootdevice");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+        assert!(response.contains("LOCATION: http://192.168.1.2:8080/dlna/description.xml\r\n"));
+        assert!(response.contains("ST: upnp:rootdevice\r\n"));
+        assert!(response.contains(&format!("USN: uuid:{}::upnp:rootdevice\r\n", config.uuid)));
+
+        let uuid = format!("uuid:{}", config.uuid);
+        let response = search_response(&config, &uuid);
+        assert!(response.contains(&format!("USN: {}\r\n", uuid)));
+    }
+
+    #[test]
+    fn the_uuid_is_stable() {
+        assert_eq!(config().uuid, config().uuid);
+        assert_eq!(config().uuid.len(), 36);
+        assert_eq!(targets(&config()).len(), DEVICE_TYPES.len() + 1);
+    }
+}
@@ -0,0 +1,74 @@
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    app_store::AppStore,
+    endpoints::utils::{json_response, ok_response},
+    errors::ServerError,
+    webhooks::DeliveryStatus,
+};
+
+/// Path: POST `/admin/reload`
+/// Reload the log filter, rate limits and allowed users from the config file
+///
+/// It needs `Authorization: Bearer {admin_token}`.
+#[tracing::instrument(skip(req, app_store))]
+pub async fn reload_config(
+    req: HttpRequest,
+    app_store: web::Data<AppStore>,
+) -> Result<HttpResponse, ServerError> {
+    check_admin(&req, &app_store)?;
+    app_store.reload_config()?;
+    ok_response()
+}
+
+/// Path: DELETE `/admin/sessions/{username}`
+/// Revoke all sessions of a user, with `--session-store file`
+#[tracing::instrument(skip(req, app_store))]
+pub async fn revoke_user_sessions(
+    req: HttpRequest,
+    app_store: web::Data<AppStore>,
+    username: web::Path<String>,
+) -> Result<HttpResponse, ServerError> {
+    check_admin(&req, &app_store)?;
+    let revoked = app_store.sessions()?.revoke_all(&username);
+    json_response(serde_json::json!({ "revoked": revoked }))
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct DeliveriesData {
+    pub status: Option<DeliveryStatus>,
+}
+
+/// Path: GET `/admin/webhooks/deliveries`
+/// The latest webhook deliveries, optionally of a `status`
+#[tracing::instrument(skip(req, app_store))]
+pub async fn webhook_deliveries(
+    req: HttpRequest,
+    app_store: web::Data<AppStore>,
+    query: web::Query<DeliveriesData>,
+) -> Result<HttpResponse, ServerError> {
+    check_admin(&req, &app_store)?;
+    let webhooks = app_store.webhooks.as_ref().ok_or_else(|| {
+        ServerError::ParamsError("Webhooks are disabled without `--webhook`".to_owned())
+    })?;
+    json_response(webhooks.deliveries(query.status))
+}
+
+pub(crate) fn check_admin(req: &HttpRequest, app_store: &AppStore) -> Result<(), ServerError> {
+    let admin_token = app_store
+        .admin_token
+        .as_deref()
+        .ok_or(ServerError::AuthenticationError)?;
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    // In constant time, so the token can't be guessed from response times
+    if token.is_some_and(|token| bool::from(token.as_bytes().ct_eq(admin_token.as_bytes()))) {
+        Ok(())
+    } else {
+        Err(ServerError::AuthenticationError)
+    }
+}
@@ -1,20 +1,22 @@
 use futures::StreamExt;
 
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 
 use rspotify::{
     clients::{BaseClient, OAuthClient},
-    model::{AlbumId, Page, SavedAlbum, SimplifiedAlbum, SimplifiedTrack},
+    model::{AlbumId, Id, Market, Page, SavedAlbum, SimplifiedAlbum, SimplifiedTrack},
 };
 
 use crate::{
     account::SpotifyAccount,
     app_store::AppStore,
+    cache::{cache_key, market_part},
     endpoints::{
-        params::{IdsData, LimitOffsetData},
+        params::{CountryLocateData, IdsData, LimitOffsetData},
         utils::{json_response, ok_response},
     },
     errors::ServerError,
+    events::EVENTS,
     session::ServerSession,
 };
 
@@ -23,6 +25,8 @@ use crate::{
 #[tracing::instrument(skip(app_store, session))]
 pub async fn album(
     id: web::Path<String>,
+    country_locate: web::Query<CountryLocateData>,
+    req: HttpRequest,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
@@ -33,8 +37,21 @@ pub async fn album(
     let album_id = AlbumId::from_id(id_str.as_str())
         .map_err(|_| ServerError::ParamsError(format!("Invalid album id: {}", id_str)))?;
 
-    let result = account.client.album(album_id, None).await?;
-    json_response(&result)
+    let market = country_locate.market(&account).await?;
+    let key = cache_key(
+        "album",
+        &[
+            album_id.id(),
+            &market_part(market, &account.credentials.username),
+        ],
+    );
+    app_store
+        .response_cache
+        .respond(&req, key, || async {
+            let result = account.client.album(album_id, Some(market)).await?;
+            Ok(serde_json::to_string(&result)?)
+        })
+        .await
 }
 
 /// Path: GET `/albums`
@@ -42,14 +59,29 @@ pub async fn album(
 #[tracing::instrument(skip(app_store, session))]
 pub async fn albums(
     query: web::Query<IdsData>,
+    country_locate: web::Query<CountryLocateData>,
+    req: HttpRequest,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
     let account = app_store.authorize(username).await?;
     let album_ids = crate::into_ids!(AlbumId, query.ids());
-    let result = account.client.albums(album_ids, None).await?;
-    json_response(&result)
+    let market = country_locate.market(&account).await?;
+    let key = cache_key(
+        "albums",
+        &[
+            &query.ids,
+            &market_part(market, &account.credentials.username),
+        ],
+    );
+    app_store
+        .response_cache
+        .respond(&req, key, || async {
+            let result = account.client.albums(album_ids, Some(market)).await?;
+            Ok(serde_json::to_string(&result)?)
+        })
+        .await
 }
 
 /// Path: GET `/albums/{id}/tracks`
@@ -59,6 +91,7 @@ pub async fn albums(
 pub async fn album_tracks(
     id: web::Path<String>,
     query: web::Query<LimitOffsetData>,
+    country_locate: web::Query<CountryLocateData>,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
@@ -67,13 +100,14 @@ pub async fn album_tracks(
     let id_str = id.into_inner();
 
     let album_id = AlbumId::from_id(id_str.as_str())
-        .map_err(|_| ServerError::ParamsError(format!("{}", id_str)))?;
+        .map_err(|_| ServerError::ParamsError(format!("Invalid album id: {}", id_str)))?;
 
+    let market = country_locate.market(&account).await?;
     if query.limit.is_some() {
-        let page = page_tracks(&account, album_id, query.limit, query.offset).await?;
+        let page = page_tracks(&account, album_id, Some(market), query.limit, query.offset).await?;
         json_response(&page)
     } else {
-        let tracks = all_tracks(&account, album_id).await?;
+        let tracks = all_tracks(&account, album_id, Some(market)).await?;
         json_response(&tracks)
     }
 }
@@ -82,15 +116,12 @@ pub async fn album_tracks(
 async fn all_tracks(
     account: &SpotifyAccount,
     album_id: AlbumId<'_>,
+    market: Option<Market>,
 ) -> Result<Vec<SimplifiedTrack>, ServerError> {
-    let mut track_stream = account.client.album_track(album_id, None);
+    let mut track_stream = account.client.album_track(album_id, market);
     let mut tracks = vec![];
     while let Some(item) = track_stream.next().await {
-        if item.is_err() {
-            return Err(ServerError::RequestError(format!("{:?}", item)));
-        } else {
-            tracks.push(item.unwrap());
-        }
+        tracks.push(item?);
     }
     Ok(tracks)
 }
@@ -99,12 +130,13 @@ async fn all_tracks(
 async fn page_tracks(
     account: &SpotifyAccount,
     album_id: AlbumId<'_>,
+    market: Option<Market>,
     limit: Option<u32>,
     offset: Option<u32>,
 ) -> Result<Page<SimplifiedTrack>, ServerError> {
     let page = account
         .client
-        .album_track_manual(album_id, None, limit, offset)
+        .album_track_manual(album_id, market, limit, offset)
         .await?;
     Ok(page)
 }
@@ -114,31 +146,32 @@ async fn page_tracks(
 #[tracing::instrument(skip(app_store, session))]
 pub async fn saved_albums(
     query: web::Query<LimitOffsetData>,
+    country_locate: web::Query<CountryLocateData>,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
     let account = app_store.authorize(username).await?;
 
+    let market = country_locate.market(&account).await?;
     if query.limit.is_some() {
-        let page = page_saved_albums(&account, query.limit, query.offset).await?;
+        let page = page_saved_albums(&account, Some(market), query.limit, query.offset).await?;
         json_response(&page)
     } else {
-        let albums = all_saved_albums(&account).await?;
+        let albums = all_saved_albums(&account, Some(market)).await?;
         json_response(&albums)
     }
 }
 
 /// Current user all saved albums
-async fn all_saved_albums(account: &SpotifyAccount) -> Result<Vec<SavedAlbum>, ServerError> {
-    let mut album_stream = account.client.current_user_saved_albums(None);
+pub async fn all_saved_albums(
+    account: &SpotifyAccount,
+    market: Option<Market>,
+) -> Result<Vec<SavedAlbum>, ServerError> {
+    let mut album_stream = account.client.current_user_saved_albums(market);
     let mut albums = vec![];
     while let Some(item) = album_stream.next().await {
-        if item.is_err() {
-            return Err(ServerError::RequestError(format!("{:?}", item)));
-        } else {
-            albums.push(item.unwrap());
-        }
+        albums.push(item?);
     }
     Ok(albums)
 }
@@ -146,12 +179,13 @@ async fn all_saved_albums(account: &SpotifyAccount) -> Result<Vec<SavedAlbum>, S
 /// Current user saved albums by page
 async fn page_saved_albums(
     account: &SpotifyAccount,
+    market: Option<Market>,
     limit: Option<u32>,
     offset: Option<u32>,
 ) -> Result<Page<SavedAlbum>, ServerError> {
     let page = account
         .client
-        .current_user_saved_albums_manual(None, limit, offset)
+        .current_user_saved_albums_manual(market, limit, offset)
         .await?;
     Ok(page)
 }
@@ -165,13 +199,14 @@ pub async fn save_albums(
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
-    let account = app_store.authorize(username).await?;
+    let account = app_store.authorize(username.clone()).await?;
 
     let album_ids = crate::into_ids!(AlbumId, query.ids());
     account
         .client
         .current_user_saved_albums_add(album_ids)
         .await?;
+    EVENTS.library_changed(true, username.as_ref(), "album", &query.ids());
     ok_response()
 }
 
@@ -184,47 +219,72 @@ pub async fn delete_albums(
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
-    let account = app_store.authorize(username).await?;
+    let account = app_store.authorize(username.clone()).await?;
 
     let album_ids = crate::into_ids!(AlbumId, query.ids());
     account
         .client
         .current_user_saved_albums_delete(album_ids)
         .await?;
+    EVENTS.library_changed(false, username.as_ref(), "album", &query.ids());
     ok_response()
 }
 
+/// Path: GET `/me/albums/contains`
+/// Check if one or more albums is already saved in the current Spotify user's 'Your Music' library.
+#[tracing::instrument(skip(app_store, session))]
+pub async fn contains_albums(
+    query: web::Query<IdsData>,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let account = app_store.authorize(username).await?;
+
+    let mut result = vec![];
+    for ids in query.batches() {
+        let album_ids = crate::into_ids!(AlbumId, ids);
+        let contains = account
+            .client
+            .current_user_saved_albums_contains(album_ids)
+            .await?;
+        result.extend(contains);
+    }
+    json_response(&result)
+}
+
 /// Path: GET `/browse/new-releases`
 /// Get a list of new album releases featured in Spotify
 /// (shown, for example, on a Spotify player’s “Browse” tab).
 #[tracing::instrument(skip(app_store, session))]
 pub async fn new_releases(
     query: web::Query<LimitOffsetData>,
+    country_locate: web::Query<CountryLocateData>,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
     let account = app_store.authorize(username).await?;
 
+    let market = country_locate.market(&account).await?;
     if query.limit.is_some() {
-        let albums = all_new_releases(&account).await?;
+        let albums = all_new_releases(&account, Some(market)).await?;
         json_response(&albums)
     } else {
-        let page = page_new_releases(&account, query.limit, query.offset).await?;
+        let page = page_new_releases(&account, Some(market), query.limit, query.offset).await?;
         json_response(&page)
     }
 }
 
 /// All new releases albums
-async fn all_new_releases(account: &SpotifyAccount) -> Result<Vec<SimplifiedAlbum>, ServerError> {
-    let mut album_stream = account.client.new_releases(None);
+pub async fn all_new_releases(
+    account: &SpotifyAccount,
+    market: Option<Market>,
+) -> Result<Vec<SimplifiedAlbum>, ServerError> {
+    let mut album_stream = account.client.new_releases(market);
     let mut albums = vec![];
     while let Some(item) = album_stream.next().await {
-        if item.is_err() {
-            return Err(ServerError::RequestError(format!("{:?}", item)));
-        } else {
-            albums.push(item.unwrap());
-        }
+        albums.push(item?);
     }
     Ok(albums)
 }
@@ -232,12 +292,13 @@ async fn all_new_releases(account: &SpotifyAccount) -> Result<Vec<SimplifiedAlbu
 /// New releases albums by page
 async fn page_new_releases(
     account: &SpotifyAccount,
+    market: Option<Market>,
     limit: Option<u32>,
     offset: Option<u32>,
 ) -> Result<Page<SimplifiedAlbum>, ServerError> {
     let page = account
         .client
-        .new_releases_manual(None, limit, offset)
+        .new_releases_manual(market, limit, offset)
         .await?;
     Ok(page)
 }
@@ -1,18 +1,22 @@
-use futures::StreamExt;
+use std::collections::HashSet;
 
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 
 use rspotify::{
-    clients::BaseClient,
-    model::{ArtistId, Page, SimplifiedAlbum},
+    clients::{BaseClient, OAuthClient},
+    http::Query,
+    model::{ArtistId, FullArtist, Id},
 };
 
 use crate::{
     account::SpotifyAccount,
     app_store::AppStore,
+    cache::cache_key,
     endpoints::{
-        params::{IdsData, LimitOffsetData},
-        utils::json_response,
+        params::{
+            ArtistAlbumsData, CountryLocateData, IdsData, LimitOffsetData, MAX_IDS_PER_REQUEST,
+        },
+        utils::{all_raw_items, json_response, page_raw_items},
     },
     errors::ServerError,
     session::ServerSession,
@@ -23,6 +27,7 @@ use crate::{
 #[tracing::instrument(skip(app_store, session))]
 pub async fn artist(
     id: web::Path<String>,
+    req: HttpRequest,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
@@ -33,8 +38,14 @@ pub async fn artist(
     let artist_id = ArtistId::from_id(id_str.as_str())
         .map_err(|_| ServerError::ParamsError(format!("Invalid artist id: {}", id_str)))?;
 
-    let result = account.client.artist(artist_id).await?;
-    json_response(&result)
+    let key = cache_key("artist", &[artist_id.id()]);
+    app_store
+        .response_cache
+        .respond(&req, key, || async {
+            let result = account.client.artist(artist_id).await?;
+            Ok(serde_json::to_string(&result)?)
+        })
+        .await
 }
 
 /// Path: GET `/artists`
@@ -42,22 +53,34 @@ pub async fn artist(
 #[tracing::instrument(skip(app_store, session))]
 pub async fn artists(
     query: web::Query<IdsData>,
+    req: HttpRequest,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
     let account = app_store.authorize(username).await?;
     let artist_ids = crate::into_ids!(ArtistId, query.ids());
-    let result = account.client.artists(artist_ids).await?;
-    json_response(&result)
+    let key = cache_key("artists", &[&query.ids]);
+    app_store
+        .response_cache
+        .respond(&req, key, || async {
+            let result = account.client.artists(artist_ids).await?;
+            Ok(serde_json::to_string(&result)?)
+        })
+        .await
 }
 
 /// Path: GET `/artists/{id}/albums`
 /// Get Spotify catalog information about an artist's albums.
+///
+/// `include_groups` filters the album groups and `dedupe=1` collapses
+/// the same releases of different markets.
 #[tracing::instrument(skip(app_store, session))]
 pub async fn artist_albums(
     id: web::Path<String>,
     query: web::Query<LimitOffsetData>,
+    albums_query: web::Query<ArtistAlbumsData>,
+    country_locate: web::Query<CountryLocateData>,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
@@ -66,46 +89,50 @@ pub async fn artist_albums(
     let id_str = id.into_inner();
 
     let artist_id = ArtistId::from_id(id_str.as_str())
-        .map_err(|_| ServerError::ParamsError(format!("{}", id_str)))?;
+        .map_err(|_| ServerError::ParamsError(format!("Invalid artist id: {}", id_str)))?;
+
+    let include_groups = albums_query.include_groups()?.map(|groups| {
+        groups
+            .into_iter()
+            .map(<&str>::from)
+            .collect::<Vec<_>>()
+            .join(",")
+    });
+    let market = country_locate.market(&account).await?;
+    let mut params = Query::new();
+    if let Some(v) = include_groups.as_deref() {
+        params.insert("include_groups", v);
+    }
+    params.insert("market", market.into());
 
+    let url = format!("artists/{}/albums", artist_id.id());
     if query.limit.is_some() {
-        let page = page_albums(&account, artist_id, query.limit, query.offset).await?;
+        let mut page = page_raw_items(&account, &url, &params, query.limit, query.offset).await?;
+        if albums_query.dedupe() {
+            page.items = dedupe_albums(page.items);
+        }
         json_response(&page)
     } else {
-        let artists = all_albums(&account, artist_id).await?;
-        json_response(&artists)
-    }
-}
-
-/// Artist all albums
-async fn all_albums(
-    account: &SpotifyAccount,
-    artist_id: ArtistId<'_>,
-) -> Result<Vec<SimplifiedAlbum>, ServerError> {
-    let mut album_stream = account.client.artist_albums(artist_id, None, None);
-    let mut artists = vec![];
-    while let Some(item) = album_stream.next().await {
-        if item.is_err() {
-            return Err(ServerError::RequestError(format!("{:?}", item)));
-        } else {
-            artists.push(item.unwrap());
+        let mut albums = all_raw_items(&account, &url, &params).await?;
+        if albums_query.dedupe() {
+            albums = dedupe_albums(albums);
         }
+        json_response(&albums)
     }
-    Ok(artists)
 }
 
-/// Artist albums by page
-async fn page_albums(
-    account: &SpotifyAccount,
-    artist_id: ArtistId<'_>,
-    limit: Option<u32>,
-    offset: Option<u32>,
-) -> Result<Page<SimplifiedAlbum>, ServerError> {
-    let page = account
-        .client
-        .artist_albums_manual(artist_id, None, None, limit, offset)
-        .await?;
-    Ok(page)
+/// Collapse the same releases of different markets,
+/// which have the same name and the same number of tracks
+fn dedupe_albums(albums: Vec<serde_json::Value>) -> Vec<serde_json::Value> {
+    let mut seen = HashSet::new();
+    albums
+        .into_iter()
+        .filter(|album| {
+            let name = album["name"].as_str().unwrap_or_default().to_lowercase();
+            let total_tracks = album["total_tracks"].as_u64();
+            seen.insert((name, total_tracks))
+        })
+        .collect()
 }
 
 /// Path: GET `/artists/{id}/top-tracks`
@@ -113,6 +140,7 @@ async fn page_albums(
 #[tracing::instrument(skip(app_store, session))]
 pub async fn artist_top_tracks(
     id: web::Path<String>,
+    country_locate: web::Query<CountryLocateData>,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
@@ -121,10 +149,11 @@ pub async fn artist_top_tracks(
     let id_str = id.into_inner();
 
     let artist_id = ArtistId::from_id(id_str.as_str())
-        .map_err(|_| ServerError::ParamsError(format!("{}", id_str)))?;
+        .map_err(|_| ServerError::ParamsError(format!("Invalid artist id: {}", id_str)))?;
+    let market = country_locate.market(&account).await?;
     let tracks = account
         .client
-        .artist_top_tracks(artist_id, Some(rspotify::model::Market::FromToken))
+        .artist_top_tracks(artist_id, Some(market))
         .await?;
     json_response(&tracks)
 }
@@ -143,7 +172,27 @@ pub async fn artist_related_artists(
     let id_str = id.into_inner();
 
     let artist_id = ArtistId::from_id(id_str.as_str())
-        .map_err(|_| ServerError::ParamsError(format!("{}", id_str)))?;
+        .map_err(|_| ServerError::ParamsError(format!("Invalid artist id: {}", id_str)))?;
     let artists = account.client.artist_related_artists(artist_id).await?;
     json_response(&artists)
 }
+
+/// All artists followed by the current user
+pub async fn all_followed_artists(
+    account: &SpotifyAccount,
+) -> Result<Vec<FullArtist>, ServerError> {
+    let mut artists = vec![];
+    let mut after: Option<String> = None;
+    loop {
+        let page = account
+            .client
+            .current_user_followed_artists(after.as_deref(), Some(MAX_IDS_PER_REQUEST as u32))
+            .await?;
+        artists.extend(page.items);
+        match page.cursors.and_then(|cursors| cursors.after) {
+            Some(cursor) if page.next.is_some() => after = Some(cursor),
+            _ => break,
+        }
+    }
+    Ok(artists)
+}
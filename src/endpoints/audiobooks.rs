@@ -0,0 +1,171 @@
+use actix_web::{web, HttpResponse};
+
+use rspotify::{clients::BaseClient, http::Query};
+
+use crate::{
+    app_store::AppStore,
+    endpoints::{
+        params::{base62_id, CountryLocateData, IdsData, LimitOffsetData},
+        utils::{
+            all_raw_items, json_response, ok_response, ok_with_body_response, page_raw_items,
+            url_with_query,
+        },
+    },
+    errors::ServerError,
+    events::EVENTS,
+    session::ServerSession,
+};
+
+/// Path: GET `/audiobooks/{id}`
+/// Get Spotify catalog information for a single audiobook.
+#[tracing::instrument(skip(app_store, session))]
+pub async fn audiobook(
+    id: web::Path<String>,
+    country_locate: web::Query<CountryLocateData>,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let account = app_store.authorize(username).await?;
+
+    let audiobook_id = base62_id("audiobook", id.as_str())?;
+
+    let market = country_locate.market(&account).await?;
+    let mut params = Query::new();
+    params.insert("market", market.into());
+    let url = format!("audiobooks/{}", audiobook_id);
+    let result = account.client.api_get(&url, &params).await?;
+    ok_with_body_response(result)
+}
+
+/// Path: GET `/audiobooks`
+/// Get Spotify catalog information for several audiobooks identified by their Spotify IDs.
+#[tracing::instrument(skip(app_store, session))]
+pub async fn audiobooks(
+    query: web::Query<IdsData>,
+    country_locate: web::Query<CountryLocateData>,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let account = app_store.authorize(username).await?;
+
+    let audiobook_ids = join_ids("audiobook", &query.ids())?;
+    let market = country_locate.market(&account).await?;
+    let mut params = Query::new();
+    params.insert("ids", audiobook_ids.as_str());
+    params.insert("market", market.into());
+    let result = account.client.api_get("audiobooks", &params).await?;
+    ok_with_body_response(result)
+}
+
+/// Path: GET `/audiobooks/{id}/chapters`
+/// Get Spotify catalog information about an audiobook's chapters.
+#[tracing::instrument(skip(app_store, session))]
+pub async fn audiobook_chapters(
+    id: web::Path<String>,
+    query: web::Query<LimitOffsetData>,
+    country_locate: web::Query<CountryLocateData>,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let account = app_store.authorize(username).await?;
+
+    let audiobook_id = base62_id("audiobook", id.as_str())?;
+    let url = format!("audiobooks/{}/chapters", audiobook_id);
+    let market = country_locate.market(&account).await?;
+    let mut params = Query::new();
+    params.insert("market", market.into());
+
+    if query.limit.is_some() {
+        let page = page_raw_items(&account, &url, &params, query.limit, query.offset).await?;
+        json_response(&page)
+    } else {
+        let chapters = all_raw_items(&account, &url, &params).await?;
+        json_response(&chapters)
+    }
+}
+
+/// Path: GET `/me/audiobooks`
+/// Get a list of the audiobooks saved in the current Spotify user's 'Your Music' library.
+#[tracing::instrument(skip(app_store, session))]
+pub async fn saved_audiobooks(
+    query: web::Query<LimitOffsetData>,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let account = app_store.authorize(username).await?;
+
+    if query.limit.is_some() {
+        let page = page_raw_items(
+            &account,
+            "me/audiobooks",
+            &Query::new(),
+            query.limit,
+            query.offset,
+        )
+        .await?;
+        json_response(&page)
+    } else {
+        let audiobooks = all_raw_items(&account, "me/audiobooks", &Query::new()).await?;
+        json_response(&audiobooks)
+    }
+}
+
+/// Path: PUT `/me/audiobooks`
+/// Save one or more audiobooks to the current Spotify user's library.
+#[tracing::instrument(skip(app_store, session))]
+pub async fn save_audiobooks(
+    query: web::Query<IdsData>,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let account = app_store.authorize(username.clone()).await?;
+
+    for ids in query.batches() {
+        let ids = join_ids("audiobook", &ids)?;
+        let mut params = Query::new();
+        params.insert("ids", &ids);
+        let url = url_with_query("me/audiobooks", &params);
+        account.client.api_put(&url, &serde_json::json!({})).await?;
+    }
+    EVENTS.library_changed(true, username.as_ref(), "audiobook", &query.ids());
+    ok_response()
+}
+
+/// Path: DELETE `/me/audiobooks`
+/// Remove one or more audiobooks from the current Spotify user's library.
+#[tracing::instrument(skip(app_store, session))]
+pub async fn delete_audiobooks(
+    query: web::Query<IdsData>,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let account = app_store.authorize(username.clone()).await?;
+
+    for ids in query.batches() {
+        let ids = join_ids("audiobook", &ids)?;
+        let mut params = Query::new();
+        params.insert("ids", &ids);
+        let url = url_with_query("me/audiobooks", &params);
+        account
+            .client
+            .api_delete(&url, &serde_json::json!({}))
+            .await?;
+    }
+    EVENTS.library_changed(false, username.as_ref(), "audiobook", &query.ids());
+    ok_response()
+}
+
+/// Check and join ids with `,`
+pub(crate) fn join_ids(kind: &str, ids: &[&str]) -> Result<String, ServerError> {
+    let ids = ids
+        .iter()
+        .map(|id| base62_id(kind, id))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(ids.join(","))
+}
@@ -4,22 +4,29 @@ use std::{
 };
 
 use actix_web::{web, HttpResponse};
-use tokio::time::timeout;
+use tracing::Instrument;
 
 use librespot::{
     audio::{AudioDecrypt, AudioFile},
-    core::{session::Session, spotify_id::SpotifyId},
+    core::{
+        session::Session,
+        spotify_id::{SpotifyAudioType, SpotifyId},
+    },
     metadata::{AudioItem, FileFormat},
 };
 
 use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 
 use crate::{
-    account::{SpotifyAccount, UserName},
+    account::{SignKey, SpotifyAccount, UserName},
     app_store::AppStore,
     common::hex,
     endpoints::utils::ok_with_body_response,
     errors::ServerError,
+    events::EVENTS,
+    metrics::METRICS,
+    quota::{RouteGroup, StreamPermit},
+    scrobble::Playback,
     session::ServerSession,
 };
 
@@ -30,7 +37,7 @@ const SPOTIFY_OGG_HEADER_END: u64 = 0xa7;
 /// Path: GET `/audio/{id}`
 /// Audio files information
 ///
-/// `id` can be `spotify:track:{..}` or `spotify:episode:{..}`
+/// `id` can be `spotify:track:{..}`, `spotify:episode:{..}` or `spotify:chapter:{..}`
 #[tracing::instrument(skip(app_store, session))]
 pub async fn audio(
     id: web::Path<String>,
@@ -41,11 +48,12 @@ pub async fn audio(
     let account = app_store.authorize(username).await?;
 
     // let spotify_id = SpotifyId::from_uri(&format!("spotify:track:{}", id.as_str()))
-    let spotify_id = SpotifyId::from_uri(id.as_str())
-        .map_err(|_| ServerError::ParamsError(format!("Spotify id {} is invalid", id.as_str())))?;
+    let spotify_id = audio_spotify_id(id.as_str())?;
 
     let account_session = &account.session.read().await;
-    let result = AudioItem::get_audio_item(&account_session, spotify_id).await?;
+    let result = AudioItem::get_audio_item(account_session, spotify_id)
+        .instrument(tracing::info_span!("AudioItem::get_audio_item"))
+        .await?;
 
     ok_with_body_response(format!("{:?}", result))
 }
@@ -59,7 +67,7 @@ struct UserNameTrackId {
 /// Path: GET `/audio-uri/{id}`
 /// Audio direct uri which returns a uri to the track audio stream
 ///
-/// `id` can be `spotify:track:{..}` or `spotify:episode:{..}`
+/// `id` can be `spotify:track:{..}`, `spotify:episode:{..}` or `spotify:chapter:{..}`
 #[tracing::instrument(skip(app_store, session))]
 pub async fn audio_uri(
     id: web::Path<String>,
@@ -69,27 +77,60 @@ pub async fn audio_uri(
     let username = session.get_username()?;
     let account = app_store.authorize(username.clone()).await?;
 
+    ok_with_body_response(signed_audio_uri(&account, &username, id.as_str())?)
+}
+
+/// The `/audio-stream-with-sign` uri of an audio, which works without cookies
+pub fn signed_audio_uri(
+    account: &SpotifyAccount,
+    username: &UserName,
+    id: &str,
+) -> Result<String, ServerError> {
+    Ok(format!(
+        "/audio-stream-with-sign/audio.ogg?{}",
+        signed_query(account.sign_key(), username, id)?
+    ))
+}
+
+/// The `sign`, `iv` and `username` query of a signed uri of `id`
+///
+/// `signed_audio_id` gives `id` back.
+pub fn signed_query(key: &SignKey, username: &UserName, id: &str) -> Result<String, ServerError> {
     let audio_sign = UserNameTrackId {
         username: username.as_ref().to_owned(),
-        track_id: id.to_string(),
+        track_id: id.to_owned(),
     };
 
-    let (enc, iv) = account.encrypt(serde_json::to_string(&audio_sign)?.as_bytes());
+    let (enc, iv) = key.encrypt(serde_json::to_string(&audio_sign)?.as_bytes());
 
-    let url = format!(
-        "/audio-stream-with-sign/audio.ogg?sign={}&iv={}&username={}",
+    Ok(format!(
+        "sign={}&iv={}&username={}",
         hex::encode(&enc),
         hex::encode(&iv),
         utf8_percent_encode(username.as_ref(), NON_ALPHANUMERIC),
-    );
-    ok_with_body_response(url)
+    ))
+}
+
+/// The audio id of the `sign` and `iv` of a signed uri
+pub fn signed_audio_id(key: &SignKey, sign: &str, iv: &str) -> Result<String, ServerError> {
+    let iv = hex::decode(iv)?;
+    let sign = hex::decode(sign)?;
+
+    let dec = key.decrypt(&iv, &sign).map_err(|e| {
+        tracing::warn!("audio sign decryption failed, {:?}", e);
+        ServerError::ParamsError(format!("audio sign decryption failed: {:?}", e))
+    })?;
+    match serde_json::from_slice::<UserNameTrackId>(&dec) {
+        Ok(username_trackid) => Ok(username_trackid.track_id),
+        Err(_) => Err(ServerError::AuthenticationError),
+    }
 }
 
 #[derive(Debug, serde::Deserialize)]
 pub struct AudioSign {
-    sign: String,
-    iv: String,
-    username: String,
+    pub sign: String,
+    pub iv: String,
+    pub username: String,
 }
 
 /// Path: GET `/audio-stream-with-sign/{id}`
@@ -99,29 +140,26 @@ pub async fn audio_stream_with_sign(
     audio_sign: web::Query<AudioSign>,
     app_store: web::Data<AppStore>,
 ) -> Result<HttpResponse, ServerError> {
-    let iv = hex::decode(audio_sign.iv.as_str())?;
-    let sign = hex::decode(audio_sign.sign.as_str())?;
-
     let username: UserName = audio_sign.username.as_str().into();
     let account = app_store.authorize(username).await?;
-
-    let dec = account.decrypt(&iv, &sign).map_err(|e| {
-        tracing::warn!("audio sign decryption failed, {:?}", e);
-        ServerError::ParamsError(format!("audio sign decryption failed: {:?}", e))
-    })?;
-    match serde_json::from_slice::<UserNameTrackId>(&dec) {
-        Ok(username_trackid) => {
-            // retry_audio_cn_stream(&username_trackid.track_id, &account, 3).await
-            audio_cn_stream(&username_trackid.track_id, &account).await
-        }
-        Err(_) => Err(ServerError::AuthenticationError),
-    }
+    let track_id = signed_audio_id(account.sign_key(), &audio_sign.sign, &audio_sign.iv)?;
+
+    app_store.quotas.check(
+        RouteGroup::AudioStream,
+        &audio_sign.username,
+        Some(&audio_sign.username),
+    )?;
+    let permit = app_store.quotas.acquire_stream(&audio_sign.username)?;
+    let playback = app_store
+        .now_playing(&audio_sign.username, &account, &track_id)
+        .await;
+    audio_cn_stream(&track_id, &account, permit, playback).await
 }
 
 /// Path: GET `/audio-stream/{id}`
 /// The track audio stream without sign but needs Cookies
 ///
-/// `id` can be `spotify:track:{..}` or `spotify:episode:{..}`
+/// `id` can be `spotify:track:{..}`, `spotify:episode:{..}` or `spotify:chapter:{..}`
 #[tracing::instrument(skip(app_store, session))]
 pub async fn audio_stream(
     id: web::Path<String>,
@@ -129,117 +167,145 @@ pub async fn audio_stream(
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
-    let account = app_store.authorize(username).await?;
-
-    // retry_audio_cn_stream(id.as_str(), &account, 3).await
-    audio_cn_stream(id.as_str(), &account).await
+    let permit = app_store.quotas.acquire_stream(username.as_ref())?;
+    let account = app_store.authorize(username.clone()).await?;
+    let playback = app_store
+        .now_playing(username.as_ref(), &account, id.as_str())
+        .await;
+    audio_cn_stream(id.as_str(), &account, permit, playback).await
 }
 
 /// Audio content stream
 ///
-/// `id` can be `spotify:track:{..}` or `spotify:episode:{..}`
-#[tracing::instrument(skip(account))]
-async fn audio_cn_without_stream(
+/// `id` can be `spotify:track:{..}`, `spotify:episode:{..}` or `spotify:chapter:{..}`
+///
+/// The stream holds `permit` until it ends, and reports the position it has
+/// served to `playback` for scrobbling.
+#[tracing::instrument(skip(account, permit, playback))]
+pub(crate) async fn audio_cn_stream(
     id: &str,
     account: &SpotifyAccount,
+    permit: StreamPermit,
+    mut playback: Option<Playback>,
 ) -> Result<HttpResponse, ServerError> {
-    println!("------------ audio_cn_without_stream start");
-    let spotify_id = SpotifyId::from_uri(id)
-        .map_err(|_| ServerError::ParamsError(format!("Track id {} is invalid", id)))?;
-
-    let account_session = &account.session.read().await;
-    println!("------------ audio_cn_without_stream: Gotten account session");
-    tracing::info!("Gotten account session");
-
-    let audio_item = AudioItem::get_audio_item(&account_session, spotify_id).await?;
-    println!("------------ audio_cn_without_stream: Gotten audio item");
-    tracing::info!("Gotten audio item");
-
-    let file_id = audio_item.files.get(&FileFormat::OGG_VORBIS_320).unwrap();
-    println!("------------ audio_cn_without_stream: Gotten audio file");
-    tracing::info!(
-        "Audio file id: {}",
-        file_id
-            .to_base16()
-            .unwrap_or("file_id decode failed".to_owned())
-    );
-
-    let enc_file = AudioFile::open(&account_session, *file_id, 500 * 1024, true).await?;
-    println!("------------ audio_cn_without_stream: Gotten encrypt file");
-    tracing::info!("Gotten encrypt file");
-
-    let stream_loader_controller = enc_file.get_stream_loader_controller();
-    stream_loader_controller.set_stream_mode();
-
-    let key = account_session
-        .audio_key()
-        .request(spotify_id, *file_id)
-        .await?;
-
-    println!("------------ audio_cn_without_stream: Gotten audio key");
-    tracing::info!("Gotten audio key: {:?}", key);
+    use tokio_stream::StreamExt;
 
-    let mut decrypted_file = AudioDecrypt::new(key, enc_file);
-    decrypted_file.seek(SeekFrom::Start(0xa7))?;
+    let (mut decrypted_file, format) = open_audio_file(id, account, &AUDIO_FORMATS).await?;
+    let bitrate = bitrate(format);
+    let mut stream_events = EVENTS.stream_started(&account.credentials.username, id);
 
     let size = 1024 * 10;
     let mut buf = vec![0u8; size];
-    match decrypted_file.read_to_end(&mut buf) {
-        Ok(n) => {
-            println!("------------ audio_cn_without_stream: Start audio stream");
-            tracing::info!("Start audio stream");
-            return Ok(HttpResponse::Ok().content_type("audio/ogg").body(buf));
+    let s = async_stream::stream! {
+        let _permit = permit;
+        let _active = METRICS.stream_started();
+        let mut served = 0u64;
+        loop {
+            let n = decrypted_file.read(&mut buf);
+            match n {
+                Ok(n) => {
+                    if n == 0 {
+                        if let Some(playback) = playback.take() {
+                            playback.finish(served * 8 / bitrate);
+                        }
+                        break;
+                    }
+                    METRICS.streamed(n);
+                    served += n as u64;
+                    if let Some(playback) = playback.as_mut() {
+                        playback.progress(served * 8 / bitrate);
+                    }
+                    yield Ok(web::BytesMut::from(&buf[..n]).freeze());
+                }
+                Err(e) => {
+                    yield Err(e);
+                    drop(decrypted_file);
+                    break;
+                },
+            }
         }
-        Err(e) => {
-            println!("------------ audio_cn_without_stream: stream timeout");
-            tracing::warn!("stream data is timeout");
-            return Err(ServerError::AudioError(format!("{:?}", e)));
+    }
+    .timeout(Duration::from_millis(100))
+    .take_while(move |r| {
+        match r {
+            Ok(Ok(bytes)) => stream_events.streamed(bytes.len()),
+            Ok(Err(_)) => {}
+            Err(_) => {
+                tracing::warn!("stream data is timeout");
+                METRICS.stream_truncated();
+                stream_events.truncated();
+            }
         }
+        r.is_ok()
+    })
+    .map(|d| d.unwrap());
+
+    tracing::info!("Start audio stream");
+
+    Ok(HttpResponse::Ok().content_type("audio/ogg").streaming(s))
+}
+
+/// The formats of audio streams, in the order of preference
+const AUDIO_FORMATS: [FileFormat; 7] = [
+    FileFormat::OGG_VORBIS_320,
+    FileFormat::MP3_320,
+    FileFormat::MP3_256,
+    FileFormat::OGG_VORBIS_160,
+    FileFormat::MP3_160,
+    FileFormat::OGG_VORBIS_96,
+    FileFormat::MP3_96,
+];
+
+/// The nominal bitrate of `format` in kbit/s, which is bits per millisecond
+fn bitrate(format: FileFormat) -> u64 {
+    match format {
+        FileFormat::OGG_VORBIS_320 | FileFormat::MP3_320 => 320,
+        FileFormat::MP3_256 => 256,
+        FileFormat::OGG_VORBIS_160 | FileFormat::MP3_160 | FileFormat::MP3_160_ENC => 160,
+        FileFormat::OGG_VORBIS_96 | FileFormat::MP3_96 => 96,
+        _ => 128,
     }
 }
 
-/// Audio content stream
+/// Open the decrypted audio file in the first available of `formats`,
+/// with the format it has
 ///
-/// `id` can be `spotify:track:{..}` or `spotify:episode:{..}`
+/// `id` can be `spotify:track:{..}`, `spotify:episode:{..}` or `spotify:chapter:{..}`
+///
+/// Ogg Vorbis files are positioned after Spotify's custom header packet.
 #[tracing::instrument(skip(account))]
-async fn audio_cn_stream(id: &str, account: &SpotifyAccount) -> Result<HttpResponse, ServerError> {
-    use tokio_stream::StreamExt;
-
-    println!("------------ audio_cn_stream start");
-    let spotify_id = SpotifyId::from_uri(id)
-        .map_err(|_| ServerError::ParamsError(format!("Spotify id {} is invalid", id)))?;
+pub(crate) async fn open_audio_file(
+    id: &str,
+    account: &SpotifyAccount,
+    formats: &[FileFormat],
+) -> Result<(AudioDecrypt<AudioFile>, FileFormat), ServerError> {
+    let spotify_id = audio_spotify_id(id)?;
 
     let account_session = &account.session.read().await;
-    println!("------------ audio_cn_stream: Gotten account session");
     tracing::info!("Gotten account session");
 
-    // let audio_item = AudioItem::get_audio_item(&account_session, spotify_id).await?;
+    // let audio_item = AudioItem::get_audio_item(account_session, spotify_id).await?;
 
-    let audio_item = match AudioItem::get_audio_item(&account_session, spotify_id).await {
-        Ok(audio) => match find_available_alternative(&account_session, audio).await {
+    let audio_item = match AudioItem::get_audio_item(account_session, spotify_id)
+        .instrument(tracing::info_span!("AudioItem::get_audio_item"))
+        .await
+    {
+        Ok(audio) => match find_available_alternative(account_session, audio).await {
             Some(audio) => audio,
             None => {
                 return Err(ServerError::LibrespotError("No audio item".to_owned()));
             }
         },
         Err(e) => {
-            return Err(ServerError::LibrespotError("No audio item".to_owned()));
+            return Err(ServerError::LibrespotError(format!(
+                "No audio item: {:?}",
+                e
+            )));
         }
     };
 
-    println!("------------ audio_cn_stream: Gotten audio item");
     tracing::info!("Gotten audio item");
 
-    let formats = [
-        FileFormat::OGG_VORBIS_320,
-        FileFormat::MP3_320,
-        FileFormat::MP3_256,
-        FileFormat::OGG_VORBIS_160,
-        FileFormat::MP3_160,
-        FileFormat::OGG_VORBIS_96,
-        FileFormat::MP3_96,
-    ];
-
     let (format, file_id) =
         match formats
             .iter()
@@ -253,16 +319,19 @@ async fn audio_cn_stream(id: &str, account: &SpotifyAccount) -> Result<HttpRespo
             }
         };
 
-    let encrypted_file = AudioFile::open(&account_session, file_id, 500 * 1024, true);
+    let encrypted_file = AudioFile::open(account_session, file_id, 500 * 1024, true)
+        .instrument(tracing::info_span!("AudioFile::open"));
     let encrypted_file = match encrypted_file.await {
         Ok(encrypted_file) => encrypted_file,
         Err(e) => {
-            return Err(ServerError::LibrespotError("No audio file".to_owned()));
+            return Err(ServerError::LibrespotError(format!(
+                "No audio file: {:?}",
+                e
+            )));
         }
     };
 
     // let file_id = audio_item.files.get(&FileFormat::OGG_VORBIS_320).unwrap();
-    println!("------------ audio_cn_stream: Gotten audio file");
     tracing::info!(
         "Audio file id: {}",
         file_id
@@ -270,8 +339,7 @@ async fn audio_cn_stream(id: &str, account: &SpotifyAccount) -> Result<HttpRespo
             .unwrap_or("file_id decode failed".to_owned())
     );
 
-    // let enc_file = AudioFile::open(&account_session, *file_id, 500 * 1024, true).await?;
-    println!("------------ audio_cn_stream: Gotten encrypt file");
+    // let enc_file = AudioFile::open(account_session, *file_id, 500 * 1024, true).await?;
     tracing::info!("Gotten encrypt file");
 
     let stream_loader_controller = encrypted_file.get_stream_loader_controller();
@@ -288,13 +356,14 @@ async fn audio_cn_stream(id: &str, account: &SpotifyAccount) -> Result<HttpRespo
     let key = account_session
         .audio_key()
         .request(spotify_id, file_id)
+        .instrument(tracing::info_span!("audio_key.request"))
         .await?;
     let mut decrypted_file = AudioDecrypt::new(key, encrypted_file);
 
-    let is_ogg_vorbis = match format {
-        FileFormat::OGG_VORBIS_320 | FileFormat::OGG_VORBIS_160 | FileFormat::OGG_VORBIS_96 => true,
-        _ => false,
-    };
+    let is_ogg_vorbis = matches!(
+        format,
+        FileFormat::OGG_VORBIS_320 | FileFormat::OGG_VORBIS_160 | FileFormat::OGG_VORBIS_96
+    );
     let offset = if is_ogg_vorbis {
         // Spotify stores normalisation data in a custom Ogg packet instead of Vorbis comments.
         SPOTIFY_OGG_HEADER_END
@@ -304,81 +373,21 @@ async fn audio_cn_stream(id: &str, account: &SpotifyAccount) -> Result<HttpRespo
 
     decrypted_file.seek(SeekFrom::Start(offset)).unwrap();
 
-    println!("------------ audio_cn_stream: Gotten audio key");
     tracing::info!("Gotten audio key: {:?}", key);
-
-    // let mut decrypted_file = AudioDecrypt::new(key, encrypted_file);
-    // decrypted_file.seek(SeekFrom::Start(0xa7))?;
-
-    let size = 1024 * 10;
-    let mut buf = vec![0u8; size];
-    let s = async_stream::stream! {
-        loop {
-            let n = decrypted_file.read(&mut buf);
-            match n {
-                Ok(n) => {
-                    if n == 0 {
-                        break;
-                    }
-                    yield Ok(web::BytesMut::from(&buf[..n]).freeze());
-                }
-                Err(e) => {
-                    yield Err(e);
-                    drop(decrypted_file);
-                    break;
-                },
-            }
-        }
-    }
-    .timeout(Duration::from_millis(100))
-    .take_while(|r| {
-        if r.is_err() {
-            println!("------------ audio_cn_stream: stream timeout");
-            tracing::warn!("stream data is timeout");
-        }
-        r.is_ok()
-    })
-    .map(|d| d.unwrap());
-
-    println!("------------ audio_cn_stream: Start audio stream");
-    tracing::info!("Start audio stream");
-
-    Ok(HttpResponse::Ok().content_type("audio/ogg").streaming(s))
+    Ok((decrypted_file, format))
 }
 
-/// Retry to get audio content stream
+/// Parse a playable Spotify uri
 ///
-/// `id` can be `spotify:track:{..}` or `spotify:episode:{..}`
-#[tracing::instrument(skip(account))]
-async fn retry_audio_cn_stream(
-    id: &str,
-    account: &SpotifyAccount,
-    retries: usize,
-) -> Result<HttpResponse, ServerError> {
-    for i in 0..retries {
-        if i > 0 {
-            tracing::warn!("Retry get audio stream by {}", i);
-        }
-
-        match timeout(Duration::from_secs(3), audio_cn_stream(id, account)).await {
-            Ok(result) => return result,
-            Err(err) => {
-                // Reset session
-                // account.reset_session().await;
-                if i + 1 == retries {
-                    return Err(ServerError::AudioError(format!(
-                        "Failed after {} retries. the last error was: {:?}",
-                        retries, err
-                    )));
-                }
-            }
-        }
+/// librespot has no chapter type, so a chapter is resolved as an episode.
+/// It only streams for the audiobooks whose chapters have episode metadata.
+fn audio_spotify_id(id: &str) -> Result<SpotifyId, ServerError> {
+    let mut spotify_id = SpotifyId::from_uri(id)
+        .map_err(|_| ServerError::ParamsError(format!("Spotify id {} is invalid", id)))?;
+    if id.starts_with("spotify:chapter:") {
+        spotify_id.audio_type = SpotifyAudioType::Podcast;
     }
-
-    Err(ServerError::AudioError(format!(
-        "Failed after {} retries",
-        retries
-    )))
+    Ok(spotify_id)
 }
 
 async fn find_available_alternative(session: &Session, audio_item: AudioItem) -> Option<AudioItem> {
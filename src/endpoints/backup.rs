@@ -0,0 +1,60 @@
+use actix_web::{web, HttpResponse};
+
+use crate::{
+    app_store::AppStore, backup::Backup, endpoints::utils::json_response, errors::ServerError,
+    session::ServerSession,
+};
+
+#[derive(Debug, serde::Deserialize)]
+pub struct RestoreData {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Path: GET `/me/backup`
+/// A versioned JSON snapshot of the current user's library
+#[tracing::instrument(skip(app_store, session))]
+pub async fn backup(
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let account = app_store.authorize(username).await?;
+
+    let backup = Backup::snapshot(&account).await?;
+    let filename = format!(
+        "spotify-backup-{}-{}.json",
+        backup.user_id,
+        backup.created_at.format("%Y%m%d")
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", filename),
+        ))
+        .body(serde_json::to_string(&backup)?))
+}
+
+/// Path: POST `/me/restore`
+/// Replay a snapshot of `/me/backup` into the current user's library
+///
+/// With `dry_run=true`, it only reports what it would restore. A section which
+/// fails is listed in the `errors` of the report, after the others are restored.
+#[tracing::instrument(skip(body, app_store, session))]
+pub async fn restore(
+    query: web::Query<RestoreData>,
+    body: web::Bytes,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let account = app_store.authorize(username.clone()).await?;
+
+    let backup = serde_json::from_slice::<Backup>(&body)
+        .map_err(|err| ServerError::ParamsError(format!("Invalid backup: {}", err)))?;
+    let report = backup
+        .restore(username.as_ref(), &account, query.dry_run)
+        .await?;
+    json_response(&report)
+}
@@ -1,19 +1,17 @@
 use futures::StreamExt;
 
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 
 use rspotify::{
     clients::BaseClient,
-    model::{Category, Page},
+    model::{Category, Market, Page},
 };
 
 use crate::{
     account::SpotifyAccount,
     app_store::AppStore,
-    endpoints::{
-        params::{CountryLocateData, LimitOffsetData},
-        utils::json_response,
-    },
+    cache::{cache_key, market_part},
+    endpoints::params::{CountryLocateData, LimitOffsetData},
     errors::ServerError,
     session::ServerSession,
 };
@@ -25,40 +23,64 @@ use crate::{
 pub async fn categories(
     country_locate: web::Query<CountryLocateData>,
     limit_offset: web::Query<LimitOffsetData>,
+    req: HttpRequest,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
     let account = app_store.authorize(username).await?;
 
-    if limit_offset.limit.is_some() {
-        let page = page_categories(
-            &account,
-            country_locate.locale.as_deref(),
-            limit_offset.limit,
-            limit_offset.offset,
-        )
-        .await?;
-        json_response(&page)
-    } else {
-        let categories = all_categories(&account, country_locate.locale.as_deref()).await?;
-        json_response(&categories)
-    }
+    let market = country_locate.market(&account).await?;
+    let limit = limit_offset
+        .limit
+        .map(|v| v.to_string())
+        .unwrap_or_default();
+    let offset = limit_offset
+        .offset
+        .map(|v| v.to_string())
+        .unwrap_or_default();
+    let market_key = market_part(market, &account.credentials.username);
+    let key = cache_key(
+        "categories",
+        &[
+            country_locate.locale().unwrap_or_default(),
+            &market_key,
+            &limit,
+            &offset,
+        ],
+    );
+    app_store
+        .response_cache
+        .respond(&req, key, || async {
+            if limit_offset.limit.is_some() {
+                let page = page_categories(
+                    &account,
+                    country_locate.locale(),
+                    Some(market),
+                    limit_offset.limit,
+                    limit_offset.offset,
+                )
+                .await?;
+                Ok(serde_json::to_string(&page)?)
+            } else {
+                let categories =
+                    all_categories(&account, country_locate.locale(), Some(market)).await?;
+                Ok(serde_json::to_string(&categories)?)
+            }
+        })
+        .await
 }
 
 /// All categories
-async fn all_categories(
+pub async fn all_categories(
     account: &SpotifyAccount,
     locate: Option<&str>,
+    market: Option<Market>,
 ) -> Result<Vec<Category>, ServerError> {
-    let mut category_stream = account.client.categories(locate, None);
+    let mut category_stream = account.client.categories(locate, market);
     let mut categories = vec![];
     while let Some(item) = category_stream.next().await {
-        if item.is_err() {
-            return Err(ServerError::RequestError(format!("{:?}", item)));
-        } else {
-            categories.push(item.unwrap());
-        }
+        categories.push(item?);
     }
     Ok(categories)
 }
@@ -67,12 +89,13 @@ async fn all_categories(
 async fn page_categories(
     account: &SpotifyAccount,
     locate: Option<&str>,
+    market: Option<Market>,
     limit: Option<u32>,
     offset: Option<u32>,
 ) -> Result<Page<Category>, ServerError> {
     let page = account
         .client
-        .categories_manual(locate, None, limit, offset)
+        .categories_manual(locate, market, limit, offset)
         .await?;
     Ok(page)
 }
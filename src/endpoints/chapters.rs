@@ -0,0 +1,57 @@
+use actix_web::{web, HttpResponse};
+
+use rspotify::{clients::BaseClient, http::Query};
+
+use crate::{
+    app_store::AppStore,
+    endpoints::{
+        audiobooks::join_ids,
+        params::{base62_id, CountryLocateData, IdsData},
+        utils::ok_with_body_response,
+    },
+    errors::ServerError,
+    session::ServerSession,
+};
+
+/// Path: GET `/chapters/{id}`
+/// Get Spotify catalog information for a single audiobook chapter.
+#[tracing::instrument(skip(app_store, session))]
+pub async fn chapter(
+    id: web::Path<String>,
+    country_locate: web::Query<CountryLocateData>,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let account = app_store.authorize(username).await?;
+
+    let chapter_id = base62_id("chapter", id.as_str())?;
+
+    let market = country_locate.market(&account).await?;
+    let mut params = Query::new();
+    params.insert("market", market.into());
+    let url = format!("chapters/{}", chapter_id);
+    let result = account.client.api_get(&url, &params).await?;
+    ok_with_body_response(result)
+}
+
+/// Path: GET `/chapters`
+/// Get Spotify catalog information for several chapters identified by their Spotify IDs.
+#[tracing::instrument(skip(app_store, session))]
+pub async fn chapters(
+    query: web::Query<IdsData>,
+    country_locate: web::Query<CountryLocateData>,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let account = app_store.authorize(username).await?;
+
+    let chapter_ids = join_ids("chapter", &query.ids())?;
+    let market = country_locate.market(&account).await?;
+    let mut params = Query::new();
+    params.insert("ids", chapter_ids.as_str());
+    params.insert("market", market.into());
+    let result = account.client.api_get("chapters", &params).await?;
+    ok_with_body_response(result)
+}
@@ -0,0 +1,260 @@
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+
+use crate::{
+    app_store::AppStore,
+    dlna::{
+        content::{self, BrowseFlag, ContentDirectory},
+        description::{self, CONNECTION_MANAGER, CONTENT_DIRECTORY},
+        didl,
+        soap::{self, SoapError},
+        DlnaConfig,
+    },
+};
+
+const XML_CONTENT_TYPE: &str = r#"text/xml; charset="utf-8""#;
+
+/// Seconds of event subscriptions
+const SUBSCRIPTION_TIMEOUT: u32 = 1800;
+
+fn xml_response(body: String) -> HttpResponse {
+    HttpResponse::Ok().content_type(XML_CONTENT_TYPE).body(body)
+}
+
+fn soap_response(
+    service: &str,
+    action: &str,
+    result: Result<Vec<(&str, String)>, SoapError>,
+) -> HttpResponse {
+    match result {
+        Ok(arguments) => xml_response(soap::response(service, action, &arguments)),
+        Err(err) => {
+            tracing::warn!("DLNA {} failed: {:?}", action, err);
+            HttpResponse::InternalServerError()
+                .content_type(XML_CONTENT_TYPE)
+                .body(soap::fault(&err))
+        }
+    }
+}
+
+fn soap_action(req: &HttpRequest) -> String {
+    req.headers()
+        .get("SOAPACTION")
+        .and_then(|value| value.to_str().ok())
+        .and_then(soap::action)
+        .unwrap_or_default()
+        .to_owned()
+}
+
+/// Path: GET `/dlna/description.xml`
+/// The UPnP device description of the DLNA MediaServer
+pub async fn description(app_store: web::Data<AppStore>) -> HttpResponse {
+    match &app_store.dlna {
+        Some(config) => xml_response(description::device(config)),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Path: GET `/dlna/ContentDirectory.xml`
+pub async fn content_directory_scpd() -> HttpResponse {
+    xml_response(description::CONTENT_DIRECTORY_SCPD.to_owned())
+}
+
+/// Path: GET `/dlna/ConnectionManager.xml`
+pub async fn connection_manager_scpd() -> HttpResponse {
+    xml_response(description::CONNECTION_MANAGER_SCPD.to_owned())
+}
+
+/// Path: POST `/dlna/control/ContentDirectory`
+/// The SOAP actions of the ContentDirectory service, mostly `Browse`
+#[tracing::instrument(skip(req, body, app_store))]
+pub async fn content_directory(
+    req: HttpRequest,
+    body: String,
+    app_store: web::Data<AppStore>,
+) -> HttpResponse {
+    let Some(config) = &app_store.dlna else {
+        return HttpResponse::NotFound().finish();
+    };
+    let action = soap_action(&req);
+    let result = match action.as_str() {
+        "Browse" => browse(config, &body, &app_store).await,
+        "GetSearchCapabilities" => Ok(vec![("SearchCaps", String::new())]),
+        "GetSortCapabilities" => Ok(vec![("SortCaps", String::new())]),
+        "GetSystemUpdateID" => Ok(vec![("Id", "0".to_owned())]),
+        _ => Err(SoapError::invalid_action()),
+    };
+    soap_response(CONTENT_DIRECTORY, &action, result)
+}
+
+async fn browse(
+    config: &DlnaConfig,
+    body: &str,
+    app_store: &AppStore,
+) -> Result<Vec<(&'static str, String)>, SoapError> {
+    let object_id = soap::argument(body, "ObjectID").ok_or_else(SoapError::invalid_args)?;
+    let flag = soap::argument(body, "BrowseFlag")
+        .and_then(|flag| BrowseFlag::parse(&flag))
+        .ok_or_else(SoapError::invalid_args)?;
+    let index = |name| {
+        soap::argument(body, name)
+            .map(|value| value.trim().parse::<usize>())
+            .transpose()
+            .map(Option::unwrap_or_default)
+            .map_err(|_| SoapError::invalid_args())
+    };
+    let starting_index = index("StartingIndex")?;
+    let requested_count = index("RequestedCount")?;
+
+    let static_browse =
+        content::browse_static(config, &object_id, flag, starting_index, requested_count);
+    let browse = match static_browse {
+        Some(browse) => browse,
+        None => {
+            let account = app_store.authorize(config.username.as_str()).await?;
+            ContentDirectory::new(config, &account)
+                .browse(&object_id, flag, starting_index, requested_count)
+                .await?
+        }
+    };
+    Ok(vec![
+        ("Result", browse.result),
+        ("NumberReturned", browse.number_returned.to_string()),
+        ("TotalMatches", browse.total_matches.to_string()),
+        ("UpdateID", "0".to_owned()),
+    ])
+}
+
+/// Path: POST `/dlna/control/ConnectionManager`
+/// The SOAP actions of the ConnectionManager service
+#[tracing::instrument(skip(req, body, app_store))]
+pub async fn connection_manager(
+    req: HttpRequest,
+    body: String,
+    app_store: web::Data<AppStore>,
+) -> HttpResponse {
+    if app_store.dlna.is_none() {
+        return HttpResponse::NotFound().finish();
+    }
+    let action = soap_action(&req);
+    let result = match action.as_str() {
+        "GetProtocolInfo" => Ok(vec![
+            ("Source", didl::PROTOCOL_INFO.to_owned()),
+            ("Sink", String::new()),
+        ]),
+        "GetCurrentConnectionIDs" => Ok(vec![("ConnectionIDs", "0".to_owned())]),
+        "GetCurrentConnectionInfo" => match soap::argument(&body, "ConnectionID").as_deref() {
+            Some("0") => Ok(vec![
+                ("RcsID", "-1".to_owned()),
+                ("AVTransportID", "-1".to_owned()),
+                ("ProtocolInfo", String::new()),
+                ("PeerConnectionManager", String::new()),
+                ("PeerConnectionID", "-1".to_owned()),
+                ("Direction", "Output".to_owned()),
+                ("Status", "OK".to_owned()),
+            ]),
+            _ => Err(SoapError::invalid_args()),
+        },
+        _ => Err(SoapError::invalid_action()),
+    };
+    soap_response(CONNECTION_MANAGER, &action, result)
+}
+
+/// Path: SUBSCRIBE/UNSUBSCRIBE `/dlna/event/{service}`
+/// Event subscriptions, which some control points require before browsing.
+/// The content never changes, so no events are sent.
+#[tracing::instrument(skip(req, app_store))]
+pub async fn subscribe(
+    service: web::Path<String>,
+    req: HttpRequest,
+    app_store: web::Data<AppStore>,
+) -> HttpResponse {
+    let Some(config) = &app_store.dlna else {
+        return HttpResponse::NotFound().finish();
+    };
+    if req.method().as_str() == "UNSUBSCRIBE" {
+        return HttpResponse::Ok().finish();
+    }
+    let sid = req
+        .headers()
+        .get("SID")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+        .unwrap_or_else(|| format!("uuid:{}-{}", config.uuid, service));
+    HttpResponse::Ok()
+        .insert_header(("SID", sid))
+        .insert_header(("TIMEOUT", format!("Second-{}", SUBSCRIPTION_TIMEOUT)))
+        .insert_header((header::SERVER, "UPnP/1.0 spotify-web-server"))
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App};
+
+    use super::*;
+
+    fn browse_request(object_id: &str, flag: &str) -> String {
+        format!(
+            r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
+  <s:Body>
+    <u:Browse xmlns:u="urn:schemas-upnp-org:service:ContentDirectory:1">
+      <ObjectID>{object_id}</ObjectID>
+      <BrowseFlag>{flag}</BrowseFlag>
+      <Filter>*</Filter>
+      <StartingIndex>4</StartingIndex>
+      <RequestedCount>10</RequestedCount>
+      <SortCriteria></SortCriteria>
+    </u:Browse>
+  </s:Body>
+</s:Envelope>"#
+        )
+    }
+
+    async fn post(body: String) -> (u16, String) {
+        let config = DlnaConfig::new(
+            "alice".to_owned(),
+            "Spotify".to_owned(),
+            "http://192.168.1.2:8080".to_owned(),
+        );
+        let app_store = AppStore::new("client", "cache", None).with_dlna(Some(config));
+        let app = test::init_service(App::new().app_data(web::Data::new(app_store)).route(
+            "/dlna/control/ContentDirectory",
+            web::post().to(content_directory),
+        ))
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/dlna/control/ContentDirectory")
+            .insert_header((
+                "SOAPACTION",
+                r#""urn:schemas-upnp-org:service:ContentDirectory:1#Browse""#,
+            ))
+            .set_payload(body)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        let status = res.status().as_u16();
+        let body = test::read_body(res).await;
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn browse_round_trip() {
+        let (status, body) = post(browse_request("0", "BrowseDirectChildren")).await;
+        assert_eq!(status, 200);
+        assert!(body.contains(
+            r#"<u:BrowseResponse xmlns:u="urn:schemas-upnp-org:service:ContentDirectory:1">"#
+        ));
+        assert!(body.contains("<NumberReturned>2</NumberReturned>"));
+        assert!(body.contains("<TotalMatches>6</TotalMatches>"));
+        let result = soap::argument(&body, "Result").unwrap();
+        assert!(result.starts_with("<DIDL-Lite "));
+        assert_eq!(result.matches("<container ").count(), 2);
+    }
+
+    #[actix_web::test]
+    async fn browse_faults() {
+        let (status, body) = post(browse_request("0", "BrowseEverything")).await;
+        assert_eq!(status, 500);
+        assert!(body.contains("<errorCode>402</errorCode>"));
+    }
+}
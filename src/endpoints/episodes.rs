@@ -4,7 +4,8 @@ use actix_web::{web, HttpResponse};
 
 use rspotify::{
     clients::BaseClient,
-    model::{EpisodeId, Market},
+    http::Query,
+    model::{EpisodeId, Id, Market},
     ClientError,
 };
 
@@ -12,10 +13,11 @@ use crate::{
     account::SpotifyAccount,
     app_store::AppStore,
     endpoints::{
-        params::{IdsData, LimitOffsetData},
-        utils::json_response,
+        params::{CountryLocateData, IdsData, LimitOffsetData},
+        utils::{all_raw_items, json_response},
     },
     errors::ServerError,
+    events::EVENTS,
     session::ServerSession,
 };
 
@@ -23,6 +25,7 @@ use crate::{
 #[tracing::instrument(skip(app_store, session))]
 pub async fn episode(
     id: web::Path<String>,
+    country_locate: web::Query<CountryLocateData>,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
@@ -33,7 +36,11 @@ pub async fn episode(
     let episode_id = EpisodeId::from_id(id_str.as_str())
         .map_err(|_| ServerError::ParamsError(format!("Invalid episode id: {}", id_str)))?;
 
-    let result = account.client.get_an_episode(episode_id, None).await?;
+    let market = country_locate.market(&account).await?;
+    let result = account
+        .client
+        .get_an_episode(episode_id, Some(market))
+        .await?;
     json_response(&result)
 }
 
@@ -41,6 +48,7 @@ pub async fn episode(
 #[tracing::instrument(skip(app_store, session))]
 pub async fn episodes(
     query: web::Query<IdsData>,
+    country_locate: web::Query<CountryLocateData>,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
@@ -48,9 +56,10 @@ pub async fn episodes(
     let account = app_store.authorize(username).await?;
 
     let episode_ids = crate::into_ids!(EpisodeId, query.ids());
+    let market = country_locate.market(&account).await?;
     let result = account
         .client
-        .get_several_episodes(episode_ids, None)
+        .get_several_episodes(episode_ids, Some(market))
         .await?;
     json_response(&result)
 }
@@ -59,6 +68,7 @@ pub async fn episodes(
 #[tracing::instrument(skip(app_store, session))]
 pub async fn saved_episodes(
     query: web::Query<LimitOffsetData>,
+    country_locate: web::Query<CountryLocateData>,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
@@ -70,7 +80,8 @@ pub async fn saved_episodes(
         limit = Some(50);
     }
 
-    let page = page_saved_episodes(&account, None, limit, query.offset).await?;
+    let market = country_locate.market(&account).await?;
+    let page = page_saved_episodes(&account, Some(&market), limit, query.offset).await?;
     json_response(&page)
 }
 
@@ -96,6 +107,18 @@ async fn page_saved_episodes(
     account.client.api_get("me/episodes", &params).await
 }
 
+/// Current user all saved episodes, which rspotify doesn't cover
+pub async fn all_saved_episodes(
+    account: &SpotifyAccount,
+    market: Option<Market>,
+) -> Result<Vec<serde_json::Value>, ServerError> {
+    let mut params = Query::new();
+    if let Some(market) = &market {
+        params.insert("market", (*market).into());
+    }
+    all_raw_items(account, "me/episodes", &params).await
+}
+
 /// Path: PUT `/me/episodes`
 #[tracing::instrument(skip(app_store, session))]
 pub async fn save_episodes(
@@ -104,7 +127,7 @@ pub async fn save_episodes(
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
-    let account = app_store.authorize(username).await?;
+    let account = app_store.authorize(username.clone()).await?;
 
     let mut ids = serde_json::map::Map::new();
     ids.insert("ids".to_string(), query.ids.split(',').collect());
@@ -114,6 +137,7 @@ pub async fn save_episodes(
         .api_put("me/episodes", &serde_json::Value::from(ids))
         .await?;
 
+    EVENTS.library_changed(true, username.as_ref(), "episode", &query.ids());
     json_response(&result)
 }
 
@@ -125,7 +149,7 @@ pub async fn delete_episodes(
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
-    let account = app_store.authorize(username).await?;
+    let account = app_store.authorize(username.clone()).await?;
 
     let mut ids = serde_json::map::Map::new();
     ids.insert("ids".to_string(), query.ids.split(',').collect());
@@ -135,5 +159,45 @@ pub async fn delete_episodes(
         .api_delete("me/episodes", &serde_json::Value::from(ids))
         .await?;
 
+    EVENTS.library_changed(false, username.as_ref(), "episode", &query.ids());
+    json_response(&result)
+}
+
+/// Path: GET `/me/episodes/contains`
+/// Check if one or more episodes is already saved in the current Spotify user's library.
+#[tracing::instrument(skip(app_store, session))]
+pub async fn contains_episodes(
+    query: web::Query<IdsData>,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let account = app_store.authorize(username).await?;
+
+    let mut result = vec![];
+    for ids in query.batches() {
+        let episode_ids = crate::into_ids!(EpisodeId, ids);
+        let contains = check_saved_episodes(&account, &episode_ids).await?;
+        result.extend(contains);
+    }
     json_response(&result)
 }
+
+/// Check if the episodes are saved in the current user's library
+async fn check_saved_episodes(
+    account: &SpotifyAccount,
+    episode_ids: &[EpisodeId<'_>],
+) -> Result<Vec<bool>, ServerError> {
+    let ids = episode_ids
+        .iter()
+        .map(|id| id.id())
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut params = HashMap::new();
+    params.insert("ids", ids.as_str());
+    let result = account
+        .client
+        .api_get("me/episodes/contains", &params)
+        .await?;
+    Ok(serde_json::from_str(&result)?)
+}
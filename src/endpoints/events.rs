@@ -0,0 +1,220 @@
+use std::time::Duration;
+
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use librespot::discovery::Credentials;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    account::UserName,
+    app_store::AppStore,
+    errors::ServerError,
+    events::{Event, EVENTS},
+    session::ServerSession,
+};
+
+/// The interval of SSE keep-alive comments and WebSocket pings, which keep
+/// proxies from closing idle connections
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+async fn event_names(app_store: &AppStore, username: UserName) -> Result<Vec<String>, ServerError> {
+    let account = app_store.authorize(username.clone()).await?;
+    Ok(names_of(&username, &account.credentials))
+}
+
+/// The names events of the user carry: the server user, and the Spotify
+/// account for the account and stream events
+fn names_of(username: &UserName, credentials: &Credentials) -> Vec<String> {
+    vec![username.as_ref().to_owned(), credentials.username.clone()]
+}
+
+fn is_for(event: &Event, names: &[String]) -> bool {
+    names.contains(&event.username)
+}
+
+/// Path: GET `/events`
+/// Server-Sent Events of the current user
+///
+/// Each event has its type as the SSE event name and its JSON as data.
+#[tracing::instrument(skip(app_store, session))]
+pub async fn events(
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let names = event_names(&app_store, username).await?;
+
+    let mut events = EVENTS.subscribe();
+    let s = async_stream::stream! {
+        let mut keep_alive = tokio::time::interval(KEEP_ALIVE);
+        loop {
+            let message = tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) if is_for(&event, &names) => match serde_json::to_string(&event) {
+                        Ok(data) => format!(
+                            "id: {}\nevent: {}\ndata: {}\n\n",
+                            event.id,
+                            event.kind.as_str(),
+                            data
+                        ),
+                        Err(_) => continue,
+                    },
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Event stream skipped {} events", skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = keep_alive.tick() => ": keep-alive\n\n".to_owned(),
+            };
+            yield Ok::<_, ServerError>(web::Bytes::from(message));
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(s))
+}
+
+/// Whether the WebSocket handshake comes from the server's own origin, an
+/// allowed CORS origin, or a client which is not a browser
+fn is_allowed_origin(req: &HttpRequest, app_store: &AppStore) -> bool {
+    let Some(origin) = req.headers().get(header::ORIGIN) else {
+        return true;
+    };
+    let Ok(origin) = origin.to_str() else {
+        return false;
+    };
+    let info = req.connection_info();
+    origin == format!("{}://{}", info.scheme(), info.host()) || app_store.cors.is_allowed(origin)
+}
+
+/// Path: GET `/events/ws`
+/// The events of `/events` over a WebSocket, as JSON text messages
+///
+/// Browsers send the session cookie with the handshake of any page, so only
+/// the allowed origins may open it.
+#[tracing::instrument(skip(req, body, app_store, session))]
+pub async fn events_ws(
+    req: HttpRequest,
+    body: web::Payload,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    if !is_allowed_origin(&req, &app_store) {
+        return Err(ServerError::AuthenticationError);
+    }
+    let username = session.get_username()?;
+    let names = event_names(&app_store, username).await?;
+
+    let (response, mut ws, mut messages) = actix_ws::handle(&req, body)
+        .map_err(|e| ServerError::ParamsError(format!("WebSocket handshake failed: {}", e)))?;
+
+    let mut events = EVENTS.subscribe();
+    actix_web::rt::spawn(async move {
+        let mut keep_alive = tokio::time::interval(KEEP_ALIVE);
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) if is_for(&event, &names) => {
+                        let Ok(text) = serde_json::to_string(&event) else {
+                            continue;
+                        };
+                        if ws.text(text).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Event WebSocket skipped {} events", skipped);
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                message = messages.recv() => match message {
+                    Some(Ok(actix_ws::Message::Ping(bytes))) => {
+                        if ws.pong(&bytes).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Ok(actix_ws::Message::Close(_))) | Some(Err(_)) | None => break,
+                    // Clients have nothing to say
+                    Some(Ok(_)) => {}
+                },
+                _ = keep_alive.tick() => {
+                    if ws.ping(b"").await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        let _ = ws.close(None).await;
+    });
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use regex::Regex;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{cors::CorsConfig, events::EventKind};
+
+    fn event(kind: EventKind, username: &str) -> Event {
+        Event {
+            id: "0123456789abcdef".to_owned(),
+            kind,
+            username: username.to_owned(),
+            timestamp: 0,
+            data: json!({}),
+        }
+    }
+
+    #[test]
+    fn events_of_the_user_and_its_account() {
+        let credentials = Credentials::with_password("spotify-alice", "password");
+        let names = names_of(&UserName::from("alice"), &credentials);
+
+        assert!(is_for(&event(EventKind::PlaylistCreated, "alice"), &names));
+        assert!(is_for(
+            &event(EventKind::TokenRefreshed, "spotify-alice"),
+            &names
+        ));
+        assert!(!is_for(&event(EventKind::PlaylistCreated, "bob"), &names));
+        assert!(!is_for(
+            &event(EventKind::TokenRefreshed, "spotify-bob"),
+            &names
+        ));
+        assert!(!is_for(&event(EventKind::Login, "ALICE"), &names));
+    }
+
+    #[test]
+    fn websocket_origins() {
+        let app_store = AppStore::new("client", "/tmp", None).with_cors(CorsConfig {
+            origins: vec!["https://app.example.com".to_owned()],
+            origin_regex: Some(Regex::new(r"^https://[a-z]+\.example\.org$").unwrap()),
+            credentials: true,
+            max_age: 0,
+        });
+        let allowed = |origin: Option<&str>| {
+            let mut req = TestRequest::get()
+                .uri("/events/ws")
+                .insert_header((header::HOST, "music.local:8080"));
+            if let Some(origin) = origin {
+                req = req.insert_header((header::ORIGIN, origin));
+            }
+            is_allowed_origin(&req.to_http_request(), &app_store)
+        };
+
+        assert!(allowed(None));
+        assert!(allowed(Some("http://music.local:8080")));
+        assert!(allowed(Some("https://app.example.com")));
+        assert!(allowed(Some("https://web.example.org")));
+        assert!(!allowed(Some("https://evil.example.net")));
+        assert!(!allowed(Some("http://music.local:9090")));
+        assert!(!allowed(Some("null")));
+    }
+}
@@ -0,0 +1,174 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use rspotify::{
+    clients::BaseClient,
+    model::{Id, ShowId},
+};
+
+use crate::{
+    account::{SignKey, SpotifyAccount, UserName},
+    app_store::AppStore,
+    endpoints::{
+        audios::{signed_audio_id, signed_audio_uri, signed_query},
+        shows::{all_episodes, all_saved_shows},
+        utils::server_url,
+    },
+    errors::ServerError,
+    feeds::{self, FeedEpisode},
+    session::ServerSession,
+};
+
+/// The signature of a feed url, which podcast apps fetch without cookies
+#[derive(Debug, serde::Deserialize)]
+pub struct FeedSign {
+    sign: Option<String>,
+    iv: Option<String>,
+    username: Option<String>,
+}
+
+/// The signature of a feed url must be of its own show
+fn check_feed_sign(key: &SignKey, sign: &FeedSign, show_id: &ShowId) -> Result<(), ServerError> {
+    let (Some(sign), Some(iv)) = (&sign.sign, &sign.iv) else {
+        return Err(ServerError::AuthenticationError);
+    };
+    if signed_audio_id(key, sign, iv)? != show_id.uri() {
+        return Err(ServerError::AuthenticationError);
+    }
+    Ok(())
+}
+
+/// The signed feed url of a show
+fn feed_url(
+    req: &HttpRequest,
+    account: &SpotifyAccount,
+    username: &UserName,
+    show_id: &ShowId,
+) -> Result<String, ServerError> {
+    Ok(format!(
+        "{}/feeds/shows/{}.xml?{}",
+        server_url(req),
+        show_id.id(),
+        signed_query(account.sign_key(), username, &show_id.uri())?
+    ))
+}
+
+/// Path: GET `/feeds/shows/{id}.xml`
+/// The podcast RSS feed of a show, whose enclosures are signed audio stream urls
+///
+/// It takes the session cookie, or the signature of the feed urls of `/feeds/shows.opml`.
+#[tracing::instrument(skip(req, app_store, session))]
+pub async fn show_feed(
+    id: web::Path<String>,
+    sign: web::Query<FeedSign>,
+    req: HttpRequest,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let show_id = ShowId::from_id(id.as_str())
+        .map_err(|_| ServerError::ParamsError("Invalid show id".to_owned()))?;
+    let username = match &sign.username {
+        Some(username) => UserName::from(username.as_str()),
+        None => session.get_username()?,
+    };
+    let account = app_store.authorize(username.clone()).await?;
+    if sign.username.is_some() {
+        check_feed_sign(account.sign_key(), &sign, &show_id)?;
+    }
+
+    let market = account.default_market().await;
+    let show = account
+        .client
+        .get_a_show(show_id.clone(), Some(market))
+        .await?;
+    let episodes = all_episodes(&account, show_id.clone(), Some(market)).await?;
+
+    let base_url = server_url(&req);
+    let episodes = episodes
+        .iter()
+        .map(|episode| {
+            Ok(FeedEpisode {
+                episode,
+                url: format!(
+                    "{}{}",
+                    base_url,
+                    signed_audio_uri(&account, &username, &episode.id.uri())?
+                ),
+            })
+        })
+        .collect::<Result<Vec<_>, ServerError>>()?;
+    let feed_url = feed_url(&req, &account, &username, &show_id)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/rss+xml; charset=utf-8")
+        .body(feeds::rss(&show, &feed_url, &episodes)))
+}
+
+/// Path: GET `/feeds/shows.opml`
+/// An OPML export of the saved shows, with signed feed urls for podcast apps
+#[tracing::instrument(skip(req, app_store, session))]
+pub async fn saved_shows_opml(
+    req: HttpRequest,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let account = app_store.authorize(username.clone()).await?;
+
+    let feeds = all_saved_shows(&account)
+        .await?
+        .into_iter()
+        .map(|saved| {
+            let url = feed_url(&req, &account, &username, &saved.show.id)?;
+            Ok((saved.show.name, url))
+        })
+        .collect::<Result<Vec<_>, ServerError>>()?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/x-opml; charset=utf-8")
+        .insert_header((
+            "Content-Disposition",
+            "attachment; filename=\"spotify-shows.opml\"",
+        ))
+        .body(feeds::opml("Spotify shows", &feeds)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHOW: &str = "38bS44xjbVVZ3No3ByF1dJ";
+    const OTHER_SHOW: &str = "5CfCWKI5pZ28U0uOzXkDHe";
+
+    fn feed_sign(key: &SignKey, username: &str, uri: &str) -> FeedSign {
+        let query = signed_query(key, &UserName::from(username), uri).unwrap();
+        web::Query::<FeedSign>::from_query(&query)
+            .unwrap()
+            .into_inner()
+    }
+
+    #[test]
+    fn feed_signatures_are_of_their_show() {
+        let key = SignKey::new([7; 32]);
+        let show_id = ShowId::from_id(SHOW).unwrap();
+        let sign = feed_sign(&key, "alice", &show_id.uri());
+        assert_eq!(sign.username.as_deref(), Some("alice"));
+        assert!(check_feed_sign(&key, &sign, &show_id).is_ok());
+
+        // The signature of another show, or of an episode, doesn't open this one
+        let other = feed_sign(&key, "alice", &ShowId::from_id(OTHER_SHOW).unwrap().uri());
+        assert!(matches!(
+            check_feed_sign(&key, &other, &show_id),
+            Err(ServerError::AuthenticationError)
+        ));
+        let episode = feed_sign(&key, "alice", "spotify:episode:512ojhOuo1ktJprKbVcKyQ");
+        assert!(check_feed_sign(&key, &episode, &show_id).is_err());
+
+        // Nor does the key of another user, or a signature without its iv
+        assert!(check_feed_sign(&SignKey::new([8; 32]), &sign, &show_id).is_err());
+        let without_iv = FeedSign { iv: None, ..sign };
+        assert!(matches!(
+            check_feed_sign(&key, &without_iv, &show_id),
+            Err(ServerError::AuthenticationError)
+        ));
+    }
+}
@@ -1,16 +1,14 @@
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 
 use rspotify::{clients::BaseClient, http::Query};
 
-use crate::{
-    app_store::AppStore, endpoints::utils::ok_with_body_response, errors::ServerError,
-    session::ServerSession,
-};
+use crate::{app_store::AppStore, cache::cache_key, errors::ServerError, session::ServerSession};
 
 /// Path: GET `/recommendations/available-genre-seeds`
 /// Retrieve a list of available genres seed parameter values for recommendations.
 #[tracing::instrument(skip(app_store, session))]
 pub async fn genres(
+    req: HttpRequest,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
@@ -18,7 +16,10 @@ pub async fn genres(
     let account = app_store.authorize(username).await?;
 
     let url = "recommendations/available-genre-seeds";
-    let result = account.client.api_get(url, &Query::new()).await?;
-
-    ok_with_body_response(result)
+    app_store
+        .response_cache
+        .respond(&req, cache_key("genres", &[]), || async {
+            Ok(account.client.api_get(url, &Query::new()).await?)
+        })
+        .await
 }
@@ -5,6 +5,7 @@ use crate::{
     app_store::AppStore,
     endpoints::params::{LoginData, UserNameData},
     errors::ServerError,
+    events::{EventKind, EVENTS},
     session::ServerSession,
 };
 
@@ -21,10 +22,21 @@ pub async fn login(
         .await?;
 
     session.insert_username(&form.username)?;
+    EVENTS.emit(EventKind::Login, &form.username, serde_json::json!({}));
 
     Ok(HttpResponse::Ok().finish())
 }
 
+/// Path: POST `/logout`
+/// Forget the user of the current session
+#[tracing::instrument(skip(session))]
+pub async fn logout(session: ServerSession) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    session.log_out();
+    EVENTS.emit(EventKind::Logout, username.as_ref(), serde_json::json!({}));
+    Ok(HttpResponse::Ok().finish())
+}
+
 #[tracing::instrument(skip(app_store, session))]
 pub async fn miracle(
     query: web::Query<UserNameData>,
@@ -33,17 +45,24 @@ pub async fn miracle(
 ) -> Result<HttpResponse, ServerError> {
     if let Some(username) = &query.username {
         let accounts = app_store.spotify_accounts.read().await;
-        if accounts.contains_key(&UserName::from(username.as_str())) {
+        if app_store.is_allowed(username)
+            && accounts.contains_key(&UserName::from(username.as_str()))
+        {
             session.insert_username(username)?;
+            EVENTS.emit(EventKind::Login, username, serde_json::json!({}));
             Ok(HttpResponse::Ok().finish())
         } else {
             Err(ServerError::NoLoginError)
         }
     } else {
         let accounts = app_store.spotify_accounts.read().await;
-        let username = accounts.keys().into_iter().next();
+        let username = accounts
+            .keys()
+            .into_iter()
+            .find(|username| app_store.is_allowed(username.as_ref()));
         if let Some(one) = username {
             session.insert_username(one.as_ref())?;
+            EVENTS.emit(EventKind::Login, one.as_ref(), serde_json::json!({}));
             Ok(HttpResponse::Ok().finish())
         } else {
             Err(ServerError::NoLoginError)
@@ -1,16 +1,14 @@
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 
 use rspotify::{clients::BaseClient, http::Query};
 
-use crate::{
-    app_store::AppStore, endpoints::utils::ok_with_body_response, errors::ServerError,
-    session::ServerSession,
-};
+use crate::{app_store::AppStore, cache::cache_key, errors::ServerError, session::ServerSession};
 
 /// Path: GET `/markets`
 /// Get the list of markets where Spotify is available.
 #[tracing::instrument(skip(app_store, session))]
 pub async fn markets(
+    req: HttpRequest,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
@@ -18,7 +16,10 @@ pub async fn markets(
     let account = app_store.authorize(username).await?;
 
     let url = "markets";
-    let result = account.client.api_get(url, &Query::new()).await?;
-
-    ok_with_body_response(result)
+    app_store
+        .response_cache
+        .respond(&req, cache_key("markets", &[]), || async {
+            Ok(account.client.api_get(url, &Query::new()).await?)
+        })
+        .await
 }
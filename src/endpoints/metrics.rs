@@ -0,0 +1,22 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use crate::{
+    app_store::AppStore, endpoints::admin::check_admin, errors::ServerError, metrics::METRICS,
+};
+
+/// Path: GET `/metrics`
+/// Server metrics in the Prometheus text format
+///
+/// The series are labelled by account, so they need the admin token as a
+/// `Bearer` token, like `/admin`.
+#[tracing::instrument(skip(req, app_store))]
+pub async fn metrics(
+    req: HttpRequest,
+    app_store: web::Data<AppStore>,
+) -> Result<HttpResponse, ServerError> {
+    check_admin(&req, &app_store)?;
+    let body = METRICS.render(&app_store).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
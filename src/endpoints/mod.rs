@@ -1,18 +1,30 @@
+pub mod admin;
 pub mod albums;
 pub mod artists;
+pub mod audiobooks;
 pub mod audios;
 pub mod auth;
+pub mod backup;
 pub mod categories;
+pub mod chapters;
+pub mod dlna;
 pub mod episodes;
+pub mod events;
+pub mod feeds;
 pub mod genres;
 pub mod health_check;
 pub mod login;
 pub mod markets;
+pub mod metrics;
 pub mod params;
+pub mod playlist_files;
 pub mod playlists;
+pub mod radio;
 pub mod recommends;
 pub mod search;
+pub mod sessions;
 pub mod shows;
+pub mod subsonic;
 pub mod tracks;
 pub mod users;
 pub mod utils;
@@ -1,9 +1,9 @@
 use rspotify::model::{
-    ArtistId, EpisodeId, IncludeExternal, Market, PlayableId, RecommendationsAttribute, SearchType,
-    TrackId,
+    AlbumType, ArtistId, Country, EpisodeId, IncludeExternal, Market, PlayableId,
+    RecommendationsAttribute, SearchType, TrackId,
 };
 
-use crate::errors::ServerError;
+use crate::{account::SpotifyAccount, errors::ServerError};
 
 #[derive(Debug, serde::Deserialize)]
 pub struct LoginData {
@@ -24,7 +24,6 @@ pub struct SearchData {
     pub q: String,
     #[serde(alias = "type")]
     pub type_: SearchType,
-    pub market: Option<Market>,
     pub include_external: Option<IncludeExternal>,
 }
 
@@ -42,6 +41,9 @@ macro_rules! into_ids {
     }};
 }
 
+/// The maximum number of ids Spotify accepts in one library request
+pub const MAX_IDS_PER_REQUEST: usize = 50;
+
 /// Ids Query Data
 #[derive(Debug, serde::Deserialize)]
 pub struct IdsData {
@@ -52,6 +54,37 @@ impl IdsData {
     pub fn ids(&self) -> Vec<&str> {
         self.ids.split(',').collect()
     }
+
+    /// Ids split into batches of at most `MAX_IDS_PER_REQUEST`
+    pub fn batches(&self) -> Vec<Vec<&str>> {
+        self.ids()
+            .chunks(MAX_IDS_PER_REQUEST)
+            .map(|chunk| chunk.to_vec())
+            .collect()
+    }
+}
+
+/// Check a base62 id of the types which rspotify doesn't model,
+/// e.g. audiobooks and chapters
+pub fn base62_id<'a>(kind: &str, id: &'a str) -> Result<&'a str, ServerError> {
+    if id.len() == 22 && id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Ok(id)
+    } else {
+        Err(ServerError::ParamsError(format!(
+            "Invalid {} id: {}",
+            kind, id
+        )))
+    }
+}
+
+/// Parse a market, which is `from_token` or an ISO 3166-1 alpha-2 country code
+pub fn parse_market(market: &str) -> Result<Market, ServerError> {
+    if market.eq_ignore_ascii_case("from_token") {
+        return Ok(Market::FromToken);
+    }
+    serde_json::from_value::<Country>(serde_json::Value::from(market.to_uppercase()))
+        .map(Market::Country)
+        .map_err(|_| ServerError::ParamsError(format!("Invalid market: {}", market)))
 }
 
 /// Page Query Data
@@ -61,6 +94,38 @@ pub struct LimitOffsetData {
     pub offset: Option<u32>,
 }
 
+/// Artist Albums Query Data
+#[derive(Debug, serde::Deserialize)]
+pub struct ArtistAlbumsData {
+    // Comma-separated `album`, `single`, `appears_on` and `compilation`
+    pub include_groups: Option<String>,
+    // 1: collapse the same releases of different markets
+    // else: keep all releases
+    pub dedupe: Option<u8>,
+}
+
+impl ArtistAlbumsData {
+    pub fn include_groups(&self) -> Result<Option<Vec<AlbumType>>, ServerError> {
+        if let Some(groups) = self.include_groups.as_ref() {
+            let mut album_types = vec![];
+            for group in groups.split(',') {
+                let album_type =
+                    serde_json::from_value(serde_json::Value::from(group)).map_err(|_| {
+                        ServerError::ParamsError(format!("Invalid album group: {group}"))
+                    })?;
+                album_types.push(album_type);
+            }
+            Ok(Some(album_types))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn dedupe(&self) -> bool {
+        self.dedupe.unwrap_or(0) == 1
+    }
+}
+
 /// Recommendations Query Data
 #[derive(Debug, serde::Deserialize)]
 pub struct RecommendationsData {
@@ -114,7 +179,7 @@ pub struct RecommendationsData {
 }
 
 impl RecommendationsData {
-    pub fn seed_artists(&self) -> Result<Option<Vec<ArtistId>>, ServerError> {
+    pub fn seed_artists(&self) -> Result<Option<Vec<ArtistId<'_>>>, ServerError> {
         if let Some(sa) = self.seed_artists.as_ref() {
             let ids = into_ids!(ArtistId, sa.split(','));
             Ok(Some(ids))
@@ -127,7 +192,7 @@ impl RecommendationsData {
         self.seed_genres.as_ref().map(|sa| sa.split(',').collect())
     }
 
-    pub fn seed_tracks(&self) -> Result<Option<Vec<TrackId>>, ServerError> {
+    pub fn seed_tracks(&self) -> Result<Option<Vec<TrackId<'_>>>, ServerError> {
         if let Some(st) = self.seed_tracks.as_ref() {
             let ids = into_ids!(TrackId, st.split(','));
             Ok(Some(ids))
@@ -291,12 +356,30 @@ pub struct PublicData {
     pub public: bool,
 }
 
+/// Market and Locale Query Data
+///
+/// `market` (or `country`) is an ISO 3166-1 alpha-2 country code or `from_token`.
 #[derive(Debug, serde::Deserialize)]
 pub struct CountryLocateData {
     pub locale: Option<String>,
+    pub market: Option<String>,
     pub country: Option<String>,
 }
 
+impl CountryLocateData {
+    /// The requested market, or the account's default market
+    pub async fn market(&self, account: &SpotifyAccount) -> Result<Market, ServerError> {
+        match self.market.as_deref().or(self.country.as_deref()) {
+            Some(market) => parse_market(market),
+            None => Ok(account.default_market().await),
+        }
+    }
+
+    pub fn locale(&self) -> Option<&str> {
+        self.locale.as_deref()
+    }
+}
+
 #[derive(Debug, serde::Deserialize)]
 pub struct TimestampData {
     pub timestamp: Option<String>,
@@ -309,7 +392,7 @@ pub struct PlaylistAddItemQueryData {
 }
 
 impl PlaylistAddItemQueryData {
-    pub fn items(&self) -> Vec<PlayableId> {
+    pub fn items(&self) -> Vec<PlayableId<'_>> {
         self.uris
             .split(',')
             .filter(|id_or_uri| {
@@ -317,13 +400,12 @@ impl PlaylistAddItemQueryData {
             })
             .map(|id_or_uri| {
                 if id_or_uri.starts_with("spotify:track:") {
-                    TrackId::from_id_or_uri(id_or_uri).map(|id| PlayableId::Track(id))
+                    TrackId::from_id_or_uri(id_or_uri).map(PlayableId::Track)
                 } else {
-                    EpisodeId::from_id_or_uri(id_or_uri).map(|id| PlayableId::Episode(id))
+                    EpisodeId::from_id_or_uri(id_or_uri).map(PlayableId::Episode)
                 }
             })
-            .filter(|id| id.is_ok())
-            .map(|id| id.unwrap())
+            .filter_map(Result::ok)
             .collect()
     }
 }
@@ -335,7 +417,7 @@ pub struct PlaylistAddItemJsonData {
 }
 
 impl PlaylistAddItemJsonData {
-    pub fn items(&self) -> Vec<PlayableId> {
+    pub fn items(&self) -> Vec<PlayableId<'_>> {
         self.uris
             .iter()
             .filter(|id_or_uri| {
@@ -343,13 +425,12 @@ impl PlaylistAddItemJsonData {
             })
             .map(|id_or_uri| {
                 if id_or_uri.starts_with("spotify:track:") {
-                    TrackId::from_id_or_uri(id_or_uri).map(|id| PlayableId::Track(id))
+                    TrackId::from_id_or_uri(id_or_uri).map(PlayableId::Track)
                 } else {
-                    EpisodeId::from_id_or_uri(id_or_uri).map(|id| PlayableId::Episode(id))
+                    EpisodeId::from_id_or_uri(id_or_uri).map(PlayableId::Episode)
                 }
             })
-            .filter(|id| id.is_ok())
-            .map(|id| id.unwrap())
+            .filter_map(Result::ok)
             .collect()
     }
 }
@@ -0,0 +1,352 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use rspotify::{
+    clients::{BaseClient, OAuthClient},
+    model::{
+        EpisodeId, Id, Market, PlayableId, PlayableItem, PlaylistId, PlaylistItem, SearchResult,
+        SearchType, TrackId,
+    },
+};
+
+use crate::{
+    account::{SpotifyAccount, UserName},
+    app_store::AppStore,
+    endpoints::{
+        audios::{signed_audio_id, signed_audio_uri, AudioSign},
+        params::{PlaylistDescData, SearchData},
+        playlists::{all_tracks, new_playlist},
+        search::find_items,
+        utils::{json_response, server_url},
+    },
+    errors::ServerError,
+    events::{EventKind, EVENTS},
+    playlist_files::{Entry, PlaylistFile, PlaylistFormat},
+    session::ServerSession,
+};
+
+/// The maximum number of items Spotify accepts in one playlist request
+const MAX_PLAYLIST_ITEMS_PER_REQUEST: usize = 100;
+
+/// The most rows an imported file may have, as each row may take a search
+const MAX_IMPORT_ROWS: usize = 10_000;
+
+/// What the locations of exported entries are
+#[derive(Debug, Default, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaylistLocations {
+    /// Spotify URIs
+    #[default]
+    Uri,
+    /// Signed audio stream urls
+    Stream,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PlaylistExportData {
+    pub format: PlaylistFormat,
+    #[serde(default)]
+    pub locations: PlaylistLocations,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PlaylistImportData {
+    pub format: PlaylistFormat,
+    /// The playlist name, or the title of the file
+    pub name: Option<String>,
+    pub public: Option<bool>,
+    pub description: Option<String>,
+}
+
+/// A row of the imported file which matches no track
+#[derive(Debug, serde::Serialize)]
+struct UnmatchedEntry {
+    /// 1-based
+    row: usize,
+    #[serde(flatten)]
+    entry: Entry,
+}
+
+/// A row of the imported file which failed to be matched or added
+#[derive(Debug, serde::Serialize)]
+struct FailedEntry {
+    /// 1-based
+    row: usize,
+    error: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct ImportResult<P: serde::Serialize> {
+    playlist: P,
+    added: usize,
+    unmatched: Vec<UnmatchedEntry>,
+    failed: Vec<FailedEntry>,
+}
+
+/// The playlist item as an entry, which has no location yet
+fn item_entry(item: PlaylistItem) -> Option<(String, Entry)> {
+    match item.track? {
+        PlayableItem::Track(track) => {
+            let uri = track.id.as_ref()?.uri();
+            let artist = track
+                .artists
+                .iter()
+                .map(|artist| artist.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let entry = Entry {
+                title: track.name,
+                artist,
+                album: track.album.name,
+                duration_ms: track.duration.num_milliseconds(),
+                isrc: track.external_ids.get("isrc").cloned(),
+                location: String::new(),
+            };
+            Some((uri, entry))
+        }
+        PlayableItem::Episode(episode) => {
+            let entry = Entry {
+                title: episode.name,
+                artist: episode.show.publisher,
+                album: episode.show.name,
+                duration_ms: episode.duration.num_milliseconds(),
+                isrc: None,
+                location: String::new(),
+            };
+            Some((episode.id.uri(), entry))
+        }
+    }
+}
+
+/// Path: GET `/playlists/{id}/export`
+/// Export the playlist items as M3U8, XSPF, JSPF or CSV
+///
+/// `locations` is `uri` for Spotify URIs, or `stream` for signed audio stream urls.
+#[tracing::instrument(skip(req, app_store, session))]
+pub async fn export_playlist(
+    id: web::Path<String>,
+    query: web::Query<PlaylistExportData>,
+    req: HttpRequest,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let account = app_store.authorize(username.clone()).await?;
+    let id_str = id.into_inner();
+
+    let playlist_id = PlaylistId::from_id(id_str.as_str())
+        .map_err(|_| ServerError::ParamsError(format!("Invalid playlist id: {}", id_str)))?;
+
+    let market = account.default_market().await;
+    let playlist = account
+        .client
+        .playlist(playlist_id.clone(), None, Some(market))
+        .await?;
+    let items = all_tracks(&account, playlist_id, None, Some(market)).await?;
+
+    let base_url = server_url(&req);
+    let entries = items
+        .into_iter()
+        .filter_map(item_entry)
+        .map(|(uri, entry)| {
+            let location = match query.locations {
+                PlaylistLocations::Uri => uri,
+                PlaylistLocations::Stream => {
+                    format!(
+                        "{}{}",
+                        base_url,
+                        signed_audio_uri(&account, &username, &uri)?
+                    )
+                }
+            };
+            Ok(Entry { location, ..entry })
+        })
+        .collect::<Result<Vec<_>, ServerError>>()?;
+    let file = PlaylistFile {
+        title: Some(playlist.name),
+        entries,
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(query.format.content_type())
+        .insert_header((
+            "Content-Disposition",
+            format!(
+                "attachment; filename=\"{}.{}\"",
+                id_str,
+                query.format.extension()
+            ),
+        ))
+        .body(file.export(query.format)?))
+}
+
+/// The item of a Spotify URI, an open.spotify.com url or a signed stream url
+fn location_item(account: &SpotifyAccount, location: &str) -> Option<PlayableId<'static>> {
+    let uri = if let Some((_, query)) = location.split_once("/audio-stream-with-sign/") {
+        let query = query.split_once('?')?.1;
+        let sign = web::Query::<AudioSign>::from_query(query).ok()?;
+        signed_audio_id(account.sign_key(), &sign.sign, &sign.iv).ok()?
+    } else if let Some((_, path)) = location.split_once("open.spotify.com/") {
+        let path = path.split(['?', '#']).next()?;
+        let (type_, id) = path.split_once('/')?;
+        format!("spotify:{}:{}", type_, id)
+    } else {
+        location.to_owned()
+    };
+
+    if let Ok(id) = TrackId::from_uri(&uri) {
+        Some(PlayableId::Track(id.into_static()))
+    } else if let Ok(id) = EpisodeId::from_uri(&uri) {
+        Some(PlayableId::Episode(id.into_static()))
+    } else {
+        None
+    }
+}
+
+/// The first track of the search
+async fn search_track(
+    account: &SpotifyAccount,
+    q: String,
+    market: Market,
+) -> Result<Option<PlayableId<'static>>, ServerError> {
+    let query = SearchData {
+        q,
+        type_: SearchType::Track,
+        include_external: None,
+    };
+    let result = find_items(account, &query, Some(market), Some(1), None).await?;
+    Ok(match result {
+        SearchResult::Tracks(page) => page
+            .items
+            .into_iter()
+            .find_map(|track| track.id)
+            .map(|id| PlayableId::Track(id.into_static())),
+        _ => None,
+    })
+}
+
+/// The item of the entry, by its location, ISRC, or title and artist
+async fn resolve_entry(
+    account: &SpotifyAccount,
+    entry: &Entry,
+    market: Market,
+) -> Result<Option<PlayableId<'static>>, ServerError> {
+    if let Some(item) = location_item(account, entry.location.trim()) {
+        return Ok(Some(item));
+    }
+    if let Some(isrc) = &entry.isrc {
+        if let Some(item) = search_track(account, format!("isrc:{}", isrc), market).await? {
+            return Ok(Some(item));
+        }
+    }
+    if entry.title.is_empty() {
+        return Ok(None);
+    }
+    // Quoted, so the filters take the whole title and artist
+    let quoted = |text: &str| format!("\"{}\"", text.replace('"', " "));
+    let q = if entry.artist.is_empty() {
+        format!("track:{}", quoted(&entry.title))
+    } else {
+        format!(
+            "track:{} artist:{}",
+            quoted(&entry.title),
+            quoted(&entry.artist)
+        )
+    };
+    search_track(account, q, market).await
+}
+
+/// Path: POST `/me/playlists/import`
+/// Create a playlist of an M3U8, XSPF, JSPF or CSV file in the body
+///
+/// Entries match by Spotify URI or signed stream url, then by ISRC, then by
+/// title and artist. The response lists the rows which match no track, and
+/// the rows which failed to be matched or added, as the others still are.
+#[tracing::instrument(skip(body, app_store, session))]
+pub async fn import_playlist(
+    query: web::Query<PlaylistImportData>,
+    body: web::Bytes,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username: UserName = session.get_username()?;
+    let account = app_store.authorize(username.clone()).await?;
+
+    let text = std::str::from_utf8(&body)
+        .map_err(|_| ServerError::ParamsError("The playlist file is not UTF-8".to_owned()))?;
+    let file = PlaylistFile::parse(query.format, text)?;
+    if file.entries.is_empty() {
+        return Err(ServerError::ParamsError(
+            "The playlist file has no entries".to_owned(),
+        ));
+    }
+    if file.entries.len() > MAX_IMPORT_ROWS {
+        return Err(ServerError::ParamsError(format!(
+            "The playlist file has more than {} entries",
+            MAX_IMPORT_ROWS
+        )));
+    }
+
+    let market = account.default_market().await;
+    let mut items = vec![];
+    let mut unmatched = vec![];
+    let mut failed = vec![];
+    for (index, entry) in file.entries.into_iter().enumerate() {
+        let row = index + 1;
+        match resolve_entry(&account, &entry, market).await {
+            Ok(Some(item)) => items.push((row, item)),
+            Ok(None) => unmatched.push(UnmatchedEntry { row, entry }),
+            Err(err) => failed.push(FailedEntry {
+                row,
+                error: err.to_string(),
+            }),
+        }
+    }
+
+    let desc = PlaylistDescData {
+        name: query
+            .name
+            .clone()
+            .or(file.title)
+            .or_else(|| Some("Imported playlist".to_owned())),
+        public: query.public,
+        collaborative: None,
+        description: query.description.clone(),
+    };
+    let user_id = account.client.me().await?.id;
+    let playlist = new_playlist(&account, user_id, &desc).await?;
+    let mut added = 0;
+    for chunk in items.chunks(MAX_PLAYLIST_ITEMS_PER_REQUEST) {
+        let result = account
+            .client
+            .playlist_add_items(
+                playlist.id.clone(),
+                chunk.iter().map(|(_, item)| item.as_ref()),
+                None,
+            )
+            .await;
+        match result {
+            Ok(_) => added += chunk.len(),
+            Err(err) => {
+                tracing::warn!("Failed to add imported items: {}", err);
+                let error = ServerError::from(err).to_string();
+                failed.extend(chunk.iter().map(|(row, _)| FailedEntry {
+                    row: *row,
+                    error: error.clone(),
+                }));
+            }
+        }
+    }
+    failed.sort_by_key(|entry| entry.row);
+
+    EVENTS.emit(
+        EventKind::PlaylistCreated,
+        username.as_ref(),
+        serde_json::json!({ "id": playlist.id.id(), "name": playlist.name }),
+    );
+    json_response(&ImportResult {
+        playlist,
+        added,
+        unmatched,
+        failed,
+    })
+}
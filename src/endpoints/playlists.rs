@@ -1,12 +1,19 @@
 use futures::StreamExt;
 
-use chrono::{TimeZone, Utc};
+use chrono::NaiveDateTime;
 
-use actix_web::{web, HttpResponse};
+use actix_multipart::Multipart;
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+
+use base64::Engine;
+
+use serde_json::json;
 
 use rspotify::{
     clients::{BaseClient, OAuthClient},
-    model::{Page, PlaylistId, PlaylistItem, SimplifiedPlaylist, UserId},
+    http::{HttpError, Query},
+    model::{FullPlaylist, Id, Market, Page, PlaylistId, PlaylistItem, SimplifiedPlaylist, UserId},
+    ClientError,
 };
 
 use crate::{
@@ -17,9 +24,10 @@ use crate::{
             CountryLocateData, FieldsData, LimitOffsetData, PlaylistAddItemJsonData,
             PlaylistAddItemQueryData, PlaylistDescData, PublicData, TimestampData,
         },
-        utils::{json_response, ok_response},
+        utils::{json_response, ok_response, ok_with_body_response},
     },
     errors::ServerError,
+    events::{EventKind, EVENTS},
     session::ServerSession,
 };
 
@@ -29,6 +37,7 @@ use crate::{
 pub async fn playlist(
     id: web::Path<String>,
     fields_query: web::Query<FieldsData>,
+    country_locate: web::Query<CountryLocateData>,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
@@ -40,7 +49,11 @@ pub async fn playlist(
         .map_err(|_| ServerError::ParamsError(format!("Invalid playlist id: {}", id_str)))?;
     let fields = fields_query.fields.as_deref();
 
-    let result = account.client.playlist(playlist_id, fields, None).await?;
+    let market = country_locate.market(&account).await?;
+    let result = account
+        .client
+        .playlist(playlist_id, fields, Some(market))
+        .await?;
     json_response(&result)
 }
 
@@ -55,7 +68,7 @@ pub async fn change_playlist_detail(
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
-    let account = app_store.authorize(username).await?;
+    let account = app_store.authorize(username.clone()).await?;
     let id_str = id.into_inner();
 
     let playlist_id = PlaylistId::from_id(id_str.as_str())
@@ -71,9 +84,180 @@ pub async fn change_playlist_detail(
             json.collaborative,
         )
         .await?;
+    EVENTS.emit(
+        EventKind::PlaylistModified,
+        username.as_ref(),
+        json!({ "id": id_str, "change": "details" }),
+    );
     json_response(&result)
 }
 
+/// The maximum size of the base64 encoded JPEG accepted by Spotify (256 KB)
+const MAX_COVER_IMAGE_SIZE: usize = 256 * 1024;
+
+/// Path: GET `/playlists/{id}/images`
+/// Get the current image associated with a specific playlist.
+#[tracing::instrument(skip(app_store, session))]
+pub async fn playlist_cover_image(
+    id: web::Path<String>,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let account = app_store.authorize(username).await?;
+    let id_str = id.into_inner();
+
+    let playlist_id = PlaylistId::from_id(id_str.as_str())
+        .map_err(|_| ServerError::ParamsError(format!("Invalid playlist id: {}", id_str)))?;
+
+    let url = format!("playlists/{}/images", playlist_id.id());
+    let result = account.client.api_get(&url, &Query::new()).await?;
+    ok_with_body_response(result)
+}
+
+/// Path: PUT `/playlists/{id}/images`
+/// Replace the image used to represent a specific playlist.
+///
+/// The body can be a raw JPEG (`Content-Type: image/jpeg`) or a multipart form
+/// whose first field is the JPEG file.
+#[tracing::instrument(skip(req, payload, app_store, session))]
+pub async fn upload_playlist_cover_image(
+    id: web::Path<String>,
+    req: HttpRequest,
+    payload: web::Payload,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let account = app_store.authorize(username.clone()).await?;
+    let id_str = id.into_inner();
+
+    let playlist_id = PlaylistId::from_id(id_str.as_str())
+        .map_err(|_| ServerError::ParamsError(format!("Invalid playlist id: {}", id_str)))?;
+
+    let is_multipart = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .map(|ct| ct.starts_with("multipart/form-data"))
+        .unwrap_or(false);
+    let image = if is_multipart {
+        read_multipart_image(Multipart::new(req.headers(), payload)).await?
+    } else {
+        read_image(payload).await?
+    };
+
+    if !image.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        return Err(ServerError::ParamsError(
+            "Cover image must be a JPEG".to_owned(),
+        ));
+    }
+
+    let encoded = base64::engine::general_purpose::STANDARD.encode(&image);
+    if encoded.len() > MAX_COVER_IMAGE_SIZE {
+        return Err(ServerError::ParamsError(format!(
+            "Cover image is too large: {} bytes encoded, the limit is {} bytes",
+            encoded.len(),
+            MAX_COVER_IMAGE_SIZE
+        )));
+    }
+
+    upload_cover_image(&app_store, &account, playlist_id, encoded).await?;
+    EVENTS.emit(
+        EventKind::PlaylistModified,
+        username.as_ref(),
+        json!({ "id": id_str, "change": "cover_image" }),
+    );
+    ok_response()
+}
+
+/// The largest raw JPEG whose base64 encoding still fits `MAX_COVER_IMAGE_SIZE`
+const fn max_raw_image_size() -> usize {
+    MAX_COVER_IMAGE_SIZE / 4 * 3
+}
+
+/// Read a JPEG from a body stream, rejecting it once it exceeds `max_raw_image_size`
+async fn read_image<S, E>(mut stream: S) -> Result<Vec<u8>, ServerError>
+where
+    S: futures::Stream<Item = Result<web::Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    let mut image = vec![];
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| ServerError::ParamsError(format!("{}", e)))?;
+        if image.len() + chunk.len() > max_raw_image_size() {
+            return Err(ServerError::ParamsError(
+                "Cover image is too large".to_owned(),
+            ));
+        }
+        image.extend_from_slice(&chunk);
+    }
+    Ok(image)
+}
+
+/// Read the JPEG from the first field of a multipart form, which must be an
+/// `image/jpeg` file
+async fn read_multipart_image(mut multipart: Multipart) -> Result<Vec<u8>, ServerError> {
+    match multipart.next().await {
+        Some(field) => {
+            let field = field.map_err(|e| ServerError::ParamsError(format!("{}", e)))?;
+            if field.content_type().map(|mime| mime.essence_str()) != Some("image/jpeg") {
+                return Err(ServerError::ParamsError(
+                    "Cover image must be an image/jpeg field".to_owned(),
+                ));
+            }
+            read_image(field).await
+        }
+        None => Err(ServerError::ParamsError("No cover image".to_owned())),
+    }
+}
+
+/// Upload a base64 encoded JPEG as the playlist cover image
+///
+/// rspotify always sends JSON bodies, but Spotify expects the base64 data
+/// as an `image/jpeg` body, so the request is sent by hand.
+async fn upload_cover_image(
+    app_store: &AppStore,
+    account: &SpotifyAccount,
+    playlist_id: PlaylistId<'_>,
+    encoded: String,
+) -> Result<(), ServerError> {
+    let url = account
+        .client
+        .api_url(&format!("playlists/{}/images", playlist_id.id()));
+    let access_token = account
+        .client
+        .get_token()
+        .lock()
+        .await
+        .map_err(|e| ServerError::InnerError(format!("can't read token: {:?}", e)))?
+        .as_ref()
+        .map(|token| token.access_token.clone())
+        .ok_or(ServerError::AuthenticationError)?;
+
+    let request = app_store
+        .http_client
+        .put(url)
+        .bearer_auth(access_token)
+        .header(reqwest::header::CONTENT_TYPE, "image/jpeg")
+        .body(encoded);
+
+    account
+        .client
+        .limited(async move {
+            let response = request
+                .send()
+                .await
+                .map_err(|e| ClientError::from(HttpError::Client(e)))?;
+            if !response.status().is_success() {
+                return Err(ClientError::from(HttpError::StatusCode(response)));
+            }
+            Ok(())
+        })
+        .await?;
+    Ok(())
+}
+
 /// Path: GET `/playlists/{id}/tracks`
 /// Get full details of the items of a playlist owned by a Spotify user.
 #[tracing::instrument(skip(app_store, session))]
@@ -81,6 +265,7 @@ pub async fn playlist_tracks(
     id: web::Path<String>,
     query: web::Query<LimitOffsetData>,
     fields_query: web::Query<FieldsData>,
+    country_locate: web::Query<CountryLocateData>,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
@@ -89,32 +274,38 @@ pub async fn playlist_tracks(
     let id_str = id.into_inner();
 
     let playlist_id = PlaylistId::from_id(id_str.as_str())
-        .map_err(|_| ServerError::ParamsError(format!("{}", id_str)))?;
+        .map_err(|_| ServerError::ParamsError(format!("Invalid playlist id: {}", id_str)))?;
     let fields = fields_query.fields.as_deref();
 
+    let market = country_locate.market(&account).await?;
     if query.limit.is_some() {
-        let page = page_tracks(&account, playlist_id, fields, query.limit, query.offset).await?;
+        let page = page_tracks(
+            &account,
+            playlist_id,
+            fields,
+            Some(market),
+            query.limit,
+            query.offset,
+        )
+        .await?;
         json_response(&page)
     } else {
-        let tracks = all_tracks(&account, playlist_id, fields).await?;
+        let tracks = all_tracks(&account, playlist_id, fields, Some(market)).await?;
         json_response(&tracks)
     }
 }
 
 /// Playlist all tracks
-async fn all_tracks(
+pub async fn all_tracks(
     account: &SpotifyAccount,
     playlist_id: PlaylistId<'_>,
     fields: Option<&str>,
+    market: Option<Market>,
 ) -> Result<Vec<PlaylistItem>, ServerError> {
-    let mut track_stream = account.client.playlist_items(playlist_id, fields, None);
+    let mut track_stream = account.client.playlist_items(playlist_id, fields, market);
     let mut tracks = vec![];
     while let Some(item) = track_stream.next().await {
-        if item.is_err() {
-            return Err(ServerError::RequestError(format!("{:?}", item)));
-        } else {
-            tracks.push(item.unwrap());
-        }
+        tracks.push(item?);
     }
     Ok(tracks)
 }
@@ -124,12 +315,13 @@ async fn page_tracks(
     account: &SpotifyAccount,
     playlist_id: PlaylistId<'_>,
     fields: Option<&str>,
+    market: Option<Market>,
     limit: Option<u32>,
     offset: Option<u32>,
 ) -> Result<Page<PlaylistItem>, ServerError> {
     let page = account
         .client
-        .playlist_items_manual(playlist_id, fields, None, limit, offset)
+        .playlist_items_manual(playlist_id, fields, market, limit, offset)
         .await?;
     Ok(page)
 }
@@ -145,11 +337,11 @@ pub async fn playlist_add_items(
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
-    let account = app_store.authorize(username).await?;
+    let account = app_store.authorize(username.clone()).await?;
     let id_str = id.into_inner();
 
     let playlist_id = PlaylistId::from_id(id_str.as_str())
-        .map_err(|_| ServerError::ParamsError(format!("{}", id_str)))?;
+        .map_err(|_| ServerError::ParamsError(format!("Invalid playlist id: {}", id_str)))?;
 
     if !query.uris.is_empty() {
         let items = query.items();
@@ -161,6 +353,11 @@ pub async fn playlist_add_items(
                 query.position,
             )
             .await?;
+        EVENTS.emit(
+            EventKind::PlaylistModified,
+            username.as_ref(),
+            json!({ "id": id_str, "change": "items_added" }),
+        );
         return json_response(&result);
     }
     if let Some(json) = json {
@@ -173,9 +370,14 @@ pub async fn playlist_add_items(
                 json.position,
             )
             .await?;
+        EVENTS.emit(
+            EventKind::PlaylistModified,
+            username.as_ref(),
+            json!({ "id": id_str, "change": "items_added" }),
+        );
         return json_response(&result);
     }
-    Err(ServerError::ParamsError(format!("No uris")))
+    Err(ServerError::ParamsError("No uris".to_owned()))
 }
 
 /// Path: GET `/me/playlists`
@@ -199,17 +401,13 @@ pub async fn current_user_playlists(
 }
 
 /// Current user all saved playlists
-async fn all_current_user_playlists(
+pub async fn all_current_user_playlists(
     account: &SpotifyAccount,
 ) -> Result<Vec<SimplifiedPlaylist>, ServerError> {
     let mut playlist_stream = account.client.current_user_playlists();
     let mut playlists = vec![];
     while let Some(item) = playlist_stream.next().await {
-        if item.is_err() {
-            return Err(ServerError::RequestError(format!("{:?}", item)));
-        } else {
-            playlists.push(item.unwrap());
-        }
+        playlists.push(item?);
     }
     Ok(playlists)
 }
@@ -259,11 +457,7 @@ async fn all_user_playlists(
     let mut playlist_stream = account.client.user_playlists(user_id);
     let mut playlists = vec![];
     while let Some(item) = playlist_stream.next().await {
-        if item.is_err() {
-            return Err(ServerError::RequestError(format!("{:?}", item)));
-        } else {
-            playlists.push(item.unwrap());
-        }
+        playlists.push(item?);
     }
     Ok(playlists)
 }
@@ -292,7 +486,7 @@ pub async fn follow_playlist(
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
-    let account = app_store.authorize(username).await?;
+    let account = app_store.authorize(username.clone()).await?;
     let id_str = id.into_inner();
 
     let playlist_id = PlaylistId::from_id(id_str.as_str())
@@ -304,6 +498,7 @@ pub async fn follow_playlist(
     };
 
     account.client.playlist_follow(playlist_id, public).await?;
+    EVENTS.library_changed(true, username.as_ref(), "playlist", &[id_str.as_str()]);
     ok_response()
 }
 
@@ -316,13 +511,14 @@ pub async fn unfollow_playlist(
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
-    let account = app_store.authorize(username).await?;
+    let account = app_store.authorize(username.clone()).await?;
     let id_str = id.into_inner();
 
     let playlist_id = PlaylistId::from_id(id_str.as_str())
         .map_err(|_| ServerError::ParamsError(format!("Invalid playlist id: {}", id_str)))?;
 
     account.client.playlist_unfollow(playlist_id).await?;
+    EVENTS.library_changed(false, username.as_ref(), "playlist", &[id_str.as_str()]);
     ok_response()
 }
 
@@ -337,13 +533,28 @@ pub async fn create_playlist(
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
-    let account = app_store.authorize(username).await?;
+    let account = app_store.authorize(username.clone()).await?;
     let id_str = id.into_inner();
 
     let user_id = UserId::from_id(id_str.as_str())
         .map_err(|_| ServerError::ParamsError(format!("Invalid user id: {}", id_str)))?;
 
-    let name = if let Some(name) = &json.name {
+    let result = new_playlist(&account, user_id, &json).await?;
+    EVENTS.emit(
+        EventKind::PlaylistCreated,
+        username.as_ref(),
+        json!({ "id": result.id.id(), "name": result.name }),
+    );
+    json_response(&result)
+}
+
+/// Create an empty playlist of the details
+pub async fn new_playlist(
+    account: &SpotifyAccount,
+    user_id: UserId<'_>,
+    desc: &PlaylistDescData,
+) -> Result<FullPlaylist, ServerError> {
+    let name = if let Some(name) = &desc.name {
         if name.is_empty() {
             return Err(ServerError::ParamsError("Missing playlist name".to_owned()));
         }
@@ -352,17 +563,17 @@ pub async fn create_playlist(
         return Err(ServerError::ParamsError("Missing playlist name".to_owned()));
     };
 
-    let result = account
+    let playlist = account
         .client
         .user_playlist_create(
             user_id,
             name,
-            json.public,
-            json.collaborative,
-            json.description.as_deref(),
+            desc.public,
+            desc.collaborative,
+            desc.description.as_deref(),
         )
         .await?;
-    json_response(&result)
+    Ok(playlist)
 }
 
 /// Path: GET `/browse/featured-playlists`
@@ -379,7 +590,9 @@ pub async fn featured_playlists(
     let account = app_store.authorize(username).await?;
 
     let timestamp = if let Some(ts) = &timestamp.timestamp {
-        if let Ok(ts) = Utc.datetime_from_str(ts, "%Y-%m-%dT%H:%M:%S") {
+        if let Ok(ts) =
+            NaiveDateTime::parse_from_str(ts, "%Y-%m-%dT%H:%M:%S").map(|ts| ts.and_utc())
+        {
             Some(ts)
         } else {
             return Err(ServerError::ParamsError("Invalid timestamp".to_owned()));
@@ -388,11 +601,12 @@ pub async fn featured_playlists(
         None
     };
 
+    let market = country_locate.market(&account).await?;
     let result = account
         .client
         .featured_playlists(
-            country_locate.locale.as_deref(),
-            None,
+            country_locate.locale(),
+            Some(market),
             timestamp,
             limit_offset.limit,
             limit_offset.offset,
@@ -407,34 +621,34 @@ pub async fn featured_playlists(
 pub async fn category_playlists(
     id: web::Path<String>,
     query: web::Query<LimitOffsetData>,
+    country_locate: web::Query<CountryLocateData>,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
     let account = app_store.authorize(username).await?;
 
+    let market = country_locate.market(&account).await?;
     if query.limit.is_some() {
-        let page = page_category_playlists(&account, &id, query.limit, query.offset).await?;
+        let page =
+            page_category_playlists(&account, &id, Some(market), query.limit, query.offset).await?;
         json_response(&page)
     } else {
-        let tracks = all_category_playlists(&account, &id).await?;
+        let tracks = all_category_playlists(&account, &id, Some(market)).await?;
         json_response(&tracks)
     }
 }
 
 /// Category all playlists
-async fn all_category_playlists(
+pub async fn all_category_playlists(
     account: &SpotifyAccount,
     category_id: &str,
+    market: Option<Market>,
 ) -> Result<Vec<SimplifiedPlaylist>, ServerError> {
-    let mut track_stream = account.client.category_playlists(category_id, None);
+    let mut track_stream = account.client.category_playlists(category_id, market);
     let mut tracks = vec![];
     while let Some(item) = track_stream.next().await {
-        if item.is_err() {
-            return Err(ServerError::RequestError(format!("{:?}", item)));
-        } else {
-            tracks.push(item.unwrap());
-        }
+        tracks.push(item?);
     }
     Ok(tracks)
 }
@@ -443,12 +657,13 @@ async fn all_category_playlists(
 async fn page_category_playlists(
     account: &SpotifyAccount,
     category_id: &str,
+    market: Option<Market>,
     limit: Option<u32>,
     offset: Option<u32>,
 ) -> Result<Page<SimplifiedPlaylist>, ServerError> {
     let page = account
         .client
-        .category_playlists_manual(category_id, None, limit, offset)
+        .category_playlists_manual(category_id, market, limit, offset)
         .await?;
     Ok(page)
 }
@@ -0,0 +1,103 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use rspotify::{clients::BaseClient, model::PlaylistId};
+
+use crate::{
+    account::UserName,
+    app_store::AppStore,
+    endpoints::params::RecommendationsData,
+    errors::ServerError,
+    quota::StreamPermit,
+    radio::{self, Station},
+    session::ServerSession,
+};
+
+/// Does the client want ICY metadata
+fn wants_icy(req: &HttpRequest) -> bool {
+    req.headers()
+        .get("Icy-MetaData")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim() == "1")
+        .unwrap_or(false)
+}
+
+fn radio_response(
+    req: &HttpRequest,
+    name: &str,
+    station: Station,
+    username: UserName,
+    app_store: web::Data<AppStore>,
+    permit: StreamPermit,
+) -> HttpResponse {
+    let icy = wants_icy(req);
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type("audio/ogg")
+        .insert_header(("icy-name", name))
+        .insert_header(("Cache-Control", "no-cache, no-store"));
+    if icy {
+        response.insert_header(("icy-metaint", radio::METAINT.to_string()));
+    }
+    response.streaming(radio::stream(app_store, username, station, icy, permit))
+}
+
+/// Path: GET `/radio/{playlist_id}.ogg`
+/// An endless Ogg stream of the playlist tracks in a loop, like an internet radio
+///
+/// ICY title updates are sent with the `Icy-MetaData: 1` request header.
+#[tracing::instrument(skip(req, app_store, session))]
+pub async fn playlist_radio(
+    playlist_id: web::Path<String>,
+    req: HttpRequest,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let permit = app_store.quotas.acquire_stream(username.as_ref())?;
+    let playlist_id = PlaylistId::from_id(playlist_id.into_inner())
+        .map_err(|_| ServerError::ParamsError("Invalid playlist id".to_owned()))?;
+    let name = {
+        let account = app_store.authorize(username.clone()).await?;
+        account
+            .client
+            .playlist(playlist_id.clone(), None, None)
+            .await?
+            .name
+    };
+
+    Ok(radio_response(
+        &req,
+        &name,
+        Station::Playlist(playlist_id),
+        username,
+        app_store,
+        permit,
+    ))
+}
+
+/// Path: GET `/radio/recommendations.ogg`
+/// An endless Ogg stream of recommendations of the seeds, like an internet radio
+///
+/// It takes the parameters of `/recommendations`, e.g. `?seed_genres=jazz,soul`.
+#[tracing::instrument(skip(req, app_store, session))]
+pub async fn recommendations_radio(
+    query: web::Query<RecommendationsData>,
+    req: HttpRequest,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    // Reject invalid seeds before the stream starts
+    query.seed_artists()?;
+    query.seed_tracks()?;
+    let permit = app_store.quotas.acquire_stream(username.as_ref())?;
+
+    Ok(radio_response(
+        &req,
+        "Recommendations",
+        Station::Recommendations(Box::new(query.into_inner())),
+        username,
+        app_store,
+        permit,
+    ))
+}
@@ -1,10 +1,17 @@
 use actix_web::{web, HttpResponse};
 
-use rspotify::clients::BaseClient;
+use rspotify::{
+    clients::BaseClient,
+    model::{Market, Recommendations},
+};
 
 use crate::{
+    account::SpotifyAccount,
     app_store::AppStore,
-    endpoints::{params::RecommendationsData, utils::json_response},
+    endpoints::{
+        params::{CountryLocateData, RecommendationsData},
+        utils::json_response,
+    },
     errors::ServerError,
     session::ServerSession,
 };
@@ -13,12 +20,24 @@ use crate::{
 #[tracing::instrument(skip(app_store, session))]
 pub async fn recommendations(
     query: web::Query<RecommendationsData>,
+    country_locate: web::Query<CountryLocateData>,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
     let account = app_store.authorize(username).await?;
 
+    let market = country_locate.market(&account).await?;
+    let result = find_recommendations(&account, &query, market).await?;
+    json_response(&result)
+}
+
+/// Recommendations of the seeds and attributes of the query
+pub async fn find_recommendations(
+    account: &SpotifyAccount,
+    query: &RecommendationsData,
+    market: Market,
+) -> Result<Recommendations, ServerError> {
     let result = account
         .client
         .recommendations(
@@ -26,9 +45,9 @@ pub async fn recommendations(
             query.seed_artists()?,
             query.seed_genres(),
             query.seed_tracks()?,
-            None,
+            Some(market),
             query.limit(),
         )
         .await?;
-    json_response(&result)
+    Ok(result)
 }
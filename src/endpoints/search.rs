@@ -1,11 +1,15 @@
 use actix_web::{web, HttpResponse};
 
-use rspotify::clients::BaseClient;
+use rspotify::{
+    clients::BaseClient,
+    model::{Market, SearchResult},
+};
 
 use crate::{
+    account::SpotifyAccount,
     app_store::AppStore,
     endpoints::{
-        params::{LimitOffsetData, SearchData},
+        params::{CountryLocateData, LimitOffsetData, SearchData},
         utils::json_response,
     },
     errors::ServerError,
@@ -19,23 +23,44 @@ use crate::{
 pub async fn search(
     query: web::Query<SearchData>,
     limit_offset: web::Query<LimitOffsetData>,
+    country_locate: web::Query<CountryLocateData>,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
     let account = app_store.authorize(username).await?;
 
+    let market = country_locate.market(&account).await?;
+    let result = find_items(
+        &account,
+        &query,
+        Some(market),
+        limit_offset.limit,
+        limit_offset.offset,
+    )
+    .await?;
+
+    json_response(result)
+}
+
+/// Search items of the catalog
+pub async fn find_items(
+    account: &SpotifyAccount,
+    query: &SearchData,
+    market: Option<Market>,
+    limit: Option<u32>,
+    offset: Option<u32>,
+) -> Result<SearchResult, ServerError> {
     let result = account
         .client
         .search(
             &query.q,
             query.type_,
-            query.market,
-            query.include_external.clone(),
-            limit_offset.limit,
-            limit_offset.offset,
+            market,
+            query.include_external,
+            limit,
+            offset,
         )
         .await?;
-
-    json_response(result)
+    Ok(result)
 }
@@ -0,0 +1,54 @@
+use actix_web::{web, HttpResponse};
+
+use crate::{
+    app_store::AppStore,
+    endpoints::utils::{json_response, ok_response},
+    errors::ServerError,
+    events::{EventKind, EVENTS},
+    session::ServerSession,
+};
+
+/// Path: GET `/sessions`
+/// The sessions of the current user, with `--session-store file`
+#[tracing::instrument(skip(app_store, session))]
+pub async fn sessions(
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    json_response(app_store.sessions()?.list(username.as_ref()))
+}
+
+/// Path: DELETE `/sessions/{id}`
+/// Revoke a session of the current user
+#[tracing::instrument(skip(app_store, session))]
+pub async fn revoke_session(
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    if app_store.sessions()?.revoke(username.as_ref(), &id) {
+        ok_response()
+    } else {
+        Err(ServerError::ParamsError(format!("No session {}", id)))
+    }
+}
+
+/// Path: DELETE `/sessions`
+/// Revoke all sessions of the current user, i.e. log out everywhere
+#[tracing::instrument(skip(app_store, session))]
+pub async fn revoke_sessions(
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let revoked = app_store.sessions()?.revoke_all(username.as_ref());
+    session.log_out();
+    EVENTS.emit(
+        EventKind::Logout,
+        username.as_ref(),
+        serde_json::json!({ "sessions": revoked }),
+    );
+    ok_response()
+}
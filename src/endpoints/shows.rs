@@ -1,20 +1,22 @@
 use futures::StreamExt;
 
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 
 use rspotify::{
     clients::{BaseClient, OAuthClient},
-    model::{Page, Show, ShowId, SimplifiedEpisode},
+    model::{Id, Market, Page, Show, ShowId, SimplifiedEpisode},
 };
 
 use crate::{
     account::SpotifyAccount,
     app_store::AppStore,
+    cache::{cache_key, market_part},
     endpoints::{
-        params::{IdsData, LimitOffsetData},
+        params::{CountryLocateData, IdsData, LimitOffsetData},
         utils::{json_response, ok_response},
     },
     errors::ServerError,
+    events::EVENTS,
     session::ServerSession,
 };
 
@@ -22,6 +24,8 @@ use crate::{
 #[tracing::instrument(skip(app_store, session))]
 pub async fn show(
     id: web::Path<String>,
+    country_locate: web::Query<CountryLocateData>,
+    req: HttpRequest,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
@@ -31,14 +35,29 @@ pub async fn show(
     let show_id = ShowId::from_id(id.as_str())
         .map_err(|_| ServerError::ParamsError(format!("Invalid show id: {}", id.as_str())))?;
 
-    let result = account.client.get_a_show(show_id, None).await?;
-    json_response(&result)
+    let market = country_locate.market(&account).await?;
+    let key = cache_key(
+        "show",
+        &[
+            show_id.id(),
+            &market_part(market, &account.credentials.username),
+        ],
+    );
+    app_store
+        .response_cache
+        .respond(&req, key, || async {
+            let result = account.client.get_a_show(show_id, Some(market)).await?;
+            Ok(serde_json::to_string(&result)?)
+        })
+        .await
 }
 
 /// Path: GET `/shows`
 #[tracing::instrument(skip(app_store, session))]
 pub async fn shows(
     query: web::Query<IdsData>,
+    country_locate: web::Query<CountryLocateData>,
+    req: HttpRequest,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
@@ -46,8 +65,24 @@ pub async fn shows(
     let account = app_store.authorize(username).await?;
 
     let show_ids = crate::into_ids!(ShowId, query.ids());
-    let result = account.client.get_several_shows(show_ids, None).await?;
-    json_response(&result)
+    let market = country_locate.market(&account).await?;
+    let key = cache_key(
+        "shows",
+        &[
+            &query.ids,
+            &market_part(market, &account.credentials.username),
+        ],
+    );
+    app_store
+        .response_cache
+        .respond(&req, key, || async {
+            let result = account
+                .client
+                .get_several_shows(show_ids, Some(market))
+                .await?;
+            Ok(serde_json::to_string(&result)?)
+        })
+        .await
 }
 
 /// Path: GET `/shows/{id}/episodes`
@@ -55,6 +90,7 @@ pub async fn shows(
 pub async fn show_episodes(
     id: web::Path<String>,
     query: web::Query<LimitOffsetData>,
+    country_locate: web::Query<CountryLocateData>,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
@@ -64,28 +100,27 @@ pub async fn show_episodes(
     let show_id =
         ShowId::from_id(id.as_str()).map_err(|_| ServerError::ParamsError(format!("{}", id)))?;
 
+    let market = country_locate.market(&account).await?;
     if query.limit.is_some() {
-        let page = page_episodes(&account, show_id, query.limit, query.offset).await?;
+        let page =
+            page_episodes(&account, show_id, Some(market), query.limit, query.offset).await?;
         json_response(&page)
     } else {
-        let episodes = all_episodes(&account, show_id).await?;
+        let episodes = all_episodes(&account, show_id, Some(market)).await?;
         json_response(&episodes)
     }
 }
 
 /// Show all episodes
-async fn all_episodes(
+pub async fn all_episodes(
     account: &SpotifyAccount,
     show_id: ShowId<'_>,
+    market: Option<Market>,
 ) -> Result<Vec<SimplifiedEpisode>, ServerError> {
-    let mut episode_stream = account.client.get_shows_episodes(show_id, None);
+    let mut episode_stream = account.client.get_shows_episodes(show_id, market);
     let mut episodes = vec![];
     while let Some(item) = episode_stream.next().await {
-        if item.is_err() {
-            return Err(ServerError::RequestError(format!("{:?}", item)));
-        } else {
-            episodes.push(item.unwrap());
-        }
+        episodes.push(item?);
     }
     Ok(episodes)
 }
@@ -94,12 +129,13 @@ async fn all_episodes(
 async fn page_episodes(
     account: &SpotifyAccount,
     show_id: ShowId<'_>,
+    market: Option<Market>,
     limit: Option<u32>,
     offset: Option<u32>,
 ) -> Result<Page<SimplifiedEpisode>, ServerError> {
     let page = account
         .client
-        .get_shows_episodes_manual(show_id, None, limit, offset)
+        .get_shows_episodes_manual(show_id, market, limit, offset)
         .await?;
     Ok(page)
 }
@@ -124,15 +160,11 @@ pub async fn saved_shows(
 }
 
 /// Current user all saved shows
-async fn all_saved_shows(account: &SpotifyAccount) -> Result<Vec<Show>, ServerError> {
+pub async fn all_saved_shows(account: &SpotifyAccount) -> Result<Vec<Show>, ServerError> {
     let mut show_stream = account.client.get_saved_show();
     let mut shows = vec![];
     while let Some(item) = show_stream.next().await {
-        if item.is_err() {
-            return Err(ServerError::RequestError(format!("{:?}", item)));
-        } else {
-            shows.push(item.unwrap());
-        }
+        shows.push(item?);
     }
     Ok(shows)
 }
@@ -155,10 +187,11 @@ pub async fn save_shows(
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
-    let account = app_store.authorize(username).await?;
+    let account = app_store.authorize(username.clone()).await?;
 
     let show_ids = crate::into_ids!(ShowId, query.ids());
     account.client.save_shows(show_ids).await?;
+    EVENTS.library_changed(true, username.as_ref(), "show", &query.ids());
     ok_response()
 }
 
@@ -170,12 +203,33 @@ pub async fn delete_shows(
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
-    let account = app_store.authorize(username).await?;
+    let account = app_store.authorize(username.clone()).await?;
 
     let show_ids = crate::into_ids!(ShowId, query.ids());
     account
         .client
         .remove_users_saved_shows(show_ids, None)
         .await?;
+    EVENTS.library_changed(false, username.as_ref(), "show", &query.ids());
     ok_response()
 }
+
+/// Path: GET `/me/shows/contains`
+/// Check if one or more shows is already saved in the current Spotify user's library.
+#[tracing::instrument(skip(app_store, session))]
+pub async fn contains_shows(
+    query: web::Query<IdsData>,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let account = app_store.authorize(username).await?;
+
+    let mut result = vec![];
+    for ids in query.batches() {
+        let show_ids = crate::into_ids!(ShowId, ids);
+        let contains = account.client.check_users_saved_shows(show_ids).await?;
+        result.extend(contains);
+    }
+    json_response(&result)
+}
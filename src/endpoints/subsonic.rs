@@ -0,0 +1,558 @@
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+
+use rspotify::{
+    clients::{BaseClient, OAuthClient},
+    http::Query,
+    model::{
+        AlbumId, ArtistId, FullTrack, Id, ItemPositions, Market, PlayableId, PlaylistId,
+        SearchResult, SearchType, SimplifiedAlbum, TrackId,
+    },
+};
+use serde_json::{json, Value};
+
+use crate::{
+    account::SpotifyAccount,
+    app_store::AppStore,
+    endpoints::{
+        artists::all_followed_artists,
+        audios::audio_cn_stream,
+        params::MAX_IDS_PER_REQUEST,
+        playlists::{all_current_user_playlists, all_tracks},
+        utils::all_raw_items,
+    },
+    errors::ServerError,
+    events::{EventKind, EVENTS},
+    quota::RouteGroup,
+    subsonic::{self, models, Params, SubsonicError},
+};
+
+/// Spotify accepts at most 100 items per playlist request
+const MAX_PLAYLIST_ITEMS_PER_REQUEST: usize = 100;
+
+/// The hosts of Spotify images, which `getCoverArt` proxies
+const COVER_ART_HOSTS: [&str; 2] = [".scdn.co", ".spotifycdn.com"];
+
+enum Reply {
+    Payload(Value),
+    Raw(HttpResponse),
+}
+
+/// Path: GET/POST `/rest/{method}`
+/// The Subsonic API, e.g. `/rest/getAlbum.view?id=..&u=..&t=..&s=..`
+///
+/// Errors are Subsonic responses with HTTP 200, as Subsonic clients expect.
+#[tracing::instrument(skip(req, body, app_store))]
+pub async fn subsonic(
+    method: web::Path<String>,
+    req: HttpRequest,
+    body: web::Bytes,
+    app_store: web::Data<AppStore>,
+) -> HttpResponse {
+    let params = Params::parse(&req, &body);
+    let method = method.trim_end_matches(".view");
+    match dispatch(method, &params, &app_store).await {
+        Ok(Reply::Payload(payload)) => subsonic::ok(&params, payload),
+        Ok(Reply::Raw(response)) => response,
+        Err(err) => {
+            tracing::warn!("Subsonic {} failed: {:?}", method, err);
+            subsonic::failed(&params, err)
+        }
+    }
+}
+
+async fn dispatch(
+    method: &str,
+    params: &Params,
+    app_store: &AppStore,
+) -> Result<Reply, SubsonicError> {
+    let username = subsonic::authenticate(params, app_store)?;
+    app_store
+        .quotas
+        .check(RouteGroup::subsonic(method), &username, Some(&username))?;
+    let payload = match method {
+        "ping" => json!({}),
+        "getLicense" => json!({ "license": { "valid": true } }),
+        "getMusicFolders" => json!({
+            "musicFolders": { "musicFolder": [{ "id": 0, "name": "Spotify" }] }
+        }),
+        "getOpenSubsonicExtensions" => json!({
+            "openSubsonicExtensions": [{ "name": "formPost", "versions": [1] }]
+        }),
+        "getCoverArt" => return cover_art(params, app_store).await.map(Reply::Raw),
+        method => {
+            let account = app_store.authorize(username.as_str()).await?;
+            match method {
+                "getArtists" => artists(&account).await?,
+                "getArtist" => artist(params, &account).await?,
+                "getAlbum" => album(params, &account).await?,
+                "search3" => search(params, &account).await?,
+                "getPlaylists" => playlists(&account).await?,
+                "getPlaylist" => playlist(params.required("id")?, &account).await?,
+                "createPlaylist" => create_playlist(params, &username, &account).await?,
+                "updatePlaylist" => update_playlist(params, &username, &account).await?,
+                "deletePlaylist" => {
+                    let playlist_id = playlist_id(params.required("id")?)?;
+                    account
+                        .client
+                        .playlist_unfollow(playlist_id.clone())
+                        .await
+                        .map_err(ServerError::from)?;
+                    EVENTS.library_changed(false, &username, "playlist", &[playlist_id.id()]);
+                    json!({})
+                }
+                "star" => star(params, &username, &account, true).await?,
+                "unstar" => star(params, &username, &account, false).await?,
+                "stream" | "download" => {
+                    let id = params.required("id")?;
+                    let permit = app_store.quotas.acquire_stream(&username)?;
+                    let uri = format!("spotify:track:{}", id);
+                    let playback = app_store.now_playing(&username, &account, &uri).await;
+                    let stream = audio_cn_stream(&uri, &account, permit, playback).await?;
+                    return Ok(Reply::Raw(stream));
+                }
+                method => {
+                    return Err(SubsonicError::new(
+                        0,
+                        format!("Unsupported method {}", method),
+                    ))
+                }
+            }
+        }
+    };
+    Ok(Reply::Payload(payload))
+}
+
+fn album_id(id: &str) -> Result<AlbumId<'_>, SubsonicError> {
+    AlbumId::from_id(id).map_err(|_| SubsonicError::not_found("Album"))
+}
+
+fn artist_id(id: &str) -> Result<ArtistId<'_>, SubsonicError> {
+    ArtistId::from_id(id).map_err(|_| SubsonicError::not_found("Artist"))
+}
+
+fn playlist_id(id: &str) -> Result<PlaylistId<'_>, SubsonicError> {
+    PlaylistId::from_id(id).map_err(|_| SubsonicError::not_found("Playlist"))
+}
+
+fn track_ids<'a>(ids: &[&'a str]) -> Result<Vec<TrackId<'a>>, SubsonicError> {
+    ids.iter()
+        .map(|id| TrackId::from_id(*id).map_err(|_| SubsonicError::not_found("Song")))
+        .collect()
+}
+
+/// `getArtists`, the followed artists
+async fn artists(account: &SpotifyAccount) -> Result<Value, ServerError> {
+    let artists = all_followed_artists(account).await?;
+    Ok(json!({ "artists": models::artist_index(&artists) }))
+}
+
+/// `getArtist`, an artist with its albums and singles
+async fn artist(params: &Params, account: &SpotifyAccount) -> Result<Value, SubsonicError> {
+    let artist_id = artist_id(params.required("id")?)?;
+    let artist = account
+        .client
+        .artist(artist_id.clone())
+        .await
+        .map_err(ServerError::from)?;
+
+    let market: &str = account.default_market().await.into();
+    let mut query = Query::new();
+    query.insert("include_groups", "album,single");
+    query.insert("market", market);
+    let url = format!("artists/{}/albums", artist_id.id());
+    let albums = all_raw_items(account, &url, &query)
+        .await?
+        .into_iter()
+        .filter_map(|album| serde_json::from_value::<SimplifiedAlbum>(album).ok())
+        .filter_map(|album| models::album(&album))
+        .collect::<Vec<_>>();
+
+    let mut entry = models::artist(&artist);
+    entry["albumCount"] = json!(albums.len());
+    entry["album"] = json!(albums);
+    Ok(json!({ "artist": entry }))
+}
+
+/// `getAlbum`, an album with its songs
+async fn album(params: &Params, account: &SpotifyAccount) -> Result<Value, SubsonicError> {
+    let album_id = album_id(params.required("id")?)?;
+    let market = account.default_market().await;
+    let album = account
+        .client
+        .album(album_id, Some(market))
+        .await
+        .map_err(ServerError::from)?;
+    Ok(json!({ "album": models::full_album(&album) }))
+}
+
+/// `search3`, artists, albums and songs matching a query
+async fn search(params: &Params, account: &SpotifyAccount) -> Result<Value, SubsonicError> {
+    // Some clients list everything with an empty query, which Spotify doesn't support
+    let query = params.required("query")?.trim_matches('"').trim();
+    let market = account.default_market().await;
+
+    let mut artists = vec![];
+    let mut albums = vec![];
+    let mut songs = vec![];
+    if !query.is_empty() {
+        for (search_type, prefix) in [
+            (SearchType::Artist, "artist"),
+            (SearchType::Album, "album"),
+            (SearchType::Track, "song"),
+        ] {
+            let count = params
+                .number::<u32>(&format!("{}Count", prefix))?
+                .unwrap_or(20);
+            let offset = params.number::<u32>(&format!("{}Offset", prefix))?;
+            if count == 0 {
+                continue;
+            }
+            let result = search_page(account, query, search_type, market, count, offset).await?;
+            match result {
+                SearchResult::Artists(page) => {
+                    artists = page.items.iter().map(models::artist).collect()
+                }
+                SearchResult::Albums(page) => {
+                    albums = page.items.iter().filter_map(models::album).collect()
+                }
+                SearchResult::Tracks(page) => {
+                    songs = page.items.iter().filter_map(models::song).collect()
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(json!({
+        "searchResult3": { "artist": artists, "album": albums, "song": songs }
+    }))
+}
+
+async fn search_page(
+    account: &SpotifyAccount,
+    query: &str,
+    search_type: SearchType,
+    market: Market,
+    count: u32,
+    offset: Option<u32>,
+) -> Result<SearchResult, ServerError> {
+    Ok(account
+        .client
+        .search(
+            query,
+            search_type,
+            Some(market),
+            None,
+            Some(count.min(MAX_IDS_PER_REQUEST as u32)),
+            offset,
+        )
+        .await?)
+}
+
+/// `getPlaylists`, the playlists owned or followed by the user
+async fn playlists(account: &SpotifyAccount) -> Result<Value, ServerError> {
+    let playlists = all_current_user_playlists(account)
+        .await?
+        .iter()
+        .map(models::playlist)
+        .collect::<Vec<_>>();
+    Ok(json!({ "playlists": { "playlist": playlists } }))
+}
+
+/// `getPlaylist`, a playlist with its songs
+async fn playlist(id: &str, account: &SpotifyAccount) -> Result<Value, SubsonicError> {
+    let playlist_id = playlist_id(id)?;
+    let market = account.default_market().await;
+    let playlist = account
+        .client
+        .playlist(playlist_id.clone(), None, Some(market))
+        .await
+        .map_err(ServerError::from)?;
+    let tracks = playlist_tracks(account, playlist_id).await?;
+    Ok(json!({ "playlist": models::full_playlist(&playlist, &tracks) }))
+}
+
+async fn playlist_tracks(
+    account: &SpotifyAccount,
+    playlist_id: PlaylistId<'_>,
+) -> Result<Vec<FullTrack>, ServerError> {
+    let market = account.default_market().await;
+    let items = all_tracks(account, playlist_id, None, Some(market)).await?;
+    Ok(models::playlist_tracks(
+        items.into_iter().map(|item| item.track).collect(),
+    ))
+}
+
+/// Replace all songs of a playlist, in requests of at most 100 songs
+async fn replace_songs(
+    account: &SpotifyAccount,
+    playlist_id: PlaylistId<'_>,
+    track_ids: Vec<TrackId<'_>>,
+) -> Result<(), ServerError> {
+    let mut chunks = track_ids.chunks(MAX_PLAYLIST_ITEMS_PER_REQUEST);
+    let first = chunks.next().unwrap_or_default();
+    account
+        .client
+        .playlist_replace_items(
+            playlist_id.clone(),
+            first.iter().map(|id| PlayableId::Track(id.clone())),
+        )
+        .await?;
+    for chunk in chunks {
+        add_songs(account, playlist_id.clone(), chunk).await?;
+    }
+    Ok(())
+}
+
+async fn add_songs(
+    account: &SpotifyAccount,
+    playlist_id: PlaylistId<'_>,
+    track_ids: &[TrackId<'_>],
+) -> Result<(), ServerError> {
+    for chunk in track_ids.chunks(MAX_PLAYLIST_ITEMS_PER_REQUEST) {
+        account
+            .client
+            .playlist_add_items(
+                playlist_id.clone(),
+                chunk.iter().map(|id| PlayableId::Track(id.clone())),
+                None,
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+/// Remove the songs at the `getPlaylist` entry indexes by their positions,
+/// which keeps the other items, episodes and local files included, as they are
+async fn remove_songs(
+    account: &SpotifyAccount,
+    playlist_id: PlaylistId<'_>,
+    indexes: &[usize],
+) -> Result<(), SubsonicError> {
+    let playlist = account
+        .client
+        .playlist(playlist_id.clone(), None, None)
+        .await
+        .map_err(ServerError::from)?;
+    let items = all_tracks(account, playlist_id.clone(), None, None)
+        .await?
+        .into_iter()
+        .map(|item| item.track)
+        .collect::<Vec<_>>();
+    let positions = models::entry_positions(&items, indexes)
+        .ok_or_else(|| SubsonicError::new(0, "Invalid parameter songIndexToRemove"))?;
+
+    // The positions are of this snapshot, in every request
+    for chunk in positions.chunks(MAX_PLAYLIST_ITEMS_PER_REQUEST) {
+        let items = chunk.iter().map(|(id, positions)| ItemPositions {
+            id: PlayableId::Track(id.as_ref()),
+            positions,
+        });
+        account
+            .client
+            .playlist_remove_specific_occurrences_of_items(
+                playlist_id.clone(),
+                items,
+                Some(&playlist.snapshot_id),
+            )
+            .await
+            .map_err(ServerError::from)?;
+    }
+    Ok(())
+}
+
+/// `createPlaylist`, a new playlist, or the songs of `playlistId` replaced
+async fn create_playlist(
+    params: &Params,
+    username: &str,
+    account: &SpotifyAccount,
+) -> Result<Value, SubsonicError> {
+    let track_ids = track_ids(&params.all("songId"))?;
+    let id = match params.get("playlistId") {
+        Some(id) => {
+            replace_songs(account, playlist_id(id)?, track_ids).await?;
+            EVENTS.emit(
+                EventKind::PlaylistModified,
+                username,
+                json!({ "id": id, "change": "items_replaced" }),
+            );
+            id.to_owned()
+        }
+        None => {
+            let name = params.required("name")?;
+            let user_id = account.client.me().await.map_err(ServerError::from)?.id;
+            let playlist = account
+                .client
+                .user_playlist_create(user_id, name, None, None, None)
+                .await
+                .map_err(ServerError::from)?;
+            EVENTS.emit(
+                EventKind::PlaylistCreated,
+                username,
+                json!({ "id": playlist.id.id(), "name": playlist.name }),
+            );
+            add_songs(account, playlist.id.clone(), &track_ids).await?;
+            if !track_ids.is_empty() {
+                EVENTS.emit(
+                    EventKind::PlaylistModified,
+                    username,
+                    json!({ "id": playlist.id.id(), "change": "items_added" }),
+                );
+            }
+            playlist.id.id().to_owned()
+        }
+    };
+    playlist(&id, account).await
+}
+
+/// `updatePlaylist`, its details, and songs added or removed by index
+async fn update_playlist(
+    params: &Params,
+    username: &str,
+    account: &SpotifyAccount,
+) -> Result<Value, SubsonicError> {
+    let playlist_id = playlist_id(params.required("playlistId")?)?;
+
+    let name = params.get("name");
+    let comment = params.get("comment");
+    let public = params.get("public").map(|public| public == "true");
+    if name.is_some() || comment.is_some() || public.is_some() {
+        account
+            .client
+            .playlist_change_detail(playlist_id.clone(), name, public, comment, None)
+            .await
+            .map_err(ServerError::from)?;
+        EVENTS.emit(
+            EventKind::PlaylistModified,
+            username,
+            json!({ "id": playlist_id.id(), "change": "details" }),
+        );
+    }
+
+    let remove = params
+        .all("songIndexToRemove")
+        .iter()
+        .map(|index| {
+            index
+                .parse::<usize>()
+                .map_err(|_| SubsonicError::new(0, "Invalid parameter songIndexToRemove"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if !remove.is_empty() {
+        remove_songs(account, playlist_id.clone(), &remove).await?;
+        EVENTS.emit(
+            EventKind::PlaylistModified,
+            username,
+            json!({ "id": playlist_id.id(), "change": "items_removed" }),
+        );
+    }
+
+    let add = track_ids(&params.all("songIdToAdd"))?;
+    add_songs(account, playlist_id.clone(), &add).await?;
+    if !add.is_empty() {
+        EVENTS.emit(
+            EventKind::PlaylistModified,
+            username,
+            json!({ "id": playlist_id.id(), "change": "items_added" }),
+        );
+    }
+    Ok(json!({}))
+}
+
+/// `star` and `unstar`, songs and albums in the library, and followed artists
+async fn star(
+    params: &Params,
+    username: &str,
+    account: &SpotifyAccount,
+    star: bool,
+) -> Result<Value, SubsonicError> {
+    let track_ids = track_ids(&params.all("id"))?;
+    let album_ids = params
+        .all("albumId")
+        .into_iter()
+        .map(album_id)
+        .collect::<Result<Vec<_>, _>>()?;
+    let artist_ids = params
+        .all("artistId")
+        .into_iter()
+        .map(artist_id)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let client = &account.client;
+    for chunk in track_ids.chunks(MAX_IDS_PER_REQUEST) {
+        let ids = chunk.iter().cloned();
+        if star {
+            client.current_user_saved_tracks_add(ids).await
+        } else {
+            client.current_user_saved_tracks_delete(ids).await
+        }
+        .map_err(ServerError::from)?;
+    }
+    library_changed(star, username, "track", &track_ids);
+    for chunk in album_ids.chunks(MAX_IDS_PER_REQUEST) {
+        let ids = chunk.iter().cloned();
+        if star {
+            client.current_user_saved_albums_add(ids).await
+        } else {
+            client.current_user_saved_albums_delete(ids).await
+        }
+        .map_err(ServerError::from)?;
+    }
+    library_changed(star, username, "album", &album_ids);
+    for chunk in artist_ids.chunks(MAX_IDS_PER_REQUEST) {
+        let ids = chunk.iter().cloned();
+        if star {
+            client.user_follow_artists(ids).await
+        } else {
+            client.user_unfollow_artists(ids).await
+        }
+        .map_err(ServerError::from)?;
+    }
+    library_changed(star, username, "artist", &artist_ids);
+    Ok(json!({}))
+}
+
+/// Emit `library.saved` or `library.deleted` of the starred or unstarred ids
+fn library_changed<T: Id>(star: bool, username: &str, item_type: &str, ids: &[T]) {
+    if !ids.is_empty() {
+        let ids = ids.iter().map(|id| id.id()).collect::<Vec<_>>();
+        EVENTS.library_changed(star, username, item_type, &ids);
+    }
+}
+
+/// `getCoverArt`, the Spotify image proxied
+async fn cover_art(params: &Params, app_store: &AppStore) -> Result<HttpResponse, SubsonicError> {
+    let url = models::cover_art_url(params.required("id")?)
+        .and_then(|url| url::Url::parse(&url).ok())
+        .filter(|url| {
+            url.scheme() == "https"
+                && url
+                    .host_str()
+                    .map(|host| COVER_ART_HOSTS.iter().any(|suffix| host.ends_with(suffix)))
+                    .unwrap_or(false)
+        })
+        .ok_or_else(|| SubsonicError::not_found("Cover art"))?;
+
+    let response = app_store
+        .http_client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| ServerError::RequestError(e.to_string()))?;
+    if !response.status().is_success() {
+        return Err(SubsonicError::not_found("Cover art"));
+    }
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("image/jpeg")
+        .to_owned();
+    let body = response
+        .bytes()
+        .await
+        .map_err(|e| ServerError::RequestError(e.to_string()))?;
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((header::CACHE_CONTROL, "public, max-age=86400"))
+        .body(body))
+}
@@ -1,20 +1,22 @@
 use futures::StreamExt;
 
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 
 use rspotify::{
     clients::{BaseClient, OAuthClient},
-    model::{Page, SavedTrack, TrackId},
+    model::{Id, Market, Page, SavedTrack, TrackId},
 };
 
 use crate::{
     account::SpotifyAccount,
     app_store::AppStore,
+    cache::{cache_key, market_part},
     endpoints::{
-        params::{IdsData, LimitOffsetData},
+        params::{CountryLocateData, IdsData, LimitOffsetData},
         utils::{json_response, ok_response},
     },
     errors::ServerError,
+    events::EVENTS,
     session::ServerSession,
 };
 
@@ -23,6 +25,8 @@ use crate::{
 #[tracing::instrument(skip(app_store, session))]
 pub async fn track(
     id: web::Path<String>,
+    country_locate: web::Query<CountryLocateData>,
+    req: HttpRequest,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
@@ -33,8 +37,21 @@ pub async fn track(
     let track_id = TrackId::from_id(id_str.as_str())
         .map_err(|_| ServerError::ParamsError(format!("Invalid track id: {}", id_str)))?;
 
-    let result = account.client.track(track_id, None).await?;
-    json_response(&result)
+    let market = country_locate.market(&account).await?;
+    let key = cache_key(
+        "track",
+        &[
+            track_id.id(),
+            &market_part(market, &account.credentials.username),
+        ],
+    );
+    app_store
+        .response_cache
+        .respond(&req, key, || async {
+            let result = account.client.track(track_id, Some(market)).await?;
+            Ok(serde_json::to_string(&result)?)
+        })
+        .await
 }
 
 /// Path: GET `/tracks`
@@ -42,14 +59,29 @@ pub async fn track(
 #[tracing::instrument(skip(app_store, session))]
 pub async fn tracks(
     query: web::Query<IdsData>,
+    country_locate: web::Query<CountryLocateData>,
+    req: HttpRequest,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
     let account = app_store.authorize(username).await?;
     let track_ids = crate::into_ids!(TrackId, query.ids());
-    let result = account.client.tracks(track_ids, None).await?;
-    json_response(&result)
+    let market = country_locate.market(&account).await?;
+    let key = cache_key(
+        "tracks",
+        &[
+            &query.ids,
+            &market_part(market, &account.credentials.username),
+        ],
+    );
+    app_store
+        .response_cache
+        .respond(&req, key, || async {
+            let result = account.client.tracks(track_ids, Some(market)).await?;
+            Ok(serde_json::to_string(&result)?)
+        })
+        .await
 }
 
 /// Path: GET `/me/tracks`
@@ -57,31 +89,32 @@ pub async fn tracks(
 #[tracing::instrument(skip(app_store, session))]
 pub async fn saved_tracks(
     query: web::Query<LimitOffsetData>,
+    country_locate: web::Query<CountryLocateData>,
     app_store: web::Data<AppStore>,
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
     let account = app_store.authorize(username).await?;
 
+    let market = country_locate.market(&account).await?;
     if query.limit.is_some() {
-        let page = page_saved_tracks(&account, query.limit, query.offset).await?;
+        let page = page_saved_tracks(&account, Some(market), query.limit, query.offset).await?;
         json_response(&page)
     } else {
-        let tracks = all_saved_tracks(&account).await?;
+        let tracks = all_saved_tracks(&account, Some(market)).await?;
         json_response(&tracks)
     }
 }
 
 /// Current user all saved tracks
-async fn all_saved_tracks(account: &SpotifyAccount) -> Result<Vec<SavedTrack>, ServerError> {
-    let mut track_stream = account.client.current_user_saved_tracks(None);
+pub async fn all_saved_tracks(
+    account: &SpotifyAccount,
+    market: Option<Market>,
+) -> Result<Vec<SavedTrack>, ServerError> {
+    let mut track_stream = account.client.current_user_saved_tracks(market);
     let mut tracks = vec![];
     while let Some(item) = track_stream.next().await {
-        if item.is_err() {
-            return Err(ServerError::RequestError(format!("{:?}", item)));
-        } else {
-            tracks.push(item.unwrap());
-        }
+        tracks.push(item?);
     }
     Ok(tracks)
 }
@@ -89,12 +122,13 @@ async fn all_saved_tracks(account: &SpotifyAccount) -> Result<Vec<SavedTrack>, S
 /// Current user saved tracks by page
 async fn page_saved_tracks(
     account: &SpotifyAccount,
+    market: Option<Market>,
     limit: Option<u32>,
     offset: Option<u32>,
 ) -> Result<Page<SavedTrack>, ServerError> {
     let page = account
         .client
-        .current_user_saved_tracks_manual(None, limit, offset)
+        .current_user_saved_tracks_manual(market, limit, offset)
         .await?;
     Ok(page)
 }
@@ -108,13 +142,14 @@ pub async fn save_tracks(
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
-    let account = app_store.authorize(username).await?;
+    let account = app_store.authorize(username.clone()).await?;
 
     let track_ids = crate::into_ids!(TrackId, query.ids());
     account
         .client
         .current_user_saved_tracks_add(track_ids)
         .await?;
+    EVENTS.library_changed(true, username.as_ref(), "track", &query.ids());
     ok_response()
 }
 
@@ -127,16 +162,40 @@ pub async fn delete_tracks(
     session: ServerSession,
 ) -> Result<HttpResponse, ServerError> {
     let username = session.get_username()?;
-    let account = app_store.authorize(username).await?;
+    let account = app_store.authorize(username.clone()).await?;
 
     let track_ids = crate::into_ids!(TrackId, query.ids());
     account
         .client
         .current_user_saved_tracks_delete(track_ids)
         .await?;
+    EVENTS.library_changed(false, username.as_ref(), "track", &query.ids());
     ok_response()
 }
 
+/// Path: GET `/me/tracks/contains`
+/// Check if one or more tracks is already saved in the current Spotify user's 'Your Music' library.
+#[tracing::instrument(skip(app_store, session))]
+pub async fn contains_tracks(
+    query: web::Query<IdsData>,
+    app_store: web::Data<AppStore>,
+    session: ServerSession,
+) -> Result<HttpResponse, ServerError> {
+    let username = session.get_username()?;
+    let account = app_store.authorize(username).await?;
+
+    let mut result = vec![];
+    for ids in query.batches() {
+        let track_ids = crate::into_ids!(TrackId, ids);
+        let contains = account
+            .client
+            .current_user_saved_tracks_contains(track_ids)
+            .await?;
+        result.extend(contains);
+    }
+    json_response(&result)
+}
+
 /// Path: GET `/audio-features/{id}`
 /// Get audio feature information for a single track identified by its unique Spotify ID.
 #[tracing::instrument(skip(app_store, session))]
@@ -1,6 +1,12 @@
-use actix_web::{body::MessageBody, http::header::ContentType, HttpResponse};
+use actix_web::{body::MessageBody, http::header::ContentType, HttpRequest, HttpResponse};
 
-use crate::errors::ServerError;
+use rspotify::{clients::BaseClient, http::Query, model::Page};
+
+use crate::{account::SpotifyAccount, errors::ServerError};
+
+/// The page size `all_raw_items` requests, the maximum of Spotify's paging
+/// endpoints
+const RAW_ITEMS_PAGE_SIZE: u32 = 50;
 
 pub fn ok_response() -> Result<HttpResponse, ServerError> {
     Ok(HttpResponse::Ok().finish())
@@ -18,3 +24,61 @@ pub fn json_response(obj: impl serde::Serialize) -> Result<HttpResponse, ServerE
         .content_type(ContentType::json())
         .body(serde_json::to_string(&obj)?))
 }
+
+/// The url of the server, as the client reached it
+pub fn server_url(req: &HttpRequest) -> String {
+    let conn = req.connection_info();
+    format!("{}://{}", conn.scheme(), conn.host())
+}
+
+/// `url` with `params` as its encoded query, for the rspotify requests which
+/// take no query
+pub fn url_with_query(url: &str, params: &Query<'_>) -> String {
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    for (key, value) in params {
+        query.append_pair(key, value);
+    }
+    format!("{}?{}", url, query.finish())
+}
+
+/// All items of a paging endpoint which rspotify doesn't cover
+pub async fn all_raw_items(
+    account: &SpotifyAccount,
+    url: &str,
+    params: &Query<'_>,
+) -> Result<Vec<serde_json::Value>, ServerError> {
+    let mut items = vec![];
+    let mut offset = 0;
+    loop {
+        let limit = Some(RAW_ITEMS_PAGE_SIZE);
+        let page = page_raw_items(account, url, params, limit, Some(offset)).await?;
+        let count = page.items.len() as u32;
+        items.extend(page.items);
+        if page.next.is_none() || count == 0 {
+            break;
+        }
+        offset += count;
+    }
+    Ok(items)
+}
+
+/// Items of a paging endpoint which rspotify doesn't cover by page
+pub async fn page_raw_items(
+    account: &SpotifyAccount,
+    url: &str,
+    params: &Query<'_>,
+    limit: Option<u32>,
+    offset: Option<u32>,
+) -> Result<Page<serde_json::Value>, ServerError> {
+    let limit = limit.map(|s| s.to_string());
+    let offset = offset.map(|s| s.to_string());
+    let mut params = params.clone();
+    if let Some(v) = limit.as_deref() {
+        params.insert("limit", v);
+    }
+    if let Some(v) = offset.as_deref() {
+        params.insert("offset", v);
+    }
+    let result = account.client.api_get(url, &params).await?;
+    Ok(serde_json::from_str(&result)?)
+}
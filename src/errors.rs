@@ -1,10 +1,13 @@
-use actix_web::{http::StatusCode, ResponseError};
+use actix_web::{
+    http::{header, StatusCode},
+    HttpResponse, ResponseError,
+};
 
 use librespot::core::{
     audio_key::AudioKeyError, channel::ChannelError, mercury::MercuryError, session::SessionError,
 };
 
-use rspotify::ClientError;
+use rspotify::{http::HttpError, ClientError};
 
 #[derive(thiserror::Error, Debug)]
 pub enum ServerError {
@@ -32,6 +35,8 @@ pub enum ServerError {
     AudioError(String),
     #[error("Librespot Error: {0}")]
     LibrespotError(String),
+    #[error("Rate Limit Error: {reason}")]
+    RateLimitError { reason: String, retry_after: u64 },
 }
 
 impl From<MercuryError> for ServerError {
@@ -52,11 +57,225 @@ impl From<AudioKeyError> for ServerError {
     }
 }
 
+impl ServerError {
+    /// A stable, machine-readable error code
+    pub fn code(&self) -> &'static str {
+        match self {
+            ServerError::AuthenticationError => "authentication_error",
+            ServerError::NoLoginError => "no_login",
+            ServerError::InnerError(_) => "inner_error",
+            ServerError::SerdeError(_) => "serde_error",
+            ServerError::ClientError(_) => "spotify_client_error",
+            ServerError::RequestError(_) => "spotify_request_error",
+            ServerError::SessionError(_) => "spotify_session_error",
+            ServerError::TokenError(_) => "spotify_token_error",
+            ServerError::ParamsError(_) => "params_error",
+            ServerError::IOError(_) => "io_error",
+            ServerError::AudioError(_) => "audio_error",
+            ServerError::LibrespotError(_) => "librespot_error",
+            ServerError::RateLimitError { .. } => "rate_limited",
+        }
+    }
+
+    /// The unsuccessful response of Spotify Web API, if the error comes from it
+    fn spotify_response(&self) -> Option<&reqwest::Response> {
+        match self {
+            ServerError::ClientError(ClientError::Http(err)) => match err.as_ref() {
+                HttpError::StatusCode(response) => Some(response),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The status code returned by Spotify Web API
+    pub fn spotify_status(&self) -> Option<u16> {
+        self.spotify_response()
+            .map(|response| response.status().as_u16())
+    }
+
+    /// Seconds to wait before retrying, from our own rate limits or
+    /// Spotify's `Retry-After` header
+    pub fn retry_after(&self) -> Option<u64> {
+        if let ServerError::RateLimitError { retry_after, .. } = self {
+            return Some(*retry_after);
+        }
+        self.spotify_response()?
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()
+    }
+}
+
+#[derive(serde::Serialize)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(serde::Serialize)]
+struct ErrorDetail {
+    code: &'static str,
+    message: String,
+    spotify_status: Option<u16>,
+    retry_after: Option<u64>,
+}
+
 impl ResponseError for ServerError {
     fn status_code(&self) -> StatusCode {
         match self {
             ServerError::AuthenticationError => StatusCode::FORBIDDEN,
+            ServerError::NoLoginError => StatusCode::UNAUTHORIZED,
+            ServerError::ParamsError(_) => StatusCode::BAD_REQUEST,
+            ServerError::RateLimitError { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ServerError::ClientError(_) => match self.spotify_status() {
+                // Pass through the statuses which clients can react to
+                Some(status @ (403 | 404 | 429)) => {
+                    StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_GATEWAY)
+                }
+                Some(_) => StatusCode::BAD_GATEWAY,
+                None => StatusCode::INTERNAL_SERVER_ERROR,
+            },
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        let retry_after = self.retry_after();
+
+        let mut response = HttpResponse::build(self.status_code());
+        if let Some(seconds) = retry_after {
+            response.insert_header((header::RETRY_AFTER, seconds));
+        }
+        response.json(ErrorBody {
+            error: ErrorDetail {
+                code: self.code(),
+                message: self.to_string(),
+                spotify_status: self.spotify_status(),
+                retry_after,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::MessageBody;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    /// A Spotify Web API error response
+    fn spotify(status: u16, retry_after: Option<u64>) -> ServerError {
+        let mut response = http::Response::builder().status(status);
+        if let Some(seconds) = retry_after {
+            response = response.header(reqwest::header::RETRY_AFTER, seconds);
+        }
+        let response = reqwest::Response::from(response.body("").unwrap());
+        ServerError::ClientError(ClientError::from(HttpError::StatusCode(response)))
+    }
+
+    #[test]
+    fn error_responses() {
+        let cases = [
+            (ServerError::NoLoginError, 401, "no_login", None, None),
+            (
+                ServerError::AuthenticationError,
+                403,
+                "authentication_error",
+                None,
+                None,
+            ),
+            (
+                ServerError::ParamsError("Invalid id".to_owned()),
+                400,
+                "params_error",
+                None,
+                None,
+            ),
+            (
+                ServerError::RateLimitError {
+                    reason: "Too many requests".to_owned(),
+                    retry_after: 30,
+                },
+                429,
+                "rate_limited",
+                None,
+                Some(30),
+            ),
+            (
+                spotify(403, None),
+                403,
+                "spotify_client_error",
+                Some(403),
+                None,
+            ),
+            (
+                spotify(404, None),
+                404,
+                "spotify_client_error",
+                Some(404),
+                None,
+            ),
+            (
+                spotify(429, Some(7)),
+                429,
+                "spotify_client_error",
+                Some(429),
+                Some(7),
+            ),
+            (
+                spotify(500, None),
+                502,
+                "spotify_client_error",
+                Some(500),
+                None,
+            ),
+            (
+                spotify(401, None),
+                502,
+                "spotify_client_error",
+                Some(401),
+                None,
+            ),
+            (
+                ServerError::InnerError("broken".to_owned()),
+                500,
+                "inner_error",
+                None,
+                None,
+            ),
+        ];
+
+        for (error, status, code, spotify_status, retry_after) in cases {
+            let response = error.error_response();
+            assert_eq!(response.status().as_u16(), status, "{:?}", error);
+            assert_eq!(
+                response.headers().get(header::RETRY_AFTER).map(|v| v
+                    .to_str()
+                    .unwrap()
+                    .parse::<u64>()
+                    .unwrap()),
+                retry_after,
+                "{:?}",
+                error
+            );
+
+            let body = response.into_body().try_into_bytes().unwrap();
+            let body = serde_json::from_slice::<Value>(&body).unwrap();
+            assert_eq!(
+                body,
+                json!({
+                    "error": {
+                        "code": code,
+                        "message": error.to_string(),
+                        "spotify_status": spotify_status,
+                        "retry_after": retry_after,
+                    }
+                }),
+            );
+        }
+    }
 }
@@ -0,0 +1,199 @@
+//! Server events
+//!
+//! Events are published on a process-wide bus, like the metrics, so the
+//! accounts and the audio streams can emit them without `AppStore`.
+//! The webhooks deliver them to their targets, and `/events` pushes the
+//! events of a user to its clients.
+
+use std::{
+    str::FromStr,
+    sync::LazyLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+use crate::{common::hex, errors::ServerError};
+
+/// Events which slow subscribers haven't received yet, older ones are dropped
+const CAPACITY: usize = 1024;
+
+pub static EVENTS: LazyLock<Events> = LazyLock::new(Events::new);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Login,
+    Logout,
+    SessionReset,
+    SessionResetFailed,
+    TokenRefreshed,
+    TokenRefreshFailed,
+    PlaylistCreated,
+    PlaylistModified,
+    LibrarySaved,
+    LibraryDeleted,
+    StreamStarted,
+    StreamFinished,
+    StreamTruncated,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 13] = [
+        EventKind::Login,
+        EventKind::Logout,
+        EventKind::SessionReset,
+        EventKind::SessionResetFailed,
+        EventKind::TokenRefreshed,
+        EventKind::TokenRefreshFailed,
+        EventKind::PlaylistCreated,
+        EventKind::PlaylistModified,
+        EventKind::LibrarySaved,
+        EventKind::LibraryDeleted,
+        EventKind::StreamStarted,
+        EventKind::StreamFinished,
+        EventKind::StreamTruncated,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Login => "login",
+            EventKind::Logout => "logout",
+            EventKind::SessionReset => "session.reset",
+            EventKind::SessionResetFailed => "session.reset_failed",
+            EventKind::TokenRefreshed => "token.refreshed",
+            EventKind::TokenRefreshFailed => "token.refresh_failed",
+            EventKind::PlaylistCreated => "playlist.created",
+            EventKind::PlaylistModified => "playlist.modified",
+            EventKind::LibrarySaved => "library.saved",
+            EventKind::LibraryDeleted => "library.deleted",
+            EventKind::StreamStarted => "stream.started",
+            EventKind::StreamFinished => "stream.finished",
+            EventKind::StreamTruncated => "stream.truncated",
+        }
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = ServerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EventKind::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == s)
+            .ok_or_else(|| ServerError::ParamsError(format!("Unknown event type {:?}", s)))
+    }
+}
+
+impl serde::Serialize for EventKind {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Event {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    /// The server user, or the Spotify account for the account events
+    pub username: String,
+    /// Milliseconds since the epoch
+    pub timestamp: u64,
+    pub data: Value,
+}
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Events {
+    fn new() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Events { sender }
+    }
+
+    pub fn emit(&self, kind: EventKind, username: &str, data: Value) {
+        let event = Event {
+            id: hex::encode(&rand::random::<[u8; 8]>()).to_lowercase(),
+            kind,
+            username: username.to_owned(),
+            timestamp: now_millis(),
+            data,
+        };
+        tracing::debug!("Event {} of {}", kind.as_str(), username);
+        // Nobody listens without subscribers
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// Emit `library.saved` or `library.deleted` of items of `item_type`
+    pub fn library_changed(&self, saved: bool, username: &str, item_type: &str, ids: &[&str]) {
+        let kind = if saved {
+            EventKind::LibrarySaved
+        } else {
+            EventKind::LibraryDeleted
+        };
+        self.emit(
+            kind,
+            username,
+            serde_json::json!({ "type": item_type, "ids": ids }),
+        );
+    }
+
+    /// Emit `stream.started`, and `stream.finished` when the guard is dropped
+    pub fn stream_started(&self, username: &str, id: &str) -> StreamEvents {
+        self.emit(
+            EventKind::StreamStarted,
+            username,
+            serde_json::json!({ "id": id }),
+        );
+        StreamEvents {
+            username: username.to_owned(),
+            id: id.to_owned(),
+            bytes: 0,
+        }
+    }
+}
+
+/// An audio stream, which is finished when it ends or the client goes away
+pub struct StreamEvents {
+    username: String,
+    id: String,
+    bytes: u64,
+}
+
+impl StreamEvents {
+    pub fn streamed(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+    }
+
+    /// Emit `stream.truncated` when the audio data stops coming in time
+    pub fn truncated(&self) {
+        EVENTS.emit(
+            EventKind::StreamTruncated,
+            &self.username,
+            serde_json::json!({ "id": self.id, "bytes": self.bytes }),
+        );
+    }
+}
+
+impl Drop for StreamEvents {
+    fn drop(&mut self) {
+        EVENTS.emit(
+            EventKind::StreamFinished,
+            &self.username,
+            serde_json::json!({ "id": self.id, "bytes": self.bytes }),
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! Podcast RSS 2.0 feeds with iTunes tags, and OPML subscription lists
+
+use std::fmt::Write;
+
+use chrono::NaiveDate;
+use rspotify::model::{FullShow, Id, Image, SimplifiedEpisode};
+
+use crate::common::xml::escape;
+
+/// An episode and the signed url of its audio
+pub struct FeedEpisode<'a> {
+    pub episode: &'a SimplifiedEpisode,
+    pub url: String,
+}
+
+/// The largest image
+fn image(images: &[Image]) -> Option<&str> {
+    images.first().map(|image| image.url.as_str())
+}
+
+/// The RFC 822 date of a release date, which may only have the year or month
+fn pub_date(release_date: &str) -> Option<String> {
+    let date = match release_date.len() {
+        4 => format!("{}-01-01", release_date),
+        7 => format!("{}-01", release_date),
+        _ => release_date.to_owned(),
+    };
+    NaiveDate::parse_from_str(&date, "%Y-%m-%d")
+        .ok()
+        .map(|date| date.format("%a, %d %b %Y 00:00:00 +0000").to_string())
+}
+
+/// `HH:MM:SS` of `itunes:duration`
+fn duration(seconds: i64) -> String {
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn explicit(explicit: bool) -> &'static str {
+    if explicit {
+        "true"
+    } else {
+        "false"
+    }
+}
+
+pub fn rss(show: &FullShow, feed_url: &str, episodes: &[FeedEpisode]) -> String {
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd" xmlns:atom="http://www.w3.org/2005/Atom">
+<channel>
+<title>{title}</title>
+<link>{link}</link>
+<atom:link href="{feed_url}" rel="self" type="application/rss+xml"/>
+<description>{description}</description>
+<itunes:summary>{description}</itunes:summary>
+<itunes:author>{publisher}</itunes:author>
+<itunes:explicit>{explicit}</itunes:explicit>
+<itunes:type>episodic</itunes:type>
+"#,
+        title = escape(&show.name),
+        link = escape(
+            show.external_urls
+                .get("spotify")
+                .map(String::as_str)
+                .unwrap_or_default()
+        ),
+        feed_url = escape(feed_url),
+        description = escape(&show.description),
+        publisher = escape(&show.publisher),
+        explicit = explicit(show.explicit),
+    );
+    if let Some(language) = show.languages.first() {
+        let _ = writeln!(out, "<language>{}</language>", escape(language));
+    }
+    if let Some(url) = image(&show.images) {
+        let _ = write!(
+            out,
+            "<image><url>{url}</url><title>{}</title><link>{url}</link></image>\n<itunes:image href=\"{url}\"/>\n",
+            escape(&show.name),
+            url = escape(url),
+        );
+    }
+
+    for FeedEpisode { episode, url } in episodes {
+        let _ = write!(
+            out,
+            r#"<item>
+<title>{title}</title>
+<description>{description}</description>
+<guid isPermaLink="false">{guid}</guid>
+<enclosure url="{url}" length="0" type="audio/ogg"/>
+<itunes:duration>{duration}</itunes:duration>
+<itunes:explicit>{explicit}</itunes:explicit>
+"#,
+            title = escape(&episode.name),
+            description = escape(&episode.description),
+            guid = escape(&episode.id.uri()),
+            url = escape(url),
+            duration = duration(episode.duration.num_seconds()),
+            explicit = explicit(episode.explicit),
+        );
+        if let Some(date) = pub_date(&episode.release_date) {
+            let _ = writeln!(out, "<pubDate>{}</pubDate>", date);
+        }
+        if let Some(url) = image(&episode.images) {
+            let _ = writeln!(out, "<itunes:image href=\"{}\"/>", escape(url));
+        }
+        out.push_str("</item>\n");
+    }
+    out.push_str("</channel>\n</rss>\n");
+    out
+}
+
+/// An OPML list of the feeds, `(title, feed url)`
+pub fn opml(title: &str, feeds: &[(String, String)]) -> String {
+    let mut out = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<opml version=\"2.0\">\n<head><title>{}</title></head>\n<body>\n",
+        escape(title)
+    );
+    for (text, url) in feeds {
+        let _ = writeln!(
+            out,
+            r#"<outline type="rss" text="{text}" title="{text}" xmlUrl="{url}"/>"#,
+            text = escape(text),
+            url = escape(url),
+        );
+    }
+    out.push_str("</body>\n</opml>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn show() -> FullShow {
+        serde_json::from_value(json!({
+            "available_markets": [],
+            "copyrights": [],
+            "description": "News & <views>",
+            "explicit": true,
+            "episodes": {
+                "href": "https://api.spotify.com/v1/shows/38bS44xjbVVZ3No3ByF1dJ/episodes",
+                "items": [],
+                "limit": 50,
+                "next": null,
+                "offset": 0,
+                "previous": null,
+                "total": 0,
+            },
+            "external_urls": { "spotify": "https://open.spotify.com/show/38bS44xjbVVZ3No3ByF1dJ" },
+            "href": "https://api.spotify.com/v1/shows/38bS44xjbVVZ3No3ByF1dJ",
+            "id": "38bS44xjbVVZ3No3ByF1dJ",
+            "images": [{ "url": "https://i.scdn.co/image/show", "height": 640, "width": 640 }],
+            "languages": ["en"],
+            "media_type": "audio",
+            "name": "Tom & Jerry's \"Show\"",
+            "publisher": "A < B",
+        }))
+        .unwrap()
+    }
+
+    fn episode(release_date: &str) -> SimplifiedEpisode {
+        serde_json::from_value(json!({
+            "audio_preview_url": null,
+            "description": "<b>Bold</b>",
+            "duration_ms": 3_725_000,
+            "explicit": false,
+            "external_urls": {},
+            "href": "https://api.spotify.com/v1/episodes/512ojhOuo1ktJprKbVcKyQ",
+            "id": "512ojhOuo1ktJprKbVcKyQ",
+            "images": [],
+            "is_externally_hosted": false,
+            "is_playable": true,
+            "language": "en",
+            "languages": ["en"],
+            "name": "Part 1 & 2",
+            "release_date": release_date,
+            "release_date_precision": "day",
+            "resume_point": null,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn pub_dates_of_each_precision() {
+        assert_eq!(
+            pub_date("2023").as_deref(),
+            Some("Sun, 01 Jan 2023 00:00:00 +0000")
+        );
+        assert_eq!(
+            pub_date("2023-05").as_deref(),
+            Some("Mon, 01 May 2023 00:00:00 +0000")
+        );
+        assert_eq!(
+            pub_date("2023-05-17").as_deref(),
+            Some("Wed, 17 May 2023 00:00:00 +0000")
+        );
+        assert_eq!(pub_date("2023-13"), None);
+        assert_eq!(pub_date(""), None);
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(duration(0), "00:00:00");
+        assert_eq!(duration(59), "00:00:59");
+        assert_eq!(duration(3725), "01:02:05");
+        assert_eq!(duration(36_000), "10:00:00");
+    }
+
+    #[test]
+    fn rss_is_escaped() {
+        let episode = episode("2023-05");
+        let episodes = [FeedEpisode {
+            episode: &episode,
+            url: "https://music.local/audio-stream-with-sign/audio.ogg?sign=ab&iv=cd".to_owned(),
+        }];
+        let rss = rss(
+            &show(),
+            "https://music.local/feeds/shows/38bS44xjbVVZ3No3ByF1dJ.xml?sign=ab&iv=cd",
+            &episodes,
+        );
+
+        assert!(rss.contains("<title>Tom &amp; Jerry&apos;s &quot;Show&quot;</title>"));
+        assert!(rss.contains("<description>News &amp; &lt;views&gt;</description>"));
+        assert!(rss.contains("<itunes:author>A &lt; B</itunes:author>"));
+        assert!(rss.contains("<itunes:explicit>true</itunes:explicit>"));
+        assert!(rss.contains(
+            r#"<atom:link href="https://music.local/feeds/shows/38bS44xjbVVZ3No3ByF1dJ.xml?sign=ab&amp;iv=cd""#
+        ));
+        assert!(rss.contains("<title>Part 1 &amp; 2</title>"));
+        assert!(rss.contains("<description>&lt;b&gt;Bold&lt;/b&gt;</description>"));
+        assert!(rss.contains(
+            r#"<enclosure url="https://music.local/audio-stream-with-sign/audio.ogg?sign=ab&amp;iv=cd" length="0" type="audio/ogg"/>"#
+        ));
+        assert!(rss.contains(
+            r#"<guid isPermaLink="false">spotify:episode:512ojhOuo1ktJprKbVcKyQ</guid>"#
+        ));
+        assert!(rss.contains("<itunes:duration>01:02:05</itunes:duration>"));
+        assert!(rss.contains("<pubDate>Mon, 01 May 2023 00:00:00 +0000</pubDate>"));
+        assert!(!rss.contains("Tom & Jerry"));
+    }
+
+    #[test]
+    fn opml_outlines() {
+        let opml = opml(
+            "Spotify shows",
+            &[(
+                "Tom & Jerry".to_owned(),
+                "https://music.local/feeds/shows/1.xml?sign=ab&iv=cd".to_owned(),
+            )],
+        );
+        assert!(opml.contains(
+            r#"<outline type="rss" text="Tom &amp; Jerry" title="Tom &amp; Jerry" xmlUrl="https://music.local/feeds/shows/1.xml?sign=ab&amp;iv=cd"/>"#
+        ));
+    }
+}
@@ -1,8 +1,27 @@
 pub mod account;
 pub mod app_store;
+pub mod backup;
+pub mod cache;
 pub mod cmd;
 pub mod common;
+pub mod config;
+pub mod cors;
+pub mod dlna;
 pub mod endpoints;
 pub mod errors;
+pub mod events;
+pub mod feeds;
+pub mod metrics;
+pub mod mpd;
+pub mod playlist_files;
+pub mod quota;
+pub mod radio;
 pub mod routes;
+pub mod scrobble;
 pub mod session;
+pub mod session_store;
+pub mod subsonic;
+#[cfg(feature = "otlp")]
+pub mod telemetry;
+pub mod tls;
+pub mod webhooks;
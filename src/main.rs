@@ -1,54 +1,183 @@
-use actix_session::{storage::CookieSessionStore, SessionMiddleware};
-use actix_web::{
-    cookie::{Key, SameSite},
-    web, App, HttpServer,
-};
-use clap::Parser;
+use actix_session::SessionMiddleware;
+use actix_web::{cookie::Key, middleware, web, App, HttpServer};
 use tracing_actix_web::TracingLogger;
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
-use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Registry};
+use tracing_subscriber::{layer::SubscriberExt, reload, EnvFilter, Registry};
 
-use spotify_web_server::{app_store::AppStore, cmd::Cmd, routes::route};
+use spotify_web_server::{
+    app_store::AppStore, cmd::Cmd, config::Reloader, dlna, metrics, mpd, quota, routes::route,
+    scrobble::Scrobbler, session_store::SessionBackend, subsonic, tls, webhooks::Webhooks,
+};
 
 async fn async_main() -> std::io::Result<()> {
-    let cmd = Cmd::parse();
+    let cli = Cmd::from_command_line();
+    let cmd = cli.load().expect("Failed to load config");
     let cache_dir = cmd.cache_dir.clone();
 
+    // The log filter can be changed when the config file is reloaded
+    let (env_filter, env_filter_handle) = reload::Layer::new(EnvFilter::new(&cmd.log_level));
     let bunyan_formatting_layer =
         BunyanFormattingLayer::new(env!("CARGO_PKG_NAME").to_string(), std::io::stdout);
     let subscriber = Registry::default()
-        .with(EnvFilter::new(&cmd.log_level))
+        .with(env_filter)
         .with(JsonStorageLayer)
         .with(bunyan_formatting_layer);
+
+    // Export spans to an OTLP collector, besides the Bunyan logs
+    #[cfg(feature = "otlp")]
+    let (otlp_layer, _telemetry) = match &cmd.otlp_endpoint {
+        Some(endpoint) => {
+            let (layer, telemetry) =
+                spotify_web_server::telemetry::otlp_layer(endpoint, &cmd.otlp_service_name)
+                    .expect("Failed to set up OpenTelemetry");
+            (Some(layer), Some(telemetry))
+        }
+        None => (None, None),
+    };
+    #[cfg(feature = "otlp")]
+    let subscriber = subscriber.with(otlp_layer);
+
     tracing::subscriber::set_global_default(subscriber).unwrap();
 
-    let app_store = AppStore::new(&cmd.client_id, &cache_dir, cmd.proxy.clone());
+    let session_backend = cmd
+        .session_backend()
+        .expect("Failed to open the session store");
+    let scrobbler = cmd
+        .scrobble()
+        .expect("Invalid scrobble config")
+        .map(Scrobbler::open)
+        .transpose()
+        .expect("Failed to load the scrobble queue");
+    let session_secret = cmd
+        .session_secret()
+        .expect("Failed to load the session secret");
+    let cors = cmd.cors().expect("Invalid CORS config");
+    let app_store = AppStore::new(&cmd.client_id, &cache_dir, cmd.proxy.clone())
+        .with_secret(session_secret)
+        .with_cors(cors.clone())
+        .with_response_cache(cmd.response_cache())
+        .with_rate_limit(cmd.rate_limit())
+        .with_quotas(cmd.quotas())
+        .with_scope(&cmd.scope)
+        .with_allowed_users(cmd.allowed_users.iter().cloned().collect())
+        .with_admin_token(cmd.admin_token.clone())
+        .with_subsonic_users(
+            subsonic::parse_users(&cmd.subsonic_users).expect("Invalid Subsonic users"),
+        )
+        .with_dlna(cmd.dlna().expect("Invalid DLNA config"))
+        .with_scrobbler(scrobbler)
+        .with_webhooks(
+            cmd.webhooks()
+                .expect("Invalid webhook config")
+                .map(Webhooks::new),
+        )
+        .with_sessions(match &session_backend {
+            SessionBackend::File(store) => Some(store.clone()),
+            SessionBackend::Cookie => None,
+        });
+    let app_store = match &cmd.config {
+        Some(config_path) => {
+            let set_log_filter = Box::new(move |level: &str| {
+                let filter = EnvFilter::try_new(level).map_err(|e| e.to_string())?;
+                env_filter_handle.reload(filter).map_err(|e| e.to_string())
+            });
+            app_store.with_reloader(Reloader::new(
+                cli.clone(),
+                config_path.clone(),
+                set_log_filter,
+            ))
+        }
+        None => app_store,
+    };
     if cmd.load_cache {
         app_store.load_cache().await.expect("Failed to load cache");
     };
+    app_store
+        .load_accounts(&cmd.accounts)
+        .await
+        .expect("Failed to load accounts");
     let app_store = web::Data::new(app_store);
 
-    let session_secret = cmd.session_secret();
-    HttpServer::new(move || {
+    if let Some(dlna) = app_store.dlna.clone() {
+        tokio::spawn(async move {
+            if let Err(err) = dlna::ssdp::advertise(dlna).await {
+                tracing::error!("DLNA SSDP advertising stopped: {}", err);
+            }
+        });
+    }
+
+    if let Some(scrobbler) = app_store.scrobbler.clone() {
+        tokio::spawn(scrobbler.retry_queued());
+    }
+
+    if let Some(webhooks) = app_store.webhooks.clone() {
+        tokio::spawn(webhooks.run());
+    }
+
+    // Reload the config file on SIGHUP
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let app_store = app_store.clone();
+        let mut hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                if let Err(err) = app_store.reload_config() {
+                    tracing::warn!("Failed to reload config: {}", err);
+                }
+            }
+        });
+    }
+
+    if let Some(mpd) = cmd.mpd().expect("Invalid MPD config") {
+        let app_store = app_store.clone();
+        tokio::spawn(async move {
+            if let Err(err) = mpd::serve(mpd, app_store).await {
+                tracing::error!("MPD frontend stopped: {}", err);
+            }
+        });
+    }
+
+    let tls = cmd.tls().expect("Invalid TLS config");
+    let cookie = cmd.cookie();
+    let server = HttpServer::new(move || {
         App::new()
+            // Runs inside the session middleware
+            .wrap(middleware::from_fn(quota::limit))
+            .wrap(middleware::from_fn(metrics::record))
             .wrap(TracingLogger::default())
             .wrap(
-                SessionMiddleware::builder(
-                    CookieSessionStore::default(),
-                    Key::from(&session_secret),
-                )
-                .cookie_secure(false)
-                .cookie_same_site(SameSite::None)
-                .cookie_http_only(false)
-                .cookie_domain(None)
-                .build(),
+                cookie
+                    .configure(SessionMiddleware::builder(
+                        session_backend.clone(),
+                        Key::from(&session_secret),
+                    ))
+                    .build(),
             )
+            // CORS answers preflight requests before the other middlewares
+            .wrap(middleware::Condition::new(cors.enabled(), cors.build()))
             .service(route())
             .app_data(app_store.clone())
-    })
-    .bind((cmd.bind.as_str(), cmd.port))?
-    .run()
-    .await
+    });
+
+    match tls {
+        Some(tls) => {
+            let tls_config =
+                tls::load_rustls_config(&tls.cert, &tls.key).expect("Failed to load TLS config");
+            let server = server
+                .bind_rustls_0_23((cmd.bind.as_str(), cmd.port), tls_config)?
+                .run();
+            match tls.redirect_port {
+                Some(http_port) => {
+                    let redirect = tls::redirect_to_https(cmd.bind.clone(), http_port, cmd.port);
+                    futures::try_join!(server, redirect).map(|_| ())
+                }
+                None => server.await,
+            }
+        }
+        None => server.bind((cmd.bind.as_str(), cmd.port))?.run().await,
+    }
 }
 
 pub fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -0,0 +1,357 @@
+//! Prometheus metrics
+//!
+//! The series are registered on a process-wide registry, so the Spotify client,
+//! the accounts and the audio streams can record them without `AppStore`.
+//! The counters kept by the rate limiters and the quotas are collected from
+//! their stats when rendered.
+
+use std::{sync::LazyLock, time::Instant};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+};
+use prometheus::{
+    core::Collector, proto::MetricFamily, Encoder, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use rspotify::{http::HttpError, ClientError, ClientResult};
+
+use crate::{app_store::AppStore, errors::ServerError};
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    spotify_request_duration: HistogramVec,
+    spotify_request_errors: IntCounterVec,
+    token_refreshes: IntCounterVec,
+    session_resets: IntCounterVec,
+    active_streams: IntGauge,
+    streamed_bytes: IntCounter,
+    stream_truncations: IntCounter,
+    rate_limiter: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by route and status"),
+            &["route", "method", "status"],
+        )
+        .unwrap();
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "HTTP request latency by route and status",
+            ),
+            &["route", "method", "status"],
+        )
+        .unwrap();
+        let spotify_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "spotify_request_duration_seconds",
+                "Spotify Web API request latency by endpoint",
+            ),
+            &["method", "endpoint"],
+        )
+        .unwrap();
+        let spotify_request_errors = IntCounterVec::new(
+            Opts::new(
+                "spotify_request_errors_total",
+                "Failed Spotify Web API requests by endpoint and status",
+            ),
+            &["method", "endpoint", "status"],
+        )
+        .unwrap();
+        let token_refreshes = IntCounterVec::new(
+            Opts::new(
+                "spotify_token_refreshes_total",
+                "Token refreshes per account",
+            ),
+            &["account"],
+        )
+        .unwrap();
+        let session_resets = IntCounterVec::new(
+            Opts::new(
+                "spotify_session_resets_total",
+                "librespot session resets per account",
+            ),
+            &["account"],
+        )
+        .unwrap();
+        let active_streams =
+            IntGauge::new("audio_streams_active", "Audio streams in progress").unwrap();
+        let streamed_bytes =
+            IntCounter::new("audio_streamed_bytes_total", "Bytes of audio streamed").unwrap();
+        let stream_truncations = IntCounter::new(
+            "audio_stream_truncations_total",
+            "Audio streams cut short by a read timeout",
+        )
+        .unwrap();
+        let rate_limiter = IntGaugeVec::new(
+            Opts::new(
+                "spotify_rate_limiter",
+                "Spotify rate limiter state per account",
+            ),
+            &["account", "state"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry
+            .register(Box::new(http_request_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(spotify_request_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(spotify_request_errors.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(token_refreshes.clone()))
+            .unwrap();
+        registry.register(Box::new(session_resets.clone())).unwrap();
+        registry.register(Box::new(active_streams.clone())).unwrap();
+        registry.register(Box::new(streamed_bytes.clone())).unwrap();
+        registry
+            .register(Box::new(stream_truncations.clone()))
+            .unwrap();
+        registry.register(Box::new(rate_limiter.clone())).unwrap();
+
+        Self {
+            registry,
+            http_requests,
+            http_request_duration,
+            spotify_request_duration,
+            spotify_request_errors,
+            token_refreshes,
+            session_resets,
+            active_streams,
+            streamed_bytes,
+            stream_truncations,
+            rate_limiter,
+        }
+    }
+
+    /// Record a finished Spotify Web API request
+    ///
+    /// The endpoint is the url with ids replaced by `{id}`, which identifies
+    /// the rspotify method. A failed request without a response has status `none`.
+    pub fn observe_spotify_request<T>(
+        &self,
+        method: &str,
+        url: &str,
+        started_at: Instant,
+        result: &ClientResult<T>,
+    ) {
+        let endpoint = endpoint_template(url);
+        self.spotify_request_duration
+            .with_label_values(&[method, &endpoint])
+            .observe(started_at.elapsed().as_secs_f64());
+        if let Err(err) = result {
+            let status = match err {
+                ClientError::Http(err) => match err.as_ref() {
+                    HttpError::StatusCode(response) => response.status().as_str().to_owned(),
+                    _ => "none".to_owned(),
+                },
+                _ => "none".to_owned(),
+            };
+            self.spotify_request_errors
+                .with_label_values(&[method, &endpoint, &status])
+                .inc();
+        }
+    }
+
+    pub fn token_refreshed(&self, account: &str) {
+        self.token_refreshes.with_label_values(&[account]).inc();
+    }
+
+    pub fn session_reset(&self, account: &str) {
+        self.session_resets.with_label_values(&[account]).inc();
+    }
+
+    /// Count an audio stream as active until the guard is dropped
+    pub fn stream_started(&self) -> ActiveStream {
+        self.active_streams.inc();
+        ActiveStream
+    }
+
+    pub fn streamed(&self, bytes: usize) {
+        self.streamed_bytes.inc_by(bytes as u64);
+    }
+
+    pub fn stream_truncated(&self) {
+        self.stream_truncations.inc();
+    }
+
+    /// Render all series in the Prometheus text format,
+    /// with the gauges and counters read from `AppStore`
+    pub async fn render(&self, app_store: &AppStore) -> Result<String, ServerError> {
+        let throttled = counter_vec(
+            "spotify_rate_limiter_throttled_total",
+            "429 responses from Spotify per account",
+            &["account"],
+        );
+        let shed = counter_vec(
+            "spotify_rate_limiter_shed_total",
+            "Requests rejected without calling Spotify per account",
+            &["account"],
+        );
+        let coalesced = counter_vec(
+            "spotify_rate_limiter_coalesced_total",
+            "Requests served by an identical in-flight request per account",
+            &["account"],
+        );
+        {
+            let accounts = app_store.spotify_accounts.read().await;
+            for username in accounts.keys() {
+                if let Some(account) = accounts.get(username.as_ref()) {
+                    let stats = account.client.rate_limiter_stats();
+                    let name = username.as_ref();
+                    for (state, value) in [
+                        ("blocked_seconds", stats.blocked_seconds),
+                        ("queued", stats.queued),
+                        ("in_flight", stats.in_flight),
+                    ] {
+                        self.rate_limiter
+                            .with_label_values(&[name, state])
+                            .set(value as i64);
+                    }
+                    throttled
+                        .with_label_values(&[name])
+                        .inc_by(stats.throttled_total);
+                    shed.with_label_values(&[name]).inc_by(stats.shed_total);
+                    coalesced
+                        .with_label_values(&[name])
+                        .inc_by(stats.coalesced_total);
+                }
+            }
+        }
+
+        let quota_rejections = counter_vec(
+            "quota_rejections_total",
+            "Requests rejected by the server quotas per route group",
+            &["group"],
+        );
+        let quota_stats = app_store.quotas.stats();
+        for (group, rejected) in quota_stats.rejected_total {
+            let group = serde_json::to_value(group)?;
+            quota_rejections
+                .with_label_values(&[group.as_str().unwrap_or_default()])
+                .inc_by(rejected);
+        }
+        quota_rejections
+            .with_label_values(&["concurrent_streams"])
+            .inc_by(quota_stats.rejected_streams_total);
+
+        let mut families = self.registry.gather();
+        for collected in [throttled, shed, coalesced, quota_rejections] {
+            families.extend(collected.collect());
+        }
+        families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+        encode(&families)
+    }
+}
+
+/// A counter of the stats counted elsewhere, which is filled for a render
+fn counter_vec(name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    IntCounterVec::new(Opts::new(name, help), labels).unwrap()
+}
+
+fn encode(families: &[MetricFamily]) -> Result<String, ServerError> {
+    let mut buf = vec![];
+    TextEncoder::new()
+        .encode(families, &mut buf)
+        .map_err(|e| ServerError::InnerError(format!("metrics encode error: {:?}", e)))?;
+    String::from_utf8(buf).map_err(|e| ServerError::InnerError(format!("{:?}", e)))
+}
+
+/// An active audio stream, see `Metrics::stream_started`
+pub struct ActiveStream;
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        METRICS.active_streams.dec();
+    }
+}
+
+/// Replace the ids in a Spotify Web API url with `{id}`, without its query
+fn endpoint_template(url: &str) -> String {
+    let url = url.split_once('?').map_or(url, |(url, _)| url);
+    let path = url
+        .split_once("://")
+        .and_then(|(_, rest)| rest.split_once('/'))
+        .map(|(_, path)| path)
+        .unwrap_or(url);
+    let path = path.trim_start_matches("v1/");
+
+    let mut segments = vec![];
+    let mut prev = "";
+    for segment in path.split('/') {
+        let is_id = prev == "users"
+            || (segment.len() == 22 && segment.chars().all(|c| c.is_ascii_alphanumeric()));
+        segments.push(if is_id { "{id}" } else { segment });
+        prev = segment;
+    }
+    segments.join("/")
+}
+
+/// Middleware which records the count and latency of every request
+///
+/// Routes are labelled by their pattern, e.g. `/albums/{id}`, to keep the
+/// label values bounded.
+pub async fn record(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let started_at = Instant::now();
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_owned());
+    let method = req.method().to_string();
+
+    let result = next.call(req).await;
+
+    let status = match &result {
+        Ok(res) => res.status(),
+        Err(err) => err.as_response_error().status_code(),
+    };
+    let labels = [route.as_str(), method.as_str(), status.as_str()];
+    METRICS.http_requests.with_label_values(&labels).inc();
+    METRICS
+        .http_request_duration
+        .with_label_values(&labels)
+        .observe(started_at.elapsed().as_secs_f64());
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoint_template_replaces_ids_and_drops_the_query() {
+        assert_eq!(
+            endpoint_template(
+                "https://api.spotify.com/v1/albums/4aawyAB9vmqN3uQ7FjRGTy/tracks?limit=50&offset=0"
+            ),
+            "albums/{id}/tracks"
+        );
+        assert_eq!(
+            endpoint_template("https://api.spotify.com/v1/users/someone/playlists?limit=50"),
+            "users/{id}/playlists"
+        );
+        assert_eq!(
+            endpoint_template("https://api.spotify.com/v1/search?q=a/b"),
+            "search"
+        );
+    }
+}
@@ -0,0 +1,817 @@
+//! The MPD commands we support
+//!
+//! The database is virtual: `Playlists/{name} {id}` and `Albums/{artist} - {name} {id}`
+//! directories list the user's playlists and saved albums, and the stored
+//! playlists are the user's Spotify playlists.
+
+use std::{collections::BTreeSet, fmt::Write, ops::Range, sync::LazyLock};
+
+use regex::Regex;
+use rspotify::{
+    clients::{BaseClient, OAuthClient},
+    model::{
+        AlbumId, FullTrack, Id, PlayableId, PlaylistId, SearchResult, SearchType,
+        SimplifiedPlaylist, TrackId,
+    },
+};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    account::{SpotifyAccount, UserName},
+    endpoints::{
+        albums::all_saved_albums,
+        artists::all_followed_artists,
+        audios::{signed_audio_id, signed_audio_uri},
+        playlists::{all_current_user_playlists, all_tracks},
+    },
+    events::{EventKind, EVENTS},
+    quota::RouteGroup,
+};
+
+use super::{queue::Song, Ack, AckCode, Connection, Mpd};
+
+const COMMANDS: [&str; 35] = [
+    "add",
+    "addid",
+    "clear",
+    "close",
+    "command_list_begin",
+    "command_list_end",
+    "command_list_ok_begin",
+    "commands",
+    "currentsong",
+    "delete",
+    "deleteid",
+    "find",
+    "idle",
+    "list",
+    "listplaylist",
+    "listplaylistinfo",
+    "listplaylists",
+    "lsinfo",
+    "noidle",
+    "notcommands",
+    "outputs",
+    "password",
+    "ping",
+    "playlistadd",
+    "playlistid",
+    "playlistinfo",
+    "plchanges",
+    "plchangesposid",
+    "replay_gain_status",
+    "search",
+    "stats",
+    "status",
+    "tagtypes",
+    "urlhandlers",
+    "decoders",
+];
+
+const TAG_TYPES: [&str; 6] = ["Artist", "AlbumArtist", "Album", "Title", "Track", "Date"];
+
+/// Spotify accepts at most 100 items per playlist request
+const MAX_PLAYLIST_ITEMS_PER_REQUEST: usize = 100;
+
+/// Search results per request
+const SEARCH_LIMIT: u32 = 50;
+
+const PLAYLISTS_DIR: &str = "Playlists";
+const ALBUMS_DIR: &str = "Albums";
+
+/// A directory of the virtual database
+enum Listing {
+    Directories(Vec<String>),
+    Songs(Vec<Song>),
+}
+
+fn arg<'a>(args: &'a [String], index: usize, command: &str) -> Result<&'a str, Ack> {
+    args.get(index).map(String::as_str).ok_or_else(|| {
+        Ack::new(
+            AckCode::Arg,
+            format!("wrong number of arguments for \"{}\"", command),
+        )
+    })
+}
+
+/// `POS` or `START:END` of the queue, everything without an argument
+fn range(arg: Option<&String>, len: usize) -> Result<Range<usize>, Ack> {
+    let invalid = || Ack::new(AckCode::Arg, "Bad song index");
+    let range = match arg {
+        None => 0..len,
+        Some(arg) => match arg.split_once(':') {
+            Some((start, "")) => start.parse().map_err(|_| invalid())?..len,
+            Some((start, end)) => {
+                start.parse().map_err(|_| invalid())?..end.parse().map_err(|_| invalid())?
+            }
+            None => {
+                let pos: usize = arg.parse().map_err(|_| invalid())?;
+                pos..pos + 1
+            }
+        },
+    };
+    if range.start > range.end {
+        return Err(invalid());
+    }
+    Ok(range)
+}
+
+/// A `(TAG OP "VALUE")` term of a filter expression
+static FILTER_EXPRESSION: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"\((\w+)\s*(?:==|contains|=~)\s*["']((?:[^"'\\]|\\.)*)["']\)"#)
+        .expect("Invalid filter regex")
+});
+
+/// Tag filters of `find`, `search` and `list`, either `TAG VALUE` pairs or
+/// a filter expression like `((artist == "x") AND (album == "y"))`
+fn filters(args: &[String]) -> Result<Vec<(String, String)>, Ack> {
+    if let [expression] = args {
+        if expression.starts_with('(') {
+            return Ok(FILTER_EXPRESSION
+                .captures_iter(expression)
+                .map(|caps| (caps[1].to_lowercase(), caps[2].replace('\\', "")))
+                .collect());
+        }
+    }
+    let mut filters = vec![];
+    for pair in args.chunks(2) {
+        match pair {
+            [key, _] if ["sort", "window", "group"].contains(&key.as_str()) => {}
+            [tag, value] => filters.push((tag.to_lowercase(), value.clone())),
+            _ => {
+                return Err(Ack::new(
+                    AckCode::Arg,
+                    "Incorrect number of filter arguments",
+                ))
+            }
+        }
+    }
+    Ok(filters)
+}
+
+/// Does the song match the filter, exactly for `find` or ignoring case for `search`
+fn matches(song: &Song, (tag, value): &(String, String), exact: bool) -> bool {
+    let values = match tag.as_str() {
+        "any" => vec![song.tag("title"), song.tag("artist"), song.tag("album")],
+        tag => vec![song.tag(tag)],
+    };
+    values.into_iter().flatten().any(|v| {
+        if exact {
+            v == value
+        } else {
+            v.to_lowercase().contains(&value.to_lowercase())
+        }
+    })
+}
+
+/// The Spotify search query of the filters
+fn search_query(filters: &[(String, String)]) -> String {
+    filters
+        .iter()
+        .map(|(tag, value)| match tag.as_str() {
+            "artist" | "albumartist" => format!("artist:\"{}\"", value),
+            "album" => format!("album:\"{}\"", value),
+            "title" => format!("track:\"{}\"", value),
+            "date" => format!("year:{}", value),
+            _ => value.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// A directory entry name, which can't have `/`
+fn entry_name(name: &str) -> String {
+    name.replace(['/', '\n'], " ")
+}
+
+/// The id at the end of a directory name
+fn directory_id(name: &str) -> &str {
+    name.rsplit(' ').next().unwrap_or(name)
+}
+
+/// The display tag of `list`
+fn tag_name(tag: &str) -> Option<&'static str> {
+    TAG_TYPES
+        .iter()
+        .find(|name| name.eq_ignore_ascii_case(tag))
+        .copied()
+}
+
+impl Mpd {
+    pub(super) async fn execute(
+        &self,
+        conn: &mut Connection,
+        command: &str,
+        args: &[String],
+    ) -> Result<String, Ack> {
+        let mut out = String::new();
+        match command {
+            "password" => {
+                let password = arg(args, 0, command)?;
+                if let Some(expected) = &self.config.password {
+                    if bool::from(expected.as_bytes().ct_eq(password.as_bytes())) {
+                        conn.authenticated = true;
+                        return Ok(out);
+                    }
+                }
+                return Err(Ack::new(AckCode::Password, "incorrect password"));
+            }
+            "ping" => return Ok(out),
+            _ if !conn.authenticated => {
+                return Err(Ack::new(
+                    AckCode::Permission,
+                    format!("you don't have permission for \"{}\"", command),
+                ))
+            }
+            _ => {}
+        }
+
+        match command {
+            "commands" => {
+                for command in COMMANDS {
+                    let _ = writeln!(out, "command: {}", command);
+                }
+            }
+            "tagtypes" => {
+                // `tagtypes clear`, `enable`, ... don't change our responses
+                if args.is_empty() {
+                    for tag in TAG_TYPES {
+                        let _ = writeln!(out, "tagtype: {}", tag);
+                    }
+                }
+            }
+            "urlhandlers" => out.push_str("handler: http://\nhandler: https://\n"),
+            "notcommands" | "decoders" | "outputs" | "currentsong" => {}
+            "replay_gain_status" => out.push_str("replay_gain_mode: off\n"),
+            "status" => {
+                let queue = self.queue.lock().unwrap();
+                let _ = write!(
+                    out,
+                    "volume: -1\nrepeat: 0\nrandom: 0\nsingle: 0\nconsume: 0\n\
+                     playlist: {}\nplaylistlength: {}\nstate: stop\n",
+                    queue.version,
+                    queue.songs.len()
+                );
+            }
+            "stats" => out.push_str("artists: 0\nalbums: 0\nsongs: 0\nuptime: 0\nplaytime: 0\n"),
+            "playlistinfo" | "plchanges" => {
+                let queue = self.queue.lock().unwrap();
+                // All songs of `plchanges`, the queue has no history
+                let arg = args.first().filter(|_| command == "playlistinfo");
+                queue.write(&mut out, range(arg, queue.songs.len())?);
+            }
+            "plchangesposid" => {
+                let queue = self.queue.lock().unwrap();
+                for (pos, (id, _)) in queue.songs.iter().enumerate() {
+                    let _ = write!(out, "cpos: {}\nId: {}\n", pos, id);
+                }
+            }
+            "playlistid" => {
+                let queue = self.queue.lock().unwrap();
+                match args.first() {
+                    Some(id) => {
+                        let pos = queue
+                            .songs
+                            .iter()
+                            .position(|(song_id, _)| song_id.to_string() == *id)
+                            .ok_or_else(|| Ack::new(AckCode::NoExist, "No such song"))?;
+                        queue.write(&mut out, pos..pos + 1);
+                    }
+                    None => queue.write(&mut out, 0..queue.songs.len()),
+                }
+            }
+            "clear" => {
+                let mut queue = self.queue.lock().unwrap();
+                queue.songs.clear();
+                self.queue_changed(&mut queue);
+            }
+            "delete" => {
+                let mut queue = self.queue.lock().unwrap();
+                let range = range(Some(&arg(args, 0, command)?.to_owned()), queue.songs.len())?;
+                if range.end > queue.songs.len() || range.start > range.end {
+                    return Err(Ack::new(AckCode::Arg, "Bad song index"));
+                }
+                queue.songs.drain(range);
+                self.queue_changed(&mut queue);
+            }
+            "deleteid" => {
+                let id = arg(args, 0, command)?;
+                let mut queue = self.queue.lock().unwrap();
+                let len = queue.songs.len();
+                queue.songs.retain(|(song_id, _)| song_id.to_string() != id);
+                if queue.songs.len() == len {
+                    return Err(Ack::new(AckCode::NoExist, "No such song"));
+                }
+                self.queue_changed(&mut queue);
+            }
+            "add" | "addid" => {
+                let account = self.account(RouteGroup::Catalog).await?;
+                let songs = self.resolve(&account, arg(args, 0, command)?).await?;
+                let mut queue = self.queue.lock().unwrap();
+                let ids = songs
+                    .into_iter()
+                    .map(|song| queue.push(song))
+                    .collect::<Vec<_>>();
+                self.queue_changed(&mut queue);
+                if command == "addid" {
+                    if let Some(id) = ids.first() {
+                        let _ = writeln!(out, "Id: {}", id);
+                    }
+                }
+            }
+            "lsinfo" => {
+                let account = self.account(RouteGroup::Catalog).await?;
+                let path = args.first().map(String::as_str).unwrap_or("");
+                match self.directory(&account, path).await? {
+                    Listing::Directories(dirs) => {
+                        for dir in dirs {
+                            let _ = writeln!(out, "directory: {}", dir);
+                        }
+                    }
+                    Listing::Songs(songs) => songs.iter().for_each(|song| song.write(&mut out)),
+                }
+            }
+            "search" | "find" => {
+                let account = self.account(RouteGroup::Catalog).await?;
+                let filters = filters(args)?;
+                for song in self.search(&account, &filters, command == "find").await? {
+                    song.write(&mut out);
+                }
+            }
+            "list" => {
+                let account = self.account(RouteGroup::Catalog).await?;
+                let tag = arg(args, 0, command)?;
+                let Some(tag_name) = tag_name(tag) else {
+                    return Ok(out);
+                };
+                // `list album ARTIST` of old clients
+                let filters = match &args[1..] {
+                    [artist] if tag.eq_ignore_ascii_case("album") => {
+                        vec![("artist".to_owned(), artist.clone())]
+                    }
+                    args => filters(args)?,
+                };
+                for value in self.list(&account, tag, &filters).await? {
+                    let _ = writeln!(out, "{}: {}", tag_name, value);
+                }
+            }
+            "listplaylists" => {
+                let account = self.account(RouteGroup::Catalog).await?;
+                for playlist in all_current_user_playlists(&account).await? {
+                    let _ = write!(
+                        out,
+                        "playlist: {}\nLast-Modified: 1970-01-01T00:00:00Z\n",
+                        playlist.name
+                    );
+                }
+            }
+            "listplaylist" | "listplaylistinfo" => {
+                let account = self.account(RouteGroup::Catalog).await?;
+                let playlist = self
+                    .stored_playlist(&account, arg(args, 0, command)?)
+                    .await?;
+                for song in self.playlist_songs(&account, playlist.id).await? {
+                    if command == "listplaylist" {
+                        let _ = writeln!(out, "file: {}", song.file);
+                    } else {
+                        song.write(&mut out);
+                    }
+                }
+            }
+            "playlistadd" => {
+                let account = self.account(RouteGroup::LibraryWrite).await?;
+                let playlist = self
+                    .stored_playlist(&account, arg(args, 0, command)?)
+                    .await?;
+                let songs = self.resolve(&account, arg(args, 1, command)?).await?;
+                let track_ids = songs
+                    .iter()
+                    .filter_map(|song| TrackId::from_uri(&song.uri).ok())
+                    .collect::<Vec<_>>();
+                for chunk in track_ids.chunks(MAX_PLAYLIST_ITEMS_PER_REQUEST) {
+                    account
+                        .client
+                        .playlist_add_items(
+                            playlist.id.clone(),
+                            chunk.iter().map(|id| PlayableId::Track(id.clone())),
+                            None,
+                        )
+                        .await
+                        .map_err(crate::errors::ServerError::from)?;
+                }
+                if !track_ids.is_empty() {
+                    EVENTS.emit(
+                        EventKind::PlaylistModified,
+                        &self.config.username,
+                        serde_json::json!({ "id": playlist.id.id(), "change": "items_added" }),
+                    );
+                }
+            }
+            "play" | "playid" | "pause" | "stop" | "next" | "previous" | "seek" | "seekid"
+            | "seekcur" | "setvol" => {
+                return Err(Ack::new(
+                    AckCode::System,
+                    "Playback is not supported, stream the song files instead",
+                ))
+            }
+            command => {
+                return Err(Ack::new(
+                    AckCode::Unknown,
+                    format!("unknown command \"{}\"", command),
+                ))
+            }
+        }
+        Ok(out)
+    }
+
+    /// The account of the configured user, charged against its quotas as
+    /// the Subsonic requests are
+    async fn account(
+        &self,
+        group: RouteGroup,
+    ) -> Result<tokio::sync::RwLockReadGuard<'_, SpotifyAccount>, Ack> {
+        let username = self.config.username.as_str();
+        self.app_store
+            .quotas
+            .check(group, username, Some(username))?;
+        Ok(self.app_store.authorize(username).await?)
+    }
+
+    /// The stream url of a track
+    fn file(&self, account: &SpotifyAccount, uri: &str) -> Result<String, Ack> {
+        let username = UserName::from(self.config.username.as_str());
+        Ok(format!(
+            "{}{}",
+            self.config.base_url,
+            signed_audio_uri(account, &username, uri)?
+        ))
+    }
+
+    fn songs(&self, account: &SpotifyAccount, tracks: &[FullTrack]) -> Result<Vec<Song>, Ack> {
+        let mut songs = vec![];
+        for track in tracks {
+            if let Some(id) = &track.id {
+                songs.extend(Song::from_track(track, self.file(account, &id.uri())?));
+            }
+        }
+        Ok(songs)
+    }
+
+    /// The songs of a song file, a `spotify:track:` uri or a directory
+    async fn resolve(&self, account: &SpotifyAccount, uri: &str) -> Result<Vec<Song>, Ack> {
+        let track_uri = if let Some((_, query)) = uri
+            .split_once("/audio-stream-with-sign/")
+            .and_then(|(_, rest)| rest.split_once('?'))
+        {
+            let params = url::form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .collect::<std::collections::HashMap<_, _>>();
+            match (params.get("sign"), params.get("iv")) {
+                (Some(sign), Some(iv)) => Some(signed_audio_id(account.sign_key(), sign, iv)?),
+                _ => return Err(Ack::new(AckCode::NoExist, "Invalid song file")),
+            }
+        } else if uri.starts_with("spotify:track:") {
+            Some(uri.to_owned())
+        } else {
+            None
+        };
+
+        match track_uri {
+            Some(track_uri) => {
+                let track_id = TrackId::from_uri(&track_uri)
+                    .map_err(|_| Ack::new(AckCode::NoExist, "No such song"))?;
+                let market = account.default_market().await;
+                let track = account
+                    .client
+                    .track(track_id, Some(market))
+                    .await
+                    .map_err(crate::errors::ServerError::from)?;
+                self.songs(account, &[track])
+            }
+            None => match self.directory(account, uri).await? {
+                Listing::Songs(songs) => Ok(songs),
+                Listing::Directories(_) => Err(Ack::new(
+                    AckCode::Arg,
+                    "Only playlist and album directories can be added",
+                )),
+            },
+        }
+    }
+
+    /// `lsinfo` of the virtual database
+    async fn directory(&self, account: &SpotifyAccount, path: &str) -> Result<Listing, Ack> {
+        let path = path.trim_matches('/');
+        let (root, name) = match path.split_once('/') {
+            Some((root, name)) => (root, Some(name)),
+            None => (path, None),
+        };
+        match (root, name) {
+            ("", None) => Ok(Listing::Directories(vec![
+                PLAYLISTS_DIR.to_owned(),
+                ALBUMS_DIR.to_owned(),
+            ])),
+            (PLAYLISTS_DIR, None) => {
+                let playlists = all_current_user_playlists(account).await?;
+                Ok(Listing::Directories(
+                    playlists
+                        .iter()
+                        .map(|playlist| {
+                            format!(
+                                "{}/{} {}",
+                                PLAYLISTS_DIR,
+                                entry_name(&playlist.name),
+                                playlist.id.id()
+                            )
+                        })
+                        .collect(),
+                ))
+            }
+            (PLAYLISTS_DIR, Some(name)) => {
+                let playlist_id = PlaylistId::from_id(directory_id(name))
+                    .map_err(|_| Ack::new(AckCode::NoExist, "No such directory"))?;
+                Ok(Listing::Songs(
+                    self.playlist_songs(account, playlist_id).await?,
+                ))
+            }
+            (ALBUMS_DIR, None) => {
+                let market = account.default_market().await;
+                let albums = all_saved_albums(account, Some(market)).await?;
+                Ok(Listing::Directories(
+                    albums
+                        .iter()
+                        .map(|saved| {
+                            let album = &saved.album;
+                            let artist = album
+                                .artists
+                                .first()
+                                .map(|artist| artist.name.as_str())
+                                .unwrap_or_default();
+                            format!(
+                                "{}/{} - {} {}",
+                                ALBUMS_DIR,
+                                entry_name(artist),
+                                entry_name(&album.name),
+                                album.id.id()
+                            )
+                        })
+                        .collect(),
+                ))
+            }
+            (ALBUMS_DIR, Some(name)) => {
+                let album_id = AlbumId::from_id(directory_id(name))
+                    .map_err(|_| Ack::new(AckCode::NoExist, "No such directory"))?;
+                let market = account.default_market().await;
+                let album = account
+                    .client
+                    .album(album_id, Some(market))
+                    .await
+                    .map_err(crate::errors::ServerError::from)?;
+                let mut songs = vec![];
+                for track in &album.tracks.items {
+                    if let Some(id) = &track.id {
+                        let file = self.file(account, &id.uri())?;
+                        songs.extend(Song::from_album_track(track, &album, file));
+                    }
+                }
+                Ok(Listing::Songs(songs))
+            }
+            _ => Err(Ack::new(AckCode::NoExist, "No such directory")),
+        }
+    }
+
+    async fn playlist_songs(
+        &self,
+        account: &SpotifyAccount,
+        playlist_id: PlaylistId<'_>,
+    ) -> Result<Vec<Song>, Ack> {
+        let market = account.default_market().await;
+        let tracks = all_tracks(account, playlist_id, None, Some(market))
+            .await?
+            .into_iter()
+            .filter_map(|item| match item.track {
+                Some(rspotify::model::PlayableItem::Track(track)) => Some(track),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        self.songs(account, &tracks)
+    }
+
+    /// The user's playlist with the name
+    async fn stored_playlist(
+        &self,
+        account: &SpotifyAccount,
+        name: &str,
+    ) -> Result<SimplifiedPlaylist, Ack> {
+        all_current_user_playlists(account)
+            .await?
+            .into_iter()
+            .find(|playlist| playlist.name == name)
+            .ok_or_else(|| Ack::new(AckCode::NoExist, "No such playlist"))
+    }
+
+    /// Songs matching the filters, exactly for `find`
+    async fn search(
+        &self,
+        account: &SpotifyAccount,
+        filters: &[(String, String)],
+        exact: bool,
+    ) -> Result<Vec<Song>, Ack> {
+        if filters.is_empty() {
+            return Err(Ack::new(AckCode::Arg, "No filter given"));
+        }
+        let market = account.default_market().await;
+        let result = account
+            .client
+            .search(
+                &search_query(filters),
+                SearchType::Track,
+                Some(market),
+                None,
+                Some(SEARCH_LIMIT),
+                None,
+            )
+            .await
+            .map_err(crate::errors::ServerError::from)?;
+        let tracks = match result {
+            SearchResult::Tracks(page) => page.items,
+            _ => vec![],
+        };
+        Ok(self
+            .songs(account, &tracks)?
+            .into_iter()
+            .filter(|song| filters.iter().all(|filter| matches(song, filter, exact)))
+            .collect())
+    }
+
+    /// The values of a tag, from the library without filters, or from the
+    /// songs matching the filters
+    async fn list(
+        &self,
+        account: &SpotifyAccount,
+        tag: &str,
+        filters: &[(String, String)],
+    ) -> Result<BTreeSet<String>, Ack> {
+        if !filters.is_empty() {
+            return Ok(self
+                .search(account, filters, true)
+                .await?
+                .iter()
+                .filter_map(|song| song.tag(tag).map(str::to_owned))
+                .collect());
+        }
+        match tag.to_lowercase().as_str() {
+            "artist" | "albumartist" => Ok(all_followed_artists(account)
+                .await?
+                .into_iter()
+                .map(|artist| artist.name)
+                .collect()),
+            "album" => {
+                let market = account.default_market().await;
+                Ok(all_saved_albums(account, Some(market))
+                    .await?
+                    .into_iter()
+                    .map(|saved| saved.album.name)
+                    .collect())
+            }
+            _ => Ok(BTreeSet::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use actix_web::web;
+    use tokio::sync::watch;
+
+    use super::*;
+    use crate::{
+        app_store::AppStore,
+        mpd::{queue::Queue, MpdConfig},
+        quota::{QuotaConfig, Quotas},
+    };
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn mpd(app_store: AppStore) -> Mpd {
+        Mpd {
+            config: MpdConfig {
+                bind: "127.0.0.1".to_owned(),
+                port: 6600,
+                username: "bob".to_owned(),
+                password: Some("secret".to_owned()),
+                base_url: "http://127.0.0.1:8080".to_owned(),
+            },
+            app_store: web::Data::new(app_store),
+            queue: Mutex::new(Queue::default()),
+            queue_version: watch::channel(0).0,
+        }
+    }
+
+    #[tokio::test]
+    async fn password_authenticates() {
+        let mpd = mpd(AppStore::new("client", "/tmp", None));
+        let mut conn = Connection {
+            authenticated: false,
+        };
+
+        let err = mpd.execute(&mut conn, "status", &[]).await.unwrap_err();
+        assert!(matches!(err.code, AckCode::Permission));
+        for password in ["", "secre", "secret!"] {
+            let err = mpd
+                .execute(&mut conn, "password", &strings(&[password]))
+                .await
+                .unwrap_err();
+            assert!(matches!(err.code, AckCode::Password));
+            assert!(!conn.authenticated);
+        }
+        mpd.execute(&mut conn, "password", &strings(&["secret"]))
+            .await
+            .unwrap();
+        assert!(conn.authenticated);
+    }
+
+    #[tokio::test]
+    async fn commands_are_charged_against_quotas() {
+        let quotas = Quotas::new(QuotaConfig {
+            catalog: 1,
+            ..Default::default()
+        });
+        let mpd = mpd(AppStore::new("client", "/tmp", None).with_quotas(quotas));
+        let mut conn = Connection {
+            authenticated: true,
+        };
+
+        // The first lookup passes the quota, and fails without an account
+        let err = mpd.execute(&mut conn, "lsinfo", &[]).await.unwrap_err();
+        assert!(!err.message.contains("Too many requests"));
+        let err = mpd.execute(&mut conn, "lsinfo", &[]).await.unwrap_err();
+        assert!(err.message.contains("catalog limit"));
+        // Commands without the account are not charged
+        mpd.execute(&mut conn, "status", &[]).await.unwrap();
+    }
+
+    #[test]
+    fn ranges() {
+        let arg = |s: &str| s.to_owned();
+        assert_eq!(range(None, 5).unwrap(), 0..5);
+        assert_eq!(range(Some(&arg("2")), 5).unwrap(), 2..3);
+        assert_eq!(range(Some(&arg("1:3")), 5).unwrap(), 1..3);
+        assert_eq!(range(Some(&arg("3:")), 5).unwrap(), 3..5);
+        assert!(range(Some(&arg("3:1")), 5).is_err());
+        assert!(range(Some(&arg("x")), 5).is_err());
+        assert!(range(Some(&arg("-1")), 5).is_err());
+    }
+
+    #[test]
+    fn tag_value_filters() {
+        let args = strings(&["Artist", "Daft Punk", "album", "Discovery", "sort", "Track"]);
+        assert_eq!(
+            filters(&args).unwrap(),
+            vec![
+                ("artist".to_owned(), "Daft Punk".to_owned()),
+                ("album".to_owned(), "Discovery".to_owned()),
+            ]
+        );
+        assert!(filters(&strings(&["artist"])).is_err());
+    }
+
+    #[test]
+    fn filter_expressions() {
+        let args = strings(&[r#"((Artist == "Daft Punk") AND (album contains 'Dis\'covery'))"#]);
+        assert_eq!(
+            filters(&args).unwrap(),
+            vec![
+                ("artist".to_owned(), "Daft Punk".to_owned()),
+                ("album".to_owned(), "Dis'covery".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn search_queries() {
+        let filters = vec![
+            ("artist".to_owned(), "Daft Punk".to_owned()),
+            ("title".to_owned(), "One More Time".to_owned()),
+            ("date".to_owned(), "2001".to_owned()),
+            ("any".to_owned(), "robot".to_owned()),
+        ];
+        assert_eq!(
+            search_query(&filters),
+            r#"artist:"Daft Punk" track:"One More Time" year:2001 robot"#
+        );
+    }
+
+    #[test]
+    fn directory_names() {
+        assert_eq!(entry_name("AC/DC\nLive"), "AC DC Live");
+        assert_eq!(
+            directory_id("Discovery 2noRn2Aes5aoNVsU6iWThc"),
+            "2noRn2Aes5aoNVsU6iWThc"
+        );
+        assert_eq!(tag_name("albumartist"), Some("AlbumArtist"));
+    }
+}
@@ -0,0 +1,313 @@
+//! Music Player Daemon protocol frontend
+//!
+//! A subset of the MPD protocol over TCP, backed by the Spotify account of
+//! `--mpd-user`, so MPD clients like ncmpcpp can browse and queue Spotify
+//! content. The server doesn't play audio itself: the song files are
+//! `/audio-stream-with-sign` urls which clients can stream.
+
+mod commands;
+mod queue;
+
+use std::sync::{Arc, Mutex};
+
+use actix_web::web;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
+
+use crate::app_store::AppStore;
+
+use self::queue::Queue;
+
+/// The protocol version we announce
+const PROTOCOL_VERSION: &str = "0.23.5";
+
+/// Longer command lines close the connection, so clients can't grow the
+/// line buffer without bound
+const MAX_LINE_LENGTH: u64 = 64 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct MpdConfig {
+    pub bind: String,
+    pub port: u16,
+    /// The server user whose Spotify account backs the MPD frontend
+    pub username: String,
+    /// Clients must send `password` first when it is set
+    pub password: Option<String>,
+    /// Prefix of the song urls, e.g. `http://127.0.0.1:8080`
+    pub base_url: String,
+}
+
+/// MPD error codes of `ACK` responses
+#[derive(Clone, Copy, Debug)]
+enum AckCode {
+    Arg = 2,
+    Password = 3,
+    Permission = 4,
+    Unknown = 5,
+    NoExist = 50,
+    System = 52,
+}
+
+/// An `ACK` response
+#[derive(Debug)]
+struct Ack {
+    code: AckCode,
+    message: String,
+}
+
+impl Ack {
+    fn new(code: AckCode, message: impl Into<String>) -> Self {
+        Ack {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn format(&self, index: usize, command: &str) -> String {
+        format!(
+            "ACK [{}@{}] {{{}}} {}\n",
+            self.code as u32, index, command, self.message
+        )
+    }
+}
+
+impl From<crate::errors::ServerError> for Ack {
+    fn from(err: crate::errors::ServerError) -> Self {
+        Ack::new(AckCode::System, err.to_string())
+    }
+}
+
+/// Shared by all connections
+struct Mpd {
+    config: MpdConfig,
+    app_store: web::Data<AppStore>,
+    queue: Mutex<Queue>,
+    /// The queue version, which `idle` waits on
+    queue_version: watch::Sender<u32>,
+}
+
+/// State of one client connection
+struct Connection {
+    authenticated: bool,
+}
+
+/// Listen for MPD clients until the server stops
+pub async fn serve(config: MpdConfig, app_store: web::Data<AppStore>) -> std::io::Result<()> {
+    let listener = TcpListener::bind((config.bind.as_str(), config.port)).await?;
+    tracing::info!("MPD frontend listening on {}", listener.local_addr()?);
+
+    let mpd = Arc::new(Mpd {
+        config,
+        app_store,
+        queue: Mutex::new(Queue::default()),
+        queue_version: watch::channel(0).0,
+    });
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let mpd = mpd.clone();
+        tokio::spawn(async move {
+            if let Err(err) = mpd.handle(stream).await {
+                tracing::warn!("MPD connection {} failed: {}", addr, err);
+            }
+        });
+    }
+}
+
+impl Mpd {
+    async fn handle(&self, stream: TcpStream) -> std::io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut conn = Connection {
+            authenticated: self.config.password.is_none(),
+        };
+        writer
+            .write_all(format!("OK MPD {}\n", PROTOCOL_VERSION).as_bytes())
+            .await?;
+
+        // `Some(list_OK after each command)` inside a command list
+        let mut command_list: Option<(bool, Vec<String>)> = None;
+        while let Some(line) = next_line(&mut reader).await? {
+            if let Some((list_ok, commands)) = &mut command_list {
+                if line == "command_list_end" {
+                    let response = self.execute_list(&mut conn, commands, *list_ok).await;
+                    writer.write_all(response.as_bytes()).await?;
+                    command_list = None;
+                } else {
+                    commands.push(line);
+                }
+                continue;
+            }
+
+            match line.as_str() {
+                "command_list_begin" => command_list = Some((false, vec![])),
+                "command_list_ok_begin" => command_list = Some((true, vec![])),
+                "close" => break,
+                line if line == "idle" || line.starts_with("idle ") => {
+                    let mut version = self.queue_version.subscribe();
+                    let watches_queue = line == "idle" || line.contains("playlist");
+                    tokio::select! {
+                        changed = version.changed(), if watches_queue => {
+                            if changed.is_ok() {
+                                writer.write_all(b"changed: playlist\nOK\n").await?;
+                            }
+                        }
+                        next = next_line(&mut reader) => match next?.as_deref() {
+                            Some("noidle") => writer.write_all(b"OK\n").await?,
+                            _ => break,
+                        },
+                    }
+                }
+                // Only meaningful while idle
+                "noidle" => {}
+                line => {
+                    let response = self
+                        .execute_list(&mut conn, &[line.to_owned()], false)
+                        .await;
+                    writer.write_all(response.as_bytes()).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Run commands until one fails, as command lists do
+    async fn execute_list(&self, conn: &mut Connection, lines: &[String], list_ok: bool) -> String {
+        let mut response = String::new();
+        for (index, line) in lines.iter().enumerate() {
+            let args = match tokenize(line) {
+                Ok(args) => args,
+                Err(message) => {
+                    return response + &Ack::new(AckCode::Arg, message).format(index, "");
+                }
+            };
+            let Some((command, args)) = args.split_first() else {
+                return response
+                    + &Ack::new(AckCode::Unknown, "No command given").format(index, "");
+            };
+            match self.execute(conn, command, args).await {
+                Ok(output) => {
+                    response.push_str(&output);
+                    if list_ok {
+                        response.push_str("list_OK\n");
+                    }
+                }
+                Err(ack) => {
+                    tracing::debug!("MPD {} failed: {:?}", command, ack);
+                    return response + &ack.format(index, command);
+                }
+            }
+        }
+        response + "OK\n"
+    }
+
+    /// Bump the queue version and wake up idle clients
+    fn queue_changed(&self, queue: &mut Queue) {
+        queue.version += 1;
+        self.queue_version.send_replace(queue.version);
+    }
+}
+
+/// Read a line without its line break, `None` at the end of the stream
+async fn next_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> std::io::Result<Option<String>> {
+    let mut line = vec![];
+    let n = reader
+        .take(MAX_LINE_LENGTH + 1)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    } else if n as u64 > MAX_LINE_LENGTH {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Command line too long",
+        ));
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Split a command line into its arguments, which may be double-quoted
+fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut args = vec![];
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&c) = chars.peek() else {
+            return Ok(args);
+        };
+        let mut arg = String::new();
+        if c == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c) => arg.push(c),
+                        None => return Err("Unterminated quoted argument".to_owned()),
+                    },
+                    Some(c) => arg.push(c),
+                    None => return Err("Unterminated quoted argument".to_owned()),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                arg.push(c);
+            }
+        }
+        args.push(arg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_quoted_arguments() {
+        assert_eq!(tokenize("status").unwrap(), vec!["status"]);
+        assert_eq!(
+            tokenize(r#"find  artist "Daft Punk"  album Discovery"#).unwrap(),
+            vec!["find", "artist", "Daft Punk", "album", "Discovery"]
+        );
+        assert_eq!(
+            tokenize(r#"search any "say \"hi\" \\ bye""#).unwrap(),
+            vec!["search", "any", r#"say "hi" \ bye"#]
+        );
+        assert_eq!(tokenize(r#"add """#).unwrap(), vec!["add", ""]);
+        assert!(tokenize("   ").unwrap().is_empty());
+        assert!(tokenize(r#"find "artist"#).is_err());
+        assert!(tokenize(r#"find "artist\"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn lines_are_capped() {
+        let mut reader = BufReader::new(&b"status\r\nplay 1\nlast"[..]);
+        assert_eq!(
+            next_line(&mut reader).await.unwrap().as_deref(),
+            Some("status")
+        );
+        assert_eq!(
+            next_line(&mut reader).await.unwrap().as_deref(),
+            Some("play 1")
+        );
+        assert_eq!(
+            next_line(&mut reader).await.unwrap().as_deref(),
+            Some("last")
+        );
+        assert_eq!(next_line(&mut reader).await.unwrap(), None);
+
+        let long = vec![b'a'; MAX_LINE_LENGTH as usize + 10];
+        let mut reader = BufReader::new(&long[..]);
+        assert!(next_line(&mut reader).await.is_err());
+    }
+}
@@ -0,0 +1,124 @@
+//! The play queue and its songs
+
+use std::fmt::Write;
+
+use rspotify::model::{FullAlbum, FullTrack, Id, SimplifiedArtist, SimplifiedTrack};
+
+/// A Spotify track as an MPD song
+#[derive(Clone, Debug)]
+pub struct Song {
+    /// The signed stream url
+    pub file: String,
+    /// `spotify:track:{id}`
+    pub uri: String,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub album_artist: String,
+    pub track: u32,
+    pub disc: i32,
+    pub date: Option<String>,
+    /// Seconds
+    pub duration: i64,
+}
+
+fn artist_names(artists: &[SimplifiedArtist]) -> String {
+    artists
+        .iter()
+        .map(|artist| artist.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Song {
+    /// `file` of the track, which has a Spotify id
+    pub fn from_track(track: &FullTrack, file: String) -> Option<Song> {
+        Some(Song {
+            file,
+            uri: track.id.as_ref()?.uri(),
+            title: track.name.clone(),
+            artist: artist_names(&track.artists),
+            album: track.album.name.clone(),
+            album_artist: artist_names(&track.album.artists),
+            track: track.track_number,
+            disc: track.disc_number,
+            date: track.album.release_date.clone(),
+            duration: track.duration.num_seconds(),
+        })
+    }
+
+    pub fn from_album_track(
+        track: &SimplifiedTrack,
+        album: &FullAlbum,
+        file: String,
+    ) -> Option<Song> {
+        Some(Song {
+            file,
+            uri: track.id.as_ref()?.uri(),
+            title: track.name.clone(),
+            artist: artist_names(&track.artists),
+            album: album.name.clone(),
+            album_artist: artist_names(&album.artists),
+            track: track.track_number,
+            disc: track.disc_number,
+            date: Some(album.release_date.clone()),
+            duration: track.duration.num_seconds(),
+        })
+    }
+
+    /// The value of a tag, for `find` filters
+    pub fn tag(&self, tag: &str) -> Option<&str> {
+        match tag.to_lowercase().as_str() {
+            "file" => Some(&self.file),
+            "title" => Some(&self.title),
+            "artist" => Some(&self.artist),
+            "album" => Some(&self.album),
+            "albumartist" => Some(&self.album_artist),
+            "date" => self.date.as_deref(),
+            _ => None,
+        }
+    }
+
+    pub fn write(&self, out: &mut String) {
+        let _ = writeln!(out, "file: {}", self.file);
+        let _ = writeln!(out, "Title: {}", self.title);
+        let _ = writeln!(out, "Artist: {}", self.artist);
+        let _ = writeln!(out, "Album: {}", self.album);
+        let _ = writeln!(out, "AlbumArtist: {}", self.album_artist);
+        let _ = writeln!(out, "Track: {}", self.track);
+        let _ = writeln!(out, "Disc: {}", self.disc);
+        if let Some(date) = &self.date {
+            let _ = writeln!(out, "Date: {}", date);
+        }
+        let _ = writeln!(out, "Time: {}", self.duration);
+        let _ = writeln!(out, "duration: {}.000", self.duration);
+    }
+}
+
+/// The play queue, which MPD calls the current playlist
+#[derive(Default)]
+pub struct Queue {
+    pub songs: Vec<(u32, Song)>,
+    pub version: u32,
+    next_id: u32,
+}
+
+impl Queue {
+    /// Add a song, returning its id
+    pub fn push(&mut self, song: Song) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.songs.push((id, song));
+        id
+    }
+
+    pub fn write(&self, out: &mut String, range: std::ops::Range<usize>) {
+        for (pos, (id, song)) in self.songs.iter().enumerate() {
+            if range.contains(&pos) {
+                song.write(out);
+                let _ = writeln!(out, "Pos: {}", pos);
+                let _ = writeln!(out, "Id: {}", id);
+            }
+        }
+    }
+}
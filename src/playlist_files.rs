@@ -0,0 +1,539 @@
+//! Playlist files: M3U8, XSPF, JSPF and CSV
+
+use std::fmt::Write;
+
+use regex::Regex;
+
+use crate::{
+    common::xml::{escape, unescape},
+    errors::ServerError,
+};
+
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaylistFormat {
+    M3u8,
+    Xspf,
+    Jspf,
+    Csv,
+}
+
+impl PlaylistFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            PlaylistFormat::M3u8 => "audio/x-mpegurl; charset=utf-8",
+            PlaylistFormat::Xspf => "application/xspf+xml; charset=utf-8",
+            PlaylistFormat::Jspf => "application/json; charset=utf-8",
+            PlaylistFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            PlaylistFormat::M3u8 => "m3u8",
+            PlaylistFormat::Xspf => "xspf",
+            PlaylistFormat::Jspf => "jspf",
+            PlaylistFormat::Csv => "csv",
+        }
+    }
+}
+
+/// A row of a playlist file, whose missing texts are empty
+#[derive(Debug, Default, Clone, serde::Serialize)]
+pub struct Entry {
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub duration_ms: i64,
+    pub isrc: Option<String>,
+    /// A Spotify URI or a stream url
+    pub location: String,
+}
+
+#[derive(Debug, Default)]
+pub struct PlaylistFile {
+    pub title: Option<String>,
+    pub entries: Vec<Entry>,
+}
+
+impl PlaylistFile {
+    /// The file in the format
+    ///
+    /// M3U8 has no field for the ISRC, so it is left out there.
+    pub fn export(&self, format: PlaylistFormat) -> Result<String, ServerError> {
+        match format {
+            PlaylistFormat::M3u8 => Ok(self.m3u8()),
+            PlaylistFormat::Xspf => Ok(self.xspf()),
+            PlaylistFormat::Jspf => Ok(serde_json::to_string_pretty(&Jspf::from(self))?),
+            PlaylistFormat::Csv => Ok(self.csv()),
+        }
+    }
+
+    pub fn parse(format: PlaylistFormat, text: &str) -> Result<Self, ServerError> {
+        let text = text.trim_start_matches('\u{feff}');
+        match format {
+            PlaylistFormat::M3u8 => Ok(parse_m3u8(text)),
+            PlaylistFormat::Xspf => parse_xspf(text),
+            PlaylistFormat::Jspf => serde_json::from_str::<Jspf>(text)
+                .map(PlaylistFile::from)
+                .map_err(|err| ServerError::ParamsError(format!("Invalid JSPF: {}", err))),
+            PlaylistFormat::Csv => parse_csv(text),
+        }
+    }
+
+    fn m3u8(&self) -> String {
+        let mut out = String::from("#EXTM3U\n");
+        if let Some(title) = &self.title {
+            let _ = writeln!(out, "#PLAYLIST:{}", one_line(title));
+        }
+        for entry in &self.entries {
+            let name = if entry.artist.is_empty() {
+                one_line(&entry.title)
+            } else {
+                format!("{} - {}", one_line(&entry.artist), one_line(&entry.title))
+            };
+            let _ = writeln!(out, "#EXTINF:{},{}", entry.duration_ms / 1000, name);
+            if !entry.album.is_empty() {
+                let _ = writeln!(out, "#EXTALB:{}", one_line(&entry.album));
+            }
+            let _ = writeln!(out, "{}", entry.location);
+        }
+        out
+    }
+
+    fn xspf(&self) -> String {
+        let mut out = String::from(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n",
+        );
+        if let Some(title) = &self.title {
+            let _ = writeln!(out, "<title>{}</title>", escape(title));
+        }
+        out.push_str("<trackList>\n");
+        for entry in &self.entries {
+            out.push_str("<track>");
+            let _ = write!(out, "<location>{}</location>", escape(&entry.location));
+            if let Some(isrc) = &entry.isrc {
+                let _ = write!(out, "<identifier>urn:isrc:{}</identifier>", escape(isrc));
+            }
+            let _ = write!(
+                out,
+                "<title>{}</title><creator>{}</creator><album>{}</album><duration>{}</duration>",
+                escape(&entry.title),
+                escape(&entry.artist),
+                escape(&entry.album),
+                entry.duration_ms
+            );
+            out.push_str("</track>\n");
+        }
+        out.push_str("</trackList>\n</playlist>\n");
+        out
+    }
+
+    fn csv(&self) -> String {
+        let mut out = CSV_COLUMNS.join(",");
+        out.push('\n');
+        for entry in &self.entries {
+            let row = [
+                csv_field(&entry.title),
+                csv_field(&entry.artist),
+                csv_field(&entry.album),
+                entry.duration_ms.to_string(),
+                csv_field(entry.isrc.as_deref().unwrap_or_default()),
+                csv_field(&entry.location),
+            ];
+            out.push_str(&row.join(","));
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// `#EXTINF` and `#EXTALB` values end at the line
+fn one_line(text: &str) -> String {
+    text.replace(['\r', '\n'], " ")
+}
+
+fn parse_m3u8(text: &str) -> PlaylistFile {
+    let mut file = PlaylistFile::default();
+    let mut entry = Entry::default();
+    for line in text.lines().map(str::trim) {
+        if let Some(title) = line.strip_prefix("#PLAYLIST:") {
+            file.title = Some(title.to_owned());
+        } else if let Some(info) = line.strip_prefix("#EXTINF:") {
+            let (seconds, name) = info.split_once(',').unwrap_or((info, ""));
+            // Attributes may follow the duration, as `-1 tvg-id="..."`
+            let seconds = seconds.split_whitespace().next().unwrap_or_default();
+            entry.duration_ms = seconds.parse::<f64>().map_or(0, |s| (s * 1000.0) as i64);
+            match name.split_once(" - ") {
+                Some((artist, title)) => {
+                    entry.artist = artist.trim().to_owned();
+                    entry.title = title.trim().to_owned();
+                }
+                None => entry.title = name.trim().to_owned(),
+            }
+        } else if let Some(album) = line.strip_prefix("#EXTALB:") {
+            entry.album = album.to_owned();
+        } else if !line.is_empty() && !line.starts_with('#') {
+            entry.location = line.to_owned();
+            file.entries.push(std::mem::take(&mut entry));
+        }
+    }
+    file
+}
+
+/// The text of the first `name` element
+fn element(xml: &str, name: &str) -> Option<String> {
+    elements(xml, name).into_iter().next()
+}
+
+/// The texts of the `name` elements
+fn elements(xml: &str, name: &str) -> Vec<String> {
+    let re = Regex::new(&format!(
+        r"(?s)<{name}(?:\s[^>]*)?>(.*?)</{name}>",
+        name = regex::escape(name)
+    ))
+    .expect("valid element regex");
+    re.captures_iter(xml)
+        .map(|caps| unescape(caps[1].trim()))
+        .collect()
+}
+
+fn parse_xspf(text: &str) -> Result<PlaylistFile, ServerError> {
+    let (head, tracks) = text
+        .split_once("<trackList>")
+        .ok_or_else(|| ServerError::ParamsError("Invalid XSPF: no trackList".to_owned()))?;
+    let entries = Regex::new(r"(?s)<track(?:\s[^>]*)?>(.*?)</track>")
+        .expect("valid track regex")
+        .captures_iter(tracks)
+        .map(|caps| {
+            let track = &caps[1];
+            Entry {
+                title: element(track, "title").unwrap_or_default(),
+                artist: element(track, "creator").unwrap_or_default(),
+                album: element(track, "album").unwrap_or_default(),
+                duration_ms: element(track, "duration")
+                    .and_then(|duration| duration.parse().ok())
+                    .unwrap_or_default(),
+                isrc: isrc(&elements(track, "identifier")),
+                location: element(track, "location").unwrap_or_default(),
+            }
+        })
+        .collect();
+    Ok(PlaylistFile {
+        title: element(head, "title"),
+        entries,
+    })
+}
+
+/// The ISRC of `urn:isrc:` identifiers
+fn isrc(identifiers: &[String]) -> Option<String> {
+    identifiers
+        .iter()
+        .find_map(|identifier| identifier.strip_prefix("urn:isrc:"))
+        .map(str::to_owned)
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Jspf {
+    playlist: JspfPlaylist,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct JspfPlaylist {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(default)]
+    track: Vec<JspfTrack>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct JspfTrack {
+    #[serde(default)]
+    location: Vec<String>,
+    #[serde(default)]
+    identifier: Vec<String>,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    creator: Option<String>,
+    #[serde(default)]
+    album: Option<String>,
+    #[serde(default)]
+    duration: Option<i64>,
+}
+
+impl From<&PlaylistFile> for Jspf {
+    fn from(file: &PlaylistFile) -> Self {
+        let track = file
+            .entries
+            .iter()
+            .map(|entry| JspfTrack {
+                location: vec![entry.location.clone()],
+                identifier: entry
+                    .isrc
+                    .iter()
+                    .map(|isrc| format!("urn:isrc:{}", isrc))
+                    .collect(),
+                title: Some(entry.title.clone()),
+                creator: Some(entry.artist.clone()),
+                album: Some(entry.album.clone()),
+                duration: Some(entry.duration_ms),
+            })
+            .collect();
+        Jspf {
+            playlist: JspfPlaylist {
+                title: file.title.clone(),
+                track,
+            },
+        }
+    }
+}
+
+impl From<Jspf> for PlaylistFile {
+    fn from(jspf: Jspf) -> Self {
+        let entries = jspf
+            .playlist
+            .track
+            .into_iter()
+            .map(|track| Entry {
+                isrc: isrc(&track.identifier),
+                title: track.title.unwrap_or_default(),
+                artist: track.creator.unwrap_or_default(),
+                album: track.album.unwrap_or_default(),
+                duration_ms: track.duration.unwrap_or_default(),
+                location: track.location.into_iter().next().unwrap_or_default(),
+            })
+            .collect();
+        PlaylistFile {
+            title: jspf.playlist.title,
+            entries,
+        }
+    }
+}
+
+const CSV_COLUMNS: [&str; 6] = [
+    "title",
+    "artist",
+    "album",
+    "duration_ms",
+    "isrc",
+    "location",
+];
+
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_owned()
+    }
+}
+
+/// The records of RFC 4180 CSV, whose quoted fields may span lines
+fn csv_records(text: &str) -> Vec<Vec<String>> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (true, c) => field.push(c),
+            (false, '"') => quoted = true,
+            (false, ',') => record.push(std::mem::take(&mut field)),
+            (false, '\r') => {}
+            (false, '\n') => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            (false, c) => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    records.retain(|record| record.iter().any(|field| !field.is_empty()));
+    records
+}
+
+/// CSV with a header row of the columns, in any order and case
+fn parse_csv(text: &str) -> Result<PlaylistFile, ServerError> {
+    let mut records = csv_records(text).into_iter();
+    let header = records
+        .next()
+        .ok_or_else(|| ServerError::ParamsError("Invalid CSV: no header".to_owned()))?;
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|field| field.trim().eq_ignore_ascii_case(name))
+    };
+    let [title, artist, album, duration_ms, isrc, location] = CSV_COLUMNS.map(column);
+    if title.is_none() && isrc.is_none() && location.is_none() {
+        return Err(ServerError::ParamsError(
+            "Invalid CSV: no title, isrc or location column".to_owned(),
+        ));
+    }
+
+    let entries = records
+        .map(|record| {
+            let field = |index: Option<usize>| {
+                index
+                    .and_then(|index| record.get(index))
+                    .map(|field| field.trim().to_owned())
+                    .unwrap_or_default()
+            };
+            Entry {
+                title: field(title),
+                artist: field(artist),
+                album: field(album),
+                duration_ms: field(duration_ms).parse().unwrap_or_default(),
+                isrc: Some(field(isrc)).filter(|isrc| !isrc.is_empty()),
+                location: field(location),
+            }
+        })
+        .collect();
+    Ok(PlaylistFile {
+        title: None,
+        entries,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file() -> PlaylistFile {
+        PlaylistFile {
+            title: Some("Mix & Match".to_owned()),
+            entries: vec![
+                Entry {
+                    title: "Song, \"Live\"".to_owned(),
+                    artist: "Band".to_owned(),
+                    album: "Album".to_owned(),
+                    duration_ms: 61_000,
+                    isrc: Some("USRC17607839".to_owned()),
+                    location: "spotify:track:4uLU6hMCjMI75M1A2tKUQC".to_owned(),
+                },
+                Entry {
+                    title: "Line\nbreak".to_owned(),
+                    location: "spotify:episode:512ojhOuo1ktJprKbVcKyQ".to_owned(),
+                    ..Default::default()
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn m3u8() {
+        let text = file().export(PlaylistFormat::M3u8).unwrap();
+        assert_eq!(
+            text,
+            "#EXTM3U\n#PLAYLIST:Mix & Match\n\
+             #EXTINF:61,Band - Song, \"Live\"\n#EXTALB:Album\n\
+             spotify:track:4uLU6hMCjMI75M1A2tKUQC\n\
+             #EXTINF:0,Line break\nspotify:episode:512ojhOuo1ktJprKbVcKyQ\n"
+        );
+
+        let parsed = PlaylistFile::parse(PlaylistFormat::M3u8, &text).unwrap();
+        assert_eq!(parsed.title.as_deref(), Some("Mix & Match"));
+        assert_eq!(parsed.entries.len(), 2);
+        let entry = &parsed.entries[0];
+        assert_eq!(
+            (
+                entry.artist.as_str(),
+                entry.title.as_str(),
+                entry.album.as_str()
+            ),
+            ("Band", "Song, \"Live\"", "Album")
+        );
+        assert_eq!(entry.duration_ms, 61_000);
+        assert_eq!(entry.isrc, None);
+        assert_eq!(parsed.entries[1].title, "Line break");
+    }
+
+    #[test]
+    fn m3u8_extinf_attributes() {
+        let text = "\u{feff}#EXTM3U\r\n#EXTINF:-1 tvg-id=\"x\",Radio\r\n\r\nhttp://radio/stream\r\nplain.mp3\n";
+        let parsed = PlaylistFile::parse(PlaylistFormat::M3u8, text).unwrap();
+        assert_eq!(parsed.entries.len(), 2);
+        assert_eq!(parsed.entries[0].title, "Radio");
+        assert_eq!(parsed.entries[0].duration_ms, -1000);
+        assert_eq!(parsed.entries[0].location, "http://radio/stream");
+        assert_eq!(parsed.entries[1].title, "");
+        assert_eq!(parsed.entries[1].location, "plain.mp3");
+    }
+
+    #[test]
+    fn xspf() {
+        let text = file().export(PlaylistFormat::Xspf).unwrap();
+        assert!(text.contains("<title>Mix &amp; Match</title>"));
+        assert!(text.contains("<identifier>urn:isrc:USRC17607839</identifier>"));
+
+        let parsed = PlaylistFile::parse(PlaylistFormat::Xspf, &text).unwrap();
+        assert_eq!(parsed.title.as_deref(), Some("Mix & Match"));
+        assert_eq!(parsed.entries.len(), 2);
+        let entry = &parsed.entries[0];
+        assert_eq!(entry.title, "Song, \"Live\"");
+        assert_eq!(entry.isrc.as_deref(), Some("USRC17607839"));
+        assert_eq!(entry.duration_ms, 61_000);
+        assert_eq!(entry.location, "spotify:track:4uLU6hMCjMI75M1A2tKUQC");
+        assert_eq!(parsed.entries[1].isrc, None);
+
+        // Track titles don't make the playlist title
+        let untitled =
+            "<playlist><trackList><track><title>A</title></track></trackList></playlist>";
+        let parsed = PlaylistFile::parse(PlaylistFormat::Xspf, untitled).unwrap();
+        assert_eq!(parsed.title, None);
+        assert_eq!(parsed.entries[0].title, "A");
+
+        assert!(PlaylistFile::parse(PlaylistFormat::Xspf, "<playlist/>").is_err());
+    }
+
+    #[test]
+    fn jspf() {
+        let text = file().export(PlaylistFormat::Jspf).unwrap();
+        let parsed = PlaylistFile::parse(PlaylistFormat::Jspf, &text).unwrap();
+        assert_eq!(parsed.title.as_deref(), Some("Mix & Match"));
+        assert_eq!(parsed.entries[0].isrc.as_deref(), Some("USRC17607839"));
+        assert_eq!(parsed.entries[1].title, "Line\nbreak");
+
+        let minimal = r#"{"playlist": {"track": [{"title": "A"}]}}"#;
+        let parsed = PlaylistFile::parse(PlaylistFormat::Jspf, minimal).unwrap();
+        assert_eq!(parsed.entries[0].title, "A");
+        assert_eq!(parsed.entries[0].location, "");
+
+        assert!(PlaylistFile::parse(PlaylistFormat::Jspf, "{}").is_err());
+    }
+
+    #[test]
+    fn csv() {
+        let text = file().export(PlaylistFormat::Csv).unwrap();
+        assert!(text.starts_with("title,artist,album,duration_ms,isrc,location\n"));
+        assert!(text.contains("\"Song, \"\"Live\"\"\",Band,"));
+
+        let parsed = PlaylistFile::parse(PlaylistFormat::Csv, &text).unwrap();
+        assert_eq!(parsed.entries.len(), 2);
+        assert_eq!(parsed.entries[0].title, "Song, \"Live\"");
+        assert_eq!(parsed.entries[0].duration_ms, 61_000);
+        assert_eq!(parsed.entries[1].title, "Line\nbreak");
+        assert_eq!(parsed.entries[1].isrc, None);
+    }
+
+    #[test]
+    fn csv_columns_in_any_order() {
+        let text = "Location,TITLE\r\nspotify:track:1,A\r\n,\r\nspotify:track:2\r\n";
+        let parsed = PlaylistFile::parse(PlaylistFormat::Csv, text).unwrap();
+        assert_eq!(parsed.entries.len(), 2);
+        assert_eq!(parsed.entries[0].title, "A");
+        assert_eq!(parsed.entries[0].location, "spotify:track:1");
+        assert_eq!(parsed.entries[1].title, "");
+
+        assert!(PlaylistFile::parse(PlaylistFormat::Csv, "").is_err());
+        assert!(PlaylistFile::parse(PlaylistFormat::Csv, "artist,album\nA,B\n").is_err());
+    }
+}
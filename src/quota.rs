@@ -0,0 +1,487 @@
+//! Server-side rate limits and quotas
+//!
+//! All server users share the Spotify accounts logged in here, so each user
+//! (or peer address, before login), each Spotify account and each route group
+//! has a token bucket. Audio streams also have a concurrency cap per account.
+//! Rejected requests get `429 Too Many Requests` with `Retry-After`.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Instant,
+};
+
+use actix_session::SessionExt;
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::Method,
+    middleware::Next,
+    web,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{app_store::AppStore, errors::ServerError, session::ServerSession};
+
+/// Idle buckets are pruned once a map holds this many
+const MAX_BUCKETS: usize = 10_000;
+
+/// Route groups which have their own limits
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteGroup {
+    /// Catalog and library reads
+    Catalog,
+    /// Library and playlist modifications
+    LibraryWrite,
+    /// Audio information and streams
+    AudioStream,
+}
+
+impl RouteGroup {
+    /// The group of a request, `None` for the routes without limits
+    pub fn classify(method: &Method, path: &str) -> Option<RouteGroup> {
+        if ["/health_check", "/metrics", "/login", "/logout", "/miracle"].contains(&path) {
+            None
+        } else if path.starts_with("/audio/")
+            || path.starts_with("/audio-uri/")
+            || path.starts_with("/audio-stream")
+        {
+            Some(RouteGroup::AudioStream)
+        } else if let Some(method) = path.strip_prefix("/rest/") {
+            Some(RouteGroup::subsonic(method.trim_end_matches(".view")))
+        } else if method == Method::GET || method == Method::HEAD {
+            Some(RouteGroup::Catalog)
+        } else {
+            Some(RouteGroup::LibraryWrite)
+        }
+    }
+
+    /// The group of a Subsonic method
+    ///
+    /// Subsonic clients send every call as GET, or as POST with `formPost`.
+    pub fn subsonic(method: &str) -> RouteGroup {
+        match method {
+            "stream" | "download" => RouteGroup::AudioStream,
+            "star" | "unstar" | "createPlaylist" | "updatePlaylist" | "deletePlaylist" => {
+                RouteGroup::LibraryWrite
+            }
+            _ => RouteGroup::Catalog,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            RouteGroup::Catalog => "catalog",
+            RouteGroup::LibraryWrite => "library writes",
+            RouteGroup::AudioStream => "audio streams",
+        }
+    }
+}
+
+/// Requests per minute of each limit, 0 means unlimited
+#[derive(Clone, Copy, Debug)]
+pub struct QuotaConfig {
+    /// Per server user, catalog and library reads
+    pub catalog: u32,
+    /// Per server user, library and playlist modifications
+    pub library_write: u32,
+    /// Per server user, audio information and streams
+    pub audio_stream: u32,
+    /// Per Spotify account, all route groups
+    pub account: u32,
+    /// Concurrent audio streams per Spotify account
+    pub max_streams: usize,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            catalog: 600,
+            library_write: 120,
+            audio_stream: 60,
+            account: 1200,
+            max_streams: 4,
+        }
+    }
+}
+
+impl QuotaConfig {
+    fn group_rate(&self, group: RouteGroup) -> u32 {
+        match group {
+            RouteGroup::Catalog => self.catalog,
+            RouteGroup::LibraryWrite => self.library_write,
+            RouteGroup::AudioStream => self.audio_stream,
+        }
+    }
+}
+
+/// A token bucket which holds a minute of requests as its burst
+///
+/// It keeps its own rate, as the maps hold buckets of several limits.
+struct TokenBucket {
+    tokens: f64,
+    per_minute: u32,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_minute: u32, now: Instant) -> Self {
+        Self {
+            tokens: per_minute as f64,
+            per_minute,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        let capacity = self.per_minute as f64;
+        self.tokens = (self.tokens + elapsed * capacity / 60.0).min(capacity);
+        self.updated_at = now;
+    }
+
+    /// Take a token, or return the seconds until one is available
+    ///
+    /// The bucket takes on `per_minute`, which changes when the config is
+    /// reloaded.
+    fn take(&mut self, per_minute: u32, now: Instant) -> Result<(), u64> {
+        self.refill(now);
+        if self.per_minute != per_minute {
+            self.per_minute = per_minute;
+            self.tokens = self.tokens.min(per_minute as f64);
+        }
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let seconds = (1.0 - self.tokens) * 60.0 / per_minute as f64;
+            Err(seconds.ceil().max(1.0) as u64)
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.per_minute as f64
+    }
+}
+
+/// Take a token of `key`, creating its bucket when needed
+fn take<K>(buckets: &Mutex<HashMap<K, TokenBucket>>, key: K, per_minute: u32) -> Result<(), u64>
+where
+    K: std::hash::Hash + Eq,
+{
+    if per_minute == 0 {
+        return Ok(());
+    }
+
+    let now = Instant::now();
+    let mut buckets = buckets.lock().unwrap();
+    if buckets.len() >= MAX_BUCKETS {
+        buckets.retain(|_, bucket| !bucket.is_full(now));
+    }
+    buckets
+        .entry(key)
+        .or_insert_with(|| TokenBucket::new(per_minute, now))
+        .take(per_minute, now)
+}
+
+/// A snapshot of the `Quotas` state
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct QuotaStats {
+    /// Rejected requests of each route group
+    pub rejected_total: HashMap<RouteGroup, u64>,
+    /// Rejected audio streams over the concurrency cap
+    pub rejected_streams_total: u64,
+    /// Active audio streams of each Spotify account
+    pub active_streams: HashMap<String, usize>,
+}
+
+/// The concurrent-stream slot of an account, released when dropped
+pub struct StreamPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Token buckets of server users and Spotify accounts
+pub struct Quotas {
+    config: RwLock<QuotaConfig>,
+    users: Mutex<HashMap<(String, RouteGroup), TokenBucket>>,
+    accounts: Mutex<HashMap<String, TokenBucket>>,
+    // The stream slots of each account and the cap they were created with
+    streams: Mutex<HashMap<String, (usize, Arc<Semaphore>)>>,
+    rejected: Mutex<HashMap<RouteGroup, u64>>,
+    rejected_streams: AtomicU64,
+}
+
+impl Default for Quotas {
+    fn default() -> Self {
+        Quotas::new(QuotaConfig::default())
+    }
+}
+
+impl Quotas {
+    pub fn new(config: QuotaConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            users: Mutex::new(HashMap::new()),
+            accounts: Mutex::new(HashMap::new()),
+            streams: Mutex::new(HashMap::new()),
+            rejected: Mutex::new(HashMap::new()),
+            rejected_streams: AtomicU64::new(0),
+        }
+    }
+
+    pub fn config(&self) -> QuotaConfig {
+        *self.config.read().unwrap()
+    }
+
+    /// Replace the limits, e.g. when the config file is reloaded
+    ///
+    /// The token buckets keep their tokens. When the stream cap changes, the
+    /// running streams stop counting against the new cap.
+    pub fn set_config(&self, config: QuotaConfig) {
+        let mut current = self.config.write().unwrap();
+        if current.max_streams != config.max_streams {
+            self.streams.lock().unwrap().clear();
+        }
+        *current = config;
+    }
+
+    /// Check the limits of a server user and the Spotify account it uses
+    pub fn check(
+        &self,
+        group: RouteGroup,
+        user: &str,
+        account: Option<&str>,
+    ) -> Result<(), ServerError> {
+        let config = self.config();
+        let rate = config.group_rate(group);
+        let result = take(&self.users, (user.to_owned(), group), rate)
+            .map_err(|retry_after| (format!("{} limit of the user", group.name()), retry_after))
+            .and_then(|_| match account {
+                Some(account) => take(&self.accounts, account.to_owned(), config.account)
+                    .map_err(|retry_after| ("limit of the account".to_owned(), retry_after)),
+                None => Ok(()),
+            });
+
+        result.map_err(|(limit, retry_after)| {
+            *self.rejected.lock().unwrap().entry(group).or_insert(0) += 1;
+            tracing::warn!("{} exceeds the {}", user, limit);
+            ServerError::RateLimitError {
+                reason: format!("Too many requests, exceeding the {}", limit),
+                retry_after,
+            }
+        })
+    }
+
+    /// Take a concurrent-stream slot of a Spotify account
+    pub fn acquire_stream(&self, account: &str) -> Result<StreamPermit, ServerError> {
+        let max_streams = self.config().max_streams;
+        if max_streams == 0 {
+            return Ok(StreamPermit { _permit: None });
+        }
+
+        let (_, semaphore) = self
+            .streams
+            .lock()
+            .unwrap()
+            .entry(account.to_owned())
+            .or_insert_with(|| (max_streams, Arc::new(Semaphore::new(max_streams))))
+            .clone();
+        match semaphore.try_acquire_owned() {
+            Ok(permit) => Ok(StreamPermit {
+                _permit: Some(permit),
+            }),
+            Err(_) => {
+                self.rejected_streams.fetch_add(1, Ordering::Relaxed);
+                Err(ServerError::RateLimitError {
+                    reason: format!(
+                        "Too many concurrent audio streams, the limit is {}",
+                        max_streams
+                    ),
+                    retry_after: 1,
+                })
+            }
+        }
+    }
+
+    pub fn stats(&self) -> QuotaStats {
+        let active_streams = self
+            .streams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(account, (max_streams, semaphore))| {
+                let active = max_streams.saturating_sub(semaphore.available_permits());
+                (account.clone(), active)
+            })
+            .collect();
+        QuotaStats {
+            rejected_total: self.rejected.lock().unwrap().clone(),
+            rejected_streams_total: self.rejected_streams.load(Ordering::Relaxed),
+            active_streams,
+        }
+    }
+}
+
+/// The signed audio stream and the Subsonic API carry their username in the
+/// query, so their handlers charge the user once it is verified
+fn is_charged_by_handler(path: &str) -> bool {
+    path.starts_with("/rest/") || path.starts_with("/audio-stream-with-sign/")
+}
+
+/// Middleware which applies `Quotas` before the request reaches its handler
+///
+/// The server user is the session's username, or the peer address before
+/// login. The routes which charge the user in their handlers are skipped, so
+/// the users behind one address don't share a budget.
+pub async fn limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let group = RouteGroup::classify(req.method(), req.path())
+        .filter(|_| !is_charged_by_handler(req.path()));
+    if let Some(group) = group {
+        if let Some(app_store) = req.app_data::<web::Data<AppStore>>() {
+            let username = ServerSession::from(req.get_session())
+                .get_username()
+                .ok()
+                .map(|username| username.as_ref().to_owned());
+            match username {
+                Some(username) => app_store.quotas.check(group, &username, Some(&username))?,
+                // The peer address, as the forwarded headers are up to the client
+                None => {
+                    let address = req
+                        .peer_addr()
+                        .map(|addr| addr.ip().to_string())
+                        .unwrap_or_else(|| "unknown".to_owned());
+                    app_store.quotas.check(group, &address, None)?
+                }
+            }
+        }
+    }
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{http::header, ResponseError};
+
+    use super::*;
+
+    #[test]
+    fn bucket_refills_at_its_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, start);
+        assert_eq!(bucket.take(2, start), Ok(()));
+        assert_eq!(bucket.take(2, start), Ok(()));
+        assert_eq!(bucket.take(2, start), Err(30));
+        assert_eq!(bucket.take(2, start + Duration::from_secs(15)), Err(15));
+        assert_eq!(bucket.take(2, start + Duration::from_secs(30)), Ok(()));
+
+        // A minute refills the burst, and no more
+        let later = start + Duration::from_secs(600);
+        assert!(bucket.is_full(later));
+        assert_eq!(bucket.take(2, later), Ok(()));
+        assert_eq!(bucket.take(2, later), Ok(()));
+        assert!(bucket.take(2, later).is_err());
+
+        // A lower rate of a reloaded config caps the tokens
+        let mut bucket = TokenBucket::new(60, start);
+        assert_eq!(bucket.take(1, start), Ok(()));
+        assert_eq!(bucket.take(1, start), Err(60));
+    }
+
+    #[test]
+    fn pruning_keeps_the_rate_of_each_bucket() {
+        let buckets = Mutex::new(HashMap::new());
+        assert_eq!(take(&buckets, 0, 1), Ok(()));
+        assert!(take(&buckets, 0, 1).is_err());
+        for key in 1..MAX_BUCKETS {
+            take(&buckets, key, 600).unwrap();
+        }
+
+        // Pruning at another rate neither refills nor drops the drained bucket
+        take(&buckets, MAX_BUCKETS, 600).unwrap();
+        assert!(buckets.lock().unwrap().contains_key(&0));
+        assert!(take(&buckets, 0, 1).is_err());
+    }
+
+    #[test]
+    fn rejections_are_429_with_retry_after() {
+        let quotas = Quotas::new(QuotaConfig {
+            catalog: 2,
+            library_write: 0,
+            account: 3,
+            ..QuotaConfig::default()
+        });
+        quotas
+            .check(RouteGroup::Catalog, "alice", Some("alice"))
+            .unwrap();
+        quotas
+            .check(RouteGroup::Catalog, "alice", Some("alice"))
+            .unwrap();
+
+        let err = quotas
+            .check(RouteGroup::Catalog, "alice", Some("alice"))
+            .unwrap_err();
+        assert!(
+            matches!(&err, ServerError::RateLimitError { reason, retry_after: 30 } if reason.contains("user"))
+        );
+        let response = err.error_response();
+        assert_eq!(response.status(), 429);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "30");
+
+        // Unlimited groups still count against the account
+        quotas
+            .check(RouteGroup::LibraryWrite, "alice", Some("alice"))
+            .unwrap();
+        let err = quotas
+            .check(RouteGroup::LibraryWrite, "alice", Some("alice"))
+            .unwrap_err();
+        assert!(
+            matches!(&err, ServerError::RateLimitError { reason, .. } if reason.contains("account"))
+        );
+
+        // Other users have their own buckets
+        quotas.check(RouteGroup::Catalog, "bob", None).unwrap();
+        assert_eq!(quotas.stats().rejected_total[&RouteGroup::Catalog], 1);
+        assert_eq!(quotas.stats().rejected_total[&RouteGroup::LibraryWrite], 1);
+    }
+
+    #[test]
+    fn streams_are_capped_per_account() {
+        let quotas = Quotas::new(QuotaConfig {
+            max_streams: 2,
+            ..QuotaConfig::default()
+        });
+        let first = quotas.acquire_stream("alice").unwrap();
+        let _second = quotas.acquire_stream("alice").unwrap();
+        assert!(matches!(
+            quotas.acquire_stream("alice"),
+            Err(ServerError::RateLimitError { retry_after: 1, .. })
+        ));
+        let _other = quotas.acquire_stream("bob").unwrap();
+        assert_eq!(quotas.stats().active_streams["alice"], 2);
+        assert_eq!(quotas.stats().rejected_streams_total, 1);
+
+        drop(first);
+        assert_eq!(quotas.stats().active_streams["alice"], 1);
+        assert!(quotas.acquire_stream("alice").is_ok());
+    }
+
+    #[test]
+    fn handler_charged_routes() {
+        assert!(is_charged_by_handler("/rest/getAlbum.view"));
+        assert!(is_charged_by_handler("/audio-stream-with-sign/audio.ogg"));
+        assert!(!is_charged_by_handler(
+            "/audio-stream/4uLU6hMCjMI75M1A2tKUQC"
+        ));
+        assert!(!is_charged_by_handler("/playlists"));
+    }
+}
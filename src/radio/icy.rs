@@ -0,0 +1,114 @@
+//! ICY (SHOUTcast/Icecast) metadata
+//!
+//! Clients which send `Icy-MetaData: 1` get a metadata block after every
+//! `icy-metaint` bytes of audio: a length byte in 16 byte units, then
+//! `StreamTitle='..';` padded with zeros, or just a zero length byte when the
+//! title didn't change.
+
+/// Audio bytes between metadata blocks
+pub const METAINT: usize = 16000;
+
+/// The longest metadata block
+const MAX_BLOCK_LEN: usize = 255 * 16;
+
+pub struct IcyMetadata {
+    /// Audio bytes before the next metadata block
+    until_block: usize,
+    /// The title to send in the next metadata block
+    title: Option<String>,
+}
+
+impl Default for IcyMetadata {
+    fn default() -> Self {
+        IcyMetadata {
+            until_block: METAINT,
+            title: None,
+        }
+    }
+}
+
+impl IcyMetadata {
+    pub fn set_title(&mut self, title: &str) {
+        self.title = Some(title.to_owned());
+    }
+
+    /// Interleave the audio with the metadata blocks
+    pub fn interleave(&mut self, mut audio: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(audio.len() + 1);
+        while audio.len() >= self.until_block {
+            let (head, tail) = audio.split_at(self.until_block);
+            out.extend_from_slice(head);
+            out.extend(block(self.title.take().as_deref()));
+            audio = tail;
+            self.until_block = METAINT;
+        }
+        out.extend_from_slice(audio);
+        self.until_block -= audio.len();
+        out
+    }
+}
+
+fn block(title: Option<&str>) -> Vec<u8> {
+    let Some(title) = title else {
+        return vec![0];
+    };
+    // Quotes end the title, and the block has a maximum length
+    let mut text = title.replace('\'', "\u{2019}");
+    while text.len() + "StreamTitle='';".len() > MAX_BLOCK_LEN {
+        text.pop();
+    }
+    let text = format!("StreamTitle='{}';", text);
+    let units = text.len().div_ceil(16);
+    let mut block = Vec::with_capacity(1 + units * 16);
+    block.push(units as u8);
+    block.extend_from_slice(text.as_bytes());
+    block.resize(1 + units * 16, 0);
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks() {
+        assert_eq!(block(None), [0]);
+
+        let block = block(Some("A - B's"));
+        assert_eq!(block.len(), 1 + 32);
+        assert_eq!(block[0], 2);
+        let text = std::str::from_utf8(&block[1..]).unwrap();
+        assert_eq!(text.trim_end_matches('\0'), "StreamTitle='A - B\u{2019}s';");
+    }
+
+    #[test]
+    fn long_titles_are_truncated() {
+        let block = block(Some(&"x".repeat(10_000)));
+        assert_eq!(block[0], 255);
+        assert_eq!(block.len(), 1 + MAX_BLOCK_LEN);
+        assert!(block.ends_with(b"';"));
+    }
+
+    #[test]
+    fn interleave() {
+        let mut metadata = IcyMetadata::default();
+        metadata.set_title("Title");
+
+        let out = metadata.interleave(&vec![1; METAINT - 10]);
+        assert_eq!(out.len(), METAINT - 10);
+
+        // The block goes after exactly METAINT bytes of audio
+        let out = metadata.interleave(&[9; 20]);
+        assert_eq!(out.len(), 20 + 1 + 32);
+        assert_eq!(out[..10], [9; 10]);
+        assert_eq!(out[10], 2);
+        assert!(out[11..].starts_with(b"StreamTitle='Title';"));
+        assert_eq!(out[out.len() - 10..], [9; 10]);
+
+        // An unchanged title is an empty block
+        let out = metadata.interleave(&vec![3; 2 * METAINT]);
+        assert_eq!(out.len(), 2 * METAINT + 2);
+        assert_eq!(out[METAINT - 10], 0);
+        assert_eq!(out[2 * METAINT - 10 + 1], 0);
+    }
+}
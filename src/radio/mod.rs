@@ -0,0 +1,180 @@
+//! Internet radio
+//!
+//! Endless Ogg Vorbis streams of a playlist or of recommendations, which play
+//! the tracks back to back in one Ogg stream, with ICY title updates.
+
+mod icy;
+mod ogg;
+
+use std::io::Read;
+
+use actix_web::web::{self, Bytes};
+use futures::Stream;
+use librespot::metadata::FileFormat;
+use rspotify::model::{Id, PlayableItem, PlaylistId, SimplifiedArtist};
+
+use crate::{
+    account::{SpotifyAccount, UserName},
+    app_store::AppStore,
+    endpoints::{
+        audios::open_audio_file, params::RecommendationsData, playlists::all_tracks,
+        recommends::find_recommendations,
+    },
+    errors::ServerError,
+    metrics::METRICS,
+    quota::StreamPermit,
+};
+
+pub use self::icy::METAINT;
+use self::{icy::IcyMetadata, ogg::OggRemuxer};
+
+/// Only Ogg Vorbis files can be chained
+const OGG_FORMATS: [FileFormat; 3] = [
+    FileFormat::OGG_VORBIS_320,
+    FileFormat::OGG_VORBIS_160,
+    FileFormat::OGG_VORBIS_96,
+];
+
+/// The stream ends after this many tracks in a row fail to open
+const MAX_CONSECUTIVE_FAILURES: usize = 5;
+
+pub enum Station {
+    /// The playlist tracks, in a loop
+    Playlist(PlaylistId<'static>),
+    /// New recommendations of the seeds after each batch
+    Recommendations(Box<RecommendationsData>),
+}
+
+struct RadioTrack {
+    uri: String,
+    title: String,
+}
+
+impl RadioTrack {
+    fn new(uri: String, name: &str, artists: &[SimplifiedArtist]) -> Self {
+        let artists = artists
+            .iter()
+            .map(|artist| artist.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        RadioTrack {
+            uri,
+            title: format!("{} - {}", artists, name),
+        }
+    }
+}
+
+impl Station {
+    /// The next tracks to play
+    async fn tracks(&self, account: &SpotifyAccount) -> Result<Vec<RadioTrack>, ServerError> {
+        let market = account.default_market().await;
+        match self {
+            Station::Playlist(playlist_id) => {
+                Ok(all_tracks(account, playlist_id.clone(), None, Some(market))
+                    .await?
+                    .into_iter()
+                    .filter_map(|item| match item.track {
+                        Some(PlayableItem::Track(track)) => {
+                            let uri = track.id.as_ref()?.uri();
+                            Some(RadioTrack::new(uri, &track.name, &track.artists))
+                        }
+                        _ => None,
+                    })
+                    .collect())
+            }
+            Station::Recommendations(query) => Ok(find_recommendations(account, query, market)
+                .await?
+                .tracks
+                .into_iter()
+                .filter_map(|track| {
+                    let uri = track.id.as_ref()?.uri();
+                    Some(RadioTrack::new(uri, &track.name, &track.artists))
+                })
+                .collect()),
+        }
+    }
+}
+
+/// The endless stream of the station, with ICY metadata blocks when `icy` is set
+///
+/// The stream holds `permit` until it ends.
+pub fn stream(
+    app_store: web::Data<AppStore>,
+    username: UserName,
+    station: Station,
+    icy: bool,
+    permit: StreamPermit,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> {
+    async_stream::stream! {
+        let _permit = permit;
+        let _active = METRICS.stream_started();
+        let mut remuxer = OggRemuxer::new(rand::random());
+        let mut metadata = icy.then(IcyMetadata::default);
+        let mut failures = 0;
+        let mut buf = vec![0u8; 1024 * 10];
+        loop {
+            let tracks = match app_store.authorize(username.clone()).await {
+                Ok(account) => station.tracks(&account).await,
+                Err(err) => Err(err),
+            };
+            let tracks = match tracks {
+                Ok(tracks) if !tracks.is_empty() => tracks,
+                Ok(_) => {
+                    tracing::warn!("Radio station has no tracks");
+                    return;
+                }
+                Err(err) => {
+                    tracing::warn!("Failed to get radio tracks: {}", err);
+                    return;
+                }
+            };
+
+            for track in tracks {
+                let file = match app_store.authorize(username.clone()).await {
+                    Ok(account) => open_audio_file(&track.uri, &account, &OGG_FORMATS)
+                        .await
+                        .map(|(file, _)| file),
+                    Err(err) => Err(err),
+                };
+                let mut file = match file {
+                    Ok(file) => file,
+                    Err(err) => {
+                        tracing::warn!("Skip radio track {}: {}", track.uri, err);
+                        failures += 1;
+                        if failures >= MAX_CONSECUTIVE_FAILURES {
+                            return;
+                        }
+                        continue;
+                    }
+                };
+                failures = 0;
+
+                tracing::info!("Radio plays {}", track.uri);
+                remuxer.start_track();
+                if let Some(metadata) = &mut metadata {
+                    metadata.set_title(&track.title);
+                }
+                loop {
+                    let n = match file.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(n) => n,
+                        Err(err) => {
+                            tracing::warn!("Radio track {} stopped: {}", track.uri, err);
+                            break;
+                        }
+                    };
+                    METRICS.streamed(n);
+                    let pages = remuxer.push(&buf[..n]);
+                    if pages.is_empty() {
+                        continue;
+                    }
+                    let data = match &mut metadata {
+                        Some(metadata) => metadata.interleave(&pages),
+                        None => pages,
+                    };
+                    yield Ok(Bytes::from(data));
+                }
+            }
+        }
+    }
+}
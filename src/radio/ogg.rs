@@ -0,0 +1,393 @@
+//! Ogg re-muxing of tracks into one stream
+//!
+//! Each track is its own Ogg stream, with a random serial number and page
+//! sequence numbers and granule positions from zero. The remuxer rewrites
+//! the pages of every track to one serial number with continuous sequence
+//! numbers and granule positions, and drops the begin and end of stream
+//! flags between tracks, so players don't stop at the end of a track.
+//!
+//! Every track starts with the three Vorbis header packets. When they are
+//! byte-identical to the headers of the stream, they are dropped. Otherwise
+//! players need the new headers, so the remuxer ends the logical stream and
+//! chains a new one with another serial number.
+
+/// `OggS`, the capture pattern at the start of every page
+const CAPTURE_PATTERN: &[u8; 4] = b"OggS";
+/// Length of the page header before the segment table
+const HEADER_LEN: usize = 27;
+
+const FLAG_BOS: u8 = 0x02;
+const FLAG_EOS: u8 = 0x04;
+
+/// Pages which no packet ends on have no granule position
+const NO_GRANULE: u64 = u64::MAX;
+
+/// Vorbis streams start with the identification, comment and setup headers
+const HEADER_PACKETS: usize = 3;
+
+/// CRC-32 of Ogg pages, polynomial 0x04c11db7 without reflection
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc(page: &[u8]) -> u32 {
+    page.iter().fold(0, |crc, &byte| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ byte) as usize]
+    })
+}
+
+pub struct OggRemuxer {
+    serial: u32,
+    sequence: u32,
+    /// Granule position where the current track starts
+    granule_offset: u64,
+    /// Last granule position of the current track
+    track_granule: u64,
+    /// Header packets of the logical stream
+    headers: Option<Vec<Vec<u8>>>,
+    /// Whether the current track is still in its header pages
+    in_headers: bool,
+    /// Header packets of the current track
+    track_headers: Vec<Vec<u8>>,
+    /// The header packet which continues on the next page
+    packet: Vec<u8>,
+    /// Header pages of the current track, held until its headers are complete
+    header_pages: Vec<Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+impl OggRemuxer {
+    pub fn new(serial: u32) -> Self {
+        OggRemuxer {
+            serial,
+            sequence: 0,
+            granule_offset: 0,
+            track_granule: 0,
+            headers: None,
+            in_headers: true,
+            track_headers: vec![],
+            packet: vec![],
+            header_pages: vec![],
+            buf: vec![],
+        }
+    }
+
+    /// Start the next track, dropping what is left of the current one
+    pub fn start_track(&mut self) {
+        self.granule_offset += self.track_granule;
+        self.track_granule = 0;
+        self.in_headers = true;
+        self.track_headers.clear();
+        self.packet.clear();
+        self.header_pages.clear();
+        self.buf.clear();
+    }
+
+    /// Add data of the current track, returning the complete pages rewritten
+    pub fn push(&mut self, data: &[u8]) -> Vec<u8> {
+        self.buf.extend_from_slice(data);
+        let mut out = vec![];
+        loop {
+            // Skip to the next page, in case the data doesn't start at one
+            match self
+                .buf
+                .windows(CAPTURE_PATTERN.len())
+                .position(|window| window == CAPTURE_PATTERN)
+            {
+                Some(start) => {
+                    self.buf.drain(..start);
+                }
+                None => {
+                    let keep = self.buf.len().min(CAPTURE_PATTERN.len() - 1);
+                    self.buf.drain(..self.buf.len() - keep);
+                    return out;
+                }
+            }
+            if self.buf.len() < HEADER_LEN {
+                return out;
+            }
+            let segments = self.buf[HEADER_LEN - 1] as usize;
+            if self.buf.len() < HEADER_LEN + segments {
+                return out;
+            }
+            let body: usize = self.buf[HEADER_LEN..HEADER_LEN + segments]
+                .iter()
+                .map(|&len| len as usize)
+                .sum();
+            let len = HEADER_LEN + segments + body;
+            if self.buf.len() < len {
+                return out;
+            }
+            let page = self.buf.drain(..len).collect::<Vec<_>>();
+            if self.in_headers {
+                self.push_header_page(page, &mut out);
+            } else {
+                self.write(page, &mut out);
+            }
+        }
+    }
+
+    /// Hold the header pages of the track until its header packets are
+    /// complete, then drop them when they repeat the stream's headers
+    fn push_header_page(&mut self, page: Vec<u8>, out: &mut Vec<u8>) {
+        let segments = page[HEADER_LEN - 1] as usize;
+        let mut start = HEADER_LEN + segments;
+        for &len in &page[HEADER_LEN..HEADER_LEN + segments] {
+            let end = start + len as usize;
+            if self.track_headers.len() < HEADER_PACKETS {
+                self.packet.extend_from_slice(&page[start..end]);
+                // A packet ends at the first segment shorter than 255 bytes
+                if len < 255 {
+                    self.track_headers.push(std::mem::take(&mut self.packet));
+                }
+            }
+            start = end;
+        }
+        self.header_pages.push(page);
+        if self.track_headers.len() < HEADER_PACKETS {
+            return;
+        }
+
+        self.in_headers = false;
+        let headers = std::mem::take(&mut self.track_headers);
+        let pages = std::mem::take(&mut self.header_pages);
+        if self.headers.as_ref() == Some(&headers) {
+            return;
+        }
+        if self.headers.is_some() {
+            self.chain(out);
+        }
+        self.headers = Some(headers);
+        for page in pages {
+            self.write(page, out);
+        }
+    }
+
+    /// End the logical stream with an empty page and start a new one, which
+    /// players decode with the headers that follow
+    fn chain(&mut self, out: &mut Vec<u8>) {
+        let mut page = vec![0; HEADER_LEN];
+        page[..4].copy_from_slice(CAPTURE_PATTERN);
+        page[5] = FLAG_EOS;
+        page[6..14].copy_from_slice(&self.granule_offset.to_le_bytes());
+        page[14..18].copy_from_slice(&self.serial.to_le_bytes());
+        page[18..22].copy_from_slice(&self.sequence.to_le_bytes());
+        let crc = crc(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        out.extend(page);
+
+        self.serial = self.serial.wrapping_add(1);
+        self.sequence = 0;
+        self.granule_offset = 0;
+    }
+
+    fn write(&mut self, mut page: Vec<u8>, out: &mut Vec<u8>) {
+        self.rewrite(&mut page);
+        out.extend(page);
+    }
+
+    fn rewrite(&mut self, page: &mut [u8]) {
+        // Only the first page of a logical stream begins it
+        page[5] &= !FLAG_EOS;
+        if self.sequence != 0 {
+            page[5] &= !FLAG_BOS;
+        }
+
+        let granule = u64::from_le_bytes(page[6..14].try_into().unwrap());
+        if granule != NO_GRANULE {
+            self.track_granule = granule;
+            page[6..14].copy_from_slice(&(granule + self.granule_offset).to_le_bytes());
+        }
+        page[14..18].copy_from_slice(&self.serial.to_le_bytes());
+        page[18..22].copy_from_slice(&self.sequence.to_le_bytes());
+        self.sequence = self.sequence.wrapping_add(1);
+
+        page[22..26].fill(0);
+        let crc = crc(page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Page {
+        flags: u8,
+        granule: u64,
+        serial: u32,
+        sequence: u32,
+        packets: Vec<Vec<u8>>,
+    }
+
+    /// A page of whole packets
+    fn page(flags: u8, granule: u64, packets: &[&[u8]]) -> Vec<u8> {
+        let mut lacing = vec![];
+        let mut body = vec![];
+        for packet in packets {
+            lacing.extend(std::iter::repeat_n(255, packet.len() / 255));
+            lacing.push((packet.len() % 255) as u8);
+            body.extend_from_slice(packet);
+        }
+        let mut page = CAPTURE_PATTERN.to_vec();
+        page.push(0);
+        page.push(flags);
+        page.extend(granule.to_le_bytes());
+        page.extend(0x1234_5678u32.to_le_bytes());
+        page.extend(7u32.to_le_bytes());
+        page.extend([0; 4]);
+        page.push(lacing.len() as u8);
+        page.extend(lacing);
+        page.extend(body);
+        page
+    }
+
+    /// The pages of a track, after Spotify's own header
+    fn track(comment: &[u8], granules: &[u64]) -> Vec<u8> {
+        let mut data = b"spotify header".to_vec();
+        data.extend(page(FLAG_BOS, 0, &[b"\x01vorbis"]));
+        data.extend(page(0, 0, &[comment, &[5; 300]]));
+        for (i, &granule) in granules.iter().enumerate() {
+            let flags = if i + 1 == granules.len() { FLAG_EOS } else { 0 };
+            data.extend(page(flags, granule, &[&[i as u8; 100]]));
+        }
+        data
+    }
+
+    /// Parse the pages, checking their CRC
+    fn parse(mut data: &[u8]) -> Vec<Page> {
+        let mut pages = vec![];
+        while !data.is_empty() {
+            assert_eq!(&data[..4], CAPTURE_PATTERN);
+            let segments = data[HEADER_LEN - 1] as usize;
+            let lacing = &data[HEADER_LEN..HEADER_LEN + segments];
+            let len = HEADER_LEN + segments + lacing.iter().map(|&l| l as usize).sum::<usize>();
+            let mut page = data[..len].to_vec();
+            let expected = u32::from_le_bytes(page[22..26].try_into().unwrap());
+            page[22..26].fill(0);
+            assert_eq!(crc(&page), expected);
+
+            let mut packets = vec![];
+            let mut packet = vec![];
+            let mut start = HEADER_LEN + segments;
+            for &l in lacing {
+                packet.extend_from_slice(&page[start..start + l as usize]);
+                start += l as usize;
+                if l < 255 {
+                    packets.push(std::mem::take(&mut packet));
+                }
+            }
+            pages.push(Page {
+                flags: page[5],
+                granule: u64::from_le_bytes(page[6..14].try_into().unwrap()),
+                serial: u32::from_le_bytes(page[14..18].try_into().unwrap()),
+                sequence: u32::from_le_bytes(page[18..22].try_into().unwrap()),
+                packets,
+            });
+            data = &data[len..];
+        }
+        pages
+    }
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc(b"123456789"), 0x89a1_897f);
+        assert_eq!(crc(b""), 0);
+    }
+
+    #[test]
+    fn pages_split_across_pushes() {
+        let data = track(b"\x03vorbis", &[1000, 2000]);
+        let mut whole = OggRemuxer::new(1);
+        let expected = whole.push(&data);
+        assert_eq!(parse(&expected).len(), 4);
+
+        let mut split = OggRemuxer::new(1);
+        let mut out = vec![];
+        for chunk in data.chunks(7) {
+            out.extend(split.push(chunk));
+        }
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn repeated_headers_are_dropped() {
+        let mut remuxer = OggRemuxer::new(42);
+        let mut out = remuxer.push(&track(b"\x03vorbis", &[1000, 2000]));
+        remuxer.start_track();
+        out.extend(remuxer.push(&track(b"\x03vorbis", &[500, 1500])));
+
+        let pages = parse(&out);
+        let granules = pages.iter().map(|page| page.granule).collect::<Vec<_>>();
+        assert_eq!(granules, [0, 0, 1000, 2000, 2500, 3500]);
+        let sequences = pages.iter().map(|page| page.sequence).collect::<Vec<_>>();
+        assert_eq!(sequences, [0, 1, 2, 3, 4, 5]);
+        assert!(pages.iter().all(|page| page.serial == 42));
+        assert_eq!(pages[0].flags, FLAG_BOS);
+        assert!(pages[1..].iter().all(|page| page.flags == 0));
+        assert_eq!(pages[1].packets[1], [5; 300]);
+    }
+
+    #[test]
+    fn changed_headers_chain_a_new_stream() {
+        let mut remuxer = OggRemuxer::new(42);
+        let mut out = remuxer.push(&track(b"\x03vorbis one", &[1000, 2000]));
+        remuxer.start_track();
+        out.extend(remuxer.push(&track(b"\x03vorbis two", &[500, 1500])));
+
+        let pages = parse(&out);
+        let summary = pages
+            .iter()
+            .map(|page| (page.flags, page.granule, page.serial, page.sequence))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (FLAG_BOS, 0, 42, 0),
+                (0, 0, 42, 1),
+                (0, 1000, 42, 2),
+                (0, 2000, 42, 3),
+                (FLAG_EOS, 2000, 42, 4),
+                (FLAG_BOS, 0, 43, 0),
+                (0, 0, 43, 1),
+                (0, 500, 43, 2),
+                (0, 1500, 43, 3),
+            ]
+        );
+        assert!(pages[4].packets.is_empty());
+        assert_eq!(pages[6].packets[0], b"\x03vorbis two");
+    }
+
+    #[test]
+    fn a_track_cut_short_in_its_headers_is_dropped() {
+        let mut remuxer = OggRemuxer::new(42);
+        let mut out = remuxer.push(&track(b"\x03vorbis", &[1000]));
+        remuxer.start_track();
+        let data = track(b"\x03vorbis", &[1000]);
+        out.extend(remuxer.push(&data[..40]));
+        remuxer.start_track();
+        out.extend(remuxer.push(&track(b"\x03vorbis", &[1000])));
+
+        let granules = parse(&out)
+            .iter()
+            .map(|page| page.granule)
+            .collect::<Vec<_>>();
+        assert_eq!(granules, [0, 0, 1000, 2000]);
+    }
+}
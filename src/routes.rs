@@ -1,16 +1,41 @@
 use crate::endpoints::{
-    albums, artists, audios, categories, episodes, genres, health_check, login, markets, playlists,
-    recommends, search, shows, tracks, users,
+    admin, albums, artists, audiobooks, audios, backup, categories, chapters, dlna, episodes,
+    events, feeds, genres, health_check, login, markets, metrics, playlist_files, playlists, radio,
+    recommends, search, sessions, shows, subsonic, tracks, users,
 };
 
-use actix_web::web;
+use actix_web::{http::Method, web};
+
+/// Imported playlist files are larger than the default payload limit
+const MAX_PLAYLIST_FILE_SIZE: usize = 16 * 1024 * 1024;
+/// Library backups are larger still
+const MAX_BACKUP_SIZE: usize = 64 * 1024 * 1024;
 
 pub fn route() -> actix_web::Scope {
     web::scope("")
         .route("/health_check", web::get().to(health_check::health_check))
+        .route("/metrics", web::get().to(metrics::metrics))
+        // Admin api
+        .route("/admin/reload", web::post().to(admin::reload_config))
+        .route(
+            "/admin/sessions/{username}",
+            web::delete().to(admin::revoke_user_sessions),
+        )
+        .route(
+            "/admin/webhooks/deliveries",
+            web::get().to(admin::webhook_deliveries),
+        )
         // Login api
         .route("/login", web::post().to(login::login))
+        .route("/logout", web::post().to(login::logout))
         .route("/miracle", web::get().to(login::miracle))
+        // Sessions api
+        .route("/sessions", web::get().to(sessions::sessions))
+        .route("/sessions", web::delete().to(sessions::revoke_sessions))
+        .route("/sessions/{id}", web::delete().to(sessions::revoke_session))
+        // Events api
+        .route("/events", web::get().to(events::events))
+        .route("/events/ws", web::get().to(events::events_ws))
         // User api
         .route("/me", web::get().to(users::me))
         .route("/users/{id}", web::get().to(users::user))
@@ -23,6 +48,10 @@ pub fn route() -> actix_web::Scope {
         .route("/me/albums", web::get().to(albums::saved_albums))
         .route("/me/albums", web::put().to(albums::save_albums))
         .route("/me/albums", web::delete().to(albums::delete_albums))
+        .route(
+            "/me/albums/contains",
+            web::get().to(albums::contains_albums),
+        )
         .route("/browse/new-releases", web::get().to(albums::new_releases))
         // Artists apis
         .route("/artists/{id}", web::get().to(artists::artist))
@@ -46,18 +75,46 @@ pub fn route() -> actix_web::Scope {
         .route("/me/shows", web::get().to(shows::saved_shows))
         .route("/me/shows", web::put().to(shows::save_shows))
         .route("/me/shows", web::delete().to(shows::delete_shows))
+        .route("/me/shows/contains", web::get().to(shows::contains_shows))
         // Episodes apis
         .route("/episodes/{id}", web::get().to(episodes::episode))
         .route("/episodes", web::get().to(episodes::episodes))
         .route("/me/episodes", web::get().to(episodes::saved_episodes))
         .route("/me/episodes", web::put().to(episodes::save_episodes))
         .route("/me/episodes", web::delete().to(episodes::delete_episodes))
+        .route(
+            "/me/episodes/contains",
+            web::get().to(episodes::contains_episodes),
+        )
+        // Audiobooks apis
+        .route("/audiobooks/{id}", web::get().to(audiobooks::audiobook))
+        .route("/audiobooks", web::get().to(audiobooks::audiobooks))
+        .route(
+            "/audiobooks/{id}/chapters",
+            web::get().to(audiobooks::audiobook_chapters),
+        )
+        .route(
+            "/me/audiobooks",
+            web::get().to(audiobooks::saved_audiobooks),
+        )
+        .route("/me/audiobooks", web::put().to(audiobooks::save_audiobooks))
+        .route(
+            "/me/audiobooks",
+            web::delete().to(audiobooks::delete_audiobooks),
+        )
+        // Chapters apis
+        .route("/chapters/{id}", web::get().to(chapters::chapter))
+        .route("/chapters", web::get().to(chapters::chapters))
         // Tracks apis
         .route("/tracks/{id}", web::get().to(tracks::track))
         .route("/tracks", web::get().to(tracks::tracks))
         .route("/me/tracks", web::get().to(tracks::saved_tracks))
         .route("/me/tracks", web::put().to(tracks::save_tracks))
         .route("/me/tracks", web::delete().to(tracks::delete_tracks))
+        .route(
+            "/me/tracks/contains",
+            web::get().to(tracks::contains_tracks),
+        )
         .route(
             "/audio-features/{id}",
             web::get().to(tracks::track_features),
@@ -86,6 +143,14 @@ pub fn route() -> actix_web::Scope {
             "/playlists/{id}",
             web::put().to(playlists::change_playlist_detail),
         )
+        .route(
+            "/playlists/{id}/images",
+            web::get().to(playlists::playlist_cover_image),
+        )
+        .route(
+            "/playlists/{id}/images",
+            web::put().to(playlists::upload_playlist_cover_image),
+        )
         .route(
             "/playlists/{id}/tracks",
             web::get().to(playlists::playlist_tracks),
@@ -94,10 +159,19 @@ pub fn route() -> actix_web::Scope {
             "/playlists/{id}/tracks",
             web::post().to(playlists::playlist_add_items),
         )
+        .route(
+            "/playlists/{id}/export",
+            web::get().to(playlist_files::export_playlist),
+        )
         .route(
             "/me/playlists",
             web::get().to(playlists::current_user_playlists),
         )
+        .service(
+            web::resource("/me/playlists/import")
+                .app_data(web::PayloadConfig::new(MAX_PLAYLIST_FILE_SIZE))
+                .route(web::post().to(playlist_files::import_playlist)),
+        )
         .route(
             "/users/{id}/playlists",
             web::get().to(playlists::user_playlists),
@@ -129,6 +203,53 @@ pub fn route() -> actix_web::Scope {
             "/recommendations/available-genre-seeds",
             web::get().to(genres::genres),
         )
+        // Library backup
+        .route("/me/backup", web::get().to(backup::backup))
+        .service(
+            web::resource("/me/restore")
+                .app_data(web::PayloadConfig::new(MAX_BACKUP_SIZE))
+                .route(web::post().to(backup::restore)),
+        )
+        // Podcast feeds
+        .route("/feeds/shows.opml", web::get().to(feeds::saved_shows_opml))
+        .route("/feeds/shows/{id}.xml", web::get().to(feeds::show_feed))
+        // Radio
+        .route(
+            "/radio/recommendations.ogg",
+            web::get().to(radio::recommendations_radio),
+        )
+        .route(
+            "/radio/{playlist_id}.ogg",
+            web::get().to(radio::playlist_radio),
+        )
         // Markets
         .route("/markets", web::get().to(markets::markets))
+        // Subsonic api
+        .route("/rest/{method}", web::route().to(subsonic::subsonic))
+        // DLNA MediaServer
+        .route("/dlna/description.xml", web::get().to(dlna::description))
+        .route(
+            "/dlna/ContentDirectory.xml",
+            web::get().to(dlna::content_directory_scpd),
+        )
+        .route(
+            "/dlna/ConnectionManager.xml",
+            web::get().to(dlna::connection_manager_scpd),
+        )
+        .route(
+            "/dlna/control/ContentDirectory",
+            web::post().to(dlna::content_directory),
+        )
+        .route(
+            "/dlna/control/ConnectionManager",
+            web::post().to(dlna::connection_manager),
+        )
+        .route(
+            "/dlna/event/{service}",
+            web::method(Method::from_bytes(b"SUBSCRIBE").unwrap()).to(dlna::subscribe),
+        )
+        .route(
+            "/dlna/event/{service}",
+            web::method(Method::from_bytes(b"UNSUBSCRIBE").unwrap()).to(dlna::subscribe),
+        )
 }
@@ -0,0 +1,77 @@
+//! The Last.fm API 2.0, `track.updateNowPlaying` and `track.scrobble`
+
+use md5::{Digest, Md5};
+
+use crate::common::hex;
+
+use super::Listen;
+
+/// Error codes of failures which may pass on a retry: operation failed,
+/// service offline, temporarily unavailable, and rate limit exceeded
+const TEMPORARY_ERRORS: [i64; 4] = [8, 11, 16, 29];
+
+pub fn url(base_url: &str) -> String {
+    format!("{}/2.0/", base_url)
+}
+
+/// The signed form parameters of a submission with the session key `sk`
+pub fn params(
+    listen: &Listen,
+    now_playing: bool,
+    api_key: &str,
+    api_secret: &str,
+    sk: &str,
+) -> Vec<(&'static str, String)> {
+    let mut params = vec![
+        (
+            "method",
+            if now_playing {
+                "track.updateNowPlaying"
+            } else {
+                "track.scrobble"
+            }
+            .to_owned(),
+        ),
+        ("artist", listen.artist.clone()),
+        ("track", listen.track.clone()),
+        ("album", listen.album.clone()),
+        ("duration", (listen.duration_ms / 1000).to_string()),
+        ("api_key", api_key.to_owned()),
+        ("sk", sk.to_owned()),
+    ];
+    if !now_playing {
+        params.push(("timestamp", listen.listened_at.to_string()));
+    }
+    params.push(("api_sig", signature(&mut params.clone(), api_secret)));
+    params.push(("format", "json".to_owned()));
+    params
+}
+
+/// The md5 of the parameters sorted by name, each name followed by its
+/// value, and the secret
+fn signature(params: &mut [(&str, String)], api_secret: &str) -> String {
+    params.sort_by_key(|(name, _)| *name);
+    let mut text = params
+        .iter()
+        .map(|(name, value)| format!("{}{}", name, value))
+        .collect::<String>();
+    text.push_str(api_secret);
+    hex::encode(&Md5::digest(text.as_bytes())).to_lowercase()
+}
+
+/// The error code of a response body
+fn error_code(body: &str) -> Option<i64> {
+    serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|value| value["error"].as_i64())
+}
+
+/// Whether the body is an error, which Last.fm may send with a 200
+pub fn is_error(body: &str) -> bool {
+    error_code(body).is_some()
+}
+
+/// Whether the error body is of a temporary failure
+pub fn is_temporary(body: &str) -> bool {
+    error_code(body).is_some_and(|code| TEMPORARY_ERRORS.contains(&code))
+}
@@ -0,0 +1,37 @@
+//! The ListenBrainz API, `POST /1/submit-listens`
+
+use serde_json::{json, Value};
+
+use super::Listen;
+
+pub fn url(base_url: &str) -> String {
+    format!("{}/1/submit-listens", base_url)
+}
+
+/// The JSON body of a `playing_now` or `single` submission
+pub fn body(listen: &Listen, now_playing: bool) -> Value {
+    let mut additional_info = json!({
+        "duration_ms": listen.duration_ms,
+        "spotify_id": listen.spotify_url(),
+        "submission_client": env!("CARGO_PKG_NAME"),
+        "submission_client_version": env!("CARGO_PKG_VERSION"),
+    });
+    if let Some(isrc) = &listen.isrc {
+        additional_info["isrc"] = json!(isrc);
+    }
+    let mut payload = json!({
+        "track_metadata": {
+            "artist_name": listen.artist,
+            "track_name": listen.track,
+            "release_name": listen.album,
+            "additional_info": additional_info,
+        }
+    });
+    if !now_playing {
+        payload["listened_at"] = json!(listen.listened_at);
+    }
+    json!({
+        "listen_type": if now_playing { "playing_now" } else { "single" },
+        "payload": [payload],
+    })
+}
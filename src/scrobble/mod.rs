@@ -0,0 +1,711 @@
+//! Scrobbling of streamed tracks
+//!
+//! A track stream reports "now playing" when it starts, and submits a listen
+//! once it has played half of the track or 4 minutes of it: that long since
+//! it started, and no further than the stream has served. A stream which the
+//! client drops before that is not submitted. Submissions go to
+//! a ListenBrainz or a Last.fm compatible API at `--scrobble-url`, with the
+//! token of the server user from `--scrobble-token user:token`.
+//!
+//! Listens which fail for a temporary reason are kept in
+//! `{cache_dir}/scrobbles.json` and retried in the background.
+
+mod lastfm;
+mod listenbrainz;
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use rspotify::{
+    clients::BaseClient,
+    model::{FullTrack, Id, TrackId},
+};
+
+use crate::{account::SpotifyAccount, errors::ServerError};
+
+/// A listen counts after this much of the track is served, or half of it
+const LISTEN_THRESHOLD_MS: u64 = 4 * 60 * 1000;
+
+/// Tracks shorter than this are never scrobbled, as Last.fm requires
+const MIN_DURATION_MS: u64 = 30 * 1000;
+
+/// An API which doesn't answer in time fails the submission temporarily
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How often the queued listens are retried
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScrobbleService {
+    /// The ListenBrainz API, the tokens are user tokens
+    #[value(name = "listenbrainz")]
+    ListenBrainz,
+    /// The Last.fm API 2.0, the tokens are session keys
+    #[value(name = "lastfm")]
+    LastFm,
+}
+
+impl ScrobbleService {
+    pub fn default_url(&self) -> &'static str {
+        match self {
+            ScrobbleService::ListenBrainz => "https://api.listenbrainz.org",
+            ScrobbleService::LastFm => "https://ws.audioscrobbler.com",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ScrobbleConfig {
+    pub service: ScrobbleService,
+    /// The API url without a trailing slash, a local mock in tests
+    pub base_url: String,
+    /// The application key and secret, only for Last.fm
+    pub api_key: String,
+    pub api_secret: String,
+    /// The tokens of the server users who scrobble
+    pub tokens: HashMap<String, String>,
+    /// The file of the listens waiting for a retry
+    pub queue_path: PathBuf,
+}
+
+/// Parse the `user:token` entries of `--scrobble-token`
+pub fn parse_tokens(entries: &[String]) -> Result<HashMap<String, String>, ServerError> {
+    entries
+        .iter()
+        .map(|entry| {
+            entry
+                .split_once(':')
+                .map(|(username, token)| (username.to_owned(), token.to_owned()))
+                .ok_or_else(|| {
+                    ServerError::ParamsError(format!(
+                        "Invalid scrobble token {:?}, expected user:token",
+                        entry
+                    ))
+                })
+        })
+        .collect()
+}
+
+/// A played track
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Listen {
+    pub track_id: String,
+    pub artist: String,
+    pub track: String,
+    pub album: String,
+    pub duration_ms: u64,
+    pub isrc: Option<String>,
+    /// Seconds since the epoch when the track started playing
+    pub listened_at: u64,
+}
+
+impl Listen {
+    pub fn new(track: &FullTrack) -> Option<Self> {
+        Some(Listen {
+            track_id: track.id.as_ref()?.id().to_owned(),
+            artist: track.artists.first()?.name.clone(),
+            track: track.name.clone(),
+            album: track.album.name.clone(),
+            duration_ms: track.duration.num_milliseconds().max(0) as u64,
+            isrc: track.external_ids.get("isrc").cloned(),
+            listened_at: now(),
+        })
+    }
+
+    pub fn spotify_url(&self) -> String {
+        format!("https://open.spotify.com/track/{}", self.track_id)
+    }
+
+    /// The played position from which the listen counts
+    fn threshold_ms(&self) -> u64 {
+        (self.duration_ms / 2).min(LISTEN_THRESHOLD_MS)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+struct QueuedListen {
+    username: String,
+    listen: Listen,
+}
+
+/// Why a submission failed
+enum SubmitError {
+    /// The API or the network is down, the listen is retried
+    Temporary(String),
+    /// The API refused the listen
+    Permanent(String),
+}
+
+impl std::fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubmitError::Temporary(reason) => write!(f, "temporary failure, {}", reason),
+            SubmitError::Permanent(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl From<reqwest::Error> for SubmitError {
+    fn from(error: reqwest::Error) -> Self {
+        SubmitError::Temporary(error.to_string())
+    }
+}
+
+#[derive(Clone)]
+pub struct Scrobbler {
+    config: Arc<ScrobbleConfig>,
+    client: reqwest::Client,
+    queue: Arc<Mutex<Vec<QueuedListen>>>,
+}
+
+impl Scrobbler {
+    /// Load the listens queued by the last run
+    pub fn open(config: ScrobbleConfig) -> Result<Self, ServerError> {
+        let queue = if config.queue_path.exists() {
+            serde_json::from_slice(&std::fs::read(&config.queue_path)?)?
+        } else {
+            Vec::new()
+        };
+        Ok(Scrobbler {
+            config: Arc::new(config),
+            client: reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .map_err(|err| ServerError::InnerError(err.to_string()))?,
+            queue: Arc::new(Mutex::new(queue)),
+        })
+    }
+
+    /// Report the track `id` as playing for `username`
+    ///
+    /// Only tracks of the users with a token are scrobbled.
+    pub async fn now_playing(
+        &self,
+        username: &str,
+        account: &SpotifyAccount,
+        id: &str,
+    ) -> Option<Playback> {
+        if !self.config.tokens.contains_key(username) {
+            return None;
+        }
+        let track_id = TrackId::from_uri(id).ok()?;
+        let market = account.default_market().await;
+        let track = match account.client.track(track_id, Some(market)).await {
+            Ok(track) => track,
+            Err(err) => {
+                tracing::warn!("Failed to get the scrobbled track {}: {}", id, err);
+                return None;
+            }
+        };
+        let listen = Listen::new(&track)?;
+        if listen.duration_ms < MIN_DURATION_MS {
+            return None;
+        }
+
+        let scrobbler = self.clone();
+        let (user, now_playing) = (username.to_owned(), listen.clone());
+        tokio::spawn(async move {
+            if let Err(err) = scrobbler.send(&user, &now_playing, true).await {
+                tracing::warn!("Failed to update now playing: {}", err);
+            }
+        });
+
+        Some(Playback {
+            scrobbler: self.clone(),
+            username: username.to_owned(),
+            threshold_ms: listen.threshold_ms(),
+            listen,
+            started: Instant::now(),
+            submitted: false,
+        })
+    }
+
+    /// Submit a listen, or queue it when the API is unavailable
+    fn submit(&self, username: String, listen: Listen) {
+        let scrobbler = self.clone();
+        tokio::spawn(async move {
+            match scrobbler.send(&username, &listen, false).await {
+                Ok(()) => tracing::info!("Scrobbled {} for {}", listen.track_id, username),
+                Err(SubmitError::Temporary(reason)) => {
+                    tracing::warn!("Queue the listen of {}: {}", listen.track_id, reason);
+                    scrobbler.enqueue(vec![QueuedListen { username, listen }]);
+                }
+                Err(err) => tracing::warn!("Listen of {} refused: {}", listen.track_id, err),
+            }
+        });
+    }
+
+    /// Retry the queued listens, forever
+    pub async fn retry_queued(self) {
+        let mut interval = tokio::time::interval(RETRY_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.retry().await;
+        }
+    }
+
+    /// Retry the queued listens until the API fails again
+    async fn retry(&self) {
+        let queued = std::mem::take(&mut *self.queue.lock().unwrap());
+        if queued.is_empty() {
+            return;
+        }
+        let mut failed = Vec::new();
+        for (i, item) in queued.iter().enumerate() {
+            match self.send(&item.username, &item.listen, false).await {
+                Ok(()) => {}
+                Err(SubmitError::Temporary(reason)) => {
+                    // The API is still down, keep the rest for later
+                    tracing::warn!("Failed to retry queued listens: {}", reason);
+                    failed.extend_from_slice(&queued[i..]);
+                    break;
+                }
+                Err(err) => {
+                    let track_id = &item.listen.track_id;
+                    tracing::warn!("Queued listen of {} refused: {}", track_id, err);
+                }
+            }
+        }
+        tracing::info!(
+            "Retried {} queued listens, {} left",
+            queued.len(),
+            failed.len()
+        );
+        self.enqueue(failed);
+    }
+
+    fn enqueue(&self, listens: Vec<QueuedListen>) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.extend(listens);
+        self.persist(&queue);
+    }
+
+    fn persist(&self, queue: &[QueuedListen]) {
+        let path = &self.config.queue_path;
+        let tmp_path = path.with_extension("json.tmp");
+        let result = path
+            .parent()
+            .map(std::fs::create_dir_all)
+            .transpose()
+            .and_then(|_| {
+                std::fs::write(&tmp_path, serde_json::to_vec(queue)?)?;
+                std::fs::rename(&tmp_path, path)
+            });
+        if let Err(err) = result {
+            tracing::warn!("Failed to persist queued listens to {:?}: {}", path, err);
+        }
+    }
+
+    async fn send(
+        &self,
+        username: &str,
+        listen: &Listen,
+        now_playing: bool,
+    ) -> Result<(), SubmitError> {
+        let token = self.config.tokens.get(username).ok_or_else(|| {
+            SubmitError::Permanent(format!("No scrobble token of user {}", username))
+        })?;
+
+        let request = match self.config.service {
+            ScrobbleService::ListenBrainz => self
+                .client
+                .post(listenbrainz::url(&self.config.base_url))
+                .header(reqwest::header::AUTHORIZATION, format!("Token {}", token))
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(listenbrainz::body(listen, now_playing).to_string()),
+            ScrobbleService::LastFm => {
+                self.client
+                    .post(lastfm::url(&self.config.base_url))
+                    .form(&lastfm::params(
+                        listen,
+                        now_playing,
+                        &self.config.api_key,
+                        &self.config.api_secret,
+                        token,
+                    ))
+            }
+        };
+        let response = request.send().await?;
+        let status = response.status();
+        let body = response.text().await?;
+
+        let failed = match self.config.service {
+            ScrobbleService::ListenBrainz => !status.is_success(),
+            // Last.fm reports errors in the body, sometimes with a 200
+            ScrobbleService::LastFm => !status.is_success() || lastfm::is_error(&body),
+        };
+        if !failed {
+            return Ok(());
+        }
+        let reason = format!("{} {}", status, body);
+        let temporary = status.is_server_error()
+            || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            || (self.config.service == ScrobbleService::LastFm && lastfm::is_temporary(&body));
+        if temporary {
+            Err(SubmitError::Temporary(reason))
+        } else {
+            Err(SubmitError::Permanent(reason))
+        }
+    }
+}
+
+/// A scrobbled track stream
+///
+/// Dropping it before the listen counts, as when the client disconnects,
+/// submits nothing.
+pub struct Playback {
+    scrobbler: Scrobbler,
+    username: String,
+    listen: Listen,
+    threshold_ms: u64,
+    /// When the stream started, as clients play no faster than real time
+    started: Instant,
+    submitted: bool,
+}
+
+impl Playback {
+    /// The played position, given the position the stream has served
+    fn position_ms(&self, served_ms: u64) -> u64 {
+        let elapsed_ms = self.started.elapsed().as_millis() as u64;
+        elapsed_ms.min(served_ms)
+    }
+
+    fn submit(&mut self) {
+        self.submitted = true;
+        self.scrobbler
+            .submit(self.username.clone(), self.listen.clone());
+    }
+
+    /// Submit the listen once the stream has played past the threshold,
+    /// where it has served up to `served_ms`
+    pub fn progress(&mut self, served_ms: u64) {
+        if !self.submitted && self.position_ms(served_ms) >= self.threshold_ms {
+            self.submit();
+        }
+    }
+
+    /// The stream served the whole track, up to `served_ms`
+    ///
+    /// The client plays on from its buffer, so the listen is submitted once
+    /// the threshold passes in real time.
+    pub fn finish(mut self, served_ms: u64) {
+        if self.submitted || served_ms < self.threshold_ms {
+            return;
+        }
+        let remaining = self
+            .threshold_ms
+            .saturating_sub(self.position_ms(served_ms));
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(remaining)).await;
+            self.submit();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use actix_web::{http::StatusCode, web, App, HttpRequest, HttpResponse, HttpServer};
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct Request {
+        path: String,
+        authorization: Option<String>,
+        body: String,
+    }
+
+    /// A local API which answers with the responses in turn, repeating the last
+    struct MockApi {
+        url: String,
+        requests: Arc<Mutex<Vec<Request>>>,
+    }
+
+    impl MockApi {
+        fn start(responses: &[(u16, &'static str)]) -> Self {
+            let requests = Arc::new(Mutex::new(vec![]));
+            let responses = Arc::new(Mutex::new(VecDeque::from(responses.to_vec())));
+            let recorded = requests.clone();
+            let server = HttpServer::new(move || {
+                let (requests, responses) = (recorded.clone(), responses.clone());
+                App::new().default_service(web::to(move |req: HttpRequest, body: String| {
+                    let (requests, responses) = (requests.clone(), responses.clone());
+                    async move {
+                        requests.lock().unwrap().push(Request {
+                            path: req.path().to_owned(),
+                            authorization: req
+                                .headers()
+                                .get("authorization")
+                                .map(|value| value.to_str().unwrap().to_owned()),
+                            body,
+                        });
+                        let mut responses = responses.lock().unwrap();
+                        let (status, body) = match responses.len() {
+                            1 => responses[0],
+                            _ => responses.pop_front().unwrap(),
+                        };
+                        HttpResponse::build(StatusCode::from_u16(status).unwrap()).body(body)
+                    }
+                }))
+            })
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+            let url = format!("http://{}", server.addrs()[0]);
+            actix_web::rt::spawn(server.run());
+            MockApi { url, requests }
+        }
+
+        fn requests(&self) -> std::sync::MutexGuard<'_, Vec<Request>> {
+            self.requests.lock().unwrap()
+        }
+    }
+
+    fn config(service: ScrobbleService, base_url: &str) -> ScrobbleConfig {
+        let queue_path = std::env::temp_dir()
+            .join(format!("scrobbles-{}", rand::random::<u64>()))
+            .join("scrobbles.json");
+        ScrobbleConfig {
+            service,
+            base_url: base_url.to_owned(),
+            api_key: "key".to_owned(),
+            api_secret: "secret".to_owned(),
+            tokens: HashMap::from([("alice".to_owned(), "session".to_owned())]),
+            queue_path,
+        }
+    }
+
+    fn listen() -> Listen {
+        Listen {
+            track_id: "4uLU6hMCjMI75M1A2tKUQC".to_owned(),
+            artist: "Artist".to_owned(),
+            track: "Track".to_owned(),
+            album: "Album".to_owned(),
+            duration_ms: 200_000,
+            isrc: Some("USRC17607839".to_owned()),
+            listened_at: 1_700_000_000,
+        }
+    }
+
+    /// The form fields of a Last.fm request
+    fn form(body: &str) -> HashMap<String, String> {
+        url::form_urlencoded::parse(body.as_bytes())
+            .into_owned()
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn listenbrainz_submissions() {
+        let api = MockApi::start(&[(200, r#"{"status":"ok"}"#)]);
+        let scrobbler = Scrobbler::open(config(ScrobbleService::ListenBrainz, &api.url)).unwrap();
+        assert!(scrobbler.send("alice", &listen(), true).await.is_ok());
+        assert!(scrobbler.send("alice", &listen(), false).await.is_ok());
+        assert!(matches!(
+            scrobbler.send("bob", &listen(), false).await,
+            Err(SubmitError::Permanent(_))
+        ));
+
+        let requests = api.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].path, "/1/submit-listens");
+        assert_eq!(requests[0].authorization.as_deref(), Some("Token session"));
+        let now_playing = serde_json::from_str::<serde_json::Value>(&requests[0].body).unwrap();
+        assert_eq!(now_playing["listen_type"], "playing_now");
+        assert!(now_playing["payload"][0].get("listened_at").is_none());
+        let single = serde_json::from_str::<serde_json::Value>(&requests[1].body).unwrap();
+        assert_eq!(single["listen_type"], "single");
+        assert_eq!(single["payload"][0]["listened_at"], 1_700_000_000);
+        let metadata = &single["payload"][0]["track_metadata"];
+        assert_eq!(metadata["artist_name"], "Artist");
+        assert_eq!(metadata["additional_info"]["isrc"], "USRC17607839");
+    }
+
+    #[actix_web::test]
+    async fn lastfm_submissions() {
+        let api = MockApi::start(&[(200, r#"{"scrobbles":{}}"#)]);
+        let scrobbler = Scrobbler::open(config(ScrobbleService::LastFm, &api.url)).unwrap();
+        assert!(scrobbler.send("alice", &listen(), false).await.is_ok());
+        assert!(scrobbler.send("alice", &listen(), true).await.is_ok());
+
+        let requests = api.requests();
+        assert_eq!(requests[0].path, "/2.0/");
+        let scrobble = form(&requests[0].body);
+        assert_eq!(scrobble["method"], "track.scrobble");
+        assert_eq!(scrobble["sk"], "session");
+        assert_eq!(scrobble["timestamp"], "1700000000");
+        assert_eq!(scrobble["format"], "json");
+        // md5 of albumAlbumapi_keykeyartistArtistduration200methodtrack.scrobble
+        // sksessiontimestamp1700000000trackTrack and the secret
+        assert_eq!(scrobble["api_sig"], "081393356857c2e37578dda6f1bcab77");
+        let now_playing = form(&requests[1].body);
+        assert_eq!(now_playing["method"], "track.updateNowPlaying");
+        assert!(!now_playing.contains_key("timestamp"));
+    }
+
+    #[actix_web::test]
+    async fn failures_are_temporary_or_permanent() {
+        let cases = [
+            (ScrobbleService::ListenBrainz, 503, "", true),
+            (ScrobbleService::ListenBrainz, 429, "", true),
+            (ScrobbleService::ListenBrainz, 400, "bad listen", false),
+            (ScrobbleService::ListenBrainz, 401, "", false),
+            (
+                ScrobbleService::LastFm,
+                200,
+                r#"{"error":11,"message":"offline"}"#,
+                true,
+            ),
+            (
+                ScrobbleService::LastFm,
+                200,
+                r#"{"error":29,"message":"rate"}"#,
+                true,
+            ),
+            (
+                ScrobbleService::LastFm,
+                403,
+                r#"{"error":9,"message":"session"}"#,
+                false,
+            ),
+            (ScrobbleService::LastFm, 500, "", true),
+        ];
+        for (service, status, body, temporary) in cases {
+            let api = MockApi::start(&[(status, body)]);
+            let scrobbler = Scrobbler::open(config(service, &api.url)).unwrap();
+            let result = scrobbler.send("alice", &listen(), false).await;
+            assert!(
+                match result {
+                    Err(SubmitError::Temporary(_)) => temporary,
+                    Err(SubmitError::Permanent(_)) => !temporary,
+                    Ok(()) => false,
+                },
+                "{:?} {} {}",
+                service,
+                status,
+                body
+            );
+        }
+
+        // The API is unreachable
+        let scrobbler =
+            Scrobbler::open(config(ScrobbleService::ListenBrainz, "http://127.0.0.1:1")).unwrap();
+        assert!(matches!(
+            scrobbler.send("alice", &listen(), false).await,
+            Err(SubmitError::Temporary(_))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn queued_listens_persist_and_retry() {
+        let api = MockApi::start(&[(503, ""), (503, ""), (200, "{}")]);
+        let config = config(ScrobbleService::ListenBrainz, &api.url);
+        let scrobbler = Scrobbler::open(config.clone()).unwrap();
+        scrobbler.submit("alice".to_owned(), listen());
+        scrobbler.submit("alice".to_owned(), listen());
+        for _ in 0..100 {
+            if scrobbler.queue.lock().unwrap().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        // The next run loads the queue
+        let scrobbler = Scrobbler::open(config.clone()).unwrap();
+        assert_eq!(scrobbler.queue.lock().unwrap().len(), 2);
+        scrobbler.retry().await;
+        assert!(scrobbler.queue.lock().unwrap().is_empty());
+        assert!(Scrobbler::open(config)
+            .unwrap()
+            .queue
+            .lock()
+            .unwrap()
+            .is_empty());
+        assert_eq!(api.requests().len(), 4);
+    }
+
+    #[actix_web::test]
+    async fn retry_keeps_the_rest_while_the_api_is_down() {
+        let api = MockApi::start(&[(200, "{}"), (503, "")]);
+        let config = config(ScrobbleService::ListenBrainz, &api.url);
+        let scrobbler = Scrobbler::open(config.clone()).unwrap();
+        let queued = |track: &str| QueuedListen {
+            username: "alice".to_owned(),
+            listen: Listen {
+                track: track.to_owned(),
+                ..listen()
+            },
+        };
+        scrobbler.enqueue(vec![queued("one"), queued("two"), queued("three")]);
+        scrobbler.retry().await;
+
+        let queue = Scrobbler::open(config)
+            .unwrap()
+            .queue
+            .lock()
+            .unwrap()
+            .clone();
+        let tracks = queue
+            .iter()
+            .map(|item| item.listen.track.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(tracks, ["two", "three"]);
+        // The third one is not sent while the API is down
+        assert_eq!(api.requests().len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn playback_counts_played_time() {
+        let api = MockApi::start(&[(200, "{}")]);
+        let scrobbler = Scrobbler::open(config(ScrobbleService::ListenBrainz, &api.url)).unwrap();
+        let playback = |started_ago: u64| Playback {
+            scrobbler: scrobbler.clone(),
+            username: "alice".to_owned(),
+            threshold_ms: listen().threshold_ms(),
+            listen: listen(),
+            started: Instant::now() - Duration::from_millis(started_ago),
+            submitted: false,
+        };
+        assert_eq!(listen().threshold_ms(), 100_000);
+
+        // Served quickly, but not played yet
+        let mut early = playback(1_000);
+        early.progress(200_000);
+        assert!(!early.submitted);
+        // Played long enough, but not served yet
+        let mut buffering = playback(150_000);
+        buffering.progress(50_000);
+        assert!(!buffering.submitted);
+        let mut played = playback(150_000);
+        played.progress(120_000);
+        assert!(played.submitted);
+
+        // A finished stream submits once the threshold passes
+        playback(99_950).finish(200_000);
+        // A dropped one never does
+        drop(playback(1_000));
+        for _ in 0..100 {
+            if api.requests().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(api.requests().len(), 2);
+    }
+}
@@ -1,14 +1,22 @@
 use std::future::{ready, Ready};
 
-use actix_session::{Session, SessionExt};
-use actix_web::{dev::Payload, FromRequest, HttpRequest};
+use actix_session::{
+    config::{PersistentSession, SessionMiddlewareBuilder},
+    storage::SessionStore,
+    Session, SessionExt,
+};
+use actix_web::{
+    cookie::{time::Duration, SameSite},
+    dev::Payload,
+    FromRequest, HttpRequest,
+};
 
 use crate::{account::UserName, errors::ServerError};
 
 pub struct ServerSession(Session);
 
 impl ServerSession {
-    const USERNAME_KEY: &'static str = "username";
+    pub(crate) const USERNAME_KEY: &'static str = "username";
 
     pub fn get_username(&self) -> Result<UserName, ServerError> {
         self.0
@@ -28,6 +36,12 @@ impl ServerSession {
     }
 }
 
+impl From<Session> for ServerSession {
+    fn from(session: Session) -> Self {
+        ServerSession(session)
+    }
+}
+
 impl FromRequest for ServerSession {
     type Error = <Session as FromRequest>::Error;
     type Future = Ready<Result<ServerSession, Self::Error>>;
@@ -36,3 +50,34 @@ impl FromRequest for ServerSession {
         ready(Ok(ServerSession(req.get_session())))
     }
 }
+
+/// Options of the session cookie
+#[derive(Clone, Debug)]
+pub struct CookieConfig {
+    pub name: String,
+    pub domain: Option<String>,
+    pub secure: bool,
+    pub http_only: bool,
+    /// Seconds, or a browser-session cookie when `None`
+    pub max_age: Option<i64>,
+}
+
+impl CookieConfig {
+    pub fn configure<S: SessionStore>(
+        &self,
+        builder: SessionMiddlewareBuilder<S>,
+    ) -> SessionMiddlewareBuilder<S> {
+        let builder = builder
+            .cookie_name(self.name.clone())
+            .cookie_secure(self.secure)
+            .cookie_same_site(SameSite::None)
+            .cookie_http_only(self.http_only)
+            .cookie_domain(self.domain.clone());
+        match self.max_age {
+            Some(max_age) => builder.session_lifecycle(
+                PersistentSession::default().session_ttl(Duration::seconds(max_age)),
+            ),
+            None => builder,
+        }
+    }
+}
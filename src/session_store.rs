@@ -0,0 +1,455 @@
+//! Session storage backends
+//!
+//! Sessions live in the encrypted cookie by default. With `--session-store file`
+//! the cookie only carries a session key and the sessions are kept in
+//! `{cache_dir}/sessions.json`, so they survive restarts and can be listed and
+//! revoked per user.
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix_session::storage::{
+    CookieSessionStore, LoadError, SaveError, SessionKey, SessionStore, UpdateError,
+};
+use actix_web::cookie::time;
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::Digest;
+
+use crate::{common::hex, errors::ServerError, session::ServerSession};
+
+/// Only write `last_seen` back to disk once a minute per session
+const TOUCH_INTERVAL: u64 = 60;
+
+/// Changes are written together after this delay, off the request threads
+const PERSIST_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionStoreKind {
+    /// Sessions in the encrypted cookie, lost when the secret changes
+    Cookie,
+    /// Sessions in `{cache_dir}/sessions.json`
+    File,
+}
+
+/// The session store of the session middleware
+#[derive(Clone)]
+pub enum SessionBackend {
+    Cookie,
+    File(FileSessionStore),
+}
+
+#[async_trait::async_trait(?Send)]
+impl SessionStore for SessionBackend {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        match self {
+            SessionBackend::Cookie => CookieSessionStore::default().load(session_key).await,
+            SessionBackend::File(store) => Ok(store.load(session_key.as_ref())),
+        }
+    }
+
+    async fn save(
+        &self,
+        session_state: SessionState,
+        ttl: &time::Duration,
+    ) -> Result<SessionKey, SaveError> {
+        match self {
+            SessionBackend::Cookie => CookieSessionStore::default().save(session_state, ttl).await,
+            SessionBackend::File(store) => SessionKey::try_from(store.save(session_state))
+                .map_err(|e| SaveError::Other(e.into())),
+        }
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: SessionState,
+        ttl: &time::Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        match self {
+            SessionBackend::Cookie => {
+                CookieSessionStore::default()
+                    .update(session_key, session_state, ttl)
+                    .await
+            }
+            SessionBackend::File(store) => {
+                if store.update(session_key.as_ref(), session_state) {
+                    Ok(session_key)
+                } else {
+                    // The session was revoked or has expired in the meantime,
+                    // so the client starts over with an empty session
+                    SessionKey::try_from(store.save(SessionState::new()))
+                        .map_err(|e| UpdateError::Other(e.into()))
+                }
+            }
+        }
+    }
+
+    async fn update_ttl(
+        &self,
+        session_key: &SessionKey,
+        ttl: &time::Duration,
+    ) -> Result<(), anyhow::Error> {
+        match self {
+            SessionBackend::Cookie => {
+                CookieSessionStore::default()
+                    .update_ttl(session_key, ttl)
+                    .await
+            }
+            SessionBackend::File(store) => {
+                store.load(session_key.as_ref());
+                Ok(())
+            }
+        }
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        match self {
+            SessionBackend::Cookie => CookieSessionStore::default().delete(session_key).await,
+            SessionBackend::File(store) => {
+                store.delete(session_key.as_ref());
+                Ok(())
+            }
+        }
+    }
+}
+
+type SessionState = HashMap<String, String>;
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+struct SessionRecord {
+    state: SessionState,
+    // Unix timestamps in seconds
+    created_at: u64,
+    last_seen: u64,
+}
+
+impl SessionRecord {
+    fn username(&self) -> Option<String> {
+        self.state
+            .get(ServerSession::USERNAME_KEY)
+            .and_then(|value| serde_json::from_str(value).ok())
+    }
+}
+
+/// A session as shown to its user, without the session key
+#[derive(Debug, serde::Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub created_at: u64,
+    pub last_seen: u64,
+    pub expires_at: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The public id of a session, which can't be used as its key
+fn session_id(session_key: &str) -> String {
+    hex::encode(&sha2::Sha256::digest(session_key.as_bytes()))[..16].to_lowercase()
+}
+
+/// Sessions persisted as one JSON file
+///
+/// A session expires when it is idle for `idle_timeout`, or at the latest
+/// `max_lifetime` after it was created. The file is written by a background
+/// thread, so the requests never wait for the disk.
+#[derive(Clone)]
+pub struct FileSessionStore {
+    path: PathBuf,
+    idle_timeout: Duration,
+    max_lifetime: Duration,
+    persist_delay: Duration,
+    sessions: Arc<Mutex<HashMap<String, SessionRecord>>>,
+    // Set when the sessions changed since the last write
+    changed: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl FileSessionStore {
+    pub fn open(
+        path: PathBuf,
+        idle_timeout: Duration,
+        max_lifetime: Duration,
+    ) -> Result<Self, ServerError> {
+        Self::open_with_delay(path, idle_timeout, max_lifetime, PERSIST_DELAY)
+    }
+
+    fn open_with_delay(
+        path: PathBuf,
+        idle_timeout: Duration,
+        max_lifetime: Duration,
+        persist_delay: Duration,
+    ) -> Result<Self, ServerError> {
+        let sessions = if path.exists() {
+            serde_json::from_slice(&std::fs::read(&path)?)?
+        } else {
+            HashMap::new()
+        };
+        let store = FileSessionStore {
+            path,
+            idle_timeout,
+            max_lifetime,
+            persist_delay,
+            sessions: Arc::new(Mutex::new(sessions)),
+            changed: Arc::new((Mutex::new(false), Condvar::new())),
+        };
+        {
+            let mut sessions = store.sessions.lock().unwrap();
+            store.prune(&mut sessions);
+            write(&store.path, &sessions);
+        }
+
+        let writer = store.clone();
+        std::thread::Builder::new()
+            .name("session-store".to_owned())
+            .spawn(move || writer.write_changes())?;
+        Ok(store)
+    }
+
+    fn expires_at(&self, record: &SessionRecord) -> u64 {
+        (record.last_seen + self.idle_timeout.as_secs())
+            .min(record.created_at + self.max_lifetime.as_secs())
+    }
+
+    fn prune(&self, sessions: &mut HashMap<String, SessionRecord>) {
+        let now = now();
+        sessions.retain(|_, record| self.expires_at(record) > now);
+    }
+
+    /// Schedule a write of the sessions
+    fn persist(&self) {
+        let (changed, condvar) = &*self.changed;
+        *changed.lock().unwrap() = true;
+        condvar.notify_one();
+    }
+
+    /// Write the sessions after each batch of changes, forever
+    fn write_changes(&self) {
+        let (changed, condvar) = &*self.changed;
+        loop {
+            {
+                let mut changed = changed.lock().unwrap();
+                while !*changed {
+                    changed = condvar.wait(changed).unwrap();
+                }
+            }
+            std::thread::sleep(self.persist_delay);
+            *changed.lock().unwrap() = false;
+            let sessions = self.sessions.lock().unwrap().clone();
+            write(&self.path, &sessions);
+        }
+    }
+
+    fn load(&self, session_key: &str) -> Option<SessionState> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = now();
+        let record = sessions.get_mut(session_key)?;
+        if self.expires_at(record) <= now {
+            sessions.remove(session_key);
+            self.persist();
+            return None;
+        }
+        let state = record.state.clone();
+        if now.saturating_sub(record.last_seen) >= TOUCH_INTERVAL {
+            record.last_seen = now;
+            self.persist();
+        }
+        Some(state)
+    }
+
+    fn save(&self, state: SessionState) -> String {
+        let session_key: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(64)
+            .map(char::from)
+            .collect();
+        let now = now();
+        let mut sessions = self.sessions.lock().unwrap();
+        self.prune(&mut sessions);
+        sessions.insert(
+            session_key.clone(),
+            SessionRecord {
+                state,
+                created_at: now,
+                last_seen: now,
+            },
+        );
+        self.persist();
+        session_key
+    }
+
+    /// Update a live session, `false` when it doesn't exist any more
+    fn update(&self, session_key: &str, state: SessionState) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get_mut(session_key) {
+            Some(record) if self.expires_at(record) > now() => {
+                record.state = state;
+                record.last_seen = now();
+                self.persist();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn delete(&self, session_key: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.remove(session_key).is_some() {
+            self.persist();
+        }
+    }
+
+    /// The live sessions of a user, the most recently used first
+    pub fn list(&self, username: &str) -> Vec<SessionInfo> {
+        let sessions = self.sessions.lock().unwrap();
+        let now = now();
+        let mut infos = sessions
+            .iter()
+            .filter(|(_, record)| {
+                self.expires_at(record) > now && record.username().as_deref() == Some(username)
+            })
+            .map(|(session_key, record)| SessionInfo {
+                id: session_id(session_key),
+                created_at: record.created_at,
+                last_seen: record.last_seen,
+                expires_at: self.expires_at(record),
+            })
+            .collect::<Vec<_>>();
+        infos.sort_by_key(|info| Reverse(info.last_seen));
+        infos
+    }
+
+    /// Revoke the session of a user by its public id
+    pub fn revoke(&self, username: &str, id: &str) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let len = sessions.len();
+        sessions.retain(|session_key, record| {
+            !(session_id(session_key) == id && record.username().as_deref() == Some(username))
+        });
+        let revoked = sessions.len() < len;
+        if revoked {
+            self.persist();
+        }
+        revoked
+    }
+
+    /// Revoke all sessions of a user, returning how many were revoked
+    pub fn revoke_all(&self, username: &str) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        let len = sessions.len();
+        sessions.retain(|_, record| record.username().as_deref() != Some(username));
+        let revoked = len - sessions.len();
+        if revoked > 0 {
+            self.persist();
+        }
+        revoked
+    }
+}
+
+fn write(path: &Path, sessions: &HashMap<String, SessionRecord>) {
+    let tmp_path = path.with_extension("json.tmp");
+    let result = path
+        .parent()
+        .map(std::fs::create_dir_all)
+        .transpose()
+        .and_then(|_| {
+            std::fs::write(&tmp_path, serde_json::to_vec(sessions)?)?;
+            // The session keys are credentials
+            #[cfg(unix)]
+            std::fs::set_permissions(
+                &tmp_path,
+                std::os::unix::fs::PermissionsExt::from_mode(0o600),
+            )?;
+            std::fs::rename(&tmp_path, path)
+        });
+    if let Err(err) = result {
+        tracing::warn!("Failed to persist sessions to {:?}: {}", path, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// A store in a temp dir, which is removed when the dir is dropped
+    fn open_store(idle_timeout: Duration, max_lifetime: Duration) -> (TempDir, FileSessionStore) {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("sessions").join("sessions.json");
+        let store =
+            FileSessionStore::open_with_delay(path, idle_timeout, max_lifetime, Duration::ZERO)
+                .unwrap();
+        (dir, store)
+    }
+
+    fn state(username: &str) -> SessionState {
+        HashMap::from([(
+            ServerSession::USERNAME_KEY.to_owned(),
+            serde_json::to_string(username).unwrap(),
+        )])
+    }
+
+    fn written(store: &FileSessionStore) -> HashMap<String, SessionRecord> {
+        std::fs::read(&store.path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn update_of_a_revoked_session_is_refused() {
+        let (_dir, store) = open_store(Duration::from_secs(60), Duration::from_secs(3600));
+        let session_key = store.save(state("alice"));
+        assert!(store.update(&session_key, state("alice")));
+
+        assert_eq!(store.revoke_all("alice"), 1);
+        assert!(!store.update(&session_key, state("alice")));
+        assert!(store.load(&session_key).is_none());
+        assert!(store.list("alice").is_empty());
+    }
+
+    #[test]
+    fn sessions_expire() {
+        let (_dir, store) = open_store(Duration::ZERO, Duration::from_secs(3600));
+        let session_key = store.save(state("alice"));
+        assert!(store.load(&session_key).is_none());
+        assert!(!store.update(&session_key, state("alice")));
+        assert!(store.list("alice").is_empty());
+
+        let (_dir, store) = open_store(Duration::from_secs(60), Duration::ZERO);
+        let session_key = store.save(state("alice"));
+        assert!(store.load(&session_key).is_none());
+
+        let (_dir, store) = open_store(Duration::from_secs(60), Duration::from_secs(3600));
+        let session_key = store.save(state("alice"));
+        assert!(store.load(&session_key).is_some());
+        let sessions = store.list("alice");
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].expires_at, sessions[0].last_seen + 60);
+    }
+
+    #[test]
+    fn sessions_are_written_in_the_background() {
+        let (_dir, store) = open_store(Duration::from_secs(60), Duration::from_secs(3600));
+        let session_key = store.save(state("bob"));
+
+        // The writer thread runs without delay, so only wait for it to be
+        // scheduled
+        let mut waited = 0;
+        while !written(&store).contains_key(&session_key) {
+            assert!(waited < 200, "Sessions were not written");
+            std::thread::sleep(Duration::from_millis(10));
+            waited += 1;
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! Subsonic/OpenSubsonic API compatibility
+//!
+//! Subsonic clients (DSub, Symfonium, Sonixd, ...) call `/rest/{method}` with
+//! the credentials in every request, and always get HTTP 200 with a
+//! `subsonic-response` envelope, as XML by default or as JSON with `f=json`.
+//! The handlers live in `endpoints::subsonic`.
+
+pub mod models;
+mod xml;
+
+use std::collections::HashMap;
+
+use actix_web::{
+    http::header::{self, ContentType},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use md5::{Digest, Md5};
+use serde_json::{json, Map, Value};
+use subtle::ConstantTimeEq;
+
+use crate::{app_store::AppStore, common::hex, errors::ServerError};
+
+/// The Subsonic API version we implement
+pub const API_VERSION: &str = "1.16.1";
+
+/// Request parameters, from the query string and form bodies
+///
+/// Subsonic repeats keys for lists, e.g. `id=1&id=2`.
+pub struct Params(Vec<(String, String)>);
+
+impl Params {
+    pub fn parse(req: &HttpRequest, body: &[u8]) -> Self {
+        let mut params: Vec<(String, String)> =
+            url::form_urlencoded::parse(req.query_string().as_bytes())
+                .into_owned()
+                .collect();
+        let is_form = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.starts_with("application/x-www-form-urlencoded"))
+            .unwrap_or(false);
+        if is_form {
+            params.extend(url::form_urlencoded::parse(body).into_owned());
+        }
+        Params(params)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn all(&self, key: &str) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    pub fn required(&self, key: &str) -> Result<&str, SubsonicError> {
+        self.get(key).ok_or_else(|| SubsonicError::missing(key))
+    }
+
+    pub fn number<T: std::str::FromStr>(&self, key: &str) -> Result<Option<T>, SubsonicError> {
+        self.get(key)
+            .map(|v| {
+                v.parse()
+                    .map_err(|_| SubsonicError::new(0, format!("Invalid parameter {}", key)))
+            })
+            .transpose()
+    }
+
+    fn is_json(&self) -> bool {
+        matches!(self.get("f"), Some("json" | "jsonp"))
+    }
+}
+
+/// An error of the Subsonic API with its error code
+#[derive(Debug)]
+pub struct SubsonicError {
+    code: u32,
+    message: String,
+}
+
+impl SubsonicError {
+    pub fn new(code: u32, message: impl Into<String>) -> Self {
+        SubsonicError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn missing(key: &str) -> Self {
+        Self::new(10, format!("Required parameter is missing: {}", key))
+    }
+
+    pub fn wrong_credentials() -> Self {
+        Self::new(40, "Wrong username or password")
+    }
+
+    pub fn not_found(what: &str) -> Self {
+        Self::new(70, format!("{} not found", what))
+    }
+}
+
+impl From<ServerError> for SubsonicError {
+    fn from(err: ServerError) -> Self {
+        let code = match err.status_code().as_u16() {
+            400 => 10,
+            401 => 40,
+            403 => 50,
+            404 => 70,
+            _ => 0,
+        };
+        SubsonicError::new(code, err.to_string())
+    }
+}
+
+/// Check `u` with a plain (`p`), hex encoded (`p=enc:..`) or salted token (`t`, `s`) password
+pub fn authenticate(params: &Params, app_store: &AppStore) -> Result<String, SubsonicError> {
+    let username = params.required("u")?;
+    let password = app_store
+        .subsonic_users
+        .get(username)
+        .ok_or_else(SubsonicError::wrong_credentials)?;
+
+    if verify_password(params, password)? && app_store.is_allowed(username) {
+        Ok(username.to_owned())
+    } else {
+        Err(SubsonicError::wrong_credentials())
+    }
+}
+
+/// Compare the credentials of the request with `password`, in constant time
+fn verify_password(params: &Params, password: &str) -> Result<bool, SubsonicError> {
+    let matches = match (params.get("t"), params.get("s"), params.get("p")) {
+        (Some(token), Some(salt), _) => {
+            let expected = hex::encode(&Md5::digest(format!("{}{}", password, salt).as_bytes()))
+                .to_lowercase();
+            expected
+                .as_bytes()
+                .ct_eq(token.to_ascii_lowercase().as_bytes())
+        }
+        (_, _, Some(p)) => match p.strip_prefix("enc:") {
+            Some(encoded) => match hex::decode(encoded) {
+                Ok(decoded) => decoded.ct_eq(password.as_bytes()),
+                Err(_) => return Ok(false),
+            },
+            None => p.as_bytes().ct_eq(password.as_bytes()),
+        },
+        _ => return Err(SubsonicError::missing("t")),
+    };
+    Ok(matches.into())
+}
+
+fn envelope(status: &str, payload: Map<String, Value>) -> Value {
+    let mut response = Map::new();
+    response.insert("status".to_owned(), json!(status));
+    response.insert("version".to_owned(), json!(API_VERSION));
+    response.insert("type".to_owned(), json!(env!("CARGO_PKG_NAME")));
+    response.insert("serverVersion".to_owned(), json!(env!("CARGO_PKG_VERSION")));
+    response.insert("openSubsonic".to_owned(), json!(true));
+    response.extend(payload);
+    json!({ "subsonic-response": response })
+}
+
+fn respond(params: &Params, body: Value) -> HttpResponse {
+    if params.is_json() {
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body.to_string())
+    } else {
+        HttpResponse::Ok()
+            .content_type(ContentType::xml())
+            .body(xml::to_xml(&body))
+    }
+}
+
+/// A successful response, with the payload's keys in the envelope,
+/// e.g. `{"album": {..}}`
+pub fn ok(params: &Params, payload: Value) -> HttpResponse {
+    let payload = match strip_nulls(payload) {
+        Value::Object(map) => map,
+        _ => Map::new(),
+    };
+    respond(params, envelope("ok", payload))
+}
+
+/// Drop the missing optional fields, which some clients can't parse as null
+fn strip_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| (key, strip_nulls(value)))
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(strip_nulls).collect()),
+        value => value,
+    }
+}
+
+pub fn failed(params: &Params, err: SubsonicError) -> HttpResponse {
+    let mut payload = Map::new();
+    payload.insert(
+        "error".to_owned(),
+        json!({ "code": err.code, "message": err.message }),
+    );
+    respond(params, envelope("failed", payload))
+}
+
+/// Passwords of the Subsonic users, from `user:password` entries
+pub fn parse_users(entries: &[String]) -> Result<HashMap<String, String>, ServerError> {
+    entries
+        .iter()
+        .map(|entry| {
+            entry
+                .split_once(':')
+                .map(|(username, password)| (username.to_owned(), password.to_owned()))
+                .ok_or_else(|| {
+                    ServerError::ParamsError(format!(
+                        "Invalid Subsonic user {:?}, expected user:password",
+                        entry
+                    ))
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> Params {
+        Params(
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn salted_token() {
+        // The example of the Subsonic API documentation
+        let token = params(&[("t", "26719a1196d2a940705a59634eb18eab"), ("s", "c19b2d")]);
+        assert!(verify_password(&token, "sesame").unwrap());
+        assert!(!verify_password(&token, "sesam").unwrap());
+
+        let upper = params(&[("t", "26719A1196D2A940705A59634EB18EAB"), ("s", "c19b2d")]);
+        assert!(verify_password(&upper, "sesame").unwrap());
+    }
+
+    #[test]
+    fn plain_and_hex_encoded_password() {
+        assert!(verify_password(&params(&[("p", "sesame")]), "sesame").unwrap());
+        assert!(!verify_password(&params(&[("p", "sesame!")]), "sesame").unwrap());
+        assert!(verify_password(&params(&[("p", "enc:736573616d65")]), "sesame").unwrap());
+        assert!(!verify_password(&params(&[("p", "enc:zz")]), "sesame").unwrap());
+    }
+
+    #[test]
+    fn missing_credentials() {
+        let err = verify_password(&params(&[("u", "alice")]), "sesame").unwrap_err();
+        assert_eq!(err.code, 10);
+    }
+
+    #[test]
+    fn nulls_are_stripped() {
+        let value = strip_nulls(json!({ "a": null, "b": [{ "c": null, "d": 1 }] }));
+        assert_eq!(value, json!({ "b": [{ "d": 1 }] }));
+    }
+}
@@ -0,0 +1,375 @@
+//! Spotify objects as Subsonic entries
+//!
+//! The ids are the Spotify base62 ids, and `coverArt` ids encode the image url
+//! for `getCoverArt`.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rspotify::model::{
+    FullAlbum, FullArtist, FullPlaylist, FullTrack, Id, Image, PlayableItem, SimplifiedAlbum,
+    SimplifiedArtist, SimplifiedPlaylist, SimplifiedTrack, TrackId,
+};
+use serde_json::{json, Value};
+
+/// The `coverArt` id of the largest image
+pub fn cover_art(images: &[Image]) -> Option<String> {
+    images
+        .first()
+        .map(|image| URL_SAFE_NO_PAD.encode(image.url.as_bytes()))
+}
+
+/// The image url of a `coverArt` id
+pub fn cover_art_url(id: &str) -> Option<String> {
+    URL_SAFE_NO_PAD
+        .decode(id)
+        .ok()
+        .and_then(|url| String::from_utf8(url).ok())
+}
+
+fn artist_names(artists: &[SimplifiedArtist]) -> String {
+    artists
+        .iter()
+        .map(|artist| artist.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn first_artist_id(artists: &[SimplifiedArtist]) -> Option<&str> {
+    artists
+        .first()
+        .and_then(|artist| artist.id.as_ref())
+        .map(|id| id.id())
+}
+
+fn year(release_date: Option<&str>) -> Option<u32> {
+    release_date
+        .and_then(|date| date.get(..4))
+        .and_then(|y| y.parse().ok())
+}
+
+fn song_entry(
+    id: &str,
+    title: &str,
+    artists: &[SimplifiedArtist],
+    track_number: u32,
+    disc_number: i32,
+    duration: i64,
+    album: &SimplifiedAlbumRef,
+) -> Value {
+    json!({
+        "id": id,
+        "parent": album.id,
+        "isDir": false,
+        "title": title,
+        "album": album.name,
+        "albumId": album.id,
+        "artist": artist_names(artists),
+        "artistId": first_artist_id(artists),
+        "track": track_number,
+        "discNumber": disc_number,
+        "year": album.year,
+        "coverArt": album.cover_art,
+        "duration": duration,
+        "contentType": "audio/ogg",
+        "suffix": "ogg",
+        "type": "music",
+        "mediaType": "song",
+        "isVideo": false,
+    })
+}
+
+/// What songs show of their album
+struct SimplifiedAlbumRef<'a> {
+    id: Option<&'a str>,
+    name: &'a str,
+    year: Option<u32>,
+    cover_art: Option<String>,
+}
+
+pub fn song(track: &FullTrack) -> Option<Value> {
+    let id = track.id.as_ref()?;
+    let album = SimplifiedAlbumRef {
+        id: track.album.id.as_ref().map(|id| id.id()),
+        name: &track.album.name,
+        year: year(track.album.release_date.as_deref()),
+        cover_art: cover_art(&track.album.images),
+    };
+    Some(song_entry(
+        id.id(),
+        &track.name,
+        &track.artists,
+        track.track_number,
+        track.disc_number,
+        track.duration.num_seconds(),
+        &album,
+    ))
+}
+
+fn album_song(track: &SimplifiedTrack, album: &FullAlbum) -> Option<Value> {
+    let id = track.id.as_ref()?;
+    let album = SimplifiedAlbumRef {
+        id: Some(album.id.id()),
+        name: &album.name,
+        year: year(Some(&album.release_date)),
+        cover_art: cover_art(&album.images),
+    };
+    Some(song_entry(
+        id.id(),
+        &track.name,
+        &track.artists,
+        track.track_number,
+        track.disc_number,
+        track.duration.num_seconds(),
+        &album,
+    ))
+}
+
+pub fn album(album: &SimplifiedAlbum) -> Option<Value> {
+    let id = album.id.as_ref()?;
+    Some(json!({
+        "id": id.id(),
+        "name": album.name,
+        "title": album.name,
+        "isDir": true,
+        "artist": artist_names(&album.artists),
+        "artistId": first_artist_id(&album.artists),
+        "coverArt": cover_art(&album.images),
+        "year": year(album.release_date.as_deref()),
+    }))
+}
+
+/// An album with its songs
+pub fn full_album(album: &FullAlbum) -> Value {
+    let songs = album
+        .tracks
+        .items
+        .iter()
+        .filter_map(|track| album_song(track, album))
+        .collect::<Vec<_>>();
+    let duration: i64 = album
+        .tracks
+        .items
+        .iter()
+        .map(|track| track.duration.num_seconds())
+        .sum();
+    json!({
+        "id": album.id.id(),
+        "name": album.name,
+        "artist": artist_names(&album.artists),
+        "artistId": first_artist_id(&album.artists),
+        "coverArt": cover_art(&album.images),
+        "songCount": songs.len(),
+        "duration": duration,
+        "year": year(Some(&album.release_date)),
+        "genre": album.genres.first(),
+        "song": songs,
+    })
+}
+
+pub fn artist(artist: &FullArtist) -> Value {
+    json!({
+        "id": artist.id.id(),
+        "name": artist.name,
+        "coverArt": cover_art(&artist.images),
+    })
+}
+
+pub fn playlist(playlist: &SimplifiedPlaylist) -> Value {
+    json!({
+        "id": playlist.id.id(),
+        "name": playlist.name,
+        "owner": playlist.owner.id.id(),
+        "public": playlist.public.unwrap_or(false),
+        "songCount": playlist.tracks.total,
+        "coverArt": cover_art(&playlist.images),
+    })
+}
+
+/// A playlist with its songs, `items` are all the items of the playlist
+pub fn full_playlist(playlist: &FullPlaylist, items: &[FullTrack]) -> Value {
+    let entries = items.iter().filter_map(song).collect::<Vec<_>>();
+    let duration: i64 = items.iter().map(|track| track.duration.num_seconds()).sum();
+    json!({
+        "id": playlist.id.id(),
+        "name": playlist.name,
+        "comment": playlist.description,
+        "owner": playlist.owner.id.id(),
+        "public": playlist.public.unwrap_or(false),
+        "songCount": entries.len(),
+        "duration": duration,
+        "coverArt": cover_art(&playlist.images),
+        "entry": entries,
+    })
+}
+
+/// The tracks of playlist items, without episodes
+pub fn playlist_tracks(items: Vec<Option<PlayableItem>>) -> Vec<FullTrack> {
+    items
+        .into_iter()
+        .filter_map(|item| match item {
+            Some(PlayableItem::Track(track)) => Some(track),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The playlist positions of the `getPlaylist` entries at `indexes`, by track
+///
+/// The entries skip episodes, local files and unavailable items, so their
+/// indexes are not the positions of the playlist. `None` if an index is past
+/// the entries.
+pub fn entry_positions<'a>(
+    items: &'a [Option<PlayableItem>],
+    indexes: &[usize],
+) -> Option<Vec<(TrackId<'a>, Vec<u32>)>> {
+    let entries = items
+        .iter()
+        .enumerate()
+        .filter_map(|(position, item)| match item {
+            Some(PlayableItem::Track(FullTrack { id: Some(id), .. })) => Some((position, id)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let mut positions: Vec<(TrackId, Vec<u32>)> = vec![];
+    for index in indexes {
+        let (position, id) = entries.get(*index)?;
+        let position = *position as u32;
+        match positions.iter_mut().find(|(track_id, _)| track_id == *id) {
+            Some((_, track_positions)) => {
+                if !track_positions.contains(&position) {
+                    track_positions.push(position)
+                }
+            }
+            None => positions.push((id.as_ref(), vec![position])),
+        }
+    }
+    Some(positions)
+}
+
+/// Artists grouped by their initials, as `getArtists` lists them
+pub fn artist_index(artists: &[FullArtist]) -> Value {
+    let mut index: Vec<(String, Vec<Value>)> = vec![];
+    let mut sorted = artists.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|artist| artist.name.to_lowercase());
+    for artist in sorted {
+        let initial = artist
+            .name
+            .chars()
+            .next()
+            .filter(|c| c.is_alphabetic())
+            .map(|c| c.to_uppercase().to_string())
+            .unwrap_or_else(|| "#".to_owned());
+        match index.iter_mut().find(|(name, _)| *name == initial) {
+            Some((_, entries)) => entries.push(self::artist(artist)),
+            None => index.push((initial, vec![self::artist(artist)])),
+        }
+    }
+    let index = index
+        .into_iter()
+        .map(|(name, artists)| json!({ "name": name, "artist": artists }))
+        .collect::<Vec<_>>();
+    json!({ "ignoredArticles": "", "index": index })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(id: Option<&str>) -> Option<PlayableItem> {
+        serde_json::from_value(json!({
+            "album": {
+                "album_type": "album",
+                "artists": [],
+                "external_urls": {},
+                "href": null,
+                "id": null,
+                "images": [],
+                "name": "Album",
+            },
+            "artists": [],
+            "disc_number": 1,
+            "duration_ms": 180000,
+            "explicit": false,
+            "external_ids": {},
+            "external_urls": {},
+            "href": null,
+            "id": id,
+            "is_local": id.is_none(),
+            "name": "Track",
+            "popularity": 0,
+            "preview_url": null,
+            "track_number": 1,
+        }))
+        .unwrap()
+    }
+
+    fn episode() -> Option<PlayableItem> {
+        serde_json::from_value(json!({
+            "audio_preview_url": null,
+            "description": "",
+            "duration_ms": 1800000,
+            "explicit": false,
+            "external_urls": {},
+            "href": "https://api.spotify.com/v1/episodes/512ojhOuo1ktJprKbVcKyQ",
+            "id": "512ojhOuo1ktJprKbVcKyQ",
+            "images": [],
+            "is_externally_hosted": false,
+            "is_playable": true,
+            "language": "en",
+            "languages": ["en"],
+            "name": "Episode",
+            "release_date": "2023-01-01",
+            "release_date_precision": "day",
+            "resume_point": null,
+            "show": {
+                "available_markets": [],
+                "copyrights": [],
+                "description": "",
+                "explicit": false,
+                "external_urls": {},
+                "href": "https://api.spotify.com/v1/shows/38bS44xjbVVZ3No3ByF1dJ",
+                "id": "38bS44xjbVVZ3No3ByF1dJ",
+                "images": [],
+                "languages": ["en"],
+                "media_type": "audio",
+                "name": "Show",
+                "publisher": "Publisher",
+            },
+        }))
+        .unwrap()
+    }
+
+    const FIRST: &str = "4iV5W9uYEdYUVa79Axb7Rh";
+    const SECOND: &str = "1301WleyT98MSxVHPZCA6M";
+
+    #[test]
+    fn entry_positions_skip_episodes_and_local_files() {
+        let items = vec![
+            episode(),
+            track(Some(FIRST)),
+            track(None),
+            None,
+            track(Some(SECOND)),
+            track(Some(FIRST)),
+        ];
+        let entries = playlist_tracks(items.clone())
+            .iter()
+            .filter_map(song)
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 3);
+
+        // The second entry is the fifth item; the episode and local file stay
+        let positions = entry_positions(&items, &[1]).unwrap();
+        assert_eq!(positions.len(), 1);
+        assert_eq!(positions[0].0.id(), SECOND);
+        assert_eq!(positions[0].1, vec![4]);
+
+        // Each occurrence of a track removes its own position only
+        let positions = entry_positions(&items, &[2, 0, 2]).unwrap();
+        assert_eq!(positions.len(), 1);
+        assert_eq!(positions[0].0.id(), FIRST);
+        assert_eq!(positions[0].1, vec![5, 1]);
+
+        assert!(entry_positions(&items, &[3]).is_none());
+    }
+}
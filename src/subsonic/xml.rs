@@ -0,0 +1,115 @@
+//! The XML form of Subsonic responses
+//!
+//! Subsonic's JSON is a mapping of its XML: scalars are attributes, objects
+//! are child elements, and arrays are repeated child elements.
+
+use serde_json::Value;
+
+use crate::common::xml::escape;
+
+pub fn to_xml(value: &Value) -> String {
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    if let Value::Object(map) = value {
+        for (name, value) in map {
+            write_element(&mut xml, name, value, true);
+        }
+    }
+    xml
+}
+
+fn write_element(xml: &mut String, name: &str, value: &Value, root: bool) {
+    match value {
+        Value::Array(items) => {
+            for item in items {
+                write_element(xml, name, item, false);
+            }
+        }
+        Value::Object(map) => {
+            xml.push('<');
+            xml.push_str(name);
+            if root {
+                xml.push_str(r#" xmlns="http://subsonic.org/restapi""#);
+            }
+            for (key, value) in map {
+                if let Some(text) = scalar(value) {
+                    xml.push_str(&format!(r#" {}="{}""#, key, escape(&text)));
+                }
+            }
+            let children = map
+                .iter()
+                .filter(|(_, value)| value.is_object() || value.is_array())
+                .collect::<Vec<_>>();
+            if children.is_empty() {
+                xml.push_str("/>");
+            } else {
+                xml.push('>');
+                for (key, value) in children {
+                    write_element(xml, key, value, false);
+                }
+                xml.push_str(&format!("</{}>", name));
+            }
+        }
+        value => {
+            if let Some(text) = scalar(value) {
+                xml.push_str(&format!("<{0}>{1}</{0}>", name, escape(&text)));
+            }
+        }
+    }
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn scalars_are_attributes_and_arrays_repeat() {
+        let value = json!({
+            "subsonic-response": {
+                "status": "ok",
+                "album": {
+                    "id": "1",
+                    "name": "Tom & Jerry",
+                    "song": [{ "id": "a" }, { "id": "b" }],
+                },
+            }
+        });
+        assert_eq!(
+            to_xml(&value),
+            concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+                r#"<subsonic-response xmlns="http://subsonic.org/restapi" status="ok">"#,
+                r#"<album id="1" name="Tom &amp; Jerry"><song id="a"/><song id="b"/></album>"#,
+                r#"</subsonic-response>"#,
+            )
+        );
+    }
+
+    #[test]
+    fn empty_objects_and_scalar_children() {
+        let value = json!({ "subsonic-response": { "license": { "valid": true }, "genres": [] } });
+        assert_eq!(
+            to_xml(&value),
+            concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+                r#"<subsonic-response xmlns="http://subsonic.org/restapi">"#,
+                r#"<license valid="true"/>"#,
+                r#"</subsonic-response>"#,
+            )
+        );
+
+        let mut xml = String::new();
+        write_element(&mut xml, "value", &json!("<b>"), false);
+        assert_eq!(xml, "<value>&lt;b&gt;</value>");
+    }
+}
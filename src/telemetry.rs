@@ -0,0 +1,53 @@
+//! OpenTelemetry OTLP export of tracing spans
+//!
+//! It is built with the `otlp` cargo feature and enabled by `--otlp-endpoint`.
+
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    runtime,
+    trace::{Tracer, TracerProvider},
+    Resource,
+};
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+/// The tracer provider which flushes the remaining spans when it is dropped
+pub struct Telemetry {
+    provider: TracerProvider,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Err(err) = self.provider.shutdown() {
+            eprintln!("Failed to shut down OpenTelemetry: {:?}", err);
+        }
+    }
+}
+
+/// Build the tracing layer which exports spans to an OTLP gRPC collector
+pub fn otlp_layer<S>(
+    endpoint: &str,
+    service_name: &str,
+) -> Result<(OpenTelemetryLayer<S, Tracer>, Telemetry), Box<dyn std::error::Error>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            service_name.to_owned(),
+        )]))
+        .build();
+
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    opentelemetry::global::set_tracer_provider(provider.clone());
+    let layer = tracing_opentelemetry::layer().with_tracer(tracer);
+    Ok((layer, Telemetry { provider }))
+}
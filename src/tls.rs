@@ -0,0 +1,67 @@
+//! Native TLS termination with rustls
+//!
+//! It also serves an optional plain HTTP port which redirects to HTTPS.
+
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use actix_web::{http::header, web, App, HttpRequest, HttpResponse, HttpServer};
+
+use crate::errors::ServerError;
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// The plain HTTP port which redirects to HTTPS
+    pub redirect_port: Option<u16>,
+}
+
+/// Load a PEM certificate chain and private key
+pub fn load_rustls_config<P: AsRef<Path>>(
+    cert_path: P,
+    key_path: P,
+) -> Result<rustls::ServerConfig, ServerError> {
+    let cert_chain = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| ServerError::ParamsError("No private key in the TLS key file".to_owned()))?;
+
+    rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)
+        .map_err(|e| ServerError::ParamsError(format!("Invalid TLS certificate: {}", e)))
+}
+
+/// Serve HTTP on `port`, redirecting every request to HTTPS on `https_port`
+pub async fn redirect_to_https(bind: String, port: u16, https_port: u16) -> std::io::Result<()> {
+    HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(https_port))
+            .default_service(web::to(redirect))
+    })
+    .bind((bind.as_str(), port))?
+    .run()
+    .await
+}
+
+async fn redirect(req: HttpRequest, https_port: web::Data<u16>) -> HttpResponse {
+    let conn = req.connection_info();
+    let host = conn.host();
+    // Drop the port of the HTTP server, keeping IPv6 brackets
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => host,
+        _ => host,
+    };
+    let location = if **https_port == 443 {
+        format!("https://{}{}", host, req.uri())
+    } else {
+        format!("https://{}:{}{}", host, **https_port, req.uri())
+    };
+    HttpResponse::PermanentRedirect()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
@@ -0,0 +1,489 @@
+//! Outgoing webhooks
+//!
+//! Every event is POSTed as JSON to the `--webhook` targets which subscribe to
+//! its type. The body is signed with HMAC-SHA256 of `--webhook-secret`, in
+//! `X-Webhook-Signature: sha256={hex}`.
+//!
+//! Failed deliveries are retried with exponential backoff. The latest
+//! deliveries are kept for `GET /admin/webhooks/deliveries`.
+//!
+//! At most `MAX_IN_FLIGHT_DELIVERIES` are pending at once, so targets which
+//! are down can't pile up tasks; the deliveries beyond fail at once.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::sync::{broadcast::error::RecvError, OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    common::{crypto, hex},
+    events::{now_millis, Event, EventKind, EVENTS},
+};
+
+/// The backoff before the second attempt, doubled after each failure
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+/// A target which doesn't answer in time fails the attempt, which is retried
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Deliveries sent or waiting for a retry at once
+const MAX_IN_FLIGHT_DELIVERIES: usize = 256;
+
+/// Deliveries kept in the log
+const MAX_LOGGED_DELIVERIES: usize = 1000;
+
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    pub targets: Vec<String>,
+    pub secret: String,
+    /// The event types sent to the targets, all of them when empty
+    pub events: HashSet<EventKind>,
+    /// Attempts of a delivery before it fails
+    pub max_attempts: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    /// Waiting for its next attempt
+    Pending,
+    Delivered,
+    /// Out of attempts
+    Failed,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Delivery {
+    pub id: u64,
+    pub event_id: String,
+    pub event: EventKind,
+    pub url: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    /// The HTTP status of the last attempt
+    pub response_status: Option<u16>,
+    pub error: Option<String>,
+    /// Milliseconds since the epoch
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub next_attempt_at: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct Webhooks {
+    config: Arc<WebhookConfig>,
+    client: reqwest::Client,
+    deliveries: Arc<Mutex<VecDeque<Delivery>>>,
+    next_id: Arc<AtomicU64>,
+    in_flight: Arc<Semaphore>,
+    initial_backoff: Duration,
+}
+
+impl Webhooks {
+    pub fn new(config: WebhookConfig) -> Self {
+        Webhooks {
+            config: Arc::new(config),
+            client: reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("Failed to create the webhook HTTP client"),
+            deliveries: Arc::new(Mutex::new(VecDeque::new())),
+            next_id: Arc::new(AtomicU64::new(1)),
+            in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT_DELIVERIES)),
+            initial_backoff: INITIAL_BACKOFF,
+        }
+    }
+
+    /// Deliver the events, until the event bus closes
+    pub async fn run(self) {
+        let mut events = EVENTS.subscribe();
+        loop {
+            match events.recv().await {
+                Ok(event) => self.dispatch(event),
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Webhooks skipped {} events", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    fn dispatch(&self, event: Event) {
+        if !self.config.events.is_empty() && !self.config.events.contains(&event.kind) {
+            return;
+        }
+        let body = match serde_json::to_string(&event) {
+            Ok(body) => Arc::new(body),
+            Err(err) => {
+                tracing::warn!("Failed to serialize event {}: {}", event.id, err);
+                return;
+            }
+        };
+        let headers = event_headers(&event);
+        for url in &self.config.targets {
+            let id = self.log(&event, url);
+            let Ok(permit) = self.in_flight.clone().try_acquire_owned() else {
+                tracing::warn!("Webhook delivery {} to {} dropped", id, url);
+                self.update(id, |delivery| {
+                    delivery.status = DeliveryStatus::Failed;
+                    delivery.error = Some("Too many deliveries in flight".to_owned());
+                    delivery.next_attempt_at = None;
+                });
+                continue;
+            };
+            let webhooks = self.clone();
+            let (url, headers, body) = (url.clone(), headers.clone(), body.clone());
+            tokio::spawn(async move { webhooks.deliver(id, &url, &headers, &body, permit).await });
+        }
+    }
+
+    /// Attempt a delivery, with a backoff between attempts, holding its
+    /// in-flight permit until it is done
+    async fn deliver(
+        &self,
+        id: u64,
+        url: &str,
+        headers: &[(&str, String)],
+        body: &str,
+        _permit: OwnedSemaphorePermit,
+    ) {
+        let signature = format!(
+            "sha256={}",
+            hex::encode(&crypto::hmac_sha256(
+                self.config.secret.as_bytes(),
+                body.as_bytes()
+            ))
+            .to_lowercase()
+        );
+
+        let mut backoff = self.initial_backoff;
+        for attempt in 1..=self.config.max_attempts {
+            let mut request = self
+                .client
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header("X-Webhook-Signature", &signature)
+                .body(body.to_owned());
+            for (name, value) in headers {
+                request = request.header(*name, value);
+            }
+
+            let (response_status, error) = match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    self.update(id, |delivery| {
+                        delivery.status = DeliveryStatus::Delivered;
+                        delivery.attempts = attempt;
+                        delivery.response_status = Some(response.status().as_u16());
+                        delivery.error = None;
+                        delivery.next_attempt_at = None;
+                    });
+                    return;
+                }
+                Ok(response) => (Some(response.status().as_u16()), None),
+                Err(err) => (None, Some(err.to_string())),
+            };
+
+            let last = attempt == self.config.max_attempts;
+            self.update(id, |delivery| {
+                delivery.attempts = attempt;
+                delivery.response_status = response_status;
+                delivery.error = error;
+                if last {
+                    delivery.status = DeliveryStatus::Failed;
+                    delivery.next_attempt_at = None;
+                } else {
+                    delivery.next_attempt_at = Some(now_millis() + backoff.as_millis() as u64);
+                }
+            });
+            if last {
+                tracing::warn!("Webhook delivery {} to {} failed", id, url);
+                return;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Add a pending delivery of `event` to the log
+    fn log(&self, event: &Event, url: &str) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = now_millis();
+        let mut deliveries = self.deliveries.lock().unwrap();
+        if deliveries.len() == MAX_LOGGED_DELIVERIES {
+            deliveries.pop_front();
+        }
+        deliveries.push_back(Delivery {
+            id,
+            event_id: event.id.clone(),
+            event: event.kind,
+            url: url.to_owned(),
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            response_status: None,
+            error: None,
+            created_at: now,
+            updated_at: now,
+            next_attempt_at: Some(now),
+        });
+        id
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut Delivery)) {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        if let Some(delivery) = deliveries.iter_mut().rev().find(|d| d.id == id) {
+            f(delivery);
+            delivery.updated_at = now_millis();
+        }
+    }
+
+    /// The logged deliveries, the latest first
+    pub fn deliveries(&self, status: Option<DeliveryStatus>) -> Vec<Delivery> {
+        let deliveries = self.deliveries.lock().unwrap();
+        deliveries
+            .iter()
+            .rev()
+            .filter(|delivery| status.is_none_or(|status| delivery.status == status))
+            .cloned()
+            .collect()
+    }
+}
+
+/// The headers which tell the event apart without parsing the body
+fn event_headers(event: &Event) -> Vec<(&'static str, String)> {
+    vec![
+        ("X-Webhook-Event", event.kind.as_str().to_owned()),
+        ("X-Webhook-Id", event.id.clone()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, web, App, HttpRequest, HttpResponse, HttpServer};
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct Request {
+        signature: String,
+        event: String,
+        body: String,
+    }
+
+    /// A local target which answers with the statuses in turn, repeating the last
+    struct MockTarget {
+        url: String,
+        requests: Arc<Mutex<Vec<Request>>>,
+    }
+
+    impl MockTarget {
+        fn start(statuses: &[u16]) -> Self {
+            let requests = Arc::new(Mutex::new(vec![]));
+            let statuses = Arc::new(Mutex::new(VecDeque::from(statuses.to_vec())));
+            let recorded = requests.clone();
+            let server = HttpServer::new(move || {
+                let (requests, statuses) = (recorded.clone(), statuses.clone());
+                App::new().default_service(web::to(move |req: HttpRequest, body: String| {
+                    let (requests, statuses) = (requests.clone(), statuses.clone());
+                    async move {
+                        let header = |name| {
+                            req.headers()
+                                .get(name)
+                                .map(|value| value.to_str().unwrap().to_owned())
+                                .unwrap_or_default()
+                        };
+                        requests.lock().unwrap().push(Request {
+                            signature: header("X-Webhook-Signature"),
+                            event: header("X-Webhook-Event"),
+                            body,
+                        });
+                        let mut statuses = statuses.lock().unwrap();
+                        let status = match statuses.len() {
+                            1 => statuses[0],
+                            _ => statuses.pop_front().unwrap(),
+                        };
+                        HttpResponse::build(StatusCode::from_u16(status).unwrap()).finish()
+                    }
+                }))
+            })
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+            let url = format!("http://{}/hook", server.addrs()[0]);
+            actix_web::rt::spawn(server.run());
+            MockTarget { url, requests }
+        }
+
+        fn requests(&self) -> std::sync::MutexGuard<'_, Vec<Request>> {
+            self.requests.lock().unwrap()
+        }
+    }
+
+    fn webhooks(url: &str, events: &[EventKind], max_attempts: u32) -> Webhooks {
+        let mut webhooks = Webhooks::new(WebhookConfig {
+            targets: vec![url.to_owned()],
+            secret: "secret".to_owned(),
+            events: events.iter().copied().collect(),
+            max_attempts,
+        });
+        webhooks.initial_backoff = Duration::from_millis(10);
+        webhooks
+    }
+
+    fn event(kind: EventKind) -> Event {
+        Event {
+            id: format!("{:016x}", rand::random::<u64>()),
+            kind,
+            username: "alice".to_owned(),
+            timestamp: now_millis(),
+            data: json!({ "uri": "spotify:track:4uLU6hMCjMI75M1A2tKUQC" }),
+        }
+    }
+
+    /// Wait until no delivery is pending
+    async fn settled(webhooks: &Webhooks) {
+        for _ in 0..500 {
+            if webhooks
+                .deliveries(Some(DeliveryStatus::Pending))
+                .is_empty()
+            {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Deliveries are still pending");
+    }
+
+    #[actix_web::test]
+    async fn signed_body() {
+        let target = MockTarget::start(&[200]);
+        let webhooks = webhooks(&target.url, &[], 1);
+        let event = event(EventKind::StreamStarted);
+        webhooks.dispatch(event.clone());
+        settled(&webhooks).await;
+
+        let requests = target.requests();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!(request.event, "stream.started");
+        let body = serde_json::from_str::<serde_json::Value>(&request.body).unwrap();
+        assert_eq!(body["id"], event.id);
+        assert_eq!(body["type"], "stream.started");
+
+        let signature = request.signature.strip_prefix("sha256=").unwrap();
+        assert_eq!(signature, signature.to_lowercase());
+        let signature = hex::decode(signature).unwrap();
+        assert!(crypto::verify_hmac_sha256(
+            b"secret",
+            request.body.as_bytes(),
+            &signature
+        ));
+        assert!(!crypto::verify_hmac_sha256(
+            b"other",
+            request.body.as_bytes(),
+            &signature
+        ));
+
+        let delivery = &webhooks.deliveries(None)[0];
+        assert_eq!(delivery.status, DeliveryStatus::Delivered);
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.response_status, Some(200));
+    }
+
+    #[actix_web::test]
+    async fn subscribed_events_only() {
+        let target = MockTarget::start(&[200]);
+        let webhooks = webhooks(&target.url, &[EventKind::Login, EventKind::Logout], 1);
+        webhooks.dispatch(event(EventKind::StreamStarted));
+        webhooks.dispatch(event(EventKind::Login));
+        settled(&webhooks).await;
+
+        let deliveries = webhooks.deliveries(None);
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].event, EventKind::Login);
+        let requests = target.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].event, "login");
+    }
+
+    #[actix_web::test]
+    async fn retries_until_failed() {
+        let target = MockTarget::start(&[500]);
+        let webhooks = webhooks(&target.url, &[], 3);
+        webhooks.dispatch(event(EventKind::Login));
+        settled(&webhooks).await;
+
+        assert_eq!(target.requests().len(), 3);
+        let delivery = &webhooks.deliveries(None)[0];
+        assert_eq!(delivery.status, DeliveryStatus::Failed);
+        assert_eq!(delivery.attempts, 3);
+        assert_eq!(delivery.response_status, Some(500));
+        assert_eq!(delivery.next_attempt_at, None);
+
+        // A retry which succeeds delivers it
+        let target = MockTarget::start(&[503, 200]);
+        let webhooks = self::webhooks(&target.url, &[], 3);
+        webhooks.dispatch(event(EventKind::Login));
+        settled(&webhooks).await;
+        let delivery = &webhooks.deliveries(None)[0];
+        assert_eq!(delivery.status, DeliveryStatus::Delivered);
+        assert_eq!(delivery.attempts, 2);
+    }
+
+    #[actix_web::test]
+    async fn deliveries_latest_first() {
+        let target = MockTarget::start(&[200, 500, 200]);
+        let webhooks = webhooks(&target.url, &[], 1);
+        for kind in [
+            EventKind::Login,
+            EventKind::Logout,
+            EventKind::StreamStarted,
+        ] {
+            webhooks.dispatch(event(kind));
+            settled(&webhooks).await;
+        }
+
+        let ids = |status| {
+            webhooks
+                .deliveries(status)
+                .iter()
+                .map(|delivery| delivery.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(None), vec![3, 2, 1]);
+        assert_eq!(ids(Some(DeliveryStatus::Delivered)), vec![3, 1]);
+        assert_eq!(ids(Some(DeliveryStatus::Failed)), vec![2]);
+        assert!(ids(Some(DeliveryStatus::Pending)).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn in_flight_deliveries_are_capped() {
+        let target = MockTarget::start(&[200]);
+        let mut webhooks = webhooks(&target.url, &[], 1);
+        webhooks.in_flight = Arc::new(Semaphore::new(1));
+        let permit = webhooks.in_flight.clone().try_acquire_owned().unwrap();
+        webhooks.dispatch(event(EventKind::Login));
+
+        let delivery = &webhooks.deliveries(None)[0];
+        assert_eq!(delivery.status, DeliveryStatus::Failed);
+        assert_eq!(delivery.attempts, 0);
+
+        drop(permit);
+        webhooks.dispatch(event(EventKind::Logout));
+        settled(&webhooks).await;
+        assert_eq!(target.requests().len(), 1);
+        assert_eq!(
+            webhooks.deliveries(None)[0].status,
+            DeliveryStatus::Delivered
+        );
+    }
+}